@@ -0,0 +1,84 @@
+//! Temperature and humidity sensors attached to plugs through the Shelly Plus Add-On. The config
+//! file lists the component ids of the sensors per plug, as the plugs don't tell which are wired.
+
+use std::collections::HashMap;
+
+use crate::client::ShellySmartPlug;
+use crate::config::AddonSensorConfig;
+use crate::metrics::MetricFamily;
+use crate::status::AddonReading;
+
+
+#[derive(Debug)]
+pub struct AddonSensors {
+    /// Keyed by IP or alias, like the config file
+    sensors: HashMap<String, AddonSensorConfig>,
+}
+
+impl AddonSensors {
+    /// `None` when no sensors are configured
+    pub fn new(sensors: HashMap<String, AddonSensorConfig>) -> Option<AddonSensors> {
+        match sensors.is_empty() {
+            true => None,
+            false => Some(AddonSensors { sensors }),
+        }
+    }
+
+    /// Sensors of the plug, an alias entry wins over the IP one
+    pub fn sensors_of(&self, plug: &ShellySmartPlug) -> Option<&AddonSensorConfig> {
+        self.sensors.get(&plug.alias).or_else(|| self.sensors.get(&plug.target()))
+    }
+}
+
+
+/// Readings of the add-on sensors, disconnected sensors are left out
+pub fn collect(readings: &[(ShellySmartPlug, AddonReading)]) -> Vec<MetricFamily> {
+    let mut temperature = MetricFamily::gauge(
+        "shelly_addon_temperature_celsius",
+        "Temperature of the add-on sensor in celsius"
+    );
+    let mut humidity = MetricFamily::gauge(
+        "shelly_addon_humidity_percent",
+        "Relative humidity of the add-on sensor in percent"
+    );
+
+    for (plug, reading) in readings {
+        for sensor in &reading.temperatures {
+            if let Some(celsius) = sensor.celsius {
+                temperature.push(sensor_labels(plug, sensor.id), celsius);
+            }
+        }
+        for sensor in &reading.humidities {
+            if let Some(rh) = sensor.rh {
+                humidity.push(sensor_labels(plug, sensor.id), rh);
+            }
+        }
+    }
+
+    vec![temperature, humidity]
+}
+
+fn sensor_labels(plug: &ShellySmartPlug, id: u16) -> Vec<(String, String)> {
+    let mut labels = plug.metric_labels();
+    labels.push(("sensor".to_string(), id.to_string()));
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sensors_of() {
+        let sensors = AddonSensors::new(HashMap::from([
+            ("10.0.0.2".to_string(), AddonSensorConfig { temperature: vec![100], humidity: vec![] }),
+            ("kettle".to_string(), AddonSensorConfig { temperature: vec![101], humidity: vec![100] }),
+        ])).unwrap();
+
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        assert_eq!(sensors.sensors_of(&kettle).unwrap().temperature, vec![101]);
+        assert_eq!(sensors.sensors_of(&ShellySmartPlug::from_address("10.0.0.2").unwrap()).unwrap().temperature, vec![100]);
+        assert!(sensors.sensors_of(&ShellySmartPlug::from_address("10.0.0.3").unwrap()).is_none());
+        assert!(AddonSensors::new(HashMap::new()).is_none());
+    }
+}
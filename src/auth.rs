@@ -0,0 +1,274 @@
+//! Optional scrape authentication, either HTTP basic auth against a bcrypt hash or a static
+//! bearer token. Enforced for every route by the [`require_auth`] middleware. Tenants can have a
+//! token of their own, which only grants access to their `/metrics/<tenant>`. Tenant tokens require
+//! scrape auth, or every other route would serve the devices of the tenant without one.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{error, warn};
+
+
+const REALM: &str = "shelly_smartplug_exporter";
+
+
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    basic: Option<(String, String)>,
+    bearer_token: Option<String>,
+    /// Bearer tokens keyed by tenant
+    tenant_tokens: HashMap<String, String>,
+    // bcrypt is deliberately slow, remember credentials which already passed verification so
+    // every scrape doesn't pay that cost again
+    verified: Mutex<HashSet<String>>,
+}
+
+impl Authenticator {
+    /// Allow everything through
+    pub fn disabled() -> Authenticator {
+        Authenticator::default()
+    }
+
+    pub fn new(basic: Option<(String, String)>, bearer_token: Option<String>) -> Authenticator {
+        Authenticator { basic, bearer_token, tenant_tokens: HashMap::new(), verified: Mutex::new(HashSet::new()) }
+    }
+
+    pub fn with_tenant_tokens(self, tenant_tokens: HashMap<String, String>) -> Authenticator {
+        Authenticator { tenant_tokens, ..self }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.basic.is_some() || self.bearer_token.is_some()
+    }
+
+    /// Check the value of an `Authorization` header
+    pub fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+
+        let authorization = match authorization {
+            Some(value) => value.trim(),
+            None => return false,
+        };
+
+        if let (Some(token), Some(given)) = (&self.bearer_token, authorization.strip_prefix("Bearer ")) {
+            return constant_time_eq(token.as_bytes(), given.trim().as_bytes());
+        }
+
+        if let (Some((user, hash)), Some(encoded)) = (&self.basic, authorization.strip_prefix("Basic ")) {
+            let decoded = match STANDARD.decode(encoded.trim()).ok().and_then(|raw| String::from_utf8(raw).ok()) {
+                Some(decoded) => decoded,
+                None => return false,
+            };
+
+            if self.verified.lock().unwrap().contains(&decoded) {
+                return true;
+            }
+
+            let (given_user, given_password) = match decoded.split_once(':') {
+                Some(parts) => parts,
+                None => return false,
+            };
+            if !constant_time_eq(user.as_bytes(), given_user.as_bytes()) {
+                return false;
+            }
+
+            return match bcrypt::verify(given_password, hash) {
+                Ok(true) => {
+                    self.verified.lock().unwrap().insert(decoded);
+                    true
+                }
+                Ok(false) => false,
+                Err(err) => {
+                    error!("Unable to verify password against the configured hash - {err}");
+                    false
+                }
+            };
+        }
+
+        false
+    }
+
+    /// `is_authorized`, plus the token of the tenant when serving its `/metrics/<tenant>`. A tenant
+    /// with a token is never served without credentials, even when scrape auth is disabled.
+    pub fn is_authorized_for(&self, tenant: Option<&str>, authorization: Option<&str>) -> bool {
+        let tenant_token = tenant.and_then(|tenant| self.tenant_tokens.get(tenant));
+        let Some(token) = tenant_token else { return self.is_authorized(authorization) };
+
+        let given = authorization.and_then(|value| value.trim().strip_prefix("Bearer "));
+        if given.is_some_and(|given| constant_time_eq(token.as_bytes(), given.trim().as_bytes())) {
+            return true;
+        }
+        self.is_enabled() && self.is_authorized(authorization)
+    }
+
+    /// `401` asking for the configured credentials
+    pub fn unauthorized(&self) -> HttpResponse {
+        let challenge = match self.basic {
+            Some(_) => format!("Basic realm=\"{REALM}\""),
+            None => format!("Bearer realm=\"{REALM}\""),
+        };
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, challenge))
+            .body("Unauthorized")
+    }
+}
+
+
+/// Middleware rejecting requests which don't carry valid credentials. Expects the
+/// [`Authenticator`] to be registered as app data, requests pass through if it isn't.
+pub async fn require_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let authenticator = match req.app_data::<web::Data<Authenticator>>() {
+        Some(authenticator) => authenticator.clone(),
+        None => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+    };
+
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    // Routing hasn't happened yet, decode the segment like the router does, so `/metrics/home%2Da`
+    // is checked against the token of `home-a` it's served as
+    let tenant = req.path().strip_prefix("/metrics/").and_then(decode_segment);
+    if authenticator.is_authorized_for(tenant.as_deref(), authorization) {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    warn!("Rejected unauthenticated request to `{}` from {}",
+        req.path(), req.connection_info().realip_remote_addr().unwrap_or("unknown"));
+    let response = authenticator.unauthorized();
+
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// A path segment with its `%XX` escapes decoded, `None` if it has invalid ones
+fn decode_segment(raw: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(raw.len());
+    let mut bytes = raw.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let escape = [bytes.next()?, bytes.next()?];
+        if !escape.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&escape).ok()?, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{get, middleware::from_fn, App, Responder};
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+    }
+
+    #[test]
+    fn test_disabled_allows_everything() {
+        let auth = Authenticator::disabled();
+
+        assert!(!auth.is_enabled());
+        assert!(auth.is_authorized(None));
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let auth = Authenticator::new(Some(("prometheus".to_string(), hash)), None);
+
+        assert!(!auth.is_authorized(None));
+        assert!(!auth.is_authorized(Some(&basic("prometheus", "wrong"))));
+        assert!(!auth.is_authorized(Some(&basic("someone", "hunter2"))));
+        assert!(!auth.is_authorized(Some("Basic not-base64!")));
+        assert!(!auth.is_authorized(Some("Bearer hunter2")));
+        assert!(auth.is_authorized(Some(&basic("prometheus", "hunter2"))));
+
+        // Second time around is served from the verified cache
+        assert!(auth.is_authorized(Some(&basic("prometheus", "hunter2"))));
+        assert_eq!(auth.verified.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let auth = Authenticator::new(None, Some("s3cret".to_string()));
+
+        assert!(!auth.is_authorized(Some("Bearer wrong")));
+        assert!(!auth.is_authorized(Some(&basic("s3cret", "s3cret"))));
+        assert!(auth.is_authorized(Some("Bearer s3cret")));
+    }
+
+    #[test]
+    fn test_tenant_tokens() {
+        let tenant_tokens = HashMap::from([("home-a".to_string(), "a-token".to_string())]);
+        let auth = Authenticator::new(None, Some("s3cret".to_string())).with_tenant_tokens(tenant_tokens.clone());
+
+        assert!(auth.is_authorized_for(Some("home-a"), Some("Bearer a-token")));
+        assert!(auth.is_authorized_for(Some("home-a"), Some("Bearer s3cret")));
+        assert!(!auth.is_authorized_for(None, Some("Bearer a-token")));
+        assert!(!auth.is_authorized_for(Some("home-b"), Some("Bearer a-token")));
+
+        // Refused at startup, but the tenant stays protected without scrape auth
+        let auth = Authenticator::disabled().with_tenant_tokens(tenant_tokens);
+        assert!(!auth.is_authorized_for(Some("home-a"), None));
+        assert!(!auth.is_authorized_for(Some("home-a"), Some("Bearer wrong")));
+        assert!(auth.is_authorized_for(Some("home-a"), Some("Bearer a-token")));
+        assert!(auth.is_authorized_for(None, None));
+    }
+
+    #[test]
+    fn test_decode_segment() {
+        assert_eq!(decode_segment("home-a").as_deref(), Some("home-a"));
+        assert_eq!(decode_segment("home%2Da").as_deref(), Some("home-a"));
+        assert_eq!(decode_segment("%68ome%2da").as_deref(), Some("home-a"));
+        assert_eq!(decode_segment("home%2"), None);
+        assert_eq!(decode_segment("home%+1a"), None);
+        assert_eq!(decode_segment("%ff"), None);
+    }
+
+    #[get("/metrics")]
+    async fn fake_metrics() -> impl Responder {
+        "ok"
+    }
+
+    #[actix_web::test]
+    async fn test_middleware() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Authenticator::new(None, Some("s3cret".to_string()))))
+                .service(fake_metrics)
+                .wrap(from_fn(require_auth))
+        ).await;
+
+        let rejected = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(rejected.status(), 401);
+        assert_eq!(rejected.headers().get(header::WWW_AUTHENTICATE).unwrap(), "Bearer realm=\"shelly_smartplug_exporter\"");
+
+        let accepted = call_service(&app, TestRequest::get()
+            .uri("/metrics")
+            .insert_header((header::AUTHORIZATION, "Bearer s3cret"))
+            .to_request()
+        ).await;
+        assert_eq!(accepted.status(), 200);
+    }
+}
@@ -0,0 +1,187 @@
+//! Latest known status per plug, fed by polling as well as device push notifications.
+//!
+//! Shelly devices only include the fields which changed in `NotifyStatus` events, so the raw JSON
+//! of each plug is kept around and patches are merged into it before re-parsing.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde_json::Value;
+
+use crate::client::ShellySmartPlug;
+use crate::status::SwitchStatus;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedStatus {
+    pub raw: Value,
+    /// `None` until enough fields were received to form a full status
+    pub status: Option<SwitchStatus>,
+    pub updated_at: DateTime<Utc>,
+}
+
+
+#[derive(Debug, Default)]
+pub struct ReadingCache {
+    entries: RwLock<HashMap<String, CachedStatus>>,
+}
+
+impl ReadingCache {
+    pub fn new() -> ReadingCache {
+        ReadingCache::default()
+    }
+
+    /// Replace the status of a plug with a freshly polled one
+    pub fn update(&self, alias: &str, status: &SwitchStatus) {
+        let raw = serde_json::to_value(status).expect("Statuses are always serializable");
+        self.entries.write().unwrap().insert(alias.to_string(), CachedStatus {
+            raw,
+            status: Some(status.clone()),
+            updated_at: Utc::now(),
+        });
+    }
+
+    pub fn update_all(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) {
+        for (plug, status) in readings {
+            self.update(&plug.alias, status);
+        }
+    }
+
+    /// Merge a partial status into the cached one. Returns the merged status, if complete.
+    pub fn apply_patch(&self, alias: &str, patch: &Value) -> Option<SwitchStatus> {
+        let mut entries = self.entries.write().unwrap();
+        let entry = entries.entry(alias.to_string()).or_insert(CachedStatus {
+            raw: Value::Object(Default::default()),
+            status: None,
+            updated_at: Utc::now(),
+        });
+
+        merge(&mut entry.raw, patch);
+        entry.updated_at = Utc::now();
+        match serde_json::from_value::<SwitchStatus>(entry.raw.clone()) {
+            Ok(status) => entry.status = Some(status),
+            Err(err) if entry.status.is_some() => {
+                warn!("Discarding patch for `{alias}` which made its status invalid - {err}");
+            }
+            // Still waiting on the rest of the fields
+            Err(_) => {}
+        }
+
+        entry.status.clone()
+    }
+
+    pub fn get(&self, alias: &str) -> Option<CachedStatus> {
+        self.entries.read().unwrap().get(alias).cloned()
+    }
+
+    /// When the plug's entry last changed, `None` if it has none
+    pub fn updated_at(&self, alias: &str) -> Option<DateTime<Utc>> {
+        self.entries.read().unwrap().get(alias).map(|cached| cached.updated_at)
+    }
+
+    /// Cached statuses of the given plugs, plugs without a complete status are left out
+    pub fn readings(&self, plugs: &[ShellySmartPlug]) -> Vec<(ShellySmartPlug, SwitchStatus)> {
+        let entries = self.entries.read().unwrap();
+        plugs.iter()
+            .filter_map(|plug| {
+                let status = entries.get(&plug.alias)?.status.clone()?;
+                Some((plug.clone(), status))
+            })
+            .collect()
+    }
+}
+
+
+/// Recursively merge `patch` into `target`, objects are merged key by key and anything else is replaced
+pub fn merge(target: &mut Value, patch: &Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::status::{EnergyCounter, Temperature};
+
+    fn status() -> SwitchStatus {
+        SwitchStatus {
+            id: 0,
+            output: Some(true),
+            apower: 1.0,
+            voltage: 2.0,
+            current: 3.0,
+            temperature: Temperature { celsius: 20.1, fahrenheit: 68.2 },
+            aenergy: EnergyCounter { total: 10.0, by_minute: vec![], minute_ts: None },
+            sys: None,
+            wifi: None,
+            freq: None,
+            pf: None,
+        }
+    }
+
+    fn plug(alias: &str) -> ShellySmartPlug {
+        ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: alias.to_string(), labels: vec![] }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut target = json!({ "a": 1, "b": { "c": 2, "d": 3 } });
+
+        merge(&mut target, &json!({ "b": { "c": 5 }, "e": [1] }));
+
+        assert_eq!(target, json!({ "a": 1, "b": { "c": 5, "d": 3 }, "e": [1] }));
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let cache = ReadingCache::new();
+        cache.update("kitchen", &status());
+
+        let patched = cache.apply_patch("kitchen", &json!({ "apower": 42.0, "aenergy": { "total": 11.5 } })).unwrap();
+
+        assert_eq!(patched.apower, 42.0);
+        assert_eq!(patched.aenergy.total, 11.5);
+        assert_eq!(patched.voltage, 2.0);
+        assert_eq!(cache.readings(&[plug("kitchen")])[0].1, patched);
+    }
+
+    #[test]
+    fn test_apply_patch_to_unknown_plug() {
+        let cache = ReadingCache::new();
+
+        // Not enough to form a full status yet
+        assert_eq!(cache.apply_patch("new", &json!({ "apower": 42.0 })), None);
+        assert!(cache.readings(&[plug("new")]).is_empty());
+
+        let full = serde_json::to_value(status()).unwrap();
+        assert_eq!(cache.apply_patch("new", &full), Some(status()));
+    }
+
+    #[test]
+    fn test_invalid_patch_keeps_last_status() {
+        let cache = ReadingCache::new();
+        cache.update("kitchen", &status());
+
+        assert_eq!(cache.apply_patch("kitchen", &json!({ "apower": "lots" })), Some(status()));
+    }
+
+    #[test]
+    fn test_readings_skip_missing_plugs() {
+        let cache = ReadingCache::new();
+        cache.update_all(&[(plug("a"), status())]);
+
+        let actual = cache.readings(&[plug("a"), plug("b")]);
+
+        assert_eq!(actual.len(), 1);
+        assert_eq!(actual[0].0.alias, "a");
+        assert!(cache.get("b").is_none());
+    }
+}
@@ -0,0 +1,233 @@
+//! What each device can measure, detected from `Shelly.GetDeviceInfo` when the plug is registered
+//! and retried for plugs which couldn't be asked. Metrics a device doesn't support are left out
+//! instead of exported as zeros, e.g. the voltage of Gen1 plugs fed by CoIoT. The grid frequency
+//! and power factor are taken from the readings, they're only there when the device reports them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use futures_util::future::join_all;
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::client::{ShellyClient, ShellySmartPlug};
+use crate::error::ShellyError;
+use crate::metrics::MetricFamily;
+use crate::registry::PlugRegistry;
+use crate::status::{DeviceInfo, SwitchStatus};
+
+
+/// How often plugs which couldn't be asked at registration are asked again
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Capabilities {
+    /// `1` for devices without `Shelly.GetDeviceInfo`
+    pub generation: u8,
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+    pub voltage: bool,
+    pub current: bool,
+    /// Whether the latest reading had the grid frequency, `false` until a reading was seen
+    pub frequency: bool,
+    /// Whether the latest reading had the power factor, `false` until a reading was seen
+    pub power_factor: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of a device from its answer to `Shelly.GetDeviceInfo`, `None` when the device
+    /// couldn't be asked. Only Gen1 devices answer 404, they have no RPC interface.
+    pub fn detect(info: &Result<DeviceInfo, ShellyError>) -> Option<Capabilities> {
+        match info {
+            Ok(info) => Some(Capabilities {
+                generation: info.generation.unwrap_or(2),
+                model: info.model.clone(),
+                firmware: info.firmware.clone(),
+                voltage: true,
+                current: true,
+                frequency: false,
+                power_factor: false,
+            }),
+            Err(ShellyError::Status { status: 404, .. }) => Some(Capabilities {
+                generation: 1,
+                model: None,
+                firmware: None,
+                voltage: false,
+                current: false,
+                frequency: false,
+                power_factor: false,
+            }),
+            Err(_) => None,
+        }
+    }
+
+    /// Names of the plug families the device can't fill. Families of optional fields are already
+    /// left out by `exporter::collect` when a reading doesn't have them.
+    fn unsupported_families(&self) -> Vec<&'static str> {
+        [
+            (self.voltage, "voltage"),
+            (self.current, "current_amps"),
+        ].into_iter().filter(|(supported, _)| !supported).map(|(_, family)| family).collect()
+    }
+}
+
+
+/// Capabilities of every registered plug, keyed by alias. Plugs which couldn't be asked export
+/// every metric.
+#[derive(Debug, Default)]
+pub struct DeviceCapabilities {
+    entries: RwLock<HashMap<String, Capabilities>>,
+}
+
+impl DeviceCapabilities {
+    pub fn new() -> DeviceCapabilities {
+        DeviceCapabilities::default()
+    }
+
+    /// Ask every plug concurrently
+    pub async fn detect_all(&self, client: &ShellyClient, plugs: &[ShellySmartPlug]) {
+        join_all(plugs.iter().map(|plug| self.detect(client, plug))).await;
+    }
+
+    /// Ask the plugs which couldn't be asked before again
+    pub async fn detect_missing(&self, client: &ShellyClient, plugs: &[ShellySmartPlug]) {
+        let missing: Vec<ShellySmartPlug> = {
+            let entries = self.entries.read().unwrap();
+            plugs.iter().filter(|plug| !entries.contains_key(&plug.alias)).cloned().collect()
+        };
+        self.detect_all(client, &missing).await;
+    }
+
+    pub async fn detect(&self, client: &ShellyClient, plug: &ShellySmartPlug) {
+        let info = client.get_device_info(plug).await;
+        match Capabilities::detect(&info) {
+            Some(capabilities) => {
+                info!("Detected `{}` as a Gen{} device", plug.alias, capabilities.generation);
+                self.entries.write().unwrap().insert(plug.alias.clone(), capabilities);
+            }
+            None => debug!("Unable to detect the capabilities of `{}`, exporting every metric", plug.alias),
+        }
+    }
+
+    pub fn get(&self, alias: &str) -> Option<Capabilities> {
+        self.entries.read().unwrap().get(alias).cloned()
+    }
+
+    pub fn forget(&self, alias: &str) {
+        self.entries.write().unwrap().remove(alias);
+    }
+
+    /// Note the optional fields of the readings and drop the samples of the plugs from the families
+    /// they don't support. Must run before the configured naming is applied.
+    pub fn strip(&self, readings: &[(ShellySmartPlug, SwitchStatus)], families: &mut [MetricFamily]) {
+        let mut entries = self.entries.write().unwrap();
+        for (plug, status) in readings {
+            let Some(capabilities) = entries.get_mut(&plug.alias) else { continue };
+            capabilities.frequency = status.freq.is_some();
+            capabilities.power_factor = status.pf.is_some();
+            let unsupported = capabilities.unsupported_families();
+            if unsupported.is_empty() {
+                continue;
+            }
+
+            let labels = plug.metric_labels();
+            for family in families.iter_mut().filter(|family| unsupported.contains(&family.name.as_str())) {
+                family.samples.retain(|sample| sample.labels != labels);
+            }
+        }
+    }
+}
+
+
+/// Keep asking the plugs which couldn't be asked yet, e.g. because they were offline at startup
+pub async fn run(capabilities: Arc<DeviceCapabilities>, client: ShellyClient, plugs: Arc<PlugRegistry>) {
+    let mut ticker = tokio::time::interval(RETRY_INTERVAL);
+    // The first tick completes right away, the plugs were just asked at registration
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        capabilities.detect_missing(&client, &plugs.snapshot()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter;
+    use serde_json::json;
+
+    fn info(firmware: &str) -> Result<DeviceInfo, ShellyError> {
+        Ok(serde_json::from_value(json!({
+            "id": "shellyplusplugs-80646fd17d72",
+            "model": "SNPL-00112EU",
+            "gen": 2,
+            "ver": firmware,
+        })).unwrap())
+    }
+
+    #[test]
+    fn test_detect() {
+        let gen2 = Capabilities::detect(&info("1.4.4")).unwrap();
+        assert_eq!(gen2.generation, 2);
+        assert_eq!(gen2.firmware.as_deref(), Some("1.4.4"));
+        assert!(gen2.unsupported_families().is_empty());
+
+        let gen1 = Capabilities::detect(&Err(ShellyError::from_status("lamp", 404))).unwrap();
+        assert_eq!(gen1.generation, 1);
+        assert!(!gen1.voltage);
+        assert_eq!(Capabilities::detect(&Err(ShellyError::Timeout { plug: "lamp".to_string() })), None);
+    }
+
+    #[test]
+    fn test_strip() {
+        let capabilities = DeviceCapabilities::new();
+        let gen1 = Capabilities::detect(&Err(ShellyError::from_status("lamp", 404))).unwrap();
+        capabilities.entries.write().unwrap().insert("lamp".to_string(), gen1);
+        let status: SwitchStatus = serde_json::from_value(json!({
+            "apower": 5.0,
+            "voltage": 0.0,
+            "current": 0.0,
+            "temperature": { "tC": 30.0, "tF": 86.0 },
+            "aenergy": { "total": 1.0 },
+        })).unwrap();
+        let lamp = ShellySmartPlug { alias: "lamp".to_string(), ..ShellySmartPlug::from_address("10.0.0.7").unwrap() };
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let readings = vec![(lamp, status.clone()), (kettle, status)];
+        let mut families = exporter::collect(&readings);
+
+        capabilities.strip(&readings, &mut families);
+
+        let voltage = families.iter().find(|family| family.name == "voltage").unwrap();
+        assert_eq!(voltage.samples.len(), 1);
+        assert_eq!(voltage.samples[0].labels[0].1, "kettle");
+        let power = families.iter().find(|family| family.name == "power_watts").unwrap();
+        assert_eq!(power.samples.len(), 2);
+    }
+
+    #[test]
+    fn test_strip_notes_optional_fields() {
+        let capabilities = DeviceCapabilities::new();
+        capabilities.entries.write().unwrap().insert("kettle".to_string(), Capabilities::detect(&info("1.4.4")).unwrap());
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let status = |fields: serde_json::Value| -> SwitchStatus {
+            let mut status = json!({
+                "apower": 5.0,
+                "voltage": 230.0,
+                "current": 0.1,
+                "temperature": { "tC": 30.0, "tF": 86.0 },
+                "aenergy": { "total": 1.0 },
+            });
+            status.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+            serde_json::from_value(status).unwrap()
+        };
+
+        capabilities.strip(&[(kettle.clone(), status(json!({ "freq": 50.0, "pf": 0.9 })))], &mut []);
+        let detected = capabilities.get("kettle").unwrap();
+        assert!(detected.frequency && detected.power_factor);
+
+        capabilities.strip(&[(kettle, status(json!({})))], &mut []);
+        let detected = capabilities.get("kettle").unwrap();
+        assert!(!detected.frequency && !detected.power_factor);
+    }
+}
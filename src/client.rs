@@ -0,0 +1,1043 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use futures_util::future::try_join_all;
+use reqwest::{Certificate, Client, NoProxy, Proxy, Url};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::sync::{Mutex as AsyncMutex, Semaphore};
+use tracing::{debug, error};
+
+use crate::error::ShellyError;
+use crate::health::HealthTracker;
+use crate::metrics;
+use crate::config::AddonSensorConfig;
+use crate::status::{
+    AddonReading, Automations, AvailableUpdates, DeviceInfo, EmReading, InputStatus, ScheduleList, ScriptList,
+    ScriptStatus, ShellyStatus, SwitchStatus,
+};
+use crate::ws::WsPool;
+
+
+pub const DEFAULT_API_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest response body read from a device, unless configured. Plug statuses are well below 4 KiB.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+/// Characters of an unexpected response body kept in the logs
+const LOGGED_BODY_CHARS: usize = 512;
+
+
+/// Where a device is reached, parsed from `[https://]host[:port][/base/path]`. The base path is for
+/// devices behind a reverse proxy which serves several of them under different prefixes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceAddress {
+    pub https: bool,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Empty, or starting with `/` and without a trailing one
+    pub base_path: String,
+}
+
+impl DeviceAddress {
+    /// Fails for schemes other than `http://` and `https://`
+    pub fn parse(raw: &str) -> Result<DeviceAddress, &'static str> {
+        let (https, rest) = match raw.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (false, rest),
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (true, rest),
+            Some((scheme, _)) => {
+                error!("Unsupported scheme `{scheme}` in device address `{raw}`");
+                return Err("Device addresses must be http:// or https://!");
+            }
+            None => (false, raw),
+        };
+        let (authority, base_path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        // Bare IPv6 addresses are all colons, only bracketed ones can carry a port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port))
+                if !port.is_empty()
+                    && port.bytes().all(|b| b.is_ascii_digit())
+                    && (!host.contains(':') || (host.starts_with('[') && host.ends_with(']'))) =>
+            {
+                match port.parse::<u16>() {
+                    Ok(port) => (host, Some(port)),
+                    Err(_) => (authority, None),
+                }
+            }
+            _ => (authority, None),
+        };
+        let host = match host.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{host}]"),
+            Err(_) => host.to_string(),
+        };
+
+        Ok(DeviceAddress {
+            https,
+            host,
+            port,
+            base_path: base_path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// `host[:port]` of the device
+    pub fn target(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}:{port}", self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// The host as an IP address, without the brackets of IPv6 ones. `None` for hostnames.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+    }
+
+    pub fn rpc_url(&self, method: &str) -> String {
+        let scheme = if self.https { "https" } else { "http" };
+        format!("{scheme}://{}{}/rpc/{method}", self.target(), self.base_path)
+    }
+
+    pub fn ws_url(&self) -> String {
+        let scheme = if self.https { "wss" } else { "ws" };
+        format!("{scheme}://{}{}/rpc", self.target(), self.base_path)
+    }
+}
+
+impl fmt::Display for DeviceAddress {
+    /// The address without the default `http://`, as accepted by `parse`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.https {
+            write!(f, "https://")?;
+        }
+        write!(f, "{}{}", self.target(), self.base_path)
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShellySmartPlug {
+    pub url: String,
+    pub alias: String,
+    /// Extra static labels attached to every metric of the plug, e.g. `room` or `circuit`
+    pub labels: Vec<(String, String)>,
+}
+
+impl ShellySmartPlug {
+    /// Plug reached at `[https://]host[:port][/base/path]`, aliased by its address
+    pub fn from_address(raw: &str) -> Result<ShellySmartPlug, &'static str> {
+        let address = DeviceAddress::parse(raw)?;
+
+        Ok(ShellySmartPlug {
+            url: address.rpc_url("Switch.GetStatus?id=0"),
+            alias: format!("{}{}", address.target(), address.base_path),
+            labels: vec![],
+        })
+    }
+
+    /// Alias of plugs without a mapping, `host[:port][/base/path]`
+    pub fn default_alias(&self) -> String {
+        let address = self.device_address();
+        format!("{}{}", address.target(), address.base_path)
+    }
+
+    /// Scheme, host, port and base path of the plug, from its status URL
+    pub fn device_address(&self) -> DeviceAddress {
+        match Url::parse(&self.url) {
+            Ok(url) if url.host_str().is_some() => {
+                let path = url.path();
+                let base_path = path.find("/rpc/").or_else(|| path.strip_suffix("/rpc").map(str::len));
+                DeviceAddress {
+                    https: url.scheme() == "https",
+                    host: url.host_str().unwrap_or_default().to_string(),
+                    port: url.port(),
+                    base_path: base_path.map(|end| path[..end].to_string()).unwrap_or_default(),
+                }
+            }
+            _ => DeviceAddress { https: false, host: self.url.clone(), port: None, base_path: String::new() },
+        }
+    }
+
+    /// Address the plug was created from, `https://` prefixed for plugs reached over HTTPS
+    pub fn address(&self) -> String {
+        self.device_address().to_string()
+    }
+
+    /// The `hostname` label followed by the plug's extra labels
+    pub fn metric_labels(&self) -> Vec<(String, String)> {
+        let mut labels = vec![("hostname".to_string(), self.alias.clone())];
+        labels.extend(self.labels.iter().cloned());
+        labels
+    }
+
+    /// `host[:port]` of the device, as used for Prometheus targets
+    pub fn target(&self) -> String {
+        self.device_address().target()
+    }
+
+    /// URL of another RPC method of the same device
+    pub fn rpc_url(&self, method: &str) -> String {
+        self.device_address().rpc_url(method)
+    }
+
+    /// WebSocket RPC channel of the device, `wss://` for devices reached over HTTPS
+    pub fn ws_url(&self) -> String {
+        self.device_address().ws_url()
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Transport {
+    /// A plain HTTP request per RPC call
+    #[default]
+    Http,
+    /// A persistent WebSocket connection per device
+    WebSocket,
+}
+
+
+/// TLS settings for devices reached over HTTPS, e.g. behind a reverse proxy
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceTls {
+    /// PEM bundle of extra CAs to trust, on top of the built-in roots
+    pub ca_certs: Option<Vec<u8>>,
+    /// Accept any certificate, for devices with self-signed ones
+    pub insecure_skip_verify: bool,
+}
+
+
+/// Connection reuse towards the devices, unset values keep the reqwest defaults. Some firmware
+/// closes kept-alive connections early, which makes the next request on them fail.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpPool {
+    /// Idle connections kept per device, `0` opens a new connection for every request
+    pub max_idle_per_host: Option<usize>,
+    /// How long an idle connection is kept before closing it
+    pub idle_timeout: Option<Duration>,
+    /// Interval of TCP keepalive probes on open connections, off if not set
+    pub tcp_keepalive: Option<Duration>,
+    /// Never negotiate HTTP/2 with devices reached over HTTPS
+    pub http1_only: bool,
+}
+
+
+/// Last status fetched from a device, guarded so concurrent callers wait on one request
+type RecentStatus = Arc<AsyncMutex<Option<(Instant, SwitchStatus)>>>;
+
+
+/// Proxy for the requests to devices, e.g. to reach an IoT VLAN from a DMZ
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceProxy {
+    /// `http://`, `https://` or `socks5://` URL of the proxy. Without one, the `HTTP_PROXY` family
+    /// of environment variables applies.
+    pub url: Option<String>,
+    /// Comma separated hosts, domains and CIDR ranges reached without the proxy, like `NO_PROXY`
+    pub no_proxy: Option<String>,
+    /// Proxy URL per device keyed by IP or alias, or `direct` to bypass any proxy
+    pub overrides: HashMap<String, String>,
+}
+
+
+/// Client for the Shelly RPC API. Cheap to clone, the connection pool is shared.
+#[derive(Clone)]
+pub struct ShellyClient {
+    http: Client,
+    /// Clients of the devices with their own proxy or TLS settings, keyed by IP or alias, so their
+    /// connections are never pooled with those of other devices
+    device_http: HashMap<String, Client>,
+    tls: DeviceTls,
+    /// TLS settings per device keyed by IP or alias, instead of `tls`
+    device_tls: HashMap<String, DeviceTls>,
+    proxy: DeviceProxy,
+    pool: HttpPool,
+    timeout: Duration,
+    ws: Option<Arc<WsPool>>,
+    /// Caps the number of requests in flight across all devices
+    limiter: Option<Arc<Semaphore>>,
+    min_poll_interval: Option<Duration>,
+    max_response_bytes: usize,
+    /// Read the switch, sys and WiFi status in one `Shelly.GetStatus` call instead of only the switch
+    system_status: bool,
+    recent: Arc<Mutex<HashMap<String, RecentStatus>>>,
+    health: Arc<HealthTracker>,
+}
+
+impl Default for ShellyClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShellyClient {
+    pub fn new() -> ShellyClient {
+        ShellyClient::with_timeout(DEFAULT_API_TIMEOUT)
+    }
+
+    pub fn with_timeout(timeout: Duration) -> ShellyClient {
+        ShellyClient::with_transport(timeout, Transport::Http)
+    }
+
+    pub fn with_transport(timeout: Duration, transport: Transport) -> ShellyClient {
+        let health = Arc::new(HealthTracker::new());
+        ShellyClient {
+            http: http_client(timeout, &DeviceTls::default(), &HttpPool::default(), None).unwrap(),
+            device_http: HashMap::new(),
+            tls: DeviceTls::default(),
+            device_tls: HashMap::new(),
+            proxy: DeviceProxy::default(),
+            pool: HttpPool::default(),
+            timeout,
+            ws: match transport {
+                Transport::Http => None,
+                Transport::WebSocket => Some(Arc::new(WsPool::new(timeout, health.clone()))),
+            },
+            limiter: None,
+            min_poll_interval: None,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            system_status: false,
+            recent: Arc::new(Mutex::new(HashMap::new())),
+            health,
+        }
+    }
+
+    /// Use custom TLS settings for devices reached over HTTPS
+    pub fn with_device_tls(self, tls: &DeviceTls) -> Result<ShellyClient, &'static str> {
+        ShellyClient { tls: tls.clone(), ..self }.rebuild_http()
+    }
+
+    /// Use other TLS settings for some devices, keyed by IP or alias
+    pub fn with_device_tls_overrides(self, overrides: HashMap<String, DeviceTls>) -> Result<ShellyClient, &'static str> {
+        ShellyClient { device_tls: overrides, ..self }.rebuild_http()
+    }
+
+    /// Send the HTTP requests to the devices through a proxy. The WebSocket transport can't be
+    /// proxied, only `direct` overrides are accepted with it.
+    pub fn with_device_proxy(self, proxy: &DeviceProxy) -> Result<ShellyClient, &'static str> {
+        ShellyClient { proxy: proxy.clone(), ..self }.rebuild_http()
+    }
+
+    /// Tune how connections to the devices are reused
+    pub fn with_http_pool(self, pool: &HttpPool) -> Result<ShellyClient, &'static str> {
+        ShellyClient { pool: pool.clone(), ..self }.rebuild_http()
+    }
+
+    fn rebuild_http(self) -> Result<ShellyClient, &'static str> {
+        let proxy = || self.proxy.url.as_deref().map(|url| ProxyRoute::Through(url, self.proxy.no_proxy.as_deref()));
+        let http = http_client(self.timeout, &self.tls, &self.pool, proxy())?;
+
+        let mut device_http = HashMap::new();
+        for device in self.proxy.overrides.keys().chain(self.device_tls.keys()) {
+            let route = match self.proxy.overrides.get(device).map(String::as_str) {
+                Some("direct") => Some(ProxyRoute::Direct),
+                Some(url) => Some(ProxyRoute::Through(url, None)),
+                None => proxy(),
+            };
+            let tls = self.device_tls.get(device).unwrap_or(&self.tls);
+            device_http.insert(device.clone(), http_client(self.timeout, tls, &self.pool, route)?);
+        }
+
+        let ws = match &self.ws {
+            Some(_) if self.proxy.url.is_some() || self.proxy.overrides.values().any(|url| url != "direct") => {
+                error!("Devices can't be reached through a proxy over WebSockets");
+                return Err("The WebSocket transport can't use a device proxy!");
+            }
+            // Connections are only opened on the first call, nothing is lost by replacing the pool
+            Some(_) => Some(Arc::new(
+                WsPool::new(self.timeout, self.health.clone()).with_tls(&self.tls, &self.device_tls)?
+            )),
+            None => None,
+        };
+
+        Ok(ShellyClient { http, device_http, ws, ..self })
+    }
+
+    /// Client for the HTTP requests to the plug, an alias override wins over the IP one
+    fn http_of(&self, plug: &ShellySmartPlug) -> &Client {
+        self.device_http.get(&plug.alias)
+            .or_else(|| self.device_http.get(&plug.target()))
+            .unwrap_or(&self.http)
+    }
+
+    /// Never have more than `limit` requests to devices in flight at once
+    pub fn with_max_concurrent_requests(self, limit: usize) -> ShellyClient {
+        ShellyClient { limiter: Some(Arc::new(Semaphore::new(limit))), ..self }
+    }
+
+    /// Never query a device more often than every `interval`, callers within the interval get the
+    /// last status instead
+    pub fn with_min_poll_interval(self, interval: Duration) -> ShellyClient {
+        ShellyClient { min_poll_interval: Some(interval), ..self }
+    }
+
+    /// Fail requests to devices whose response body is bigger than `limit` bytes, instead of
+    /// reading it whole
+    pub fn with_max_response_bytes(self, limit: usize) -> ShellyClient {
+        ShellyClient { max_response_bytes: limit, ..self }
+    }
+
+    /// Poll `Shelly.GetStatus` instead of `Switch.GetStatus`, so the statuses carry the uptime, free
+    /// RAM and WiFi signal of the device at no extra round trip
+    pub fn with_system_status(self) -> ShellyClient {
+        ShellyClient { system_status: true, ..self }
+    }
+
+    /// Count the request durations of `shelly_device_request_duration_seconds` into buckets with
+    /// these upper bounds, in seconds
+    pub fn with_latency_buckets(self, bounds: &[f64]) -> Result<ShellyClient, &'static str> {
+        if !metrics::is_valid_buckets(bounds) {
+            return Err("Latency buckets must be increasing!");
+        }
+        // The WebSocket pool records dropped connections in the new tracker
+        ShellyClient { health: Arc::new(HealthTracker::with_latency_buckets(bounds)), ..self }.rebuild_http()
+    }
+
+    /// Outcome of the latest requests to every plug
+    pub fn health(&self) -> &HealthTracker {
+        &self.health
+    }
+
+    /// Fetch the current switch status of the given plug
+    pub async fn get_status(&self, plug: &ShellySmartPlug) -> Result<SwitchStatus, ShellyError> {
+        let result = self.poll_status(plug).await;
+        match &result {
+            Ok(_) => self.health.record_success(&plug.alias, Utc::now()),
+            Err(e) => self.health.record_failure(&plug.alias, e),
+        }
+        result
+    }
+
+    async fn poll_status(&self, plug: &ShellySmartPlug) -> Result<SwitchStatus, ShellyError> {
+        let min_poll_interval = match self.min_poll_interval {
+            Some(interval) => interval,
+            None => return self.fetch_status(plug).await,
+        };
+
+        let recent = self.recent.lock().unwrap().entry(plug.url.clone()).or_default().clone();
+        let mut recent = recent.lock().await;
+        if let Some((fetched_at, status)) = recent.as_ref() {
+            if fetched_at.elapsed() < min_poll_interval {
+                debug!(alias = %plug.alias, "Polled within the minimum interval, reusing the last status");
+                return Ok(status.clone());
+            }
+        }
+
+        let status = self.fetch_status(plug).await?;
+        *recent = Some((Instant::now(), status.clone()));
+        Ok(status)
+    }
+
+    async fn fetch_status(&self, plug: &ShellySmartPlug) -> Result<SwitchStatus, ShellyError> {
+        if !self.system_status {
+            return self.rpc(plug, "Switch.GetStatus", json!({ "id": 0 }), &plug.url).await;
+        }
+
+        let status: ShellyStatus = self.rpc(plug, "Shelly.GetStatus", json!({}), &plug.rpc_url("Shelly.GetStatus")).await?;
+        Ok(SwitchStatus { sys: status.sys, wifi: status.wifi, ..status.switch })
+    }
+
+    /// Fetch the model, firmware and configured name of the given plug
+    pub async fn get_device_info(&self, plug: &ShellySmartPlug) -> Result<DeviceInfo, ShellyError> {
+        self.rpc(plug, "Shelly.GetDeviceInfo", json!({}), &plug.rpc_url("Shelly.GetDeviceInfo")).await
+    }
+
+    /// Ask the plug whether a newer firmware is available
+    pub async fn check_for_update(&self, plug: &ShellySmartPlug) -> Result<AvailableUpdates, ShellyError> {
+        self.rpc(plug, "Shelly.CheckForUpdate", json!({}), &plug.rpc_url("Shelly.CheckForUpdate")).await
+    }
+
+    /// Have the plug install the latest firmware of `stage`, `stable` or `beta`. The plug answers
+    /// before it downloads the update, and reboots once it's installed.
+    pub async fn update_firmware(&self, plug: &ShellySmartPlug, stage: &str) -> Result<(), ShellyError> {
+        let url = plug.rpc_url(&format!("Shelly.Update?stage={stage}"));
+        let _: Value = self.rpc(plug, "Shelly.Update", json!({ "stage": stage }), &url).await?;
+        Ok(())
+    }
+
+    /// Reboot the plug, to recover one whose RPC interface hangs. The plug answers before it goes
+    /// down.
+    pub async fn reboot(&self, plug: &ShellySmartPlug) -> Result<(), ShellyError> {
+        let _: Value = self.rpc(plug, "Shelly.Reboot", json!({}), &plug.rpc_url("Shelly.Reboot")).await?;
+        self.health.record_reboot(&plug.alias);
+        Ok(())
+    }
+
+    /// Fetch the state of the physical input (button or switch) with the given id. Not recorded in
+    /// the plug health, plenty of plugs have no input to read.
+    pub async fn get_input_status(&self, plug: &ShellySmartPlug, id: u8) -> Result<InputStatus, ShellyError> {
+        let url = plug.rpc_url(&format!("Input.GetStatus?id={id}"));
+        self.rpc(plug, "Input.GetStatus", json!({ "id": id }), &url).await
+    }
+
+    /// Fetch the scripts and schedules of the given plug, along with the status of every script
+    pub async fn get_automations(&self, plug: &ShellySmartPlug) -> Result<Automations, ShellyError> {
+        let (scripts_url, schedules_url) = (plug.rpc_url("Script.List"), plug.rpc_url("Schedule.List"));
+        let (scripts, schedules): (ScriptList, ScheduleList) = tokio::try_join!(
+            self.rpc(plug, "Script.List", json!({}), &scripts_url),
+            self.rpc(plug, "Schedule.List", json!({}), &schedules_url),
+        )?;
+        let statuses: Vec<ScriptStatus> = try_join_all(scripts.scripts.iter().map(|script| {
+            let url = plug.rpc_url(&format!("Script.GetStatus?id={}", script.id));
+            async move { self.rpc(plug, "Script.GetStatus", json!({ "id": script.id }), &url).await }
+        })).await?;
+
+        Ok(Automations {
+            scripts: scripts.scripts.into_iter().zip(statuses).collect(),
+            schedules: schedules.jobs,
+        })
+    }
+
+    /// Fetch the given Plus Add-On sensors of the plug
+    pub async fn get_addon_sensors(
+        &self,
+        plug: &ShellySmartPlug,
+        sensors: &AddonSensorConfig,
+    ) -> Result<AddonReading, ShellyError> {
+        let temperatures = try_join_all(sensors.temperature.iter().map(|id| async move {
+            let url = plug.rpc_url(&format!("Temperature.GetStatus?id={id}"));
+            self.rpc(plug, "Temperature.GetStatus", json!({ "id": id }), &url).await
+        }));
+        let humidities = try_join_all(sensors.humidity.iter().map(|id| async move {
+            let url = plug.rpc_url(&format!("Humidity.GetStatus?id={id}"));
+            self.rpc(plug, "Humidity.GetStatus", json!({ "id": id }), &url).await
+        }));
+
+        let (temperatures, humidities) = tokio::try_join!(temperatures, humidities)?;
+        Ok(AddonReading { temperatures, humidities })
+    }
+
+    /// Fetch the per phase readings and energy counters of a 3-phase energy meter
+    pub async fn get_em_status(&self, meter: &ShellySmartPlug) -> Result<EmReading, ShellyError> {
+        let (status_url, data_url) = (meter.rpc_url("EM.GetStatus?id=0"), meter.rpc_url("EMData.GetStatus?id=0"));
+        let result = tokio::try_join!(
+            self.rpc(meter, "EM.GetStatus", json!({ "id": 0 }), &status_url),
+            self.rpc(meter, "EMData.GetStatus", json!({ "id": 0 }), &data_url),
+        );
+        match &result {
+            Ok(_) => self.health.record_success(&meter.alias, Utc::now()),
+            Err(e) => self.health.record_failure(&meter.alias, e),
+        }
+
+        let (status, data) = result?;
+        Ok(EmReading { status, data })
+    }
+
+    /// Call an RPC method of the device, over the WebSocket with `params` or else at `http_url`.
+    /// Only the latency is recorded in the plug health: the status polls record their outcome
+    /// themselves, auxiliary reads like inputs, add-on sensors or scripts don't.
+    async fn rpc<T: DeserializeOwned>(
+        &self,
+        plug: &ShellySmartPlug,
+        method: &str,
+        params: Value,
+        http_url: &str,
+    ) -> Result<T, ShellyError> {
+        let _permit = match &self.limiter {
+            Some(limiter) => Some(limiter.acquire().await.expect("The limiter is never closed")),
+            None => None,
+        };
+
+        let started = Instant::now();
+        let result = match &self.ws {
+            Some(ws) => ws.call(plug, method, params).await.and_then(|result| {
+                let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+                debug!(alias = %plug.alias, url = %plug.ws_url(), method, latency_ms, "Received RPC response");
+                serde_json::from_value(result).map_err(|err| {
+                    error!(alias = %plug.alias, "Invalid response returned - {err}");
+                    ShellyError::InvalidResponse { plug: plug.alias.clone(), source: err.into() }
+                })
+            }),
+            None => self.call(plug, http_url).await,
+        };
+        self.health.record_latency(&plug.alias, started.elapsed());
+        result
+    }
+
+    /// Fetch the status of every plug concurrently, the output keeps the order of `plugs`. Fails on
+    /// the first plug which can't be read.
+    pub async fn get_all_statuses(
+        &self,
+        plugs: &[ShellySmartPlug]
+    ) -> Result<Vec<(ShellySmartPlug, SwitchStatus)>, ShellyError> {
+        try_join_all(plugs.iter().map(|plug| async move {
+            Ok((plug.clone(), self.get_status(plug).await?))
+        })).await
+    }
+
+    async fn call<T: DeserializeOwned>(&self, plug: &ShellySmartPlug, url: &str) -> Result<T, ShellyError> {
+        let alias = &plug.alias;
+        let started = Instant::now();
+        let output = match self.http_of(plug).get(url).send().await {
+            Ok(data) => data,
+            Err(err) => {
+                error!(%alias, "Failed to build the request at URI {url} - {err}");
+                return Err(ShellyError::from_request(alias, err));
+            }
+        };
+
+        let http_status_code = output.status().as_u16();
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        debug!(%alias, %url, status = http_status_code, latency_ms, "Received API response");
+        if !(200..=299).contains(&http_status_code) {
+            let http_raw_data = match read_body(output, self.max_response_bytes).await {
+                Ok(Body::Complete(body)) | Ok(Body::Truncated(body)) => body,
+                Err(_) => Vec::new(),
+            };
+
+            error!(
+                %alias,
+                %url,
+                status = http_status_code,
+                latency_ms,
+                "Expected 200 http status code, got {} with body `{}`", http_status_code, logged_body(&http_raw_data)
+            );
+            return Err(ShellyError::from_status(alias, http_status_code));
+        }
+
+        let body = match read_body(output, self.max_response_bytes).await {
+            Ok(Body::Complete(body)) => body,
+            Ok(Body::Truncated(_)) => {
+                error!(%alias, %url, limit = self.max_response_bytes, "Response body exceeds the maximum size");
+                return Err(ShellyError::InvalidResponse {
+                    plug: alias.clone(),
+                    source: format!("response exceeds {} bytes", self.max_response_bytes).into(),
+                });
+            }
+            Err(err) => {
+                error!(%alias, %url, "Failed to read the response - {err}");
+                return Err(match err.is_timeout() {
+                    true => ShellyError::Timeout { plug: alias.clone() },
+                    false => ShellyError::InvalidResponse { plug: alias.clone(), source: err.into() },
+                });
+            }
+        };
+        let payload = match serde_json::from_slice::<T>(&body) {
+            Ok(data) => data,
+            Err(err) => {
+                error!(%alias, %url, "Invalid response returned - {err} with body `{}`", logged_body(&body));
+                return Err(ShellyError::InvalidResponse { plug: alias.clone(), source: err.into() });
+            }
+        };
+
+        Ok(payload)
+    }
+}
+
+enum Body {
+    Complete(Vec<u8>),
+    /// The first `limit` bytes of a bigger body
+    Truncated(Vec<u8>),
+}
+
+/// Read the response body up to `limit` bytes, without buffering the rest of a bigger one
+async fn read_body(mut response: reqwest::Response, limit: usize) -> Result<Body, reqwest::Error> {
+    if response.content_length().is_some_and(|length| length > limit as u64) {
+        return Ok(Body::Truncated(Vec::new()));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            body.extend_from_slice(&chunk[..limit - body.len()]);
+            return Ok(Body::Truncated(body));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Body::Complete(body))
+}
+
+/// The start of a response body for the logs, devices don't always answer with valid UTF-8
+fn logged_body(body: &[u8]) -> String {
+    let text = String::from_utf8_lossy(body);
+    match text.char_indices().nth(LOGGED_BODY_CHARS) {
+        Some((end, _)) => format!("{}... ({} bytes)", &text[..end], body.len()),
+        None => text.into_owned(),
+    }
+}
+
+/// How a client reaches the devices, when it shouldn't follow the environment
+enum ProxyRoute<'a> {
+    Direct,
+    /// Proxy URL, and the hosts reached without it
+    Through(&'a str, Option<&'a str>),
+}
+
+fn http_client(timeout: Duration, tls: &DeviceTls, pool: &HttpPool, proxy: Option<ProxyRoute>) -> Result<Client, &'static str> {
+    let mut builder = Client::builder()
+        .timeout(timeout)
+        .danger_accept_invalid_certs(tls.insecure_skip_verify)
+        .tcp_keepalive(pool.tcp_keepalive);
+
+    if let Some(max_idle) = pool.max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = pool.idle_timeout {
+        builder = builder.pool_idle_timeout(idle_timeout);
+    }
+    if pool.http1_only {
+        builder = builder.http1_only();
+    }
+
+    if let Some(pem) = &tls.ca_certs {
+        let certs = Certificate::from_pem_bundle(pem)
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .ok_or_else(|| {
+                error!("No valid PEM certificates found in the device CA bundle");
+                "Invalid CA certificate!"
+            })?;
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    match proxy {
+        Some(ProxyRoute::Direct) => builder = builder.no_proxy(),
+        Some(ProxyRoute::Through(url, no_proxy)) => {
+            let proxy = Proxy::all(url).map_err(|err| {
+                error!("Invalid device proxy `{url}` - {err}");
+                "Invalid device proxy!"
+            })?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy.and_then(NoProxy::from_string)));
+        }
+        None => {}
+    }
+
+    builder.build().map_err(|err| {
+        error!("Failed to build the HTTP client - {err}");
+        "Invalid TLS settings!"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Server, ServerGuard};
+    use test_context::{test_context, AsyncTestContext};
+    use serde_json::json;
+
+    struct TestSetup {
+        fake_server: ServerGuard,
+        client: ShellyClient,
+        good_shelly_data: String
+    }
+
+    impl AsyncTestContext for TestSetup {
+        async fn setup() -> TestSetup {
+            TestSetup {
+                fake_server: Server::new_async().await,
+                client: ShellyClient::new(),
+                good_shelly_data: json!({
+                    "apower": 1.0,
+                    "voltage": 2.0,
+                    "current": 3.0,
+                    "temperature": {
+                        "tC": 20.1,
+                        "tF": 68.2
+                    },
+                    "aenergy": {
+                        "total": 45645634.12
+                    }
+                }).to_string()
+            }
+        }
+    }
+
+    fn plug(url: String) -> ShellySmartPlug {
+        ShellySmartPlug { url, alias: "alias".to_string(), labels: vec![] }
+    }
+
+    #[test]
+    fn test_plug_target() {
+        assert_eq!(plug("http://10.0.0.2/rpc/Switch.GetStatus?id=0".to_string()).target(), "10.0.0.2");
+        assert_eq!(plug("http://plug.lan:8080/rpc".to_string()).target(), "plug.lan:8080");
+        assert_eq!(plug("not a url".to_string()).target(), "not a url");
+        assert_eq!(plug("http://plug.lan:8080/rpc".to_string()).ws_url(), "ws://plug.lan:8080/rpc");
+        assert_eq!(plug("https://plug.lan/rpc".to_string()).ws_url(), "wss://plug.lan/rpc");
+        assert_eq!(ShellySmartPlug::from_address("https://plug.lan:8443/").unwrap().address(), "https://plug.lan:8443");
+        assert_eq!(ShellySmartPlug::from_address("10.0.0.2").unwrap().url, "http://10.0.0.2/rpc/Switch.GetStatus?id=0");
+        assert_eq!(
+            plug("https://plug.lan/rpc/Switch.GetStatus?id=0".to_string()).rpc_url("Shelly.GetDeviceInfo"),
+            "https://plug.lan/rpc/Shelly.GetDeviceInfo"
+        );
+    }
+
+    #[test]
+    fn test_device_address() {
+        let proxied = ShellySmartPlug::from_address("https://proxy.lan:8443/shelly/kitchen/").unwrap();
+
+        assert_eq!(proxied.url, "https://proxy.lan:8443/shelly/kitchen/rpc/Switch.GetStatus?id=0");
+        assert_eq!(proxied.alias, "proxy.lan:8443/shelly/kitchen");
+        assert_eq!(proxied.target(), "proxy.lan:8443");
+        assert_eq!(proxied.address(), "https://proxy.lan:8443/shelly/kitchen");
+        assert_eq!(proxied.rpc_url("Shelly.GetDeviceInfo"), "https://proxy.lan:8443/shelly/kitchen/rpc/Shelly.GetDeviceInfo");
+        assert_eq!(proxied.ws_url(), "wss://proxy.lan:8443/shelly/kitchen/rpc");
+
+        assert_eq!(DeviceAddress::parse("10.0.0.2:8080").unwrap(), DeviceAddress {
+            https: false,
+            host: "10.0.0.2".to_string(),
+            port: Some(8080),
+            base_path: String::new(),
+        });
+        assert_eq!(DeviceAddress::parse("[fe80::1]").unwrap().target(), "[fe80::1]");
+        assert_eq!(DeviceAddress::parse("[fe80::1]:80").unwrap().port, Some(80));
+        assert_eq!(DeviceAddress::parse("[fe80::1]:80").unwrap().ip(), Some("fe80::1".parse().unwrap()));
+        assert_eq!(DeviceAddress::parse("10.0.0.2:8080").unwrap().ip(), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(DeviceAddress::parse("plug.lan").unwrap().ip(), None);
+        assert_eq!(ShellySmartPlug::from_address("[fe80::1]:80").unwrap().device_address().ip(), Some("fe80::1".parse().unwrap()));
+        assert_eq!(DeviceAddress::parse("plug.lan:99999").unwrap().host, "plug.lan:99999");
+
+        // The last group of a bare IPv6 address isn't a port
+        let bare = DeviceAddress::parse("fe80::1").unwrap();
+        assert_eq!((bare.host.as_str(), bare.port), ("[fe80::1]", None));
+        assert_eq!(bare.rpc_url("Shelly.GetDeviceInfo"), "http://[fe80::1]/rpc/Shelly.GetDeviceInfo");
+        assert_eq!(bare.ip(), Some("fe80::1".parse().unwrap()));
+        assert_eq!(ShellySmartPlug::from_address("fe80::1").unwrap().alias, "[fe80::1]");
+
+        assert!(DeviceAddress::parse("HTTPS://plug.lan").unwrap().https);
+        assert_eq!(DeviceAddress::parse("ftp://plug.lan").err(), Some("Device addresses must be http:// or https://!"));
+        assert!(ShellySmartPlug::from_address("ws://plug.lan").is_err());
+        assert_eq!(ShellySmartPlug::from_address("10.0.0.2:8080/a").unwrap().address(), "10.0.0.2:8080/a");
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_invalid_url(ctx: &mut TestSetup) {
+        let test_path = format!("{}/not-home", ctx.fake_server.url());
+        let test_path_bad = "https://i-do-not-exist.com:9001/aaaa".to_string();
+
+        ctx.fake_server.mock("GET", "/not-home")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        // Check that we can get a non-200 error to an endpoint which exists (our mock server)
+        let actual = ctx.client.get_status(&plug(test_path)).await;
+        assert!(matches!(actual, Err(ShellyError::Status { status: 404, .. })), "{actual:?}");
+
+        // Check that we can't even dial into a URL which doesn't exist
+        let actual_bad = ctx.client.get_status(&plug(test_path_bad)).await;
+        assert!(matches!(actual_bad, Err(ShellyError::Connect { .. })), "{actual_bad:?}");
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_bad_response(ctx: &mut TestSetup) {
+        let test_path = format!("{}/", ctx.fake_server.url());
+
+        ctx.fake_server.mock("GET", "/")
+            .with_status(200)
+            .with_body("lol I'm not json")
+            .create_async()
+            .await;
+
+        let actual = ctx.client.get_status(&plug(test_path)).await;
+        assert!(matches!(actual, Err(ShellyError::InvalidResponse { .. })), "{actual:?}");
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_malformed_response(ctx: &mut TestSetup) {
+        let test_path = format!("{}/", ctx.fake_server.url());
+
+        ctx.fake_server.mock("GET", "/")
+            .with_status(500)
+            .with_body(vec![0xff, 0xfe, b'o', b'o', b'p', b's'])
+            .create_async()
+            .await;
+        let actual = ctx.client.get_status(&plug(test_path.clone())).await;
+        assert!(matches!(actual, Err(ShellyError::Status { status: 500, .. })), "{actual:?}");
+
+        ctx.fake_server.reset();
+        ctx.fake_server.mock("GET", "/")
+            .with_status(200)
+            .with_body(ctx.good_shelly_data.clone())
+            .create_async()
+            .await;
+        let client = ShellyClient::new().with_max_response_bytes(16);
+        let actual = client.get_status(&plug(test_path)).await;
+        assert!(matches!(actual, Err(ShellyError::InvalidResponse { .. })), "{actual:?}");
+    }
+
+    #[test]
+    fn test_logged_body() {
+        assert_eq!(logged_body(b"not found"), "not found");
+        assert_eq!(logged_body(&[b'a', 0xff]), "a\u{fffd}");
+        let long = "é".repeat(LOGGED_BODY_CHARS + 1);
+        assert_eq!(logged_body(long.as_bytes()), format!("{}... (1026 bytes)", "é".repeat(LOGGED_BODY_CHARS)));
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_get_status(ctx: &mut TestSetup) {
+        let test_path = format!("{}/", ctx.fake_server.url());
+
+        ctx.fake_server.mock("GET", "/")
+            .with_status(200)
+            .with_body(ctx.good_shelly_data.clone())
+            .create_async()
+            .await;
+
+        let actual = ctx.client.get_status(&plug(test_path)).await.unwrap();
+
+        assert_eq!(actual.apower, 1.0);
+        assert_eq!(actual.voltage, 2.0);
+        assert_eq!(actual.current, 3.0);
+        assert_eq!(actual.temperature.celsius, 20.1);
+        assert_eq!(actual.aenergy.total, 45645634.12);
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_get_all_statuses_fails_fast(ctx: &mut TestSetup) {
+        let good_path = format!("{}/", ctx.fake_server.url());
+        let bad_path = format!("{}/broken", ctx.fake_server.url());
+
+        ctx.fake_server.mock("GET", "/")
+            .with_status(200)
+            .with_body(ctx.good_shelly_data.clone())
+            .create_async()
+            .await;
+        ctx.fake_server.mock("GET", "/broken")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let good = ctx.client.get_all_statuses(&[plug(good_path.clone()), plug(good_path.clone())]).await;
+        assert_eq!(good.unwrap().len(), 2);
+
+        let bad = ctx.client.get_all_statuses(&[plug(good_path), plug(bad_path)]).await;
+        assert_eq!(bad.unwrap_err().status(), Some(500));
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_min_poll_interval(ctx: &mut TestSetup) {
+        let test_path = format!("{}/", ctx.fake_server.url());
+        let mock = ctx.fake_server.mock("GET", "/")
+            .with_status(200)
+            .with_body(ctx.good_shelly_data.clone())
+            .expect(1)
+            .create_async()
+            .await;
+        let client = ShellyClient::new()
+            .with_max_concurrent_requests(1)
+            .with_min_poll_interval(Duration::from_secs(60));
+
+        let plugs = [plug(test_path.clone()), plug(test_path.clone()), plug(test_path)];
+        let actual = client.get_all_statuses(&plugs).await.unwrap();
+
+        assert_eq!(actual.len(), 3);
+        assert_eq!(actual[2].1.apower, 1.0);
+        mock.assert_async().await;
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_system_status(ctx: &mut TestSetup) {
+        let mut good_shelly_data: Value = serde_json::from_str(&ctx.good_shelly_data).unwrap();
+        good_shelly_data["id"] = json!(0);
+        let mock = ctx.fake_server.mock("GET", "/rpc/Shelly.GetStatus")
+            .with_status(200)
+            .with_body(json!({
+                "switch:0": good_shelly_data,
+                "sys": { "uptime": 3600, "ram_free": 150000, "restart_required": false },
+                "wifi": { "sta_ip": "10.0.0.2", "status": "got ip", "ssid": "iot", "rssi": -58 },
+                "input:0": { "id": 0, "state": false },
+            }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let client = ShellyClient::new().with_system_status();
+        let plug = ShellySmartPlug::from_address(&ctx.fake_server.host_with_port()).unwrap();
+
+        let actual = client.get_status(&plug).await.unwrap();
+
+        assert_eq!(actual.apower, 1.0);
+        assert_eq!(actual.sys.unwrap().uptime, 3600);
+        assert_eq!(actual.wifi.unwrap().rssi, Some(-58.0));
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn test_device_tls() {
+        let ca = rcgen::generate_simple_self_signed(vec!["plug.lan".to_string()]).unwrap();
+        let trusted = DeviceTls { ca_certs: Some(ca.cert.pem().into_bytes()), insecure_skip_verify: false };
+        let garbage = DeviceTls { ca_certs: Some(b"not a certificate".to_vec()), insecure_skip_verify: false };
+        let insecure = DeviceTls { ca_certs: None, insecure_skip_verify: true };
+
+        assert!(ShellyClient::new().with_device_tls(&trusted).is_ok());
+        assert!(ShellyClient::new().with_device_tls(&insecure).is_ok());
+        assert_eq!(ShellyClient::new().with_device_tls(&garbage).err(), Some("Invalid CA certificate!"));
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_device_proxy(ctx: &mut TestSetup) {
+        // A plain HTTP proxy gets the absolute URL of the device, mockito matches on its path
+        let mock = ctx.fake_server.mock("GET", "/rpc/Switch.GetStatus?id=0")
+            .with_status(200)
+            .with_body(ctx.good_shelly_data.clone())
+            .expect(1)
+            .create_async()
+            .await;
+        let proxy = DeviceProxy {
+            url: Some(ctx.fake_server.url()),
+            no_proxy: Some("127.0.0.0/8".to_string()),
+            overrides: HashMap::from([("direct-plug".to_string(), "direct".to_string())]),
+        };
+        let client = ShellyClient::with_timeout(Duration::from_secs(2)).with_device_proxy(&proxy).unwrap();
+        let unreachable = ShellySmartPlug::from_address("10.255.255.1").unwrap();
+
+        assert_eq!(client.get_status(&unreachable).await.unwrap().apower, 1.0);
+        let direct = ShellySmartPlug { alias: "direct-plug".to_string(), ..unreachable };
+        assert!(client.get_status(&direct).await.is_err());
+        // Hosts on the no proxy list are requested directly
+        assert!(client.get_status(&ShellySmartPlug::from_address("127.0.0.1:1").unwrap()).await.is_err());
+        mock.assert_async().await;
+
+        let invalid = DeviceProxy { url: Some("not a url".to_string()), ..DeviceProxy::default() };
+        assert_eq!(ShellyClient::new().with_device_proxy(&invalid).err(), Some("Invalid device proxy!"));
+
+        let websocket = ShellyClient::with_transport(DEFAULT_API_TIMEOUT, Transport::WebSocket);
+        let direct = DeviceProxy { overrides: HashMap::from([("kettle".to_string(), "direct".to_string())]), ..DeviceProxy::default() };
+        assert!(websocket.clone().with_device_proxy(&direct).is_ok());
+        assert_eq!(websocket.with_device_proxy(&proxy).err(), Some("The WebSocket transport can't use a device proxy!"));
+    }
+
+    #[test_context(TestSetup)]
+    #[tokio::test]
+    async fn test_http_pool(ctx: &mut TestSetup) {
+        let mock = ctx.fake_server.mock("GET", "/rpc/Switch.GetStatus?id=0")
+            .with_status(200)
+            .with_body(ctx.good_shelly_data.clone())
+            .expect(2)
+            .create_async()
+            .await;
+        let pool = HttpPool {
+            max_idle_per_host: Some(0),
+            idle_timeout: Some(Duration::from_secs(5)),
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            http1_only: true,
+        };
+        let insecure = DeviceTls { ca_certs: None, insecure_skip_verify: true };
+        let client = ShellyClient::new()
+            .with_http_pool(&pool).unwrap()
+            .with_device_tls_overrides(HashMap::from([("kettle".to_string(), insecure)])).unwrap();
+        let plug = ShellySmartPlug::from_address(&ctx.fake_server.host_with_port()).unwrap();
+
+        assert_eq!(client.get_status(&plug).await.unwrap().apower, 1.0);
+        // The device with its own TLS settings doesn't share the connection pool
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..plug };
+        assert_eq!(client.get_status(&kettle).await.unwrap().apower, 1.0);
+        assert_eq!(client.device_http.keys().collect::<Vec<_>>(), vec!["kettle"]);
+        mock.assert_async().await;
+
+        let garbage = DeviceTls { ca_certs: Some(b"not a certificate".to_vec()), insecure_skip_verify: false };
+        let actual = ShellyClient::new().with_device_tls_overrides(HashMap::from([("kettle".to_string(), garbage)]));
+        assert_eq!(actual.err(), Some("Invalid CA certificate!"));
+    }
+}
@@ -0,0 +1,225 @@
+//! Listener for the CoIoT status announcements of Gen1 devices: CoAP messages multicast to
+//! 224.0.1.187:5683 whenever a value changes, and every few seconds otherwise. The announcements
+//! go into the cache like the notifications of Gen2 devices, so Gen1 plugs don't need polling.
+//!
+//! Ref: https://shelly-api-docs.shelly.cloud/gen1/#coiot-protocol
+//! Ref: https://datatracker.ietf.org/doc/html/rfc7252#section-3
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use log::{debug, warn};
+use serde_json::{json, Map, Value};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::cache::ReadingCache;
+use crate::registry::PlugRegistry;
+
+
+pub const DEFAULT_PORT: u16 = 5683;
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+/// CoAP code `0.30` Shelly uses for status announcements
+const CODE_STATUS: u8 = 30;
+/// Option carrying the device id, `<model>#<mac>#<CoIoT version>`
+const OPTION_DEVICE_ID: u16 = 3332;
+/// CoIoT v2 sensor ids of the values of a plug
+const SENSOR_OUTPUT: i64 = 1101;
+const SENSOR_TEMPERATURE_C: i64 = 3104;
+const SENSOR_POWER_W: i64 = 4101;
+/// Energy consumed since the device booted, in watt-minutes
+const SENSOR_ENERGY_WMIN: i64 = 4103;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announcement {
+    pub device_id: Option<String>,
+    pub payload: Value,
+}
+
+
+/// Join the CoIoT multicast group on `port`, sharing the port with other listeners on the host
+pub fn bind(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    socket.join_multicast_v4(&MULTICAST_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Feed the announcements of the registered plugs into the cache, plugs are matched by the
+/// address the announcement came from
+pub async fn run(socket: UdpSocket, plugs: Arc<PlugRegistry>, cache: Arc<ReadingCache>) {
+    let mut buffer = [0; 2048];
+    loop {
+        let (length, sender) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("Failed to receive a CoIoT announcement - {err}");
+                continue;
+            }
+        };
+        ingest(&buffer[..length], sender.ip(), &plugs, &cache);
+    }
+}
+
+/// Apply an announcement to the cache, returns the alias of the plug it was for
+fn ingest(datagram: &[u8], sender: IpAddr, plugs: &PlugRegistry, cache: &ReadingCache) -> Option<String> {
+    let sender = sender.to_canonical();
+    let plug = plugs.snapshot().into_iter().find(|plug| plug.device_address().ip() == Some(sender))?;
+
+    let announcement = match parse(datagram) {
+        Ok(Some(announcement)) => announcement,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("Invalid CoIoT announcement from `{}` - {e}", plug.alias);
+            return None;
+        }
+    };
+    let patch = switch_patch(&announcement.payload)?;
+    debug!("CoIoT announcement from `{}` ({})", plug.alias, announcement.device_id.as_deref().unwrap_or("unknown id"));
+    cache.apply_patch(&plug.alias, &patch);
+    Some(plug.alias)
+}
+
+/// Decode a CoAP datagram, `None` for messages which aren't status announcements
+pub fn parse(datagram: &[u8]) -> Result<Option<Announcement>, &'static str> {
+    let (header, code) = match datagram {
+        [header, code, _, _, ..] if header >> 6 == 1 => (*header, *code),
+        _ => return Err("Not a CoAP message!"),
+    };
+    if code != CODE_STATUS {
+        return Ok(None);
+    }
+
+    let token_length = (header & 0x0f) as usize;
+    let mut rest = datagram.get(4 + token_length..).ok_or("Truncated CoAP message!")?;
+    let mut option = 0;
+    let mut device_id = None;
+    loop {
+        match rest.split_first() {
+            None => return Err("Status announcement without payload!"),
+            Some((0xff, payload)) => {
+                let payload = serde_json::from_slice(payload).map_err(|_| "Invalid status payload!")?;
+                return Ok(Some(Announcement { device_id, payload }));
+            }
+            Some((&option_header, tail)) => {
+                let (delta, tail) = option_field(option_header >> 4, tail)?;
+                let (length, tail) = option_field(option_header & 0x0f, tail)?;
+                option += delta;
+                let value = tail.get(..length).ok_or("Truncated CoAP option!")?;
+                if option == OPTION_DEVICE_ID as usize {
+                    device_id = Some(String::from_utf8_lossy(value).into_owned());
+                }
+                rest = &tail[length..];
+            }
+        }
+    }
+}
+
+/// Option delta or length, `13` and `14` are followed by one or two more bytes
+fn option_field(nibble: u8, bytes: &[u8]) -> Result<(usize, &[u8]), &'static str> {
+    match (nibble, bytes) {
+        (0..=12, _) => Ok((nibble as usize, bytes)),
+        (13, [extended, rest @ ..]) => Ok((*extended as usize + 13, rest)),
+        (14, [high, low, rest @ ..]) => Ok((u16::from_be_bytes([*high, *low]) as usize + 269, rest)),
+        _ => Err("Invalid CoAP option!"),
+    }
+}
+
+/// Switch status of the first channel from the `G` sensor values, `None` if it has none. Gen1 plugs
+/// measure neither voltage nor current, both are reported as 0.
+pub fn switch_patch(payload: &Value) -> Option<Value> {
+    let mut patch = Map::new();
+    for sensor in payload["G"].as_array()? {
+        let (channel, id, value) = match sensor.as_array().map(Vec::as_slice) {
+            Some([channel, id, value]) => (channel.as_i64(), id.as_i64(), value),
+            _ => continue,
+        };
+        if channel != Some(0) {
+            continue;
+        }
+
+        match (id, value.as_f64()) {
+            (Some(SENSOR_OUTPUT), Some(output)) => patch.insert("output".to_string(), json!(output == 1.0)),
+            (Some(SENSOR_TEMPERATURE_C), Some(celsius)) => patch.insert(
+                "temperature".to_string(),
+                json!({ "tC": celsius, "tF": celsius * 9.0 / 5.0 + 32.0 }),
+            ),
+            (Some(SENSOR_POWER_W), Some(watts)) => patch.insert("apower".to_string(), json!(watts)),
+            (Some(SENSOR_ENERGY_WMIN), Some(wmin)) => patch.insert("aenergy".to_string(), json!({ "total": wmin / 60.0 })),
+            _ => None,
+        };
+    }
+
+    if patch.is_empty() {
+        return None;
+    }
+    patch.insert("voltage".to_string(), json!(0.0));
+    patch.insert("current".to_string(), json!(0.0));
+    Some(Value::Object(patch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ShellySmartPlug;
+
+    /// Non-confirmable `0.30` message with a device id and a `G` payload, as sent by a Plug S
+    fn announcement(payload: &str) -> Vec<u8> {
+        let device_id = b"SHPLG-S#A4CF12F45AB1#2";
+        let mut datagram = vec![0x50, CODE_STATUS, 0x12, 0x34];
+        // Option 3332 is a delta of 269 + 3063, with a length of 13 + 9
+        datagram.extend([0xed, 0x0b, 0xf7, device_id.len() as u8 - 13]);
+        datagram.extend(device_id);
+        datagram.push(0xff);
+        datagram.extend(payload.as_bytes());
+        datagram
+    }
+
+    #[test]
+    fn test_parse() {
+        let datagram = announcement(r#"{"G":[[0,1101,1],[0,3104,40.0],[0,4101,12.5],[0,4103,600]]}"#);
+
+        let actual = parse(&datagram).unwrap().unwrap();
+
+        assert_eq!(actual.device_id.as_deref(), Some("SHPLG-S#A4CF12F45AB1#2"));
+        assert_eq!(switch_patch(&actual.payload), Some(json!({
+            "output": true,
+            "temperature": { "tC": 40.0, "tF": 104.0 },
+            "apower": 12.5,
+            "aenergy": { "total": 10.0 },
+            "voltage": 0.0,
+            "current": 0.0,
+        })));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse(b"hi"), Err("Not a CoAP message!"));
+        assert_eq!(parse(&[0x50, 0x45, 0x12, 0x34]), Ok(None));
+        assert_eq!(parse(&[0x50, CODE_STATUS, 0x12, 0x34, 0xd1]), Err("Invalid CoAP option!"));
+        assert_eq!(parse(&announcement("{")), Err("Invalid status payload!"));
+        assert_eq!(switch_patch(&json!({ "G": [[1, 4101, 5.0]] })), None);
+    }
+
+    #[test]
+    fn test_ingest() {
+        let plugs = PlugRegistry::new(vec![
+            ShellySmartPlug { alias: "lamp".to_string(), ..ShellySmartPlug::from_address("10.0.0.7").unwrap() },
+            ShellySmartPlug { alias: "fan".to_string(), ..ShellySmartPlug::from_address("http://[fd00::7]:8080").unwrap() },
+        ]);
+        let cache = ReadingCache::new();
+        let datagram = announcement(r#"{"G":[[0,1101,0],[0,3104,30.0],[0,4101,0.0],[0,4103,60]]}"#);
+
+        assert_eq!(ingest(&datagram, "10.0.0.8".parse().unwrap(), &plugs, &cache), None);
+        assert_eq!(ingest(&datagram, "10.0.0.7".parse().unwrap(), &plugs, &cache), Some("lamp".to_string()));
+        // Dual-stack sockets report IPv4 senders mapped into IPv6
+        assert_eq!(ingest(&datagram, "::ffff:10.0.0.7".parse().unwrap(), &plugs, &cache), Some("lamp".to_string()));
+        assert_eq!(ingest(&datagram, "fd00::7".parse().unwrap(), &plugs, &cache), Some("fan".to_string()));
+
+        let status = cache.get("lamp").unwrap().status.unwrap();
+        assert_eq!(status.output, Some(false));
+        assert_eq!(status.aenergy.total, 1.0);
+    }
+}
@@ -0,0 +1,437 @@
+//! Optional TOML config file for settings which don't fit nicely on the command line.
+//!
+//! ```toml
+//! [tariff]
+//! currency = "EUR"
+//! price_per_kwh = 0.25
+//!
+//! [[tariff.bands]]
+//! start = "23:00"
+//! end = "07:00"
+//! price_per_kwh = 0.12
+//!
+//! # Extra labels per plug, keyed by IP or alias
+//! [labels."10.0.0.2"]
+//! room = "kitchen"
+//! circuit = "3"
+//!
+//! # Readings which flag `shelly_threshold_exceeded`, keyed by IP or alias
+//! [thresholds.kettle]
+//! max_power_watts = 2200
+//! max_temperature_c = 70
+//!
+//! # Group of the plug summed in `shelly_group_power_watts`, keyed by IP or alias
+//! [groups]
+//! kettle = "kitchen"
+//! "10.0.0.2" = "kitchen"
+//!
+//! # Component ids of the Plus Add-On sensors to read, keyed by IP or alias
+//! [addon_sensors.kettle]
+//! temperature = [100, 101]
+//! humidity = [100]
+//!
+//! # Proxy of the requests to the plug, keyed by IP or alias, instead of `--device-proxy`
+//! [device_proxies]
+//! "10.0.0.2" = "socks5://jump.lan:1080"
+//! kettle = "direct"
+//!
+//! # TLS settings of the plug, keyed by IP or alias, instead of `--device-ca-cert` and
+//! # `--insecure-skip-verify`
+//! [device_tls.garage]
+//! ca_cert = "/etc/shelly/garage-ca.pem"
+//! insecure_skip_verify = false
+//!
+//! # Plugs and meters served on `/metrics/home-a`, by IP or alias, optionally with their own token
+//! [tenants.home-a]
+//! plugs = ["kettle", "10.0.0.3"]
+//! token_file = "/etc/shelly/home-a.token"
+//!
+//! # Background poll interval in seconds, keyed by IP or alias, instead of `--poll-interval`
+//! [poll_intervals]
+//! heater = 5
+//!
+//! # Plugs polled next to the `-i` ones, also where the admin API persists its changes
+//! [[plugs]]
+//! address = "10.0.0.4"
+//! alias = "kettle"
+//! labels = { room = "kitchen" }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use chrono::NaiveTime;
+use log::error;
+use serde::{Deserialize, Deserializer, Serialize};
+use toml_edit::{value, ArrayOfTables, DocumentMut, InlineTable, Table};
+
+use crate::client::DeviceAddress;
+use crate::energy::write_atomic;
+
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub tariff: Option<TariffConfig>,
+    #[serde(default)]
+    pub labels: HashMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    pub thresholds: HashMap<String, ThresholdConfig>,
+    #[serde(default)]
+    pub poll_intervals: HashMap<String, u64>,
+    #[serde(default)]
+    pub groups: HashMap<String, String>,
+    #[serde(default)]
+    pub addon_sensors: HashMap<String, AddonSensorConfig>,
+    /// Proxy URL, or `direct`
+    #[serde(default)]
+    pub device_proxies: HashMap<String, String>,
+    #[serde(default)]
+    pub device_tls: HashMap<String, DeviceTlsConfig>,
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
+    #[serde(default)]
+    pub plugs: Vec<PlugConfig>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlugConfig {
+    /// `[https://]host[:port]`, like `--ip-addr`
+    pub address: String,
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TariffConfig {
+    pub currency: Option<String>,
+    /// Price outside of any band
+    pub price_per_kwh: Option<f64>,
+    #[serde(default)]
+    pub bands: Vec<TariffBand>,
+}
+
+
+/// Limits of a plug's readings, unset ones aren't checked
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ThresholdConfig {
+    pub max_power_watts: Option<f64>,
+    pub max_temperature_c: Option<f64>,
+}
+
+
+/// Sensors attached to a plug through the Plus Add-On, by component id (`100` and up)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AddonSensorConfig {
+    /// DS18B20 or DHT22 temperature sensors, read through `Temperature.GetStatus`
+    #[serde(default)]
+    pub temperature: Vec<u16>,
+    /// DHT22 humidity sensors, read through `Humidity.GetStatus`
+    #[serde(default)]
+    pub humidity: Vec<u16>,
+}
+
+
+/// TLS settings of a plug reached over HTTPS, its requests get their own connection pool
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceTlsConfig {
+    /// PEM file with extra CA certificates to trust
+    pub ca_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+
+/// Devices served to a tenant on `/metrics/<tenant>`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    /// IPs or aliases of the plugs and meters
+    pub plugs: Vec<String>,
+    /// File containing a bearer token which only grants access to the tenant's metrics
+    pub token_file: Option<PathBuf>,
+}
+
+
+/// A time of day price, `start` is inclusive and `end` exclusive. Bands may wrap past midnight.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TariffBand {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+    pub price_per_kwh: f64,
+}
+
+
+pub fn load(path: &Path) -> Result<Config, &'static str> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(err) => {
+            error!("Failed to read config file `{}` - {err}", path.display());
+            return Err("Unable to read config file!");
+        }
+    };
+
+    parse(&raw)
+}
+
+pub fn parse(raw: &str) -> Result<Config, &'static str> {
+    let config: Config = toml::from_str(raw).map_err(|err| {
+        error!("Invalid config file - {err}");
+        "Invalid config file!"
+    })?;
+    for plug in &config.plugs {
+        DeviceAddress::parse(&plug.address)?;
+    }
+    Ok(config)
+}
+
+/// Replace the `[[plugs]]` of the config file, leaving everything else (comments included) untouched
+pub fn save_plugs(path: &Path, plugs: &[PlugConfig]) -> Result<(), &'static str> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            error!("Failed to read config file `{}` - {err}", path.display());
+            return Err("Unable to read config file!");
+        }
+    };
+    let mut document: DocumentMut = raw.parse().map_err(|err| {
+        error!("Invalid config file - {err}");
+        "Invalid config file!"
+    })?;
+
+    let mut tables = ArrayOfTables::new();
+    for plug in plugs {
+        let mut table = Table::new();
+        table["address"] = value(&plug.address);
+        if let Some(alias) = &plug.alias {
+            table["alias"] = value(alias);
+        }
+        if !plug.labels.is_empty() {
+            let labels: InlineTable = plug.labels.iter().map(|(name, label)| (name.as_str(), label.as_str())).collect();
+            table["labels"] = value(labels);
+        }
+        tables.push(table);
+    }
+    if tables.is_empty() {
+        document.remove("plugs");
+    } else {
+        document["plugs"] = toml_edit::Item::ArrayOfTables(tables);
+    }
+
+    write_atomic(path, document.to_string().as_bytes()).map_err(|err| {
+        error!("Failed to write config file `{}` - {err}", path.display());
+        "Unable to write config file!"
+    })
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&raw, "%H:%M")
+        .map_err(|err| serde::de::Error::custom(format!("invalid time `{raw}`, expected HH:MM - {err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tariff() {
+        let actual = parse(r#"
+            [tariff]
+            currency = "EUR"
+            price_per_kwh = 0.25
+
+            [[tariff.bands]]
+            start = "23:00"
+            end = "07:00"
+            price_per_kwh = 0.12
+        "#).unwrap();
+
+        let tariff = actual.tariff.unwrap();
+        assert_eq!(tariff.currency, Some("EUR".to_string()));
+        assert_eq!(tariff.price_per_kwh, Some(0.25));
+        assert_eq!(tariff.bands.len(), 1);
+        assert_eq!(tariff.bands[0].start, NaiveTime::from_hms_opt(23, 0, 0).unwrap());
+        assert_eq!(tariff.bands[0].end, NaiveTime::from_hms_opt(7, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        let actual = parse(r#"
+            [labels."10.0.0.2"]
+            room = "kitchen"
+            floor = "1"
+        "#).unwrap();
+
+        let labels = &actual.labels["10.0.0.2"];
+        assert_eq!(labels["room"], "kitchen");
+        assert_eq!(labels["floor"], "1");
+    }
+
+    #[test]
+    fn test_parse_thresholds() {
+        let actual = parse(r#"
+            [thresholds.kettle]
+            max_power_watts = 2200
+        "#).unwrap();
+
+        assert_eq!(actual.thresholds["kettle"], ThresholdConfig { max_power_watts: Some(2200.0), max_temperature_c: None });
+        assert_eq!(parse("[thresholds.kettle]\nmax_watts = 1\n"), Err("Invalid config file!"));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let actual = parse(r#"
+            [groups]
+            kettle = "kitchen"
+            "10.0.0.2" = "office"
+        "#).unwrap();
+
+        assert_eq!(actual.groups, HashMap::from([
+            ("kettle".to_string(), "kitchen".to_string()),
+            ("10.0.0.2".to_string(), "office".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn test_parse_addon_sensors() {
+        let actual = parse(r#"
+            [addon_sensors.kettle]
+            temperature = [100, 101]
+        "#).unwrap();
+
+        assert_eq!(actual.addon_sensors["kettle"], AddonSensorConfig { temperature: vec![100, 101], humidity: vec![] });
+    }
+
+    #[test]
+    fn test_parse_device_proxies() {
+        let actual = parse(r#"
+            [device_proxies]
+            "10.0.0.2" = "socks5://jump.lan:1080"
+            kettle = "direct"
+        "#).unwrap();
+
+        assert_eq!(actual.device_proxies["10.0.0.2"], "socks5://jump.lan:1080");
+        assert_eq!(actual.device_proxies["kettle"], "direct");
+    }
+
+    #[test]
+    fn test_parse_tenants() {
+        let actual = parse(r#"
+            [tenants.home-a]
+            plugs = ["kettle", "10.0.0.3"]
+            token_file = "home-a.token"
+
+            [tenants.home-b]
+            plugs = ["10.0.0.4"]
+        "#).unwrap();
+
+        assert_eq!(actual.tenants["home-a"].plugs, vec!["kettle", "10.0.0.3"]);
+        assert_eq!(actual.tenants["home-a"].token_file, Some(PathBuf::from("home-a.token")));
+        assert_eq!(actual.tenants["home-b"].token_file, None);
+        assert_eq!(parse("[tenants.home-a]\ntoken_file = \"a\"\n"), Err("Invalid config file!"));
+    }
+
+    #[test]
+    fn test_parse_device_tls() {
+        let actual = parse(r#"
+            [device_tls.garage]
+            ca_cert = "garage-ca.pem"
+
+            [device_tls."10.0.0.9"]
+            insecure_skip_verify = true
+        "#).unwrap();
+
+        assert_eq!(actual.device_tls["garage"].ca_cert, Some(PathBuf::from("garage-ca.pem")));
+        assert!(!actual.device_tls["garage"].insecure_skip_verify);
+        assert!(actual.device_tls["10.0.0.9"].insecure_skip_verify);
+    }
+
+    #[test]
+    fn test_parse_poll_intervals() {
+        let actual = parse(r#"
+            [poll_intervals]
+            heater = 5
+            "10.0.0.3" = 120
+        "#).unwrap();
+
+        assert_eq!(actual.poll_intervals, HashMap::from([("heater".to_string(), 5), ("10.0.0.3".to_string(), 120)]));
+    }
+
+    #[test]
+    fn test_parse_plugs() {
+        let actual = parse(r#"
+            [[plugs]]
+            address = "10.0.0.4"
+            alias = "kettle"
+            labels = { room = "kitchen" }
+
+            [[plugs]]
+            address = "https://plug.lan"
+        "#).unwrap();
+
+        assert_eq!(actual.plugs, vec![
+            PlugConfig {
+                address: "10.0.0.4".to_string(),
+                alias: Some("kettle".to_string()),
+                labels: BTreeMap::from([("room".to_string(), "kitchen".to_string())]),
+            },
+            PlugConfig { address: "https://plug.lan".to_string(), alias: None, labels: BTreeMap::new() },
+        ]);
+
+        let unknown_scheme = parse(r#"
+            [[plugs]]
+            address = "ftp://plug.lan"
+        "#);
+        assert_eq!(unknown_scheme.err(), Some("Device addresses must be http:// or https://!"));
+    }
+
+    #[test]
+    fn test_save_plugs_keeps_other_settings() {
+        let path = std::env::temp_dir().join(format!("shelly_config_{}.toml", std::process::id()));
+        std::fs::write(&path, "# Our tariff\n[tariff]\nprice_per_kwh = 0.25\n\n[[plugs]]\naddress = \"10.0.0.9\"\n").unwrap();
+        let plugs = vec![PlugConfig {
+            address: "10.0.0.4".to_string(),
+            alias: Some("kettle".to_string()),
+            labels: BTreeMap::from([("room".to_string(), "kitchen".to_string())]),
+        }];
+
+        save_plugs(&path, &plugs).unwrap();
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(raw.starts_with("# Our tariff\n"));
+        let actual = parse(&raw).unwrap();
+        assert_eq!(actual.tariff.unwrap().price_per_kwh, Some(0.25));
+        assert_eq!(actual.plugs, plugs);
+
+        save_plugs(&path, &[]).unwrap();
+        assert!(parse(&std::fs::read_to_string(&path).unwrap()).unwrap().plugs.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse("[tariff]\nunknown = 1").unwrap_err(), "Invalid config file!");
+        assert_eq!(
+            parse("[[tariff.bands]]\nstart = \"7am\"\nend = \"09:00\"\nprice_per_kwh = 1.0").unwrap_err(),
+            "Invalid config file!"
+        );
+        assert_eq!(load(Path::new("/i/do/not/exist.toml")).unwrap_err(), "Unable to read config file!");
+    }
+}
@@ -0,0 +1,179 @@
+//! Energy cost derived from the plugs' energy counters and a (time of day) tariff.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::NaiveTime;
+
+use crate::client::ShellySmartPlug;
+use crate::config::{TariffBand, TariffConfig};
+use crate::metrics::MetricFamily;
+use crate::status::SwitchStatus;
+
+
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tariff {
+    pub currency: String,
+    pub price_per_kwh: f64,
+    pub bands: Vec<TariffBand>,
+}
+
+impl Tariff {
+    /// Merge the CLI flags over the config file, returns `None` when no price was configured at all
+    pub fn from_settings(
+        price_per_kwh: Option<f64>,
+        currency: Option<String>,
+        config: Option<&TariffConfig>
+    ) -> Option<Tariff> {
+        let bands = config.map(|tariff| tariff.bands.clone()).unwrap_or_default();
+        let price_per_kwh = price_per_kwh.or(config.and_then(|tariff| tariff.price_per_kwh));
+        if price_per_kwh.is_none() && bands.is_empty() {
+            return None;
+        }
+
+        Some(Tariff {
+            currency: currency
+                .or(config.and_then(|tariff| tariff.currency.clone()))
+                .unwrap_or(DEFAULT_CURRENCY.to_string()),
+            price_per_kwh: price_per_kwh.unwrap_or_default(),
+            bands,
+        })
+    }
+
+    /// Price in effect at the given time of day, the first matching band wins
+    pub fn price_at(&self, time: NaiveTime) -> f64 {
+        self.bands.iter()
+            .find(|band| {
+                if band.start <= band.end {
+                    band.start <= time && time < band.end
+                } else {
+                    // Band wraps past midnight, e.g. 23:00 -> 07:00
+                    time >= band.start || time < band.end
+                }
+            })
+            .map(|band| band.price_per_kwh)
+            .unwrap_or(self.price_per_kwh)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PlugCost {
+    last_total_wh: f64,
+    cost: f64,
+}
+
+
+/// Accumulates cost per plug, charging each energy increment at the price in effect when it was
+/// observed. Consumption from before the exporter started is charged at the price of the first
+/// observation since there is no way to know when it happened.
+#[derive(Debug)]
+pub struct CostTracker {
+    tariff: Tariff,
+    plugs: Mutex<HashMap<String, PlugCost>>,
+}
+
+impl CostTracker {
+    pub fn new(tariff: Tariff) -> CostTracker {
+        CostTracker { tariff, plugs: Mutex::new(HashMap::new()) }
+    }
+
+    /// Record a new energy counter reading and return the accumulated cost for the plug
+    pub fn observe(&self, alias: &str, total_wh: f64, time: NaiveTime) -> f64 {
+        let price = self.tariff.price_at(time);
+        let mut plugs = self.plugs.lock().unwrap();
+
+        let entry = plugs.entry(alias.to_string())
+            .and_modify(|plug| {
+                // The device counter restarts from zero when it reboots
+                let delta_wh = if total_wh >= plug.last_total_wh { total_wh - plug.last_total_wh } else { total_wh };
+                plug.cost += delta_wh / 1000.0 * price;
+                plug.last_total_wh = total_wh;
+            })
+            .or_insert(PlugCost { last_total_wh: total_wh, cost: total_wh / 1000.0 * price });
+
+        entry.cost
+    }
+
+    pub fn collect(&self, readings: &[(ShellySmartPlug, SwitchStatus)], time: NaiveTime) -> MetricFamily {
+        let mut family = MetricFamily::counter(
+            "shelly_energy_cost_total",
+            "Cost of the energy consumed since the exporter started tracking the plug"
+        );
+
+        for (plug, status) in readings {
+            let cost = self.observe(&plug.alias, status.aenergy.total, time);
+            let mut labels = plug.metric_labels();
+            labels.push(("currency".to_string(), self.tariff.currency.clone()));
+            family.push(labels, cost);
+        }
+
+        family
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn banded_tariff() -> Tariff {
+        Tariff {
+            currency: "EUR".to_string(),
+            price_per_kwh: 0.30,
+            bands: vec![
+                TariffBand { start: time(23, 0), end: time(7, 0), price_per_kwh: 0.10 },
+                TariffBand { start: time(12, 0), end: time(14, 0), price_per_kwh: 0.20 },
+            ]
+        }
+    }
+
+    #[test]
+    fn test_price_at() {
+        let tariff = banded_tariff();
+
+        assert_eq!(tariff.price_at(time(23, 0)), 0.10);
+        assert_eq!(tariff.price_at(time(3, 30)), 0.10);
+        assert_eq!(tariff.price_at(time(7, 0)), 0.30);
+        assert_eq!(tariff.price_at(time(12, 59)), 0.20);
+        assert_eq!(tariff.price_at(time(14, 0)), 0.30);
+    }
+
+    #[test]
+    fn test_from_settings() {
+        let config = TariffConfig { currency: Some("EUR".to_string()), price_per_kwh: Some(0.5), bands: vec![] };
+
+        assert_eq!(Tariff::from_settings(None, None, None), None);
+
+        let from_config = Tariff::from_settings(None, None, Some(&config)).unwrap();
+        assert_eq!(from_config.currency, "EUR");
+        assert_eq!(from_config.price_per_kwh, 0.5);
+
+        // CLI flags win over the config file
+        let from_cli = Tariff::from_settings(Some(0.1), Some("GBP".to_string()), Some(&config)).unwrap();
+        assert_eq!(from_cli.currency, "GBP");
+        assert_eq!(from_cli.price_per_kwh, 0.1);
+
+        assert_eq!(Tariff::from_settings(Some(0.1), None, None).unwrap().currency, DEFAULT_CURRENCY);
+    }
+
+    #[test]
+    fn test_observe_accumulates_per_band() {
+        let tracker = CostTracker::new(banded_tariff());
+
+        // 1 kWh before tracking started, charged at the off-peak price it was first seen at
+        assert!((tracker.observe("plug", 1000.0, time(6, 0)) - 0.10).abs() < 1e-9);
+        // 2 kWh more at the day price
+        assert!((tracker.observe("plug", 3000.0, time(9, 0)) - 0.70).abs() < 1e-9);
+        // Device rebooted and has used 500 Wh since
+        assert!((tracker.observe("plug", 500.0, time(12, 30)) - 0.80).abs() < 1e-9);
+
+        // Plugs are tracked independently
+        assert!((tracker.observe("other", 1000.0, time(9, 0)) - 0.30).abs() < 1e-9);
+    }
+}
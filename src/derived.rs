@@ -0,0 +1,187 @@
+//! Power statistics the exporter derives from the readings it sees, for setups without recording
+//! rules: average power over the last minute and quarter hour, peak power and the energy consumed
+//! in the last hour.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::client::ShellySmartPlug;
+use crate::metrics::MetricFamily;
+use crate::status::SwitchStatus;
+
+
+/// Windows of `shelly_power_average_watts`, by `window` label
+const AVERAGE_WINDOWS: [(&str, Duration); 2] = [("1m", Duration::minutes(1)), ("15m", Duration::minutes(15))];
+/// How long readings are kept, the longest window of any statistic
+const RETENTION: Duration = Duration::hours(1);
+
+
+#[derive(Debug, Default)]
+struct PlugSamples {
+    /// `(polled at, power in watts, energy consumed in watt-hours)`, oldest first
+    readings: VecDeque<(DateTime<Utc>, f64, f64)>,
+    peak_watts: f64,
+    /// Raw `aenergy.total` of the latest reading, to carry the energy across device reboots
+    last_total_wh: Option<f64>,
+    consumed_wh: f64,
+}
+
+
+#[derive(Debug, Default)]
+pub struct PowerStats {
+    plugs: Mutex<HashMap<String, PlugSamples>>,
+}
+
+impl PowerStats {
+    pub fn new() -> PowerStats {
+        PowerStats::default()
+    }
+
+    /// Remember the readings polled since the last call, `polls` holds when each of `readings` was
+    /// polled in the same order, then compute the statistics of every plug in `readings` over the
+    /// windows ending at `now`. A reading served again from the cache isn't counted twice.
+    pub fn collect(
+        &self,
+        readings: &[(ShellySmartPlug, SwitchStatus)],
+        polls: &[(ShellySmartPlug, DateTime<Utc>)],
+        now: DateTime<Utc>
+    ) -> Vec<MetricFamily> {
+        let mut average = MetricFamily::gauge(
+            "shelly_power_average_watts",
+            "Average power over the window in watts, from the readings the exporter saw"
+        );
+        let mut peak = MetricFamily::gauge(
+            "shelly_power_peak_watts",
+            "Highest power the exporter saw since it started in watts"
+        );
+        let mut last_hour = MetricFamily::gauge(
+            "shelly_energy_last_hour_wh",
+            "Energy consumed in the last hour in watt-hours, from the readings the exporter saw"
+        );
+
+        let mut plugs = self.plugs.lock().unwrap();
+        for ((plug, status), (_, polled_at)) in readings.iter().zip(polls) {
+            let samples = plugs.entry(plug.alias.clone()).or_default();
+            samples.record(status, *polled_at);
+
+            for (window, length) in AVERAGE_WINDOWS {
+                let mut labels = plug.metric_labels();
+                labels.push(("window".to_string(), window.to_string()));
+                average.push(labels, samples.average_watts(now - length, now));
+            }
+            peak.push(plug.metric_labels(), samples.peak_watts);
+            last_hour.push(plug.metric_labels(), samples.consumed_since(now - RETENTION));
+        }
+
+        vec![average, peak, last_hour]
+    }
+}
+
+impl PlugSamples {
+    fn record(&mut self, status: &SwitchStatus, polled_at: DateTime<Utc>) {
+        if self.readings.back().is_some_and(|(latest, _, _)| *latest >= polled_at) {
+            return;
+        }
+        let total_wh = status.aenergy.total;
+        self.consumed_wh += match self.last_total_wh {
+            // The device rebooted and started counting from zero again
+            Some(last) if total_wh < last => total_wh,
+            Some(last) => total_wh - last,
+            None => 0.0,
+        };
+        self.last_total_wh = Some(total_wh);
+        self.peak_watts = self.peak_watts.max(status.apower);
+
+        self.readings.push_back((polled_at, status.apower, self.consumed_wh));
+        while self.readings.front().is_some_and(|(seen_at, _, _)| *seen_at < polled_at - RETENTION) {
+            self.readings.pop_front();
+        }
+    }
+
+    /// Time-weighted average power between `since` and `until`, every reading holds until the next
+    /// one was polled
+    fn average_watts(&self, since: DateTime<Utc>, until: DateTime<Utc>) -> f64 {
+        let mut watt_ms = 0.0;
+        let mut covered_ms = 0.0;
+        for (i, (polled_at, watts, _)) in self.readings.iter().enumerate() {
+            let start = (*polled_at).max(since);
+            let end = self.readings.get(i + 1).map_or(until, |(next, _, _)| *next).min(until);
+            if end > start {
+                let held_ms = (end - start).num_milliseconds() as f64;
+                watt_ms += watts * held_ms;
+                covered_ms += held_ms;
+            }
+        }
+        if covered_ms == 0.0 {
+            // Only a reading polled just now, it hasn't held for any time yet
+            return self.readings.back().map_or(0.0, |(_, watts, _)| *watts);
+        }
+        watt_ms / covered_ms
+    }
+
+    fn consumed_since(&self, since: DateTime<Utc>) -> f64 {
+        let oldest = self.readings.iter().find(|(seen_at, _, _)| *seen_at >= since);
+        match (oldest, self.readings.back()) {
+            (Some((_, _, first)), Some((_, _, latest))) => latest - first,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn reading(apower: f64, total: f64) -> SwitchStatus {
+        serde_json::from_value(json!({
+            "apower": apower,
+            "voltage": 230.0,
+            "current": 1.0,
+            "temperature": { "tC": 40.0, "tF": 104.0 },
+            "aenergy": { "total": total }
+        })).unwrap()
+    }
+
+    fn values(family: &MetricFamily) -> Vec<f64> {
+        family.samples.iter().map(|sample| sample.value).collect()
+    }
+
+    #[test]
+    fn test_collect() {
+        let stats = PowerStats::new();
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let start = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+        let collect = |status: SwitchStatus, polled_at: DateTime<Utc>, now: DateTime<Utc>| {
+            stats.collect(&[(kettle.clone(), status)], &[(kettle.clone(), polled_at)], now)
+        };
+
+        collect(reading(2000.0, 100.0), start, start);
+        collect(reading(100.0, 120.0), start + Duration::minutes(10), start + Duration::minutes(10));
+        let polled_at = start + Duration::minutes(10) + Duration::seconds(30);
+        let actual = collect(reading(200.0, 130.0), polled_at, polled_at);
+
+        // 1m then 15m averages, weighted by how long each reading held
+        assert_eq!(values(&actual[0]), vec![1050.0, 1203000.0 / 630.0]);
+        assert_eq!(actual[0].samples[0].labels[1], ("window".to_string(), "1m".to_string()));
+        assert_eq!(values(&actual[1]), vec![2000.0]);
+        assert_eq!(values(&actual[2]), vec![30.0]);
+
+        // A reading served again under the same poll time isn't recorded again
+        let again = collect(reading(5000.0, 500.0), polled_at, polled_at);
+        assert_eq!(again, actual);
+
+        // The first reading left the hour, a device reboot restarts the raw counter
+        let polled_at = start + Duration::minutes(65);
+        let actual = collect(reading(50.0, 5.0), polled_at, polled_at);
+        assert_eq!(values(&actual[0]), vec![200.0, 200.0]);
+        assert_eq!(values(&actual[1]), vec![2000.0]);
+        assert_eq!(values(&actual[2]), vec![15.0]);
+
+        // Scraped again half a minute later without a new poll
+        let actual = collect(reading(50.0, 5.0), polled_at, polled_at + Duration::seconds(30));
+        assert_eq!(values(&actual[0]), vec![125.0, 195.0]);
+    }
+}
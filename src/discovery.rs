@@ -0,0 +1,138 @@
+//! mDNS discovery of Gen2+ Shelly devices on the local network, which announce themselves as
+//! `_shelly._tcp` services with their model and firmware in the TXT record.
+//!
+//! Ref: https://shelly-api-docs.shelly.cloud/gen2/General/mDNS
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Duration;
+use log::error;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+
+pub const SERVICE_TYPE: &str = "_shelly._tcp.local.";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredDevice {
+    /// Device id, e.g. `shellyplusplugs-80646fd17d72`
+    pub name: String,
+    pub address: IpAddr,
+    pub port: u16,
+    /// Model family from the `app` TXT entry, e.g. `PlusPlugS`
+    pub app: Option<String>,
+    pub generation: Option<String>,
+    pub firmware: Option<String>,
+}
+
+impl DiscoveredDevice {
+    /// Build a device from a resolved service, `None` if it didn't announce any IPv4 address
+    pub fn from_service(
+        fullname: &str,
+        addresses: &[IpAddr],
+        port: u16,
+        txt: &HashMap<String, String>,
+    ) -> Option<DiscoveredDevice> {
+        let address = addresses.iter().copied().filter(IpAddr::is_ipv4).min()?;
+        let name = fullname.strip_suffix(SERVICE_TYPE).unwrap_or(fullname).trim_end_matches('.');
+
+        Some(DiscoveredDevice {
+            name: name.to_string(),
+            address,
+            port,
+            app: txt.get("app").cloned(),
+            generation: txt.get("gen").cloned(),
+            firmware: txt.get("ver").cloned(),
+        })
+    }
+
+    /// Value to pass to `--ip-addr`, the port is left out when it's the default one
+    pub fn ip_addr(&self) -> String {
+        match self.port {
+            80 => self.address.to_string(),
+            port => format!("{}:{port}", self.address),
+        }
+    }
+}
+
+
+/// Browse for devices for `timeout`, sorted by name
+pub async fn discover(timeout: Duration) -> Result<Vec<DiscoveredDevice>, &'static str> {
+    let daemon = ServiceDaemon::new().map_err(|err| {
+        error!("Failed to start the mDNS daemon - {err}");
+        "Unable to start mDNS discovery!"
+    })?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(|err| {
+        error!("Failed to browse for `{SERVICE_TYPE}` - {err}");
+        "Unable to start mDNS discovery!"
+    })?;
+
+    let mut devices: BTreeMap<String, DiscoveredDevice> = BTreeMap::new();
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = events.recv_async() => match event {
+                Ok(ServiceEvent::ServiceResolved(service)) => {
+                    let addresses: Vec<IpAddr> = service.addresses.iter().map(|ip| ip.to_ip_addr()).collect();
+                    let txt = service.txt_properties.clone().into_property_map_str();
+                    if let Some(device) = DiscoveredDevice::from_service(&service.fullname, &addresses, service.port, &txt) {
+                        devices.insert(device.name.clone(), device);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            },
+        }
+    }
+
+    let _ = daemon.shutdown();
+    Ok(devices.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_service() {
+        let txt = HashMap::from([
+            ("gen".to_string(), "2".to_string()),
+            ("app".to_string(), "PlusPlugS".to_string()),
+            ("ver".to_string(), "1.4.4".to_string()),
+        ]);
+        let addresses = ["fe80::1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+
+        let actual = DiscoveredDevice::from_service(
+            "shellyplusplugs-80646fd17d72._shelly._tcp.local.", &addresses, 80, &txt
+        ).unwrap();
+
+        assert_eq!(actual.name, "shellyplusplugs-80646fd17d72");
+        assert_eq!(actual.ip_addr(), "10.0.0.2");
+        assert_eq!(actual.app, Some("PlusPlugS".to_string()));
+        assert_eq!(actual.generation, Some("2".to_string()));
+        assert_eq!(actual.firmware, Some("1.4.4".to_string()));
+    }
+
+    #[test]
+    fn test_from_service_without_ipv4() {
+        let ipv6_only = ["fe80::1".parse().unwrap()];
+
+        assert_eq!(DiscoveredDevice::from_service("plug._shelly._tcp.local.", &ipv6_only, 80, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn test_ip_addr_with_port() {
+        let device = DiscoveredDevice {
+            name: "plug".to_string(),
+            address: "10.0.0.2".parse().unwrap(),
+            port: 8080,
+            app: None,
+            generation: None,
+            firmware: None,
+        };
+
+        assert_eq!(device.ip_addr(), "10.0.0.2:8080");
+    }
+}
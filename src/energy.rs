@@ -0,0 +1,224 @@
+//! Monotonic energy counter spanning device reboots.
+//!
+//! Shelly devices restart `aenergy.total` from zero when they reboot. The ledger remembers the last
+//! reading per plug and carries the pre-reboot total forward as an offset, optionally persisting
+//! that state to a small JSON file so it also survives exporter restarts. Energy consumed between
+//! the last reading and the reboot is lost, the per-minute buffer of the device restarts with it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::client::ShellySmartPlug;
+use crate::metrics::MetricFamily;
+use crate::status::SwitchStatus;
+
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PlugEnergy {
+    /// Last raw `aenergy.total` reported by the device
+    pub last_total_wh: f64,
+    /// Energy consumed in previous device boots
+    pub offset_wh: f64,
+}
+
+impl PlugEnergy {
+    pub fn consumed_wh(&self) -> f64 {
+        self.offset_wh + self.last_total_wh
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct EnergyLedger {
+    state_file: Option<PathBuf>,
+    plugs: Mutex<HashMap<String, PlugEnergy>>,
+}
+
+impl EnergyLedger {
+    /// In-memory ledger, resets are only tracked while the exporter is running
+    pub fn new() -> EnergyLedger {
+        EnergyLedger::default()
+    }
+
+    /// Ledger backed by a state file, previous state is loaded if the file exists
+    pub fn with_state_file(path: &Path) -> Result<EnergyLedger, &'static str> {
+        let plugs = match std::fs::read_to_string(path) {
+            Ok(raw) => serde_json::from_str(&raw).map_err(|err| {
+                error!("Invalid energy state file `{}` - {err}", path.display());
+                "Invalid energy state file!"
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("Energy state file `{}` doesn't exist yet, starting fresh", path.display());
+                HashMap::new()
+            }
+            Err(err) => {
+                error!("Failed to read energy state file `{}` - {err}", path.display());
+                return Err("Unable to read energy state file!");
+            }
+        };
+
+        Ok(EnergyLedger { state_file: Some(path.to_path_buf()), plugs: Mutex::new(plugs) })
+    }
+
+    /// Record a raw counter reading and return the total consumption across device reboots
+    pub fn observe(&self, alias: &str, total_wh: f64) -> f64 {
+        self.record(alias, total_wh).0
+    }
+
+    /// `observe`, plus whether the reading changed the state of the plug
+    fn record(&self, alias: &str, total_wh: f64) -> (f64, bool) {
+        let mut plugs = self.plugs.lock().unwrap();
+        let known = plugs.contains_key(alias);
+        let plug = plugs.entry(alias.to_string()).or_default();
+        let changed = !known || total_wh != plug.last_total_wh;
+
+        if total_wh < plug.last_total_wh {
+            warn!("Energy counter of `{alias}` went from {} to {total_wh}, assuming the device rebooted",
+                plug.last_total_wh);
+            plug.offset_wh += plug.last_total_wh;
+        }
+        plug.last_total_wh = total_wh;
+
+        (plug.consumed_wh(), changed)
+    }
+
+    /// Total consumption of the plug across device reboots, as of its last observed reading
+    pub fn consumed_wh_of(&self, alias: &str) -> Option<f64> {
+        self.plugs.lock().unwrap().get(alias).map(PlugEnergy::consumed_wh)
+    }
+
+    /// Write the current state to the state file, if one is configured
+    pub fn persist(&self) -> Result<(), &'static str> {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let raw = serde_json::to_string_pretty(&*self.plugs.lock().unwrap())
+            .expect("Energy state is always serializable");
+        write_atomic(path, raw.as_bytes()).map_err(|err| {
+            error!("Failed to write energy state file `{}` - {err}", path.display());
+            "Unable to write energy state file!"
+        })
+    }
+
+    pub fn collect(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) -> MetricFamily {
+        let mut family = MetricFamily::counter(
+            "shelly_energy_consumed_wh_total",
+            "Total energy consumed in watt-hours, carried across device reboots"
+        );
+
+        let mut changed = false;
+        for (plug, status) in readings {
+            let (consumed, updated) = self.record(&plug.alias, status.aenergy.total);
+            changed |= updated;
+            family.push(plug.metric_labels(), consumed);
+        }
+
+        if changed {
+            // A failed write is logged and retried on the next change, no reason to fail this scrape
+            let _ = self.persist();
+        }
+
+        family
+    }
+}
+
+
+/// Write to a temporary sibling file first so a crash never leaves a half written file behind
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("shelly_energy_{name}_{}.json", std::process::id()))
+    }
+
+    #[test]
+    fn test_observe_handles_resets() {
+        let ledger = EnergyLedger::new();
+
+        assert_eq!(ledger.observe("plug", 100.0), 100.0);
+        assert_eq!(ledger.observe("plug", 150.0), 150.0);
+        // Device rebooted
+        assert_eq!(ledger.observe("plug", 10.0), 160.0);
+        assert_eq!(ledger.observe("plug", 20.0), 170.0);
+        // And again
+        assert_eq!(ledger.observe("plug", 0.0), 170.0);
+
+        assert_eq!(ledger.observe("other", 5.0), 5.0);
+    }
+
+    #[test]
+    fn test_persist_and_reload() {
+        let path = temp_file("reload");
+        let _ = std::fs::remove_file(&path);
+
+        let ledger = EnergyLedger::with_state_file(&path).unwrap();
+        ledger.observe("plug", 100.0);
+        ledger.observe("plug", 10.0);
+        ledger.persist().unwrap();
+
+        // The exporter restarted, and so did the device
+        let reloaded = EnergyLedger::with_state_file(&path).unwrap();
+        assert_eq!(reloaded.observe("plug", 5.0), 115.0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_collect_persists_changes_only() {
+        let path = temp_file("collect");
+        let _ = std::fs::remove_file(&path);
+        let ledger = EnergyLedger::with_state_file(&path).unwrap();
+        let plug = ShellySmartPlug::from_address("10.0.0.2").unwrap();
+        let reading = |total: f64| -> Vec<(ShellySmartPlug, SwitchStatus)> {
+            vec![(plug.clone(), serde_json::from_value(json!({
+                "apower": 0.0,
+                "voltage": 230.0,
+                "current": 0.0,
+                "temperature": { "tC": 40.0, "tF": 104.0 },
+                "aenergy": { "total": total }
+            })).unwrap())]
+        };
+
+        ledger.collect(&reading(100.0));
+        std::fs::remove_file(&path).unwrap();
+        // Scraped again without the counter moving
+        ledger.collect(&reading(100.0));
+        assert!(!path.exists());
+
+        ledger.collect(&reading(101.0));
+        assert!(path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_state_file() {
+        let path = temp_file("invalid");
+        std::fs::write(&path, "not json").unwrap();
+
+        assert_eq!(EnergyLedger::with_state_file(&path).unwrap_err(), "Invalid energy state file!");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_without_state_file() {
+        assert_eq!(EnergyLedger::new().persist(), Ok(()));
+    }
+}
@@ -0,0 +1,94 @@
+//! Errors of requests to the devices, so callers can tell an unreachable device from rejected
+//! credentials or a response which isn't what the exporter expected.
+
+use thiserror::Error;
+
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+
+#[derive(Debug, Error)]
+pub enum ShellyError {
+    #[error("Failed to connect to `{plug}` - {source}")]
+    Connect { plug: String, source: BoxError },
+    #[error("Lost the connection to `{plug}`")]
+    ConnectionLost { plug: String },
+    #[error("`{plug}` didn't answer in time")]
+    Timeout { plug: String },
+    #[error("`{plug}` rejected the credentials with status {status}")]
+    Unauthorized { plug: String, status: u16 },
+    #[error("`{plug}` answered with status {status}")]
+    Status { plug: String, status: u16 },
+    #[error("`{plug}` returned RPC error {code} - {message}")]
+    Rpc { plug: String, code: i64, message: String },
+    #[error("Invalid response from `{plug}` - {source}")]
+    InvalidResponse { plug: String, source: BoxError },
+}
+
+impl ShellyError {
+    /// Alias of the device the request went to
+    pub fn plug(&self) -> &str {
+        match self {
+            ShellyError::Connect { plug, .. }
+            | ShellyError::ConnectionLost { plug }
+            | ShellyError::Timeout { plug }
+            | ShellyError::Unauthorized { plug, .. }
+            | ShellyError::Status { plug, .. }
+            | ShellyError::Rpc { plug, .. }
+            | ShellyError::InvalidResponse { plug, .. } => plug,
+        }
+    }
+
+    /// HTTP status the device answered with, if it answered at all
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ShellyError::Unauthorized { status, .. } | ShellyError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Short name of the kind of failure, used as metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ShellyError::Connect { .. } | ShellyError::ConnectionLost { .. } => "connect",
+            ShellyError::Timeout { .. } => "timeout",
+            ShellyError::Unauthorized { .. } => "unauthorized",
+            ShellyError::Status { .. } => "status",
+            ShellyError::Rpc { .. } => "rpc",
+            ShellyError::InvalidResponse { .. } => "invalid_response",
+        }
+    }
+
+    /// Error for a request to `plug` which failed before any response arrived
+    pub fn from_request(plug: &str, err: reqwest::Error) -> ShellyError {
+        match err.is_timeout() {
+            true => ShellyError::Timeout { plug: plug.to_string() },
+            false => ShellyError::Connect { plug: plug.to_string(), source: err.into() },
+        }
+    }
+
+    /// Error for a device which answered `status` outside of 2xx
+    pub fn from_status(plug: &str, status: u16) -> ShellyError {
+        match status {
+            401 | 403 => ShellyError::Unauthorized { plug: plug.to_string(), status },
+            _ => ShellyError::Status { plug: plug.to_string(), status },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_status() {
+        let unauthorized = ShellyError::from_status("kettle", 401);
+        assert_eq!(unauthorized.reason(), "unauthorized");
+        assert_eq!(unauthorized.status(), Some(401));
+        assert_eq!(unauthorized.to_string(), "`kettle` rejected the credentials with status 401");
+
+        let failed = ShellyError::from_status("kettle", 500);
+        assert_eq!(failed.reason(), "status");
+        assert_eq!(failed.plug(), "kettle");
+    }
+}
@@ -0,0 +1,318 @@
+use chrono::{DateTime, Utc};
+
+use crate::client::{ShellyClient, ShellySmartPlug};
+use crate::error::ShellyError;
+use crate::metrics::{self, Format, MetricFamily};
+use crate::status::{Automations, EmReading, InputStatus, SwitchStatus};
+
+
+/// Poll every plug and render the readings in the requested exposition format
+pub async fn get_metrics(
+    client: &ShellyClient,
+    plugs: &[ShellySmartPlug],
+    format: Format
+) -> Result<String, ShellyError> {
+    let readings = client.get_all_statuses(plugs).await?;
+    Ok(format_metrics(&readings, format))
+}
+
+/// Render a set of plug readings in the requested exposition format
+pub fn format_metrics(readings: &[(ShellySmartPlug, SwitchStatus)], format: Format) -> String {
+    metrics::encode(&collect(readings), format)
+}
+
+/// Build the metric families for a set of plug readings
+pub fn collect(readings: &[(ShellySmartPlug, SwitchStatus)]) -> Vec<MetricFamily> {
+    let mut power = MetricFamily::gauge("power_watts", "Instantaneous active power in watts");
+    let mut voltage = MetricFamily::gauge("voltage", "Supply voltage in volts");
+    let mut current = MetricFamily::gauge("current_amps", "Current in amperes");
+    let mut temp_c = MetricFamily::gauge("temperature_celsius", "Device temperature in celsius");
+    let mut temp_f = MetricFamily::gauge("temperature_fahrenheit", "Device temperature in fahrenheit");
+    let mut total = MetricFamily::counter(
+        "running_total_power_consumed_watts",
+        "Total energy consumed since the device last restarted in watt-hours"
+    );
+    let mut frequency = MetricFamily::gauge("shelly_frequency_hertz", "Grid frequency in hertz");
+    let mut power_factor = MetricFamily::gauge("shelly_power_factor", "Power factor of the load");
+
+    for (plug, status) in readings {
+        let labels = plug.metric_labels();
+
+        power.push(labels.clone(), status.apower);
+        voltage.push(labels.clone(), status.voltage);
+        current.push(labels.clone(), status.current);
+        temp_c.push(labels.clone(), status.temperature.celsius);
+        temp_f.push(labels.clone(), status.temperature.fahrenheit);
+        if let Some(freq) = status.freq {
+            frequency.push(labels.clone(), freq);
+        }
+        if let Some(pf) = status.pf {
+            power_factor.push(labels.clone(), pf);
+        }
+        total.push(labels, status.aenergy.total);
+    }
+
+    vec![power, voltage, current, temp_c, temp_f, total, frequency, power_factor]
+}
+
+/// When the reading of every plug was polled, so cached readings can be told apart from fresh ones
+pub fn collect_poll_timestamps(polls: &[(ShellySmartPlug, DateTime<Utc>)]) -> MetricFamily {
+    let mut family = MetricFamily::gauge(
+        "shelly_poll_timestamp_seconds",
+        "Time the latest reading of the device was polled, in seconds since the epoch"
+    );
+    for (plug, at) in polls {
+        family.push(plug.metric_labels(), at.timestamp_millis() as f64 / 1000.0);
+    }
+    family
+}
+
+/// Attach the poll time to the samples of each plug, the families must be labelled like `collect`
+pub fn stamp(families: &mut [MetricFamily], polls: &[(ShellySmartPlug, DateTime<Utc>)]) {
+    let polls: Vec<(Vec<(String, String)>, i64)> = polls.iter()
+        .map(|(plug, at)| (plug.metric_labels(), at.timestamp_millis()))
+        .collect();
+    for sample in families.iter_mut().flat_map(|family| family.samples.iter_mut()) {
+        sample.timestamp_ms = polls.iter().find(|(labels, _)| *labels == sample.labels).map(|(_, at)| *at);
+    }
+}
+
+/// Uptime, free RAM and WiFi signal of the plugs, only the statuses read through `Shelly.GetStatus`
+/// carry them
+pub fn collect_system(readings: &[(ShellySmartPlug, SwitchStatus)]) -> Vec<MetricFamily> {
+    let mut uptime = MetricFamily::gauge("shelly_uptime_seconds", "Seconds since the device booted");
+    let mut ram_free = MetricFamily::gauge("shelly_ram_free_bytes", "Free RAM of the device in bytes");
+    let mut restart_required = MetricFamily::gauge(
+        "shelly_restart_required",
+        "Whether the device has to be rebooted to apply changed settings"
+    );
+    let mut rssi = MetricFamily::gauge("shelly_wifi_rssi_dbm", "Signal strength of the WiFi connection in dBm");
+
+    for (plug, status) in readings {
+        if let Some(sys) = &status.sys {
+            uptime.push(plug.metric_labels(), sys.uptime as f64);
+            ram_free.push(plug.metric_labels(), sys.ram_free as f64);
+            restart_required.push(plug.metric_labels(), if sys.restart_required { 1.0 } else { 0.0 });
+        }
+        if let Some(signal) = status.wifi.as_ref().and_then(|wifi| wifi.rssi) {
+            rssi.push(plug.metric_labels(), signal);
+        }
+    }
+
+    vec![uptime, ram_free, restart_required, rssi]
+}
+
+/// Difference between the clock of each plug and `now`, to spot plugs with broken NTP. The plugs
+/// only report the start of the current minute (`aenergy.minute_ts`), so the resolution is a minute
+/// and plugs without it are left out.
+pub fn collect_time_drift(readings: &[(ShellySmartPlug, SwitchStatus)], now: DateTime<Utc>) -> MetricFamily {
+    let mut drift = MetricFamily::gauge(
+        "shelly_device_time_drift_seconds",
+        "Device clock minus exporter clock in seconds, at minute resolution"
+    );
+    let exporter_minute = now.timestamp() - now.timestamp().rem_euclid(60);
+
+    for (plug, status) in readings {
+        if let Some(minute_ts) = status.aenergy.minute_ts {
+            drift.push(plug.metric_labels(), (minute_ts - exporter_minute) as f64);
+        }
+    }
+
+    drift
+}
+
+/// State of the physical inputs, to tell manual toggles apart from software control. Inputs of the
+/// `button` type have no state and are left out.
+pub fn collect_inputs(readings: &[(ShellySmartPlug, InputStatus)]) -> MetricFamily {
+    let mut state = MetricFamily::gauge("shelly_input_state", "Whether the physical input is on");
+
+    for (plug, input) in readings {
+        if let Some(on) = input.state {
+            let mut labels = plug.metric_labels();
+            labels.push(("input".to_string(), input.id.to_string()));
+            state.push(labels, if on { 1.0 } else { 0.0 });
+        }
+    }
+
+    state
+}
+
+/// Whether the scripts and schedules on the devices are still active. A script the device stopped
+/// for an error, e.g. one that crashed, reports `shelly_script_error` 1.
+pub fn collect_automations(readings: &[(ShellySmartPlug, Automations)]) -> Vec<MetricFamily> {
+    let mut running = MetricFamily::gauge("shelly_script_running", "Whether the script is running");
+    let mut error = MetricFamily::gauge("shelly_script_error", "Whether the script was stopped by an error");
+    let mut enabled = MetricFamily::gauge("shelly_schedule_enabled", "Whether the schedule is enabled");
+
+    for (plug, automations) in readings {
+        for (script, status) in &automations.scripts {
+            let mut labels = plug.metric_labels();
+            labels.push(("script".to_string(), script.id.to_string()));
+            labels.push(("script_name".to_string(), script.name.clone()));
+            running.push(labels.clone(), if status.running { 1.0 } else { 0.0 });
+            error.push(labels, if status.errors.is_empty() { 0.0 } else { 1.0 });
+        }
+        for job in &automations.schedules {
+            let mut labels = plug.metric_labels();
+            labels.push(("schedule".to_string(), job.id.to_string()));
+            enabled.push(labels, if job.enable { 1.0 } else { 0.0 });
+        }
+    }
+
+    vec![running, error, enabled]
+}
+
+/// Build the per phase metric families of a set of 3-phase energy meter readings
+pub fn collect_meters(readings: &[(ShellySmartPlug, EmReading)]) -> Vec<MetricFamily> {
+    let mut voltage = MetricFamily::gauge("shelly_em_voltage", "Phase voltage in volts");
+    let mut current = MetricFamily::gauge("shelly_em_current_amps", "Phase current in amperes");
+    let mut active_power = MetricFamily::gauge("shelly_em_active_power_watts", "Phase active power in watts");
+    let mut apparent_power = MetricFamily::gauge(
+        "shelly_em_apparent_power_va",
+        "Phase apparent power in volt-amperes"
+    );
+    let mut power_factor = MetricFamily::gauge("shelly_em_power_factor", "Phase power factor");
+    let mut energy = MetricFamily::counter(
+        "shelly_em_active_energy_wh",
+        "Total active energy consumed on the phase in watt-hours"
+    );
+
+    for (meter, reading) in readings {
+        for (phase, values) in reading.phases() {
+            let mut labels = meter.metric_labels();
+            labels.push(("phase".to_string(), phase.to_string()));
+
+            voltage.push(labels.clone(), values.voltage);
+            current.push(labels.clone(), values.current);
+            active_power.push(labels.clone(), values.active_power);
+            apparent_power.push(labels.clone(), values.apparent_power);
+            power_factor.push(labels.clone(), values.power_factor);
+            energy.push(labels, values.total_active_energy);
+        }
+    }
+
+    vec![voltage, current, active_power, apparent_power, power_factor, energy]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+    use serde_json::json;
+
+    fn good_shelly_data() -> String {
+        json!({
+            "apower": 1.0,
+            "voltage": 2.0,
+            "current": 3.0,
+            "temperature": {
+                "tC": 20.1,
+                "tF": 68.2
+            },
+            "aenergy": {
+                "total": 45645634.12
+            }
+        }).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_get_metrics() {
+        let mut fake_server = Server::new_async().await;
+        let test_path = format!("{}/", fake_server.url());
+        let plugs: Vec<ShellySmartPlug> = vec![
+            ShellySmartPlug{ url: test_path.clone(), alias: "alias1".to_string(), labels: vec![] },
+            ShellySmartPlug{
+                url: test_path.clone(),
+                alias: "alias2".to_string(),
+                labels: vec![("room".to_string(), "office".to_string())],
+            }
+        ];
+
+        fake_server.mock("GET", "/")
+            .with_status(200)
+            .with_body(good_shelly_data())
+            .create_async()
+            .await;
+
+        let actual = get_metrics(&ShellyClient::new(), &plugs, Format::Prometheus).await.unwrap();
+
+        assert_eq!(actual,
+r#"# HELP power_watts Instantaneous active power in watts
+# TYPE power_watts gauge
+power_watts{hostname="alias1"} 1.0
+power_watts{hostname="alias2",room="office"} 1.0
+# HELP voltage Supply voltage in volts
+# TYPE voltage gauge
+voltage{hostname="alias1"} 2.0
+voltage{hostname="alias2",room="office"} 2.0
+# HELP current_amps Current in amperes
+# TYPE current_amps gauge
+current_amps{hostname="alias1"} 3.0
+current_amps{hostname="alias2",room="office"} 3.0
+# HELP temperature_celsius Device temperature in celsius
+# TYPE temperature_celsius gauge
+temperature_celsius{hostname="alias1"} 20.1
+temperature_celsius{hostname="alias2",room="office"} 20.1
+# HELP temperature_fahrenheit Device temperature in fahrenheit
+# TYPE temperature_fahrenheit gauge
+temperature_fahrenheit{hostname="alias1"} 68.2
+temperature_fahrenheit{hostname="alias2",room="office"} 68.2
+# HELP running_total_power_consumed_watts Total energy consumed since the device last restarted in watt-hours
+# TYPE running_total_power_consumed_watts counter
+running_total_power_consumed_watts{hostname="alias1"} 45645634.12
+running_total_power_consumed_watts{hostname="alias2",room="office"} 45645634.12
+"#
+        );
+
+        let actual_openmetrics = get_metrics(&ShellyClient::new(), &plugs, Format::OpenMetrics).await.unwrap();
+
+        assert!(actual_openmetrics.contains("\nrunning_total_power_consumed_watts_total{hostname=\"alias1\"} 45645634.12\n"));
+        assert!(actual_openmetrics.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_collect_time_drift() {
+        let reading = |alias: &str, minute_ts: Option<i64>| {
+            let mut status: SwitchStatus = serde_json::from_str(&good_shelly_data()).unwrap();
+            status.aenergy.minute_ts = minute_ts;
+            (ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: alias.to_string(), labels: vec![] }, status)
+        };
+        // 2025-01-01T12:00:42Z
+        let now = DateTime::from_timestamp(1735732842, 0).unwrap();
+
+        let actual = collect_time_drift(
+            &[reading("synced", Some(1735732800)), reading("late", Some(1735732500)), reading("old", None)],
+            now,
+        );
+
+        assert_eq!(actual.samples.iter().map(|s| (s.labels[0].1.as_str(), s.value)).collect::<Vec<_>>(), vec![
+            ("synced", 0.0),
+            ("late", -300.0),
+        ]);
+    }
+
+    #[test]
+    fn test_poll_timestamps() {
+        let status: SwitchStatus = serde_json::from_str(&good_shelly_data()).unwrap();
+        let fresh = ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: "fresh".to_string(), labels: vec![] };
+        let cached = ShellySmartPlug { alias: "cached".to_string(), ..fresh.clone() };
+        let polls = [
+            (fresh.clone(), DateTime::from_timestamp_millis(1735732842500).unwrap()),
+            (cached.clone(), DateTime::from_timestamp(1735732000, 0).unwrap()),
+        ];
+
+        let actual = collect_poll_timestamps(&polls);
+        assert_eq!(actual.samples.iter().map(|s| s.value).collect::<Vec<_>>(), vec![1735732842.5, 1735732000.0]);
+
+        let mut families = collect(&[(fresh, status.clone()), (cached, status)]);
+        stamp(&mut families, &polls);
+        assert_eq!(families[0].samples[0].timestamp_ms, Some(1735732842500));
+        assert_eq!(families[0].samples[1].timestamp_ms, Some(1735732000000));
+    }
+
+    #[test]
+    fn test_format_metrics_empty() {
+        assert_eq!(format_metrics(&[], Format::Prometheus), "");
+        assert_eq!(format_metrics(&[], Format::OpenMetrics), "# EOF\n");
+    }
+}
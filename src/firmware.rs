@@ -0,0 +1,100 @@
+//! Firmware updates available to the plugs. A check has the plug ask the Shelly update server, so
+//! every plug is only checked once per interval and the latest answer is served in between.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::client::{ShellyClient, ShellySmartPlug};
+use crate::error::ShellyError;
+use crate::metrics::MetricFamily;
+use crate::status::AvailableUpdates;
+
+
+/// Release channels of `Shelly.CheckForUpdate`, also the stages `Shelly.Update` takes
+pub const CHANNELS: [&str; 2] = ["stable", "beta"];
+
+
+#[derive(Debug)]
+pub struct FirmwareChecks {
+    interval: Duration,
+    /// Latest answer of every plug, keyed by alias
+    checked: Mutex<HashMap<String, (Instant, AvailableUpdates)>>,
+}
+
+impl FirmwareChecks {
+    pub fn new(interval: Duration) -> FirmwareChecks {
+        FirmwareChecks { interval, checked: Mutex::new(HashMap::new()) }
+    }
+
+    /// Updates available to the plug, only asking it again once the interval passed
+    pub async fn updates_of(&self, client: &ShellyClient, plug: &ShellySmartPlug) -> Result<AvailableUpdates, ShellyError> {
+        if let Some((checked_at, updates)) = self.checked.lock().unwrap().get(&plug.alias) {
+            if checked_at.elapsed() < self.interval {
+                return Ok(updates.clone());
+            }
+        }
+
+        let updates = client.check_for_update(plug).await?;
+        self.checked.lock().unwrap().insert(plug.alias.clone(), (Instant::now(), updates.clone()));
+        Ok(updates)
+    }
+
+    /// Check the plug again next time, e.g. once it was told to update
+    pub fn forget(&self, alias: &str) {
+        self.checked.lock().unwrap().remove(alias);
+    }
+}
+
+
+pub fn collect(readings: &[(ShellySmartPlug, AvailableUpdates)]) -> MetricFamily {
+    let mut family = MetricFamily::gauge(
+        "shelly_firmware_update_available",
+        "Whether a newer firmware is available to the plug, by release channel"
+    );
+
+    for (plug, updates) in readings {
+        for (channel, update) in CHANNELS.into_iter().zip([&updates.stable, &updates.beta]) {
+            let mut labels = plug.metric_labels();
+            labels.push(("channel".to_string(), channel.to_string()));
+            family.push(labels, if update.is_some() { 1.0 } else { 0.0 });
+        }
+    }
+
+    family
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_updates_of() {
+        let mut server = Server::new_async().await;
+        let check = server.mock("GET", "/rpc/Shelly.CheckForUpdate")
+            .with_status(200)
+            .with_body(json!({ "beta": { "version": "1.5.0-beta1", "build_id": "20250101-000000/1.5.0-beta1" } }).to_string())
+            .expect(2)
+            .create_async()
+            .await;
+        let plug = ShellySmartPlug::from_address(&server.host_with_port()).unwrap();
+        let client = ShellyClient::new();
+        let checks = FirmwareChecks::new(Duration::from_secs(3600));
+
+        let actual = checks.updates_of(&client, &plug).await.unwrap();
+        assert_eq!(actual.stable, None);
+        assert_eq!(actual.beta.unwrap().version, "1.5.0-beta1");
+        checks.updates_of(&client, &plug).await.unwrap();
+        checks.forget(&plug.alias);
+        let actual = checks.updates_of(&client, &plug).await.unwrap();
+        check.assert_async().await;
+
+        let family = collect(&[(plug, actual)]);
+        let values: Vec<(&str, f64)> = family.samples.iter()
+            .map(|sample| (sample.labels[1].1.as_str(), sample.value))
+            .collect();
+        assert_eq!(values, vec![("stable", 0.0), ("beta", 1.0)]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Renderers of plug readings as JSON and CSV dumps, served on `/export` for ad-hoc scripts and
+//! spreadsheets. The exposition formats are rendered by `metrics::encode`, from every family.
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+use crate::client::ShellySmartPlug;
+use crate::status::SwitchStatus;
+
+
+/// Columns of the CSV export, in order
+const CSV_COLUMNS: [&str; 12] = [
+    "alias",
+    "address",
+    "labels",
+    "output",
+    "power_watts",
+    "voltage",
+    "current_amps",
+    "temperature_celsius",
+    "temperature_fahrenheit",
+    "energy_total_wh",
+    "frequency_hertz",
+    "power_factor",
+];
+
+
+pub trait MetricFormatter {
+    fn content_type(&self) -> &'static str;
+
+    fn format(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) -> String;
+}
+
+/// An array with an object per plug
+pub struct JsonFormatter;
+
+/// A header line and a line per plug, labels are joined into one `name=value;...` column
+pub struct CsvFormatter;
+
+
+/// Formatter of `/export` by its `format` parameter, `None` for unknown ones. The exposition
+/// formats are left out, `/metrics` serves them with every family rather than only the readings.
+pub fn by_name(name: &str) -> Option<Box<dyn MetricFormatter>> {
+    match name {
+        "json" => Some(Box::new(JsonFormatter)),
+        "csv" => Some(Box::new(CsvFormatter)),
+        _ => None,
+    }
+}
+
+
+#[derive(Debug, PartialEq, Serialize)]
+struct ExportedReading<'a> {
+    alias: &'a str,
+    address: String,
+    labels: BTreeMap<&'a str, &'a str>,
+    output: Option<bool>,
+    power_watts: f64,
+    voltage: f64,
+    current_amps: f64,
+    temperature_celsius: f64,
+    temperature_fahrenheit: f64,
+    energy_total_wh: f64,
+    frequency_hertz: Option<f64>,
+    power_factor: Option<f64>,
+}
+
+impl<'a> ExportedReading<'a> {
+    fn new(plug: &'a ShellySmartPlug, status: &SwitchStatus) -> ExportedReading<'a> {
+        ExportedReading {
+            alias: &plug.alias,
+            address: plug.address(),
+            labels: plug.labels.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect(),
+            output: status.output,
+            power_watts: status.apower,
+            voltage: status.voltage,
+            current_amps: status.current,
+            temperature_celsius: status.temperature.celsius,
+            temperature_fahrenheit: status.temperature.fahrenheit,
+            energy_total_wh: status.aenergy.total,
+            frequency_hertz: status.freq,
+            power_factor: status.pf,
+        }
+    }
+}
+
+
+impl MetricFormatter for JsonFormatter {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn format(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) -> String {
+        let exported: Vec<ExportedReading> = readings.iter()
+            .map(|(plug, status)| ExportedReading::new(plug, status))
+            .collect();
+        serde_json::to_string(&exported).expect("Readings are always serializable")
+    }
+}
+
+impl MetricFormatter for CsvFormatter {
+    fn content_type(&self) -> &'static str {
+        "text/csv; charset=utf-8"
+    }
+
+    fn format(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) -> String {
+        let mut output = CSV_COLUMNS.join(",") + "\n";
+        for (plug, status) in readings {
+            let reading = ExportedReading::new(plug, status);
+            let labels: Vec<String> = reading.labels.iter().map(|(name, value)| format!("{name}={value}")).collect();
+            let optional = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+            let row = [
+                csv_field(reading.alias),
+                csv_field(&reading.address),
+                csv_field(&labels.join(";")),
+                reading.output.map(|output| output.to_string()).unwrap_or_default(),
+                reading.power_watts.to_string(),
+                reading.voltage.to_string(),
+                reading.current_amps.to_string(),
+                reading.temperature_celsius.to_string(),
+                reading.temperature_fahrenheit.to_string(),
+                reading.energy_total_wh.to_string(),
+                optional(reading.frequency_hertz),
+                optional(reading.power_factor),
+            ];
+            output += &row.join(",");
+            output += "\n";
+        }
+        output
+    }
+}
+
+
+/// Quote fields with separators, quotes or line breaks, RFC 4180 style
+fn csv_field(raw: &str) -> String {
+    match raw.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", raw.replace('"', "\"\"")),
+        false => raw.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn readings() -> Vec<(ShellySmartPlug, SwitchStatus)> {
+        let status: SwitchStatus = serde_json::from_value(json!({
+            "output": true,
+            "apower": 1850.2,
+            "voltage": 231.4,
+            "current": 8.03,
+            "freq": 50.0,
+            "temperature": { "tC": 41.2, "tF": 106.2 },
+            "aenergy": { "total": 45645.1 }
+        })).unwrap();
+        let kettle = ShellySmartPlug {
+            alias: "kettle".to_string(),
+            labels: vec![("room".to_string(), "kitchen, upstairs".to_string())],
+            ..ShellySmartPlug::from_address("10.0.0.2").unwrap()
+        };
+        vec![(kettle, status)]
+    }
+
+    #[test]
+    fn test_json() {
+        let actual: serde_json::Value = serde_json::from_str(&JsonFormatter.format(&readings())).unwrap();
+
+        assert_eq!(actual, json!([{
+            "alias": "kettle",
+            "address": "10.0.0.2",
+            "labels": { "room": "kitchen, upstairs" },
+            "output": true,
+            "power_watts": 1850.2,
+            "voltage": 231.4,
+            "current_amps": 8.03,
+            "temperature_celsius": 41.2,
+            "temperature_fahrenheit": 106.2,
+            "energy_total_wh": 45645.1,
+            "frequency_hertz": 50.0,
+            "power_factor": null,
+        }]));
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(CsvFormatter.format(&readings()), "\
+            alias,address,labels,output,power_watts,voltage,current_amps,temperature_celsius,temperature_fahrenheit,energy_total_wh,frequency_hertz,power_factor\n\
+            kettle,10.0.0.2,\"room=kitchen, upstairs\",true,1850.2,231.4,8.03,41.2,106.2,45645.1,50,\n");
+        assert_eq!(csv_field("kettle"), "kettle");
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn test_by_name() {
+        assert_eq!(by_name("csv").unwrap().content_type(), "text/csv; charset=utf-8");
+        assert!(by_name("openmetrics").is_none());
+        assert!(by_name("xml").is_none());
+    }
+}
@@ -0,0 +1,154 @@
+//! The simple JSON datasource contract, letting Grafana chart the local history straight from the
+//! exporter. Every plug offers one `<alias>.<series>` target per stored series.
+//!
+//! Ref: https://github.com/grafana/simple-json-datasource
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::client::ShellySmartPlug;
+use crate::history::HistoryPoint;
+
+
+pub const POWER_WATTS: &str = "power_watts";
+pub const ENERGY_TOTAL_WH: &str = "energy_total_wh";
+const SERIES: [&str; 2] = [POWER_WATTS, ENERGY_TOTAL_WH];
+
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchRequest {
+    /// Whatever was typed in the query editor so far
+    #[serde(default)]
+    pub target: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRange {
+    pub from: String,
+    pub to: String,
+}
+
+impl QueryRange {
+    pub fn parse(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let from = DateTime::parse_from_rfc3339(&self.from).ok()?;
+        let to = DateTime::parse_from_rfc3339(&self.to).ok()?;
+        Some((from.with_timezone(&Utc), to.with_timezone(&Utc)))
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct QueryTarget {
+    pub target: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub range: QueryRange,
+    pub targets: Vec<QueryTarget>,
+    pub max_data_points: Option<usize>,
+}
+
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TimeSeries {
+    pub target: String,
+    /// `[value, unix milliseconds]` pairs, oldest first
+    pub datapoints: Vec<(f64, i64)>,
+}
+
+
+/// Targets offered for the plugs which contain `filter`
+pub fn search(plugs: &[ShellySmartPlug], filter: &str) -> Vec<String> {
+    plugs.iter()
+        .flat_map(|plug| SERIES.iter().map(move |series| format!("{}.{series}", plug.alias)))
+        .filter(|target| target.contains(filter))
+        .collect()
+}
+
+
+/// Split a target into the plug alias and series, `None` if the series is unknown
+pub fn parse_target(target: &str) -> Option<(&str, &'static str)> {
+    let (alias, series) = target.rsplit_once('.')?;
+    let series = SERIES.iter().find(|known| **known == series)?;
+    Some((alias, series))
+}
+
+
+/// Build the series of a target out of the plug's history, keeping at most `max_data_points`
+/// evenly spread points
+pub fn time_series(
+    target: &str,
+    series: &str,
+    points: &[HistoryPoint],
+    max_data_points: Option<usize>,
+) -> TimeSeries {
+    let step = match max_data_points {
+        Some(max) if max > 0 => points.len().div_ceil(max).max(1),
+        _ => 1,
+    };
+
+    let datapoints = points.iter()
+        .step_by(step)
+        .map(|point| {
+            let value = match series {
+                ENERGY_TOTAL_WH => point.energy_total_wh,
+                _ => point.power_watts,
+            };
+            (value, point.timestamp.timestamp_millis())
+        })
+        .collect();
+
+    TimeSeries { target: target.to_string(), datapoints }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn point(minute: u32, power_watts: f64) -> HistoryPoint {
+        HistoryPoint {
+            timestamp: Utc.with_ymd_and_hms(2025, 1, 1, 12, minute, 0).unwrap(),
+            power_watts,
+            energy_total_wh: 10.0 + minute as f64,
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let plugs = vec![
+            ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: "kettle".to_string(), labels: vec![] },
+            ShellySmartPlug { url: "http://10.0.0.3".to_string(), alias: "tv".to_string(), labels: vec![] },
+        ];
+
+        assert_eq!(search(&plugs, ""), vec![
+            "kettle.power_watts", "kettle.energy_total_wh", "tv.power_watts", "tv.energy_total_wh",
+        ]);
+        assert_eq!(search(&plugs, "tv.p"), vec!["tv.power_watts"]);
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(parse_target("living.room.energy_total_wh"), Some(("living.room", ENERGY_TOTAL_WH)));
+        assert_eq!(parse_target("kettle.voltage"), None);
+        assert_eq!(parse_target("kettle"), None);
+    }
+
+    #[test]
+    fn test_time_series() {
+        let points = vec![point(0, 1.0), point(1, 2.0), point(2, 3.0)];
+
+        let power = time_series("kettle.power_watts", POWER_WATTS, &points, None);
+        assert_eq!(power, TimeSeries {
+            target: "kettle.power_watts".to_string(),
+            datapoints: vec![(1.0, 1735732800000), (2.0, 1735732860000), (3.0, 1735732920000)],
+        });
+
+        let energy = time_series("kettle.energy_total_wh", ENERGY_TOTAL_WH, &points, Some(2));
+        assert_eq!(energy.datapoints, vec![(10.0, 1735732800000), (12.0, 1735732920000)]);
+    }
+}
@@ -0,0 +1,102 @@
+//! Sums of the readings of plugs grouped in the config file, e.g. by room, so dashboards don't need
+//! a `sum by` query for every grouping.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::client::ShellySmartPlug;
+use crate::energy::EnergyLedger;
+use crate::metrics::MetricFamily;
+use crate::status::SwitchStatus;
+
+
+#[derive(Debug)]
+pub struct Groups {
+    /// Group names keyed by IP or alias, like the config file
+    groups: HashMap<String, String>,
+}
+
+impl Groups {
+    /// `None` when no plug is in a group
+    pub fn new(groups: HashMap<String, String>) -> Option<Groups> {
+        match groups.is_empty() {
+            true => None,
+            false => Some(Groups { groups }),
+        }
+    }
+
+    /// Group of the plug, an alias entry wins over the IP one
+    pub fn group_of(&self, plug: &ShellySmartPlug) -> Option<&str> {
+        self.groups.get(&plug.alias).or_else(|| self.groups.get(&plug.target())).map(String::as_str)
+    }
+
+    /// Power and energy of every group, summed over the members in `readings`. The energy must
+    /// already be observed by the ledger.
+    pub fn collect(&self, readings: &[(ShellySmartPlug, SwitchStatus)], energy: &EnergyLedger) -> Vec<MetricFamily> {
+        let mut power = MetricFamily::gauge(
+            "shelly_group_power_watts",
+            "Current power of the plugs in the group in watts"
+        );
+        let mut consumed = MetricFamily::counter(
+            "shelly_group_energy_total",
+            "Total energy consumed by the plugs in the group in watt-hours, carried across device reboots"
+        );
+
+        let mut sums: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+        for (plug, status) in readings {
+            if let Some(group) = self.group_of(plug) {
+                let sum = sums.entry(group).or_default();
+                sum.0 += status.apower;
+                sum.1 += energy.consumed_wh_of(&plug.alias).unwrap_or_default();
+            }
+        }
+        for (group, (watts, wh)) in sums {
+            let labels = vec![("group".to_string(), group.to_string())];
+            power.push(labels.clone(), watts);
+            consumed.push(labels, wh);
+        }
+
+        vec![power, consumed]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reading(apower: f64, total: f64) -> SwitchStatus {
+        serde_json::from_value(json!({
+            "apower": apower,
+            "voltage": 230.0,
+            "current": 1.0,
+            "temperature": { "tC": 40.0, "tF": 104.0 },
+            "aenergy": { "total": total }
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_collect() {
+        let groups = Groups::new(HashMap::from([
+            ("10.0.0.2".to_string(), "office".to_string()),
+            ("kettle".to_string(), "kitchen".to_string()),
+            ("10.0.0.3".to_string(), "office".to_string()),
+        ])).unwrap();
+        let readings = vec![
+            (ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.4").unwrap() }, reading(2000.0, 10.0)),
+            (ShellySmartPlug::from_address("10.0.0.2").unwrap(), reading(100.0, 200.0)),
+            (ShellySmartPlug::from_address("10.0.0.3").unwrap(), reading(50.0, 20.0)),
+            (ShellySmartPlug::from_address("10.0.0.5").unwrap(), reading(1.0, 1.0)),
+        ];
+        let energy = EnergyLedger::new();
+        energy.collect(&readings);
+
+        let actual = groups.collect(&readings, &energy);
+
+        let samples = |family: &MetricFamily| -> Vec<(String, f64)> {
+            family.samples.iter().map(|sample| (sample.labels[0].1.clone(), sample.value)).collect()
+        };
+        assert_eq!(samples(&actual[0]), vec![("kitchen".to_string(), 2000.0), ("office".to_string(), 150.0)]);
+        assert_eq!(samples(&actual[1]), vec![("kitchen".to_string(), 10.0), ("office".to_string(), 220.0)]);
+        assert!(Groups::new(HashMap::new()).is_none());
+    }
+}
@@ -0,0 +1,233 @@
+//! Per plug outcome of the latest requests, to alert on plugs which have been unreachable for a while.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+
+use crate::client::ShellySmartPlug;
+use crate::error::ShellyError;
+use crate::metrics::{Histogram, MetricFamily};
+
+
+/// Bucket bounds of `shelly_device_request_duration_seconds` in seconds, unless configured
+pub const DEFAULT_LATENCY_BUCKETS: [f64; 9] = [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlugHealth {
+    pub up: bool,
+    pub last_success: Option<DateTime<Utc>>,
+    pub consecutive_failures: u64,
+    /// Why the latest request failed, cleared by the next successful one
+    pub last_error: Option<String>,
+    /// Failed requests since the exporter started, by `ShellyError::reason`
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+
+/// Health of every plug the client talked to, keyed by alias
+#[derive(Debug)]
+pub struct HealthTracker {
+    entries: RwLock<HashMap<String, PlugHealth>>,
+    latency_buckets: Vec<f64>,
+    latencies: RwLock<HashMap<String, Histogram>>,
+    reboots: RwLock<HashMap<String, u64>>,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        HealthTracker::with_latency_buckets(&DEFAULT_LATENCY_BUCKETS)
+    }
+}
+
+impl HealthTracker {
+    pub fn new() -> HealthTracker {
+        HealthTracker::default()
+    }
+
+    /// Bucket bounds must be validated with `metrics::is_valid_buckets`
+    pub fn with_latency_buckets(bounds: &[f64]) -> HealthTracker {
+        HealthTracker {
+            entries: RwLock::new(HashMap::new()),
+            latency_buckets: bounds.to_vec(),
+            latencies: RwLock::new(HashMap::new()),
+            reboots: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Duration of a request to the plug, whether it succeeded or not
+    pub fn record_latency(&self, alias: &str, duration: Duration) {
+        let mut latencies = self.latencies.write().unwrap();
+        latencies.entry(alias.to_string())
+            .or_insert_with(|| Histogram::new(&self.latency_buckets))
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Reboot of the plug the exporter asked for
+    pub fn record_reboot(&self, alias: &str) {
+        *self.reboots.write().unwrap().entry(alias.to_string()).or_default() += 1;
+    }
+
+    pub fn record_success(&self, alias: &str, at: DateTime<Utc>) {
+        let mut entries = self.entries.write().unwrap();
+        let entry = entries.entry(alias.to_string()).or_default();
+        entry.up = true;
+        entry.last_success = Some(at);
+        entry.consecutive_failures = 0;
+        entry.last_error = None;
+    }
+
+    pub fn record_failure(&self, alias: &str, error: &ShellyError) {
+        let mut entries = self.entries.write().unwrap();
+        let entry = entries.entry(alias.to_string()).or_default();
+        entry.up = false;
+        entry.consecutive_failures += 1;
+        entry.last_error = Some(error.to_string());
+        *entry.errors.entry(error.reason()).or_default() += 1;
+    }
+
+    pub fn get(&self, alias: &str) -> Option<PlugHealth> {
+        self.entries.read().unwrap().get(alias).cloned()
+    }
+
+    /// Health families of the given plugs, plugs which were never contacted are left out
+    pub fn collect(&self, plugs: &[ShellySmartPlug]) -> Vec<MetricFamily> {
+        let mut up = MetricFamily::gauge("shelly_plug_up", "Whether the latest request to the plug succeeded");
+        let mut last_success = MetricFamily::gauge(
+            "shelly_plug_last_successful_scrape_timestamp_seconds",
+            "Unix time of the latest successful request to the plug",
+        );
+        let mut failures = MetricFamily::gauge(
+            "shelly_plug_consecutive_failures",
+            "Number of failed requests to the plug since the last successful one",
+        );
+        let mut errors = MetricFamily::counter(
+            "shelly_plug_errors_total",
+            "Failed requests to the plug since the exporter started, by reason",
+        );
+        let mut latency = MetricFamily::histogram(
+            "shelly_device_request_duration_seconds",
+            "Duration of the requests to the plug in seconds",
+        );
+        let mut reboots_triggered = MetricFamily::counter(
+            "shelly_device_reboots_triggered_total",
+            "Reboots of the plug triggered through the exporter since it started",
+        );
+
+        let entries = self.entries.read().unwrap();
+        let latencies = self.latencies.read().unwrap();
+        let reboots = self.reboots.read().unwrap();
+        for plug in plugs {
+            if let Some(count) = reboots.get(&plug.alias) {
+                reboots_triggered.push(plug.metric_labels(), *count as f64);
+            }
+            if let Some(histogram) = latencies.get(&plug.alias) {
+                latency.push_histogram(plug.metric_labels(), histogram);
+            }
+            let health = match entries.get(&plug.alias) {
+                Some(health) => health,
+                None => continue,
+            };
+
+            up.push(plug.metric_labels(), if health.up { 1.0 } else { 0.0 });
+            if let Some(at) = health.last_success {
+                last_success.push(plug.metric_labels(), at.timestamp_millis() as f64 / 1000.0);
+            }
+            failures.push(plug.metric_labels(), health.consecutive_failures as f64);
+            for (reason, count) in &health.errors {
+                let mut labels = plug.metric_labels();
+                labels.push(("reason".to_string(), reason.to_string()));
+                errors.push(labels, *count as f64);
+            }
+        }
+
+        vec![up, last_success, failures, errors, latency, reboots_triggered]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn timeout(alias: &str) -> ShellyError {
+        ShellyError::Timeout { plug: alias.to_string() }
+    }
+
+    fn plug(alias: &str) -> ShellySmartPlug {
+        ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: alias.to_string(), labels: vec![] }
+    }
+
+    #[test]
+    fn test_failures_reset_on_success() {
+        let tracker = HealthTracker::new();
+        let at = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+
+        tracker.record_failure("kettle", &timeout("kettle"));
+        tracker.record_failure("kettle", &ShellyError::from_status("kettle", 500));
+        assert_eq!(tracker.get("kettle"), Some(PlugHealth {
+            up: false,
+            last_success: None,
+            consecutive_failures: 2,
+            last_error: Some("`kettle` answered with status 500".to_string()),
+            errors: BTreeMap::from([("status", 1), ("timeout", 1)]),
+        }));
+
+        tracker.record_success("kettle", at);
+        assert_eq!(tracker.get("kettle").unwrap().last_error, None);
+        tracker.record_failure("kettle", &timeout("kettle"));
+        assert_eq!(tracker.get("kettle").unwrap().consecutive_failures, 1);
+        assert_eq!(tracker.get("kettle").unwrap().last_success, Some(at));
+    }
+
+    #[test]
+    fn test_collect() {
+        let tracker = HealthTracker::new();
+        tracker.record_success("kettle", Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap());
+        tracker.record_failure("tv", &timeout("tv"));
+        tracker.record_failure("tv", &timeout("tv"));
+
+        let actual = tracker.collect(&[plug("kettle"), plug("tv"), plug("garage")]);
+        let labels = |alias: &str| vec![("hostname".to_string(), alias.to_string())];
+
+        assert_eq!(actual[0].samples.iter().map(|s| (s.labels.clone(), s.value)).collect::<Vec<_>>(), vec![
+            (labels("kettle"), 1.0),
+            (labels("tv"), 0.0),
+        ]);
+        assert_eq!(actual[1].samples.len(), 1);
+        assert_eq!(actual[1].samples[0].value, 1735732800.0);
+        assert_eq!(actual[2].samples.iter().map(|s| s.value).collect::<Vec<_>>(), vec![0.0, 2.0]);
+        assert_eq!(actual[3].samples.len(), 1);
+        assert_eq!(actual[3].samples[0].labels[1], ("reason".to_string(), "timeout".to_string()));
+        assert_eq!(actual[3].samples[0].value, 2.0);
+    }
+
+    #[test]
+    fn test_collect_latency() {
+        let tracker = HealthTracker::with_latency_buckets(&[0.1, 1.0]);
+        tracker.record_latency("kettle", Duration::from_millis(50));
+        tracker.record_latency("kettle", Duration::from_secs(2));
+
+        let actual = &tracker.collect(&[plug("kettle"), plug("tv")])[4];
+
+        let buckets: Vec<(&str, f64)> = actual.samples.iter()
+            .filter(|sample| sample.suffix == "_bucket")
+            .map(|sample| (sample.labels[1].1.as_str(), sample.value))
+            .collect();
+        assert_eq!(buckets, vec![("0.1", 1.0), ("1.0", 1.0), ("+Inf", 2.0)]);
+        assert_eq!(actual.samples.last().unwrap().value, 2.0);
+    }
+
+    #[test]
+    fn test_collect_reboots() {
+        let tracker = HealthTracker::new();
+        tracker.record_reboot("kettle");
+        tracker.record_reboot("kettle");
+
+        let actual = &tracker.collect(&[plug("kettle"), plug("tv")])[5];
+
+        assert_eq!(actual.samples.len(), 1);
+        assert_eq!(actual.samples[0].value, 2.0);
+    }
+}
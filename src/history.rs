@@ -0,0 +1,209 @@
+//! Optional local history of the background polls in an embedded SQLite database, so short-term
+//! power and energy series are available even without Prometheus.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::{error, info};
+use rusqlite::{params, Connection};
+use serde::{Serialize, Serializer};
+use tokio::sync::broadcast;
+
+use crate::client::ShellySmartPlug;
+use crate::poller::{self, Readings};
+use crate::status::SwitchStatus;
+
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS readings (
+        alias TEXT NOT NULL,
+        timestamp_ms INTEGER NOT NULL,
+        power_watts REAL NOT NULL,
+        energy_total_wh REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS readings_alias_timestamp ON readings (alias, timestamp_ms);
+";
+
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryPoint {
+    /// Time of the poll, serialized as RFC 3339
+    #[serde(serialize_with = "serialize_rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    pub power_watts: f64,
+    pub energy_total_wh: f64,
+}
+
+
+pub struct History {
+    connection: Mutex<Connection>,
+    /// Readings older than this are deleted, kept forever if `None`
+    retention: Option<Duration>,
+}
+
+impl History {
+    /// Open (or create) the database at `path`
+    pub fn open(path: &Path, retention: Option<Duration>) -> Result<History, &'static str> {
+        let connection = Connection::open(path)
+            .and_then(|connection| connection.execute_batch(SCHEMA).map(|_| connection))
+            .map_err(|err| {
+                error!("Failed to open history database `{}` - {err}", path.display());
+                "Unable to open history database!"
+            })?;
+
+        Ok(History { connection: Mutex::new(connection), retention })
+    }
+
+    pub fn record(
+        &self,
+        readings: &[(ShellySmartPlug, SwitchStatus)],
+        at: DateTime<Utc>,
+    ) -> Result<(), &'static str> {
+        let mut connection = self.connection.lock().unwrap();
+        let result = connection.transaction().and_then(|transaction| {
+            for (plug, status) in readings {
+                transaction.execute(
+                    "INSERT INTO readings (alias, timestamp_ms, power_watts, energy_total_wh) VALUES (?1, ?2, ?3, ?4)",
+                    params![plug.alias, at.timestamp_millis(), status.apower, status.aenergy.total],
+                )?;
+            }
+            if let Some(retention) = self.retention {
+                transaction.execute(
+                    "DELETE FROM readings WHERE timestamp_ms < ?1",
+                    params![(at - retention).timestamp_millis()],
+                )?;
+            }
+            transaction.commit()
+        });
+
+        result.map_err(|err| {
+            error!("Failed to record readings in the history database - {err}");
+            "Unable to write history!"
+        })
+    }
+
+    /// Readings of the plug between `from` and `to` (both inclusive), oldest first
+    pub fn query(
+        &self,
+        alias: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<HistoryPoint>, &'static str> {
+        let connection = self.connection.lock().unwrap();
+        let result = connection
+            .prepare(
+                "SELECT timestamp_ms, power_watts, energy_total_wh FROM readings \
+                WHERE alias = ?1 AND timestamp_ms BETWEEN ?2 AND ?3 ORDER BY timestamp_ms"
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map(params![alias, from.timestamp_millis(), to.timestamp_millis()], |row| {
+                        Ok(HistoryPoint {
+                            timestamp: Utc.timestamp_millis_opt(row.get(0)?).single().unwrap_or_default(),
+                            power_watts: row.get(1)?,
+                            energy_total_wh: row.get(2)?,
+                        })
+                    })?
+                    .collect()
+            });
+
+        result.map_err(|err| {
+            error!("Failed to query the history database - {err}");
+            "Unable to read history!"
+        })
+    }
+}
+
+
+fn serialize_rfc3339<S: Serializer>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&timestamp.to_rfc3339())
+}
+
+
+/// Record every poll's readings until the poller goes away. Plugs with a longer poll interval than
+/// others are broadcast unchanged in between their own polls, only new readings are recorded.
+pub async fn run(history: Arc<History>, mut readings: broadcast::Receiver<Readings>) {
+    info!("Recording readings in the history database after every poll");
+    let mut recorded: HashMap<String, SwitchStatus> = HashMap::new();
+
+    while let Some(readings) = poller::next_readings(&mut readings).await {
+        let readings: Vec<(ShellySmartPlug, SwitchStatus)> = readings.iter()
+            .filter(|(plug, status)| recorded.get(&plug.alias) != Some(status))
+            .cloned()
+            .collect();
+        if readings.is_empty() {
+            continue;
+        }
+        recorded.extend(readings.iter().map(|(plug, status)| (plug.alias.clone(), status.clone())));
+
+        let history = history.clone();
+        // A failed write is logged, the next poll is recorded regardless
+        let _ = tokio::task::spawn_blocking(move || history.record(&readings, Utc::now())).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::{EnergyCounter, Temperature};
+
+    fn reading(alias: &str, apower: f64, total: f64) -> (ShellySmartPlug, SwitchStatus) {
+        let plug = ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: alias.to_string(), labels: vec![] };
+        let status = SwitchStatus {
+            id: 0,
+            output: Some(true),
+            apower,
+            voltage: 2.0,
+            current: 3.0,
+            temperature: Temperature { celsius: 20.1, fahrenheit: 68.2 },
+            aenergy: EnergyCounter { total, by_minute: vec![], minute_ts: None },
+            sys: None,
+            wifi: None,
+            freq: None,
+            pf: None,
+        };
+
+        (plug, status)
+    }
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 1, 12, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_record_and_query() {
+        let history = History::open(Path::new(":memory:"), None).unwrap();
+        history.record(&[reading("kettle", 1.0, 10.0), reading("tv", 5.0, 50.0)], at(0)).unwrap();
+        history.record(&[reading("kettle", 2.0, 11.0)], at(1)).unwrap();
+        history.record(&[reading("kettle", 3.0, 12.0)], at(2)).unwrap();
+
+        let actual = history.query("kettle", at(1), at(2)).unwrap();
+
+        assert_eq!(actual, vec![
+            HistoryPoint { timestamp: at(1), power_watts: 2.0, energy_total_wh: 11.0 },
+            HistoryPoint { timestamp: at(2), power_watts: 3.0, energy_total_wh: 12.0 },
+        ]);
+        assert!(history.query("garage", at(0), at(2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retention() {
+        let history = History::open(Path::new(":memory:"), Some(Duration::minutes(5))).unwrap();
+        history.record(&[reading("kettle", 1.0, 10.0)], at(0)).unwrap();
+        history.record(&[reading("kettle", 2.0, 11.0)], at(10)).unwrap();
+
+        let actual = history.query("kettle", at(0), at(10)).unwrap();
+
+        assert_eq!(actual.len(), 1);
+        assert_eq!(actual[0].power_watts, 2.0);
+    }
+
+    #[test]
+    fn test_open_invalid_path() {
+        assert_eq!(
+            History::open(Path::new("/i/do/not/exist/history.db"), None).err(),
+            Some("Unable to open history database!")
+        );
+    }
+}
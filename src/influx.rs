@@ -0,0 +1,87 @@
+//! InfluxDB line protocol output, one point per plug channel.
+//!
+//! Ref: https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/
+
+use crate::client::ShellySmartPlug;
+use crate::status::SwitchStatus;
+
+
+pub const CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+pub const MEASUREMENT: &str = "shelly";
+
+
+/// Serialize the readings as line protocol, `timestamp_ns` is attached to every point
+pub fn format_line_protocol(readings: &[(ShellySmartPlug, SwitchStatus)], timestamp_ns: i64) -> String {
+    let mut output = String::new();
+
+    for (plug, status) in readings {
+        let fields = [
+            ("power_watts", status.apower),
+            ("voltage", status.voltage),
+            ("current_amps", status.current),
+            ("temperature_celsius", status.temperature.celsius),
+            ("temperature_fahrenheit", status.temperature.fahrenheit),
+            ("energy_total_wh", status.aenergy.total),
+        ];
+
+        let extra_tags: String = plug.labels.iter()
+            .map(|(name, value)| format!(",{}={}", escape_tag(name), escape_tag(value)))
+            .collect();
+
+        output += &format!(
+            "{MEASUREMENT},hostname={},channel={}{extra_tags} {} {timestamp_ns}\n",
+            escape_tag(&plug.alias),
+            status.id,
+            fields.iter()
+                .map(|(name, value)| format!("{name}={value:?}"))
+                .collect::<Vec<String>>()
+                .join(",")
+        );
+    }
+
+    output
+}
+
+fn escape_tag(value: &str) -> String {
+    value.replace('\\', r"\\").replace(',', r"\,").replace('=', r"\=").replace(' ', r"\ ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::{EnergyCounter, Temperature};
+
+    #[test]
+    fn test_format_line_protocol() {
+        let status = SwitchStatus {
+            id: 0,
+            output: Some(true),
+            apower: 1.0,
+            voltage: 2.0,
+            current: 3.0,
+            temperature: Temperature { celsius: 20.1, fahrenheit: 68.2 },
+            aenergy: EnergyCounter { total: 45.5, by_minute: vec![], minute_ts: None },
+            sys: None,
+            wifi: None,
+            freq: None,
+            pf: None,
+        };
+        let plug = ShellySmartPlug {
+            url: "http://10.0.0.2".to_string(),
+            alias: "living room,tv=1".to_string(),
+            labels: vec![("room".to_string(), "first floor".to_string())],
+        };
+
+        let actual = format_line_protocol(&[(plug, status)], 1735620900000000000);
+
+        assert_eq!(actual,
+            "shelly,hostname=living\\ room\\,tv\\=1,channel=0,room=first\\ floor power_watts=1.0,voltage=2.0,current_amps=3.0,\
+            temperature_celsius=20.1,temperature_fahrenheit=68.2,energy_total_wh=45.5 1735620900000000000\n"
+        );
+    }
+
+    #[test]
+    fn test_format_line_protocol_empty() {
+        assert_eq!(format_line_protocol(&[], 0), "");
+    }
+}
@@ -0,0 +1,52 @@
+//! Polling and formatting logic for Shelly Gen2 smart plugs.
+//!
+//! The [`ShellyClient`] talks to the plugs over their RPC interface and returns typed
+//! [`SwitchStatus`] readings, which the [`exporter`] module turns into Prometheus metrics.
+
+pub mod addons;
+pub mod auth;
+pub mod cache;
+pub mod capabilities;
+pub mod client;
+pub mod coiot;
+pub mod config;
+pub mod cost;
+pub mod derived;
+pub mod discovery;
+pub mod energy;
+pub mod error;
+pub mod firmware;
+pub mod formatter;
+pub mod exporter;
+pub mod grafana;
+pub mod groups;
+pub mod health;
+pub mod history;
+pub mod influx;
+pub mod metrics;
+pub mod mqtt;
+pub mod notifier;
+pub mod otlp;
+pub mod pages;
+pub mod poller;
+pub mod push;
+pub mod registry;
+pub mod scan;
+pub mod scheduler;
+pub mod scrape_cache;
+pub mod server;
+pub mod service;
+pub mod shutdown;
+pub mod status;
+pub mod systemd;
+pub mod tenants;
+pub mod textfile;
+pub mod thresholds;
+pub mod tls;
+pub mod webhook;
+pub mod ws;
+
+pub use client::{DeviceAddress, ShellyClient, ShellySmartPlug, Transport};
+pub use error::ShellyError;
+pub use metrics::Format;
+pub use status::{DeviceInfo, EmReading, EnergyCounter, SwitchStatus, Temperature};
@@ -1,125 +1,1291 @@
-use actix_web::{App, get, HttpResponse, HttpServer, Responder, web};
-use actix_web::middleware::Logger;
-use clap::Parser;
-use colog;
-use log::{error, warn};
+use actix_web::{App, HttpServer, web};
+use actix_web::dev::Server;
+use actix_web::middleware::{from_fn, Compress, Logger};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use futures_util::future::join_all;
+use log::{error, info, warn, LevelFilter};
+use tokio::sync::oneshot;
 
-use crate::shelly_service::ShellySmartPlug;
-
-mod shelly_service;
+use shelly_smartplug_exporter::addons::AddonSensors;
+use shelly_smartplug_exporter::auth::{self, Authenticator};
+use shelly_smartplug_exporter::cost::{CostTracker, Tariff};
+use shelly_smartplug_exporter::derived::PowerStats;
+use shelly_smartplug_exporter::energy::EnergyLedger;
+use shelly_smartplug_exporter::capabilities::{self, DeviceCapabilities};
+use shelly_smartplug_exporter::firmware::FirmwareChecks;
+use shelly_smartplug_exporter::groups::Groups;
+use shelly_smartplug_exporter::history::{self, History};
+use shelly_smartplug_exporter::server::{self, AppState};
+use shelly_smartplug_exporter::metrics::{self, is_valid_label_name, MetricNaming};
+use shelly_smartplug_exporter::mqtt::{self, MqttConfig};
+use shelly_smartplug_exporter::notifier::{self, Notifier, NotifyTarget};
+use shelly_smartplug_exporter::otlp::{self, OtlpConfig};
+use shelly_smartplug_exporter::cache::ReadingCache;
+use shelly_smartplug_exporter::poller::Poller;
+use shelly_smartplug_exporter::push::{self, PushConfig};
+use shelly_smartplug_exporter::registry::{PlugRegistry, RESERVED_LABELS};
+use shelly_smartplug_exporter::scan::{self, Ipv4Cidr};
+use shelly_smartplug_exporter::scheduler::Schedule;
+use shelly_smartplug_exporter::scrape_cache::ScrapeCache;
+use shelly_smartplug_exporter::tenants::Tenants;
+use shelly_smartplug_exporter::thresholds::ThresholdTracker;
+use shelly_smartplug_exporter::client::{DeviceAddress, DeviceProxy, DeviceTls, HttpPool, DEFAULT_API_TIMEOUT};
+use shelly_smartplug_exporter::{coiot, config, discovery, service, shutdown, systemd, textfile, tls, Format, ShellyClient, ShellySmartPlug, Transport};
 
 #[derive(Parser, Debug)]
 #[command(about = "Prometheus exporter for shelly smart plugs")]
 #[command(name = "Shelly Smart Plug Exporter", version, long_about = None)]
-struct Args {
-    /// IP address of your smart plug(s) on your local network
-    #[arg(short, long = "ip-addr", required = true, value_delimiter = ' ')]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Log output format, `json` emits one structured object per line for Loki / ELK
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text, env = "SHELLY_EXPORTER_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Minimum level to log, one of off, error, warn, info, debug, trace
+    #[arg(long, global = true, default_value_t = LevelFilter::Info, env = "SHELLY_EXPORTER_LOG_LEVEL")]
+    log_level: LevelFilter,
+}
+
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the exporter
+    Serve(Box<ServeArgs>),
+    /// Print the Shelly devices found on the local network over mDNS
+    Discover(DiscoverArgs),
+    /// Validate the flags and config file, probe every device and exit
+    Check(Box<CheckArgs>),
+    /// Poll all plugs once and print the metrics to stdout
+    ScrapeOnce(Box<ScrapeOnceArgs>),
+}
+
+
+/// Which plugs to poll and how
+#[derive(clap::Args, Debug)]
+struct PlugArgs {
+    /// IP address of your smart plug(s) on your local network, in `[https://]host[:port][/base/path]`
+    /// format. Prefix with `https://` for plugs behind an HTTPS reverse proxy
+    #[arg(
+        short,
+        long = "ip-addr",
+        required_unless_present_any = ["scan", "config", "em_addrs"],
+        value_delimiter = ' ',
+        value_parser = parse_device_address,
+        env = "SHELLY_EXPORTER_IP_ADDRS"
+    )]
     ip_addrs: Vec<String>,
 
-    /// Port to run the webserver at
-    #[arg(short = 'p', long, default_value_t = 9001)]
-    server_port: u16,
+    /// IP address of 3-phase energy meter(s) like the Pro 3EM, exported per phase. `-m` mappings
+    /// and labels apply to them like to plugs
+    #[arg(long = "em-addr", value_delimiter = ' ', value_parser = parse_device_address, env = "SHELLY_EXPORTER_EM_ADDRS")]
+    em_addrs: Vec<String>,
+
+    /// IPv4 range to probe for Shelly devices at startup, e.g. `192.168.1.0/24`, can be repeated
+    #[arg(long, value_delimiter = ' ', env = "SHELLY_EXPORTER_SCAN")]
+    scan: Vec<Ipv4Cidr>,
+
+    /// File to remember the devices found by `--scan` in, so they aren't rescanned on every start
+    #[arg(long, requires = "scan", env = "SHELLY_EXPORTER_SCAN_CACHE")]
+    scan_cache: Option<PathBuf>,
 
     /// IP -> Hostname mapping in `ip_address:hostname` format
-    #[arg(short = 'm', long, required = false)]
+    #[arg(short = 'm', long, required = false, value_delimiter = ' ', env = "SHELLY_EXPORTER_HOSTNAME_IP_MAPPING")]
     hostname_ip_mapping: Vec<String>,
+
+    /// Extra label for a plug's metrics in `ip_address:name=value` format, can be repeated
+    #[arg(short = 'l', long = "label", required = false)]
+    labels: Vec<String>,
+
+    /// Path to an optional TOML config file, see the README for the available settings
+    #[arg(short = 'c', long, env = "SHELLY_EXPORTER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Flat energy price used for the `shelly_energy_cost_total` metric, overrides the config file
+    #[arg(long, env = "SHELLY_EXPORTER_PRICE_PER_KWH")]
+    price_per_kwh: Option<f64>,
+
+    /// Currency label attached to the energy cost metric [default: USD]
+    #[arg(long, env = "SHELLY_EXPORTER_CURRENCY")]
+    currency: Option<String>,
+
+    /// File to persist energy counters in, so `shelly_energy_consumed_wh_total` survives restarts
+    #[arg(long, env = "SHELLY_EXPORTER_ENERGY_STATE_FILE")]
+    energy_state_file: Option<PathBuf>,
+
+    /// Maximum number of requests to the plugs in flight at once, unlimited if not set
+    #[arg(long, env = "SHELLY_EXPORTER_MAX_CONCURRENT_REQUESTS")]
+    max_concurrent_requests: Option<NonZeroUsize>,
+
+    /// Largest response body in bytes read from a plug, bigger ones fail the request [default: 1048576]
+    #[arg(long, env = "SHELLY_EXPORTER_MAX_RESPONSE_BYTES")]
+    max_response_bytes: Option<NonZeroUsize>,
+
+    /// Upper bounds in seconds of the `shelly_device_request_duration_seconds` buckets, in increasing
+    /// order [default: 0.025 0.05 0.1 0.25 0.5 1 2.5 5 10]
+    #[arg(long, num_args = 1.., value_delimiter = ' ', env = "SHELLY_EXPORTER_LATENCY_BUCKETS")]
+    latency_buckets: Option<Vec<f64>>,
+
+    /// Never query a plug more often than every this many seconds, regardless of scrape frequency
+    #[arg(long, env = "SHELLY_EXPORTER_MIN_POLL_INTERVAL")]
+    min_poll_interval: Option<u64>,
+
+    /// PEM file with extra CA certificates to trust for plugs reached over HTTPS
+    #[arg(long, env = "SHELLY_EXPORTER_DEVICE_CA_CERT")]
+    device_ca_cert: Option<PathBuf>,
+
+    /// Don't verify the certificates of plugs reached over HTTPS, e.g. self-signed ones
+    #[arg(long, env = "SHELLY_EXPORTER_INSECURE_SKIP_VERIFY")]
+    insecure_skip_verify: bool,
+
+    /// Idle connections kept open per plug, `0` opens a new connection for every request, for
+    /// firmware which drops kept-alive connections
+    #[arg(long, env = "SHELLY_EXPORTER_DEVICE_POOL_MAX_IDLE")]
+    device_pool_max_idle: Option<usize>,
+
+    /// Seconds an idle connection to a plug is kept open [default: 90]
+    #[arg(long, env = "SHELLY_EXPORTER_DEVICE_POOL_IDLE_TIMEOUT")]
+    device_pool_idle_timeout: Option<u64>,
+
+    /// Send TCP keepalive probes on the connections to the plugs every this many seconds
+    #[arg(long, env = "SHELLY_EXPORTER_DEVICE_TCP_KEEPALIVE")]
+    device_tcp_keepalive: Option<u64>,
+
+    /// Only speak HTTP/1.1 to plugs reached over HTTPS
+    #[arg(long, env = "SHELLY_EXPORTER_DEVICE_HTTP1_ONLY")]
+    device_http1_only: bool,
+
+    /// `http://`, `https://` or `socks5://` proxy to send the requests to the plugs through, the
+    /// config file can override it per plug
+    #[arg(long, env = "SHELLY_EXPORTER_DEVICE_PROXY")]
+    device_proxy: Option<String>,
+
+    /// Comma separated hosts, domains and CIDR ranges to reach without `--device-proxy`
+    #[arg(long, requires = "device_proxy", env = "SHELLY_EXPORTER_DEVICE_NO_PROXY")]
+    device_no_proxy: Option<String>,
+
+    /// How to talk to the plugs, `websocket` keeps a persistent RPC connection per device
+    #[arg(long, value_enum, default_value_t = CliTransport::Http, env = "SHELLY_EXPORTER_TRANSPORT")]
+    transport: CliTransport,
+
+    /// Use the name configured on each plug as its alias at startup, `-m` mappings take precedence
+    #[arg(long, env = "SHELLY_EXPORTER_RESOLVE_ALIASES")]
+    resolve_aliases: bool,
+
+    /// Also export the 1 and 15 minute average power, the peak power and the energy of the last hour
+    /// of every plug, derived from the readings the exporter saw
+    #[arg(long, env = "SHELLY_EXPORTER_DERIVED_METRICS")]
+    derived_metrics: bool,
+
+    /// Poll every plug with `Shelly.GetStatus`, which adds its uptime, free RAM and WiFi signal to
+    /// the switch status in the same round trip
+    #[arg(long, env = "SHELLY_EXPORTER_SYSTEM_STATUS")]
+    system_status: bool,
+
+    /// Also read the physical input of every plug, exported as `shelly_input_state`
+    #[arg(long, env = "SHELLY_EXPORTER_INPUT_STATE")]
+    input_state: bool,
+
+    /// Also read the scripts and schedules of every plug, exported as `shelly_script_running`,
+    /// `shelly_script_error` and `shelly_schedule_enabled`
+    #[arg(long, env = "SHELLY_EXPORTER_AUTOMATION_STATE")]
+    automation_state: bool,
+
+    /// Check every plug for firmware updates at most every this many seconds, exported as
+    /// `shelly_firmware_update_available`
+    #[arg(long, env = "SHELLY_EXPORTER_FIRMWARE_CHECK_INTERVAL")]
+    firmware_check_interval: Option<u64>,
+
+    /// Ask every plug for its generation and firmware when it's registered, and leave out the
+    /// metrics it can't measure instead of exporting zeros
+    #[arg(long, env = "SHELLY_EXPORTER_DETECT_CAPABILITIES")]
+    detect_capabilities: bool,
+
+    /// Append the time each plug was polled to its readings, so Prometheus doesn't take cached
+    /// readings for fresh ones
+    #[arg(long, env = "SHELLY_EXPORTER_SAMPLE_TIMESTAMPS")]
+    sample_timestamps: bool,
+
+    /// Prefix of every metric name, may be empty
+    #[arg(long, default_value = metrics::DEFAULT_METRIC_PREFIX, env = "SHELLY_EXPORTER_METRIC_PREFIX")]
+    metric_prefix: String,
+
+    /// Name of the label telling the plugs apart
+    #[arg(long, default_value = metrics::DEFAULT_INSTANCE_LABEL, env = "SHELLY_EXPORTER_INSTANCE_LABEL_NAME")]
+    instance_label_name: String,
+
+    /// Serve the metric names from before they were prefixed, e.g. `power_watts`
+    #[arg(long, conflicts_with = "metric_prefix", env = "SHELLY_EXPORTER_LEGACY_METRIC_NAMES")]
+    legacy_metric_names: bool,
+}
+
+
+#[derive(clap::Args, Debug)]
+#[command(group(ArgGroup::new("background_output")
+    .multiple(true)
+    .args(["push_gateway_url", "mqtt_host", "textfile_output", "history_db", "otlp_endpoint", "notify_url", "notify_ntfy_url"])))]
+struct ServeArgs {
+    #[command(flatten)]
+    plugs: PlugArgs,
+
+    /// Port to run the webserver at
+    #[arg(short = 'p', long, default_value_t = 9001, env = "SHELLY_EXPORTER_PORT")]
+    server_port: u16,
+
+    /// PEM certificate (chain) to serve the exporter over HTTPS
+    #[arg(long, requires = "tls_key", env = "SHELLY_EXPORTER_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching `--tls-cert`
+    #[arg(long, requires = "tls_cert", env = "SHELLY_EXPORTER_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// How often in seconds to check the TLS certificate files for rotation
+    #[arg(long, default_value_t = 300, env = "SHELLY_EXPORTER_TLS_RELOAD_INTERVAL")]
+    tls_reload_interval: u64,
+
+    /// Require HTTP basic auth with this username, use together with `--auth-password-hash`
+    #[arg(long, requires = "auth_password_hash", env = "SHELLY_EXPORTER_AUTH_USER")]
+    auth_user: Option<String>,
+
+    /// bcrypt hash of the basic auth password, e.g. from `htpasswd -nbBC 10 "" <password>`
+    #[arg(long, requires = "auth_user", env = "SHELLY_EXPORTER_AUTH_PASSWORD_HASH", hide_env_values = true)]
+    auth_password_hash: Option<String>,
+
+    /// File containing a bearer token which scrapers may send instead of basic auth credentials
+    #[arg(long, env = "SHELLY_EXPORTER_AUTH_TOKEN_FILE")]
+    auth_token_file: Option<PathBuf>,
+
+    /// Enable the `/plugs` admin API to list, add and remove plugs at runtime, requires auth
+    #[arg(long, env = "SHELLY_EXPORTER_ADMIN_API")]
+    admin_api: bool,
+
+    /// Write plugs added or removed through the admin API back to the `[[plugs]]` of `--config`
+    #[arg(long, requires_all = ["admin_api", "config"], env = "SHELLY_EXPORTER_PERSIST_PLUGS")]
+    persist_plugs: bool,
+
+    /// Serve scrapes of the same devices within this many seconds of each other from the same
+    /// result, e.g. for two Prometheus replicas
+    #[arg(long, env = "SHELLY_EXPORTER_SCRAPE_CACHE_TTL")]
+    scrape_cache_ttl: Option<u64>,
+
+    /// Serve `/metrics` from readings cached by the background poller and device notifications
+    /// sent to `/webhook`, instead of polling the plugs on every scrape
+    #[arg(long, env = "SHELLY_EXPORTER_SERVE_FROM_CACHE")]
+    serve_from_cache: bool,
+
+    /// Listen for the CoIoT status announcements of Gen1 plugs on UDP port 5683 and feed them into
+    /// the cache, for use with `--serve-from-cache`
+    #[arg(long, env = "SHELLY_EXPORTER_COIOT")]
+    coiot: bool,
+
+    /// How often in seconds to poll the plugs in the background for push mode, MQTT, the textfile,
+    /// the history, notifications and the cache, unless the config file sets a `poll_intervals` entry for the plug
+    #[arg(long, visible_alias = "push-interval", default_value_t = 60, env = "SHELLY_EXPORTER_POLL_INTERVAL")]
+    poll_interval: u64,
+
+    /// Pushgateway base URL, e.g. `http://pushgateway:9091`. Enables push mode
+    #[arg(long, env = "SHELLY_EXPORTER_PUSH_GATEWAY_URL")]
+    push_gateway_url: Option<String>,
+
+    /// Pushgateway job name the metrics are grouped under
+    #[arg(long, default_value = push::DEFAULT_JOB, env = "SHELLY_EXPORTER_PUSH_JOB")]
+    push_job: String,
+
+    /// OTLP/HTTP receiver to export the metrics to after every background poll, e.g.
+    /// `http://otel-collector:4318`
+    #[arg(long, env = "SHELLY_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// `service.name` resource attribute of the OTLP export
+    #[arg(long, default_value = otlp::DEFAULT_SERVICE_NAME, env = "SHELLY_EXPORTER_OTLP_SERVICE_NAME")]
+    otlp_service_name: String,
+
+    /// Write the metrics to this file after every background poll, for the node_exporter textfile
+    /// collector. Should end in `.prom`
+    #[arg(long, env = "SHELLY_EXPORTER_TEXTFILE_OUTPUT")]
+    textfile_output: Option<PathBuf>,
+
+    /// SQLite database to record every background poll in, served on `/history`
+    #[arg(long, env = "SHELLY_EXPORTER_HISTORY_DB")]
+    history_db: Option<PathBuf>,
+
+    /// Days of history to keep, forever if not set
+    #[arg(long, requires = "history_db", env = "SHELLY_EXPORTER_HISTORY_RETENTION_DAYS")]
+    history_retention_days: Option<u32>,
+
+    /// MQTT broker to publish the readings to, enables the MQTT publisher
+    #[arg(long, env = "SHELLY_EXPORTER_MQTT_HOST")]
+    mqtt_host: Option<String>,
+
+    /// MQTT broker port
+    #[arg(long, default_value_t = mqtt::DEFAULT_PORT, env = "SHELLY_EXPORTER_MQTT_PORT")]
+    mqtt_port: u16,
+
+    /// MQTT client id
+    #[arg(long, default_value = "shelly_smartplug_exporter", env = "SHELLY_EXPORTER_MQTT_CLIENT_ID")]
+    mqtt_client_id: String,
+
+    /// MQTT username, use together with `--mqtt-password-file`
+    #[arg(long, requires = "mqtt_password_file", env = "SHELLY_EXPORTER_MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+
+    /// File containing the MQTT password
+    #[arg(long, requires = "mqtt_username", env = "SHELLY_EXPORTER_MQTT_PASSWORD_FILE")]
+    mqtt_password_file: Option<PathBuf>,
+
+    /// Readings are published to `<prefix>/<alias>/<metric>`
+    #[arg(long, default_value = mqtt::DEFAULT_TOPIC_PREFIX, env = "SHELLY_EXPORTER_MQTT_TOPIC_PREFIX")]
+    mqtt_topic_prefix: String,
+
+    /// Home Assistant discovery prefix
+    #[arg(long, default_value = mqtt::DEFAULT_DISCOVERY_PREFIX, env = "SHELLY_EXPORTER_MQTT_DISCOVERY_PREFIX")]
+    mqtt_discovery_prefix: String,
+
+    /// Don't publish Home Assistant discovery payloads
+    #[arg(long, env = "SHELLY_EXPORTER_MQTT_NO_DISCOVERY")]
+    mqtt_no_discovery: bool,
+
+    /// Webhook to `POST` a JSON notification to when a plug goes down or comes back up, crosses its
+    /// `max_power_watts` threshold or switches its relay, checked on every background poll
+    #[arg(long, env = "SHELLY_EXPORTER_NOTIFY_URL")]
+    notify_url: Option<String>,
+
+    /// ntfy topic to publish the notifications to instead, e.g. `https://ntfy.sh/my-plugs`
+    #[arg(long, conflicts_with = "notify_url", env = "SHELLY_EXPORTER_NOTIFY_NTFY_URL")]
+    notify_ntfy_url: Option<String>,
+
+    /// Seconds to wait for in-flight scrapes and device calls to finish on SIGTERM / SIGINT
+    #[arg(long, default_value_t = shutdown::DEFAULT_GRACE_PERIOD_SECS, env = "SHELLY_EXPORTER_SHUTDOWN_GRACE_PERIOD")]
+    shutdown_grace_period: u64,
+
+    /// Don't serve metrics over HTTP, only push, publish or write them
+    #[arg(long, requires = "background_output", env = "SHELLY_EXPORTER_NO_HTTP_SERVER")]
+    no_http_server: bool,
+
+    /// Fork into the background, detached from the terminal
+    #[cfg(unix)]
+    #[arg(long, env = "SHELLY_EXPORTER_DAEMON")]
+    daemon: bool,
+
+    /// File to write the pid of the exporter to, removed on shutdown
+    #[cfg(unix)]
+    #[arg(long, env = "SHELLY_EXPORTER_PID_FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// File the logs of `--daemon` are appended to, discarded if not set
+    #[cfg(unix)]
+    #[arg(long, requires = "daemon", env = "SHELLY_EXPORTER_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Run as a Windows service started by the service control manager, logging to the event log
+    #[cfg(windows)]
+    #[arg(long, env = "SHELLY_EXPORTER_WINDOWS_SERVICE")]
+    windows_service: bool,
+}
+
+
+#[derive(clap::Args, Debug)]
+struct DiscoverArgs {
+    /// How long in seconds to listen for device announcements
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+}
+
+
+#[derive(clap::Args, Debug)]
+struct CheckArgs {
+    #[command(flatten)]
+    plugs: PlugArgs,
+
+    /// Only validate the flags and config file, without requesting anything from the devices
+    #[arg(long)]
+    skip_probe: bool,
+}
+
+
+#[derive(clap::Args, Debug)]
+struct ScrapeOnceArgs {
+    #[command(flatten)]
+    plugs: PlugArgs,
+
+    /// Exposition format to print
+    #[arg(long, value_enum, default_value_t = OutputFormat::Prometheus)]
+    format: OutputFormat,
 }
 
 
-#[derive(Clone)]
-struct AppState {
-    plugs: Vec<ShellySmartPlug>,
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
 
-#[get("/metrics")]
-async fn metrics(state: web::Data<AppState>) -> impl Responder {
-    match shelly_service::get_metrics(&state.plugs).await {
-        Ok(output) => HttpResponse::Ok().body(output),
-        Err(e) => {
-            error!("An error occurred during processing - {e}");
-            HttpResponse::InternalServerError()
-                .body("Failed to process, please check application logs")
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Prometheus,
+    Openmetrics,
+}
+
+impl From<OutputFormat> for Format {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Prometheus => Format::Prometheus,
+            OutputFormat::Openmetrics => Format::OpenMetrics,
         }
     }
 }
 
 
-fn load_plugs(cli_args: &Args) -> Vec<ShellySmartPlug> {
-    let mut plugs: Vec<ShellySmartPlug> = vec![];
-    for ip in &cli_args.ip_addrs {
-        // Will overwrite if user provided a hostname mapping, else just use the IP
-        let mut alias = ip.clone();
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CliTransport {
+    Http,
+    Websocket,
+}
+
+impl From<CliTransport> for Transport {
+    fn from(transport: CliTransport) -> Self {
+        match transport {
+            CliTransport::Http => Transport::Http,
+            CliTransport::Websocket => Transport::WebSocket,
+        }
+    }
+}
 
-        for mapping in &cli_args.hostname_ip_mapping {
-            if mapping.contains(&ip.clone()) {
-                // Since clap has an awkward time having field parsers for Vec<String> adding a
-                // little check here to ensure the format is correct. Deciding to warn the user and
-                // continue since this isn't a catastrophic error
-                // Ref: https://github.com/clap-rs/clap/issues/4808
-                if !mapping.contains(":") {
-                    warn!("Invalid mapping `{}`! Please use format `ip:hostname`",mapping);
-                    break;
-                }
 
-                alias = mapping.split(':').collect::<Vec<&str>>()[1].to_string();
+/// Plugs of `--ip-addr`, followed by the `extra` addresses (config file, scan) which aren't in there
+fn load_plugs(cli_args: &PlugArgs, extra: &[String]) -> Vec<ShellySmartPlug> {
+    let mut seen: HashSet<&String> = cli_args.ip_addrs.iter().collect();
+    let extra = extra.iter().filter(|address| seen.insert(address));
+    cli_args.ip_addrs.iter()
+        .chain(extra)
+        .map(|raw_ip| load_device(raw_ip, &cli_args.hostname_ip_mapping))
+        .collect()
+}
+
+
+/// Energy meters of `--em-addr`
+fn load_meters(cli_args: &PlugArgs) -> Vec<ShellySmartPlug> {
+    cli_args.em_addrs.iter().map(|raw_ip| load_device(raw_ip, &cli_args.hostname_ip_mapping)).collect()
+}
+
+
+/// Reject addresses with other schemes than `http://` and `https://` while parsing the arguments
+fn parse_device_address(raw: &str) -> Result<String, &'static str> {
+    DeviceAddress::parse(raw).map(|_| raw.to_string())
+}
+
+
+/// Addresses come from the arguments, the config file or a scan and were all validated already
+fn load_device(raw_ip: &str, hostname_ip_mapping: &[String]) -> ShellySmartPlug {
+    // Will overwrite if user provided a hostname mapping, else just use the IP
+    let mut plug = ShellySmartPlug::from_address(raw_ip).expect("Device addresses are validated when parsed");
+    let ip = plug.alias.clone();
+
+    for mapping in hostname_ip_mapping {
+        if mapping.contains(&ip) {
+            // Since clap has an awkward time having field parsers for Vec<String> adding a
+            // little check here to ensure the format is correct. Deciding to warn the user and
+            // continue since this isn't a catastrophic error
+            // Ref: https://github.com/clap-rs/clap/issues/4808
+            if !mapping.contains(":") {
+                warn!("Invalid mapping `{}`! Please use format `ip:hostname`",mapping);
                 break;
             }
+
+            // The address may contain a port itself, the hostname is after the last colon
+            plug.alias = mapping.rsplit_once(':').map(|(_, hostname)| hostname).unwrap_or_default().to_string();
+            break;
         }
+    }
 
-        plugs.push(ShellySmartPlug {
-            url: format!("http://{}/rpc/Switch.GetStatus?id=0", ip.clone()),
-            alias,
-        });
+    plug
+}
+
+
+fn metric_naming(args: &PlugArgs) -> std::io::Result<MetricNaming> {
+    if !metrics::is_valid_metric_prefix(&args.metric_prefix) {
+        return Err(std::io::Error::other(format!(
+            "Invalid metric prefix `{}`, it must match [a-zA-Z_:][a-zA-Z0-9_:]*", args.metric_prefix
+        )));
     }
+    let instance_label = &args.instance_label_name;
+    let reserved = RESERVED_LABELS.iter().any(|name| name != &metrics::DEFAULT_INSTANCE_LABEL && name == instance_label);
+    if !is_valid_label_name(instance_label) || reserved {
+        return Err(std::io::Error::other(format!("Invalid instance label name `{instance_label}`")));
+    }
+
+    Ok(MetricNaming {
+        prefix: (!args.legacy_metric_names).then(|| args.metric_prefix.clone()),
+        instance_label: instance_label.clone(),
+    })
+}
+
 
-    plugs
+/// Use the aliases of the `[[plugs]]` in the config file, for plugs without a `-m` mapping
+fn apply_config_aliases(plugs: &mut [ShellySmartPlug], config: &config::Config) {
+    for entry in &config.plugs {
+        let alias = match &entry.alias {
+            Some(alias) => alias,
+            None => continue,
+        };
+        let Ok(configured) = ShellySmartPlug::from_address(&entry.address) else { continue };
+        if let Some(plug) = plugs.iter_mut().find(|plug| plug.url == configured.url && plug.alias == configured.alias) {
+            plug.alias = alias.clone();
+        }
+    }
 }
 
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    colog::init();
-    let cli = Args::parse();
-    let state = AppState { plugs: load_plugs(&cli) };
+/// Replace the alias of plugs without a `-m` mapping by the name configured on the device. Plugs
+/// which can't be reached or have no name keep their address.
+async fn resolve_aliases(client: &ShellyClient, plugs: &mut [ShellySmartPlug]) {
+    let infos = join_all(plugs.iter().map(|plug| async move {
+        match plug.alias == plug.default_alias() {
+            true => Some(client.get_device_info(plug).await),
+            false => None,
+        }
+    })).await;
 
-    HttpServer::new(move || {
+    for (plug, info) in plugs.iter_mut().zip(infos) {
+        match info.map(|info| info.map(|info| info.name.filter(|name| !name.trim().is_empty()))) {
+            Some(Ok(Some(name))) => {
+                info!("Using the configured name `{name}` as alias of {}", plug.target());
+                plug.alias = name;
+            }
+            Some(Ok(None)) => warn!("Plug {} has no name configured, keeping its address as alias", plug.target()),
+            Some(Err(e)) => warn!("Unable to resolve the name of {}, keeping its address as alias - {e}", plug.target()),
+            None => {}
+        }
+    }
+}
+
+
+/// Attach the extra labels from the config file, then the CLI, to the plugs. Config labels may be
+/// set on the `[[plugs]]` entry or keyed by IP or alias, later sources override earlier ones.
+fn apply_labels(
+    plugs: &mut [ShellySmartPlug],
+    cli_args: &PlugArgs,
+    config: &config::Config,
+) -> Result<(), &'static str> {
+    let mut cli_labels: Vec<(&str, &str, &str)> = vec![];
+    for raw in &cli_args.labels {
+        let parsed = raw.split_once(':')
+            .and_then(|(ip, label)| label.split_once('=').map(|(name, value)| (ip, name, value)));
+        match parsed {
+            Some(label) => cli_labels.push(label),
+            None => {
+                error!("Invalid label `{raw}`! Please use format `ip:name=value`");
+                return Err("Invalid plug label!");
+            }
+        }
+    }
+
+    for plug in plugs {
+        let ip = plug.target();
+        let mut labels: BTreeMap<&str, &str> = BTreeMap::new();
+        let entries = config.plugs.iter()
+            .filter(|entry| ShellySmartPlug::from_address(&entry.address).is_ok_and(|configured| configured.url == plug.url));
+        for entry in entries {
+            labels.extend(entry.labels.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        }
+        for key in [&ip, &plug.alias] {
+            if let Some(config_labels) = config.labels.get(key) {
+                labels.extend(config_labels.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+            }
+        }
+        labels.extend(cli_labels.iter()
+            .filter(|(label_ip, _, _)| *label_ip == ip)
+            .map(|(_, name, value)| (*name, *value)));
+
+        for name in labels.keys() {
+            validate_label_name(name, &plug.alias)?;
+            if *name == cli_args.instance_label_name {
+                error!("Invalid label name `{name}` for `{}`! It's already used as the instance label", plug.alias);
+                return Err("Invalid plug label!");
+            }
+        }
+
+        plug.labels = labels.into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    }
+
+    Ok(())
+}
+
+
+fn validate_label_name(name: &str, plug: &str) -> Result<(), &'static str> {
+    if !is_valid_label_name(name) || RESERVED_LABELS.contains(&name) {
+        error!(
+            "Invalid label name `{name}` for `{plug}`! Names must match [a-zA-Z_][a-zA-Z0-9_]* and can't be one of {:?}",
+            RESERVED_LABELS
+        );
+        return Err("Invalid plug label!");
+    }
+
+    Ok(())
+}
+
+
+fn load_authenticator(cli_args: &ServeArgs, config: &config::Config) -> std::io::Result<Authenticator> {
+    let basic = match (&cli_args.auth_user, &cli_args.auth_password_hash) {
+        (Some(user), Some(hash)) => Some((user.clone(), hash.clone())),
+        _ => None,
+    };
+
+    let bearer_token = cli_args.auth_token_file.as_deref().map(read_token).transpose()?;
+
+    let mut tenant_tokens = HashMap::new();
+    for (tenant, tenant_config) in &config.tenants {
+        if let Some(path) = &tenant_config.token_file {
+            tenant_tokens.insert(tenant.clone(), read_token(path)?);
+        }
+    }
+
+    let authenticator = Authenticator::new(basic, bearer_token);
+    // Every other route serves the devices of the tenants too
+    if !tenant_tokens.is_empty() && !authenticator.is_enabled() {
+        return Err(std::io::Error::other("Tenant tokens require `--auth-user` or `--auth-token-file`"));
+    }
+    Ok(authenticator.with_tenant_tokens(tenant_tokens))
+}
+
+fn read_token(path: &Path) -> std::io::Result<String> {
+    let token = std::fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err(std::io::Error::other(format!("Auth token file `{}` is empty", path.display())));
+    }
+    Ok(token)
+}
+
+
+fn load_mqtt_config(cli_args: &ServeArgs) -> std::io::Result<Option<MqttConfig>> {
+    let host = match &cli_args.mqtt_host {
+        Some(host) => host.clone(),
+        None => return Ok(None),
+    };
+
+    let credentials = match (&cli_args.mqtt_username, &cli_args.mqtt_password_file) {
+        (Some(username), Some(path)) => {
+            Some((username.clone(), std::fs::read_to_string(path)?.trim().to_string()))
+        }
+        _ => None,
+    };
+
+    Ok(Some(MqttConfig {
+        host,
+        port: cli_args.mqtt_port,
+        client_id: cli_args.mqtt_client_id.clone(),
+        credentials,
+        topic_prefix: cli_args.mqtt_topic_prefix.clone(),
+        discovery_prefix: match cli_args.mqtt_no_discovery {
+            true => None,
+            false => Some(cli_args.mqtt_discovery_prefix.clone()),
+        },
+    }))
+}
+
+
+fn init_logging(format: LogFormat, level: LevelFilter) {
+    match format {
+        LogFormat::Text => colog::default_builder().filter_level(level).init(),
+        LogFormat::Json => {
+            let level = match level {
+                LevelFilter::Off => tracing::level_filters::LevelFilter::OFF,
+                LevelFilter::Error => tracing::level_filters::LevelFilter::ERROR,
+                LevelFilter::Warn => tracing::level_filters::LevelFilter::WARN,
+                LevelFilter::Info => tracing::level_filters::LevelFilter::INFO,
+                LevelFilter::Debug => tracing::level_filters::LevelFilter::DEBUG,
+                LevelFilter::Trace => tracing::level_filters::LevelFilter::TRACE,
+            };
+            // Also installs the bridge picking up `log` records, e.g. from the actix logger. Logs go
+            // to stderr like the text format, stdout is reserved for command output
+            tracing_subscriber::fmt()
+                .json()
+                .with_writer(std::io::stderr)
+                .with_max_level(level)
+                .with_current_span(true)
+                .init();
+        }
+    }
+}
+
+
+fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    // The service dispatcher takes over the main thread, and the runtime threads don't survive a
+    // fork, so both come before the runtime starts
+    #[cfg(windows)]
+    let cli = match cli.command {
+        Command::Serve(args) if args.windows_service => {
+            if let Some(level) = cli.log_level.to_level() {
+                service::windows::init_event_log(level).map_err(std::io::Error::other)?;
+            }
+            return service::windows::run(move || actix_web::rt::System::new().block_on(serve(*args)));
+        }
+        command => Cli { command, ..cli },
+    };
+    init_logging(cli.log_format, cli.log_level);
+    #[cfg(unix)]
+    if let Command::Serve(args) = &cli.command {
+        if args.daemon {
+            service::daemonize(args.pid_file.as_deref(), args.log_file.as_deref()).map_err(std::io::Error::other)?;
+        }
+    }
+
+    actix_web::rt::System::new().block_on(run(cli.command))
+}
+
+
+async fn run(command: Command) -> std::io::Result<()> {
+    match command {
+        Command::Serve(args) => serve(*args).await,
+        Command::Discover(args) => discover(args).await,
+        Command::Check(args) => check(*args).await,
+        Command::ScrapeOnce(args) => scrape_once(*args).await,
+    }
+}
+
+
+fn load_config(args: &PlugArgs) -> std::io::Result<config::Config> {
+    match &args.config {
+        Some(path) => config::load(path).map_err(std::io::Error::other),
+        None => Ok(config::Config::default()),
+    }
+}
+
+
+/// Set up the plugs, client and metric trackers shared by every command
+async fn build_state(args: &PlugArgs, config: &config::Config, serve_cached: bool) -> std::io::Result<AppState> {
+    let energy = match &args.energy_state_file {
+        Some(path) => EnergyLedger::with_state_file(path).map_err(std::io::Error::other)?,
+        None => EnergyLedger::new(),
+    };
+    let tariff = Tariff::from_settings(args.price_per_kwh, args.currency.clone(), config.tariff.as_ref());
+    let mut client = ShellyClient::with_transport(DEFAULT_API_TIMEOUT, args.transport.into());
+    if args.device_ca_cert.is_some() || args.insecure_skip_verify {
+        if args.insecure_skip_verify {
+            warn!("Certificates of plugs reached over HTTPS won't be verified");
+        }
+        let tls = DeviceTls {
+            ca_certs: args.device_ca_cert.as_ref().map(std::fs::read).transpose()?,
+            insecure_skip_verify: args.insecure_skip_verify,
+        };
+        client = client.with_device_tls(&tls).map_err(std::io::Error::other)?;
+    }
+    if !config.device_tls.is_empty() {
+        let mut overrides = HashMap::new();
+        for (device, tls) in &config.device_tls {
+            let tls = DeviceTls {
+                ca_certs: tls.ca_cert.as_ref().map(std::fs::read).transpose()?,
+                insecure_skip_verify: tls.insecure_skip_verify,
+            };
+            if tls.insecure_skip_verify {
+                warn!("Certificates of `{device}` won't be verified");
+            }
+            overrides.insert(device.clone(), tls);
+        }
+        client = client.with_device_tls_overrides(overrides).map_err(std::io::Error::other)?;
+    }
+    if args.device_proxy.is_some() || !config.device_proxies.is_empty() {
+        let proxy = DeviceProxy {
+            url: args.device_proxy.clone(),
+            no_proxy: args.device_no_proxy.clone(),
+            overrides: config.device_proxies.clone(),
+        };
+        client = client.with_device_proxy(&proxy).map_err(std::io::Error::other)?;
+    }
+    let pool = HttpPool {
+        max_idle_per_host: args.device_pool_max_idle,
+        idle_timeout: args.device_pool_idle_timeout.map(Duration::from_secs),
+        tcp_keepalive: args.device_tcp_keepalive.map(Duration::from_secs),
+        http1_only: args.device_http1_only,
+    };
+    if pool != HttpPool::default() {
+        client = client.with_http_pool(&pool).map_err(std::io::Error::other)?;
+    }
+    if let Some(limit) = args.max_concurrent_requests {
+        client = client.with_max_concurrent_requests(limit.get());
+    }
+    if let Some(limit) = args.max_response_bytes {
+        client = client.with_max_response_bytes(limit.get());
+    }
+    if let Some(interval) = args.min_poll_interval {
+        client = client.with_min_poll_interval(Duration::from_secs(interval));
+    }
+    if args.system_status {
+        client = client.with_system_status();
+    }
+    if let Some(buckets) = &args.latency_buckets {
+        client = client.with_latency_buckets(buckets).map_err(std::io::Error::other)?;
+    }
+
+    let scanned = match args.scan.is_empty() {
+        true => vec![],
+        false => scan::scan_cached(&args.scan, scan::DEFAULT_PROBE_TIMEOUT, args.scan_cache.as_deref())
+            .await
+            .map_err(std::io::Error::other)?,
+    };
+    let mut extra: Vec<String> = config.plugs.iter().map(|plug| plug.address.clone()).collect();
+    extra.extend(scanned);
+    let mut plugs = load_plugs(args, &extra);
+    apply_config_aliases(&mut plugs, config);
+    if args.resolve_aliases {
+        resolve_aliases(&client, &mut plugs).await;
+    }
+    apply_labels(&mut plugs, args, config).map_err(std::io::Error::other)?;
+    let mut meters = load_meters(args);
+    apply_labels(&mut meters, args, config).map_err(std::io::Error::other)?;
+    let capabilities = match args.detect_capabilities {
+        true => {
+            let capabilities = DeviceCapabilities::new();
+            capabilities.detect_all(&client, &plugs).await;
+            Some(Arc::new(capabilities))
+        }
+        false => None,
+    };
+
+    Ok(AppState {
+        client,
+        plugs: Arc::new(PlugRegistry::new(plugs)),
+        meters,
+        cost: tariff.map(|tariff| Arc::new(CostTracker::new(tariff))),
+        thresholds: ThresholdTracker::new(config.thresholds.clone()).map(Arc::new),
+        power_stats: args.derived_metrics.then(|| Arc::new(PowerStats::new())),
+        groups: Groups::new(config.groups.clone()).map(Arc::new),
+        energy: Arc::new(energy),
+        cache: Arc::new(ReadingCache::new()),
+        history: None,
+        serve_cached,
+        admin_api: false,
+        naming: metric_naming(args)?,
+        inputs: args.input_state,
+        automations: args.automation_state,
+        addons: AddonSensors::new(config.addon_sensors.clone()).map(Arc::new),
+        firmware: args.firmware_check_interval
+            .map(|interval| Arc::new(FirmwareChecks::new(Duration::from_secs(interval)))),
+        scrape_cache: None,
+        capabilities,
+        tenants: Tenants::new(&config.tenants).map(Arc::new),
+        sample_timestamps: args.sample_timestamps,
+    })
+}
+
+
+async fn serve(cli: ServeArgs) -> std::io::Result<()> {
+    let config = load_config(&cli.plugs)?;
+    let mut state = build_state(&cli.plugs, &config, cli.serve_from_cache).await?;
+    if let Some(path) = &cli.history_db {
+        let retention = cli.history_retention_days.map(|days| chrono::Duration::days(days.into()));
+        state.history = Some(Arc::new(History::open(path, retention).map_err(std::io::Error::other)?));
+    }
+    let authenticator = web::Data::new(load_authenticator(&cli, &config)?);
+    if cli.admin_api {
+        if !authenticator.is_enabled() {
+            return Err(std::io::Error::other("The admin API requires `--auth-user` or `--auth-token-file`"));
+        }
+        state.admin_api = true;
+    }
+    if let Some(ttl) = cli.scrape_cache_ttl.filter(|ttl| *ttl > 0) {
+        state.scrape_cache = Some(Arc::new(ScrapeCache::new(Duration::from_secs(ttl))));
+    }
+    if let (true, Some(path)) = (cli.persist_plugs, &cli.plugs.config) {
+        state.plugs = Arc::new(PlugRegistry::new(state.plugs.snapshot()).with_config_file(path));
+    }
+
+    if let Some(capabilities) = &state.capabilities {
+        tokio::spawn(capabilities::run(capabilities.clone(), state.client.clone(), state.plugs.clone()));
+    }
+    if cli.coiot {
+        let socket = coiot::bind(coiot::DEFAULT_PORT)?;
+        info!("Listening for CoIoT announcements on UDP port {}", coiot::DEFAULT_PORT);
+        tokio::spawn(coiot::run(socket, state.plugs.clone(), state.cache.clone()));
+    }
+
+    let poller = Poller::new();
+    if let Some(gateway_url) = &cli.push_gateway_url {
+        let push_config = PushConfig::new(gateway_url, &cli.push_job, Duration::from_secs(cli.poll_interval));
+        tokio::spawn(push::run(state.clone(), push_config, poller.subscribe()));
+    }
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        let otlp_config = OtlpConfig::new(endpoint, &cli.otlp_service_name);
+        tokio::spawn(otlp::run(state.clone(), otlp_config, poller.subscribe()));
+    }
+    let recording = state.history.as_ref().map(|history| tokio::spawn(history::run(history.clone(), poller.subscribe())));
+    if let Some(path) = &cli.textfile_output {
+        tokio::spawn(textfile::run(state.clone(), path.clone(), poller.subscribe()));
+    }
+    if let Some(mqtt_config) = load_mqtt_config(&cli)? {
+        tokio::spawn(mqtt::run(mqtt_config, poller.subscribe()));
+    }
+    let notify_target = match (&cli.notify_url, &cli.notify_ntfy_url) {
+        (Some(url), _) => Some(NotifyTarget::Webhook(url.clone())),
+        (_, Some(url)) => Some(NotifyTarget::Ntfy(url.clone())),
+        _ => None,
+    };
+    if let Some(target) = notify_target {
+        let notifier = Notifier::new(target, state.thresholds.clone());
+        tokio::spawn(notifier::run(notifier, state.plugs.clone(), poller.subscribe()));
+    }
+    let background_output = cli.push_gateway_url.is_some()
+        || cli.mqtt_host.is_some()
+        || cli.textfile_output.is_some()
+        || cli.history_db.is_some()
+        || cli.otlp_endpoint.is_some()
+        || cli.notify_url.is_some()
+        || cli.notify_ntfy_url.is_some();
+    let background_polling = background_output || cli.serve_from_cache;
+    if let Some(interval) = systemd::watchdog_interval() {
+        match background_polling {
+            true => tokio::spawn(systemd::run_watchdog(interval, Duration::from_secs(cli.poll_interval), poller.subscribe())),
+            false => tokio::spawn(systemd::run_watchdog_timer(interval)),
+        };
+    }
+    let poll_intervals = config.poll_intervals.iter()
+        .map(|(plug, interval)| (plug.clone(), Duration::from_secs(*interval)))
+        .collect();
+    let schedule = Schedule::new(Duration::from_secs(cli.poll_interval)).with_intervals(poll_intervals);
+    let (stop_polling, polling_stopped) = oneshot::channel::<()>();
+    let polling = match background_polling {
+        true => Some(tokio::spawn(poller.run(
+            state.client.clone(),
+            state.plugs.clone(),
+            state.cache.clone(),
+            schedule,
+            async { let _ = polling_stopped.await; },
+        ))),
+        false => None,
+    };
+
+    let grace_period = Duration::from_secs(cli.shutdown_grace_period);
+    let energy = state.energy.clone();
+    let server = match cli.no_http_server {
+        true => None,
+        false => Some(bind_server(&cli, state, authenticator, grace_period)?),
+    };
+    // Only once setup went through, so a failed start doesn't leave a stale pid file behind
+    #[cfg(unix)]
+    if let (false, Some(path)) = (cli.daemon, &cli.pid_file) {
+        service::write_pid_file(path).map_err(std::io::Error::other)?;
+    }
+    systemd::notify_ready();
+    match server {
+        Some(server) => server.await?,
+        None => {
+            shutdown::signal().await;
+            systemd::notify_stopping();
+        }
+    }
+
+    // The HTTP server has drained by now, give the background poll the same grace period
+    let _ = stop_polling.send(());
+    if let Some(polling) = polling {
+        if tokio::time::timeout(grace_period, polling).await.is_err() {
+            warn!("Background poll didn't finish within the grace period");
+        }
+    }
+    // The history stops once the poller is gone, after writing the last poll
+    if let Some(recording) = recording {
+        if tokio::time::timeout(grace_period, recording).await.is_err() {
+            warn!("History didn't finish recording the last poll within the grace period");
+        }
+    }
+    let persisted = energy.persist();
+    #[cfg(unix)]
+    if let Some(path) = &cli.pid_file {
+        service::remove_pid_file(path);
+    }
+
+    if let Err(e) = persisted {
+        warn!("Energy counters changed since the last save are lost");
+        return Err(std::io::Error::other(e));
+    }
+    info!("Shut down cleanly");
+    Ok(())
+}
+
+
+async fn discover(args: DiscoverArgs) -> std::io::Result<()> {
+    let devices = discovery::discover(Duration::from_secs(args.timeout)).await.map_err(std::io::Error::other)?;
+    if devices.is_empty() {
+        warn!("No Shelly devices answered within {} seconds", args.timeout);
+    }
+
+    for device in devices {
+        println!(
+            "{}\t{}\t{}\tgen{}\t{}",
+            device.ip_addr(),
+            device.name,
+            device.app.as_deref().unwrap_or("unknown"),
+            device.generation.as_deref().unwrap_or("?"),
+            device.firmware.as_deref().unwrap_or("unknown"),
+        );
+    }
+
+    Ok(())
+}
+
+
+/// Fails on invalid flags or config, unreachable devices are only reported
+async fn check(args: CheckArgs) -> std::io::Result<()> {
+    let config = load_config(&args.plugs)?;
+    for (plug, labels) in &config.labels {
+        for name in labels.keys() {
+            validate_label_name(name, plug).map_err(std::io::Error::other)?;
+        }
+    }
+    let state = build_state(&args.plugs, &config, false).await?;
+    match &args.plugs.config {
+        Some(path) => println!("Config file `{}` is valid", path.display()),
+        None => println!("Flags are valid"),
+    }
+
+    let devices = [state.plugs.snapshot(), state.meters.clone()].concat();
+    if args.skip_probe || devices.is_empty() {
+        return Ok(());
+    }
+
+    let probes = join_all(devices.iter().map(|device| state.client.get_device_info(device))).await;
+    let mut rows = vec![["ALIAS", "ADDRESS", "STATUS", "MODEL", "FIRMWARE"].map(str::to_string)];
+    for (device, probe) in devices.iter().zip(&probes) {
+        let (status, model, firmware) = match probe {
+            Ok(info) => (
+                "reachable".to_string(),
+                info.model.clone().unwrap_or_else(|| "unknown".to_string()),
+                info.firmware.clone().unwrap_or_else(|| "unknown".to_string()),
+            ),
+            Err(e) => (format!("unreachable ({})", e.reason()), "-".to_string(), "-".to_string()),
+        };
+        rows.push([device.alias.clone(), device.address(), status, model, firmware]);
+    }
+    print!("{}", format_table(&rows));
+
+    let unreachable = probes.iter().filter(|probe| probe.is_err()).count();
+    if unreachable > 0 {
+        warn!("{unreachable} of {} devices are unreachable", devices.len());
+    }
+    Ok(())
+}
+
+
+/// Left aligned columns, as wide as their widest cell
+fn format_table<const N: usize>(rows: &[[String; N]]) -> String {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    for row in rows {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{cell:<width$}")).collect();
+        output += cells.join("  ").trim_end();
+        output += "\n";
+    }
+    output
+}
+
+
+async fn scrape_once(args: ScrapeOnceArgs) -> std::io::Result<()> {
+    let state = build_state(&args.plugs, &load_config(&args.plugs)?, false).await?;
+    let families = state.scrape(&state.plugs.snapshot(), &state.meters).await.map_err(std::io::Error::other)?;
+
+    print!("{}", metrics::encode(&families, args.format.into()));
+    Ok(())
+}
+
+
+/// Bind the HTTP server, which stops accepting connections on SIGTERM / SIGINT and then waits up to
+/// `grace_period` for in-flight scrapes
+fn bind_server(
+    cli: &ServeArgs,
+    state: AppState,
+    authenticator: web::Data<Authenticator>,
+    grace_period: Duration,
+) -> std::io::Result<Server> {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(state.clone()))
-            .service(metrics)
+            .app_data(authenticator.clone())
+            .configure(server::configure)
+            .wrap(from_fn(auth::require_auth))
+            // Honors `Accept-Encoding`, Prometheus asks for gzip
+            .wrap(Compress::default())
             .wrap(Logger::default())
     })
-        .bind(("0.0.0.0", cli.server_port))?
-        .run()
-        .await
+        .shutdown_timeout(grace_period.as_secs())
+        .disable_signals();
+
+    // Sockets passed by systemd socket activation replace `--server-port`
+    let listeners = systemd::listeners()?;
+    let server = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let resolver = tls::ReloadingCertResolver::new(cert_path, key_path)
+                .map(Arc::new)
+                .map_err(std::io::Error::other)?;
+            tls::spawn_reload_task(resolver.clone(), Duration::from_secs(cli.tls_reload_interval));
+
+            match listeners.is_empty() {
+                true => server.bind_rustls_0_23(("0.0.0.0", cli.server_port), tls::server_config(resolver))?,
+                false => listeners.into_iter().try_fold(server, |server, listener| {
+                    server.listen_rustls_0_23(listener, tls::server_config(resolver.clone()))
+                })?,
+            }
+        }
+        _ => match listeners.is_empty() {
+            true => server.bind(("0.0.0.0", cli.server_port))?,
+            false => listeners.into_iter().try_fold(server, |server, listener| server.listen(listener))?,
+        },
+    };
+
+    let server = server.run();
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        systemd::notify_stopping();
+        handle.stop(true).await;
+    });
+
+    Ok(server)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn serve_args(args: &[&str]) -> ServeArgs {
+        let cli = Cli::parse_from(["shelly_smartplug_exporter", "serve"].iter().chain(args));
+        match cli.command {
+            Command::Serve(args) => *args,
+            command => panic!("Expected the serve command, got {command:?}"),
+        }
+    }
+
     #[test]
     fn test_verify_cli() {
         use clap::CommandFactory;
-        Args::command().debug_assert();
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_env_vars() {
+        use clap::CommandFactory;
+        let command = Cli::command();
+        let serve = command.find_subcommand("serve").unwrap();
+        let env = |id: &str| serve.get_arguments()
+            .find(|arg| arg.get_id() == id)
+            .and_then(|arg| arg.get_env())
+            .map(|env| env.to_string_lossy().to_string());
+
+        assert_eq!(env("server_port").as_deref(), Some("SHELLY_EXPORTER_PORT"));
+        assert_eq!(env("ip_addrs").as_deref(), Some("SHELLY_EXPORTER_IP_ADDRS"));
+        assert_eq!(env("mqtt_password_file").as_deref(), Some("SHELLY_EXPORTER_MQTT_PASSWORD_FILE"));
+        // Label values may contain spaces, they are set through the config file instead
+        assert_eq!(env("labels"), None);
+        for arg in serve.get_arguments().filter(|arg| !["labels", "help", "version"].contains(&arg.get_id().as_str())) {
+            assert!(arg.get_env().is_some(), "`{}` has no environment variable", arg.get_id());
+        }
+    }
+
+    #[test]
+    fn test_log_flags() {
+        let defaults = Cli::parse_from(["shelly_smartplug_exporter", "serve", "-i", "10.0.0.1"]);
+        assert_eq!(defaults.log_format, LogFormat::Text);
+        assert_eq!(defaults.log_level, LevelFilter::Info);
+
+        let json = Cli::parse_from([
+            "shelly_smartplug_exporter",
+            "--log-format", "json",
+            "serve",
+            "-i", "10.0.0.1",
+            "--log-level", "debug",
+        ]);
+        assert_eq!(json.log_format, LogFormat::Json);
+        assert_eq!(json.log_level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let check = check_args(&["--config", "exporter.toml"]);
+        assert_eq!(check.plugs.config.as_deref(), Some(std::path::Path::new("exporter.toml")));
+        assert!(!check.skip_probe);
+
+        let discover = Cli::parse_from(["shelly_smartplug_exporter", "discover"]);
+        assert!(matches!(discover.command, Command::Discover(DiscoverArgs { timeout: 5 })));
+
+        let scrape = Cli::parse_from(["shelly_smartplug_exporter", "scrape-once", "-i", "10.0.0.1", "--format", "openmetrics"]);
+        match scrape.command {
+            Command::ScrapeOnce(args) => {
+                assert_eq!(args.format, OutputFormat::Openmetrics);
+                assert_eq!(args.plugs.ip_addrs, vec!["10.0.0.1"]);
+            }
+            command => panic!("Expected the scrape-once command, got {command:?}"),
+        }
+
+        // Plugs (or a config file) are required for everything but discover
+        assert!(Cli::try_parse_from(["shelly_smartplug_exporter", "serve"]).is_err());
+        assert!(Cli::try_parse_from(["shelly_smartplug_exporter"]).is_err());
+    }
+
+    fn check_args(args: &[&str]) -> CheckArgs {
+        let cli = Cli::parse_from(["shelly_smartplug_exporter", "check"].iter().chain(args));
+        match cli.command {
+            Command::Check(args) => *args,
+            command => panic!("Expected the check command, got {command:?}"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_check() {
+        let dir = std::env::temp_dir().join(format!("shelly_check_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let valid = dir.join("valid.toml");
+        let invalid = dir.join("invalid.toml");
+        std::fs::write(&valid, "[labels.kettle]\nroom = \"kitchen\"\n").unwrap();
+        std::fs::write(&invalid, "[labels.kettle]\nhostname = \"kitchen\"\n").unwrap();
+        let path = |path: &PathBuf| path.to_str().unwrap().to_string();
+
+        assert!(check(check_args(&["--config", &path(&valid)])).await.is_ok());
+        assert!(check(check_args(&["--config", &path(&invalid)])).await.is_err());
+        assert!(check(check_args(&["--config", &path(&dir.join("missing.toml"))])).await.is_err());
+        assert!(check(check_args(&["-i", "10.0.0.2", "-l", "10.0.0.2:hostname=x", "--skip-probe"])).await.is_err());
+        // Unreachable devices are reported, but don't fail the check
+        assert!(check(check_args(&["-i", "127.0.0.1:1"])).await.is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_table() {
+        let rows = [
+            ["ALIAS", "STATUS"].map(str::to_string),
+            ["kettle", "reachable"].map(str::to_string),
+            ["tv", ""].map(str::to_string),
+        ];
+
+        assert_eq!(format_table(&rows), "ALIAS   STATUS\nkettle  reachable\ntv\n");
     }
 
     #[test]
     fn test_load_plugs_from_cli_args() {
-        let test_args = Args {
-            ip_addrs: vec![
-                "10.0.0.1".to_string(),
-                "10.0.0.2".to_string(),
-                "10.0.0.3".to_string()
-            ],
-            server_port: 9002,
-            hostname_ip_mapping: vec![
-                "10.0.0.1~something_invalid".to_string(),
-                "10.0.0.2:valid".to_string()
-            ]
-        };
+        let test_args = serve_args(&[
+            "-i", "10.0.0.1",
+            "-i", "10.0.0.2",
+            "-i", "10.0.0.3",
+            "-p", "9002",
+            "-m", "10.0.0.1~something_invalid",
+            "-m", "10.0.0.2:valid",
+        ]);
 
-        let actual = load_plugs(&test_args);
+        let actual = load_plugs(&test_args.plugs, &[]);
 
         assert_eq!(actual.len(), 3);
         assert_eq!(actual[0].alias, "10.0.0.1");
@@ -127,4 +1293,166 @@ mod tests {
         assert_eq!(actual[1].alias, "valid");
         assert_eq!(actual[2].alias, "10.0.0.3");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_load_scanned_plugs() {
+        let test_args = serve_args(&["--scan", "10.0.0.0/24", "-i", "10.0.0.2", "-m", "10.0.0.3:kettle"]);
+        assert_eq!(test_args.plugs.scan, vec!["10.0.0.0/24".parse().unwrap()]);
+
+        let actual = load_plugs(&test_args.plugs, &["10.0.0.2".to_string(), "10.0.0.3".to_string()]);
+
+        assert_eq!(actual.len(), 2);
+        assert_eq!(actual[0].alias, "10.0.0.2");
+        assert_eq!(actual[1].alias, "kettle");
+        assert_eq!(actual[1].url, "http://10.0.0.3/rpc/Switch.GetStatus?id=0");
+
+        let scan_only = serve_args(&["--scan", "10.0.0.0/24"]);
+        assert!(scan_only.plugs.ip_addrs.is_empty());
+    }
+
+    #[test]
+    fn test_load_https_plugs() {
+        let test_args = serve_args(&["-i", "https://plug.lan:8443/", "-i", "10.0.0.2"]);
+
+        let actual = load_plugs(&test_args.plugs, &[]);
+
+        assert_eq!(actual[0].url, "https://plug.lan:8443/rpc/Switch.GetStatus?id=0");
+        assert_eq!(actual[0].alias, "plug.lan:8443");
+        assert_eq!(actual[1].url, "http://10.0.0.2/rpc/Switch.GetStatus?id=0");
+
+        let proxied = serve_args(&["-i", "https://proxy.lan:8443/shelly/kitchen", "-m", "proxy.lan:8443/shelly/kitchen:kitchen"]);
+        let actual = load_plugs(&proxied.plugs, &[]);
+        assert_eq!(actual[0].alias, "kitchen");
+        assert_eq!(actual[0].url, "https://proxy.lan:8443/shelly/kitchen/rpc/Switch.GetStatus?id=0");
+
+        assert!(Cli::try_parse_from(["shelly_smartplug_exporter", "serve", "-i", "ftp://plug.lan"]).is_err());
+        assert!(Cli::try_parse_from(["shelly_smartplug_exporter", "serve", "--em-addr", "ws://10.0.0.5"]).is_err());
+    }
+
+    #[test]
+    fn test_load_meters() {
+        let test_args = serve_args(&["--em-addr", "10.0.0.5", "-m", "10.0.0.5:mains"]);
+
+        let actual = load_meters(&test_args.plugs);
+
+        assert!(test_args.plugs.ip_addrs.is_empty());
+        assert_eq!(actual.len(), 1);
+        assert_eq!(actual[0].alias, "mains");
+        assert_eq!(actual[0].rpc_url("EM.GetStatus?id=0"), "http://10.0.0.5/rpc/EM.GetStatus?id=0");
+    }
+
+    #[actix_web::test]
+    async fn test_resolve_aliases() {
+        let mut named = mockito::Server::new_async().await;
+        named.mock("GET", "/rpc/Shelly.GetDeviceInfo")
+            .with_status(200)
+            .with_body(r#"{"id": "shellyplusplugs-80646fd17d72", "name": "Kettle", "gen": 2}"#)
+            .create_async()
+            .await;
+        let mut unnamed = mockito::Server::new_async().await;
+        unnamed.mock("GET", "/rpc/Shelly.GetDeviceInfo")
+            .with_status(200)
+            .with_body(r#"{"id": "shellyplusplugs-80646fd17d73", "name": null}"#)
+            .create_async()
+            .await;
+        let test_args = serve_args(&[
+            "-i", &named.host_with_port(),
+            "-i", &unnamed.host_with_port(),
+            "-i", "10.0.0.9",
+            "-m", "10.0.0.9:garage",
+        ]);
+        let mut plugs = load_plugs(&test_args.plugs, &[]);
+
+        resolve_aliases(&ShellyClient::new(), &mut plugs).await;
+
+        assert_eq!(plugs[0].alias, "Kettle");
+        assert_eq!(plugs[1].alias, unnamed.host_with_port());
+        assert_eq!(plugs[2].alias, "garage");
+    }
+
+    #[test]
+    fn test_apply_labels() {
+        let test_args = serve_args(&[
+            "-i", "10.0.0.1",
+            "-i", "10.0.0.2",
+            "-m", "10.0.0.2:kettle",
+            "-l", "10.0.0.1:room=office",
+            "-l", "10.0.0.2:circuit=3",
+        ]);
+        let config = config::parse(r#"
+            [labels."10.0.0.2"]
+            room = "kitchen"
+            circuit = "1"
+
+            [labels.kettle]
+            floor = "ground"
+        "#).unwrap();
+        let mut plugs = load_plugs(&test_args.plugs, &[]);
+
+        apply_labels(&mut plugs, &test_args.plugs, &config).unwrap();
+
+        assert_eq!(plugs[0].labels, vec![("room".to_string(), "office".to_string())]);
+        assert_eq!(plugs[1].labels, vec![
+            ("circuit".to_string(), "3".to_string()),
+            ("floor".to_string(), "ground".to_string()),
+            ("room".to_string(), "kitchen".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_config_plugs() {
+        let test_args = serve_args(&["-c", "exporter.toml", "-i", "10.0.0.2", "-m", "10.0.0.4:from-cli"]);
+        let config = config::parse(r#"
+            [[plugs]]
+            address = "10.0.0.3"
+            alias = "kettle"
+            labels = { room = "kitchen" }
+
+            [[plugs]]
+            address = "10.0.0.4"
+            alias = "ignored"
+        "#).unwrap();
+        let extra: Vec<String> = config.plugs.iter().map(|plug| plug.address.clone()).collect();
+        let mut plugs = load_plugs(&test_args.plugs, &extra);
+
+        apply_config_aliases(&mut plugs, &config);
+        apply_labels(&mut plugs, &test_args.plugs, &config).unwrap();
+
+        assert_eq!(plugs.iter().map(|plug| plug.alias.as_str()).collect::<Vec<_>>(), vec!["10.0.0.2", "kettle", "from-cli"]);
+        assert_eq!(plugs[1].labels, vec![("room".to_string(), "kitchen".to_string())]);
+        assert!(serve_args(&["-c", "exporter.toml"]).plugs.ip_addrs.is_empty());
+    }
+
+    #[test]
+    fn test_metric_naming() {
+        let defaults = serve_args(&["-i", "10.0.0.1"]);
+        assert_eq!(metric_naming(&defaults.plugs).unwrap(), MetricNaming::default());
+
+        let legacy = serve_args(&["-i", "10.0.0.1", "--legacy-metric-names", "--instance-label-name", "plug"]);
+        assert_eq!(
+            metric_naming(&legacy.plugs).unwrap(),
+            MetricNaming { prefix: None, instance_label: "plug".to_string() }
+        );
+
+        for invalid in [["--metric-prefix", "shelly-"], ["--instance-label-name", "channel"]] {
+            let args = serve_args(&["-i", "10.0.0.1", invalid[0], invalid[1]]);
+            assert!(metric_naming(&args.plugs).is_err(), "{invalid:?}");
+        }
+
+        let clashing = serve_args(&["-i", "10.0.0.1", "--instance-label-name", "room", "-l", "10.0.0.1:room=den"]);
+        let mut plugs = load_plugs(&clashing.plugs, &[]);
+        assert_eq!(apply_labels(&mut plugs, &clashing.plugs, &config::Config::default()), Err("Invalid plug label!"));
+    }
+
+    #[test]
+    fn test_apply_invalid_labels() {
+        for label in ["10.0.0.1:room", "10.0.0.1:2nd=floor", "10.0.0.1:hostname=other"] {
+            let test_args = serve_args(&["-i", "10.0.0.1", "-l", label]);
+            let mut plugs = load_plugs(&test_args.plugs, &[]);
+
+            let actual = apply_labels(&mut plugs, &test_args.plugs, &config::Config::default());
+
+            assert_eq!(actual, Err("Invalid plug label!"), "{label}");
+        }
+    }
+}
@@ -0,0 +1,507 @@
+//! Minimal metric model with encoders for the Prometheus text and OpenMetrics exposition formats.
+//!
+//! Ref: https://prometheus.io/docs/instrumenting/exposition_formats/
+//! Ref: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use std::fmt::Write;
+
+
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+pub const DEFAULT_METRIC_PREFIX: &str = "shelly_";
+/// Label telling the plugs apart
+pub const DEFAULT_INSTANCE_LABEL: &str = "hostname";
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+    Histogram,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp: Option<f64>,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    /// Only rendered in the OpenMetrics format, and only on counters
+    pub exemplar: Option<Exemplar>,
+    /// Appended to the family name, `_bucket`, `_sum` or `_count` for the samples of a histogram
+    pub suffix: &'static str,
+    /// When the value was read in milliseconds since the epoch, rendered after the value
+    pub timestamp_ms: Option<i64>,
+}
+
+impl Sample {
+    pub fn new(labels: Vec<(String, String)>, value: f64) -> Sample {
+        Sample { labels, value, exemplar: None, suffix: "", timestamp_ms: None }
+    }
+}
+
+
+/// Observations counted into buckets by upper bound, like the histograms of the Prometheus clients
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    /// Upper bounds of the buckets in increasing order, without `+Inf`
+    bounds: Vec<f64>,
+    /// Observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram { bounds: bounds.to_vec(), counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Bucket bounds must be finite and strictly increasing
+pub fn is_valid_buckets(bounds: &[f64]) -> bool {
+    bounds.iter().all(|bound| bound.is_finite()) && bounds.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+
+/// A named group of samples sharing the same type and help text
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: String,
+    pub kind: MetricType,
+    pub samples: Vec<Sample>,
+}
+
+impl MetricFamily {
+    pub fn new(name: &str, help: &str, kind: MetricType) -> MetricFamily {
+        MetricFamily { name: name.to_string(), help: help.to_string(), kind, samples: vec![] }
+    }
+
+    pub fn gauge(name: &str, help: &str) -> MetricFamily {
+        MetricFamily::new(name, help, MetricType::Gauge)
+    }
+
+    pub fn counter(name: &str, help: &str) -> MetricFamily {
+        MetricFamily::new(name, help, MetricType::Counter)
+    }
+
+    pub fn histogram(name: &str, help: &str) -> MetricFamily {
+        MetricFamily::new(name, help, MetricType::Histogram)
+    }
+
+    pub fn push(&mut self, labels: Vec<(String, String)>, value: f64) {
+        self.samples.push(Sample::new(labels, value));
+    }
+
+    /// Push the cumulative `_bucket` samples with their `le` label, then `_sum` and `_count`
+    pub fn push_histogram(&mut self, labels: Vec<(String, String)>, histogram: &Histogram) {
+        let mut cumulative = 0;
+        let bounds = histogram.bounds.iter().map(|bound| format_value(*bound)).chain(["+Inf".to_string()]);
+        let counts = histogram.counts.iter().copied().chain([histogram.count - histogram.counts.iter().sum::<u64>()]);
+        for (bound, count) in bounds.zip(counts) {
+            cumulative += count;
+            let mut bucket_labels = labels.clone();
+            bucket_labels.push(("le".to_string(), bound));
+            self.samples.push(Sample { suffix: "_bucket", ..Sample::new(bucket_labels, cumulative as f64) });
+        }
+        self.samples.push(Sample { suffix: "_sum", ..Sample::new(labels.clone(), histogram.sum) });
+        self.samples.push(Sample { suffix: "_count", ..Sample::new(labels, histogram.count as f64) });
+    }
+}
+
+
+/// Renaming applied to every family before it's served, so the plug metrics don't collide with
+/// those of other exporters
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricNaming {
+    /// Replaces the `shelly_` prefix of every name, and is added to the names without it. `None`
+    /// keeps the names as they were originally, with unprefixed plug metrics.
+    pub prefix: Option<String>,
+    /// Replaces the `hostname` label of every sample
+    pub instance_label: String,
+}
+
+impl Default for MetricNaming {
+    fn default() -> Self {
+        MetricNaming { prefix: Some(DEFAULT_METRIC_PREFIX.to_string()), instance_label: DEFAULT_INSTANCE_LABEL.to_string() }
+    }
+}
+
+impl MetricNaming {
+    /// The names from before they were configurable
+    pub fn legacy() -> MetricNaming {
+        MetricNaming { prefix: None, ..MetricNaming::default() }
+    }
+
+    pub fn apply(&self, families: &mut [MetricFamily]) {
+        for family in families {
+            if let Some(prefix) = &self.prefix {
+                let name = family.name.strip_prefix(DEFAULT_METRIC_PREFIX).unwrap_or(&family.name);
+                family.name = format!("{prefix}{name}");
+            }
+            if self.instance_label != DEFAULT_INSTANCE_LABEL {
+                for sample in &mut family.samples {
+                    for (name, _) in &mut sample.labels {
+                        if name == DEFAULT_INSTANCE_LABEL {
+                            name.clone_from(&self.instance_label);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Prometheus,
+    OpenMetrics,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Prometheus => PROMETHEUS_CONTENT_TYPE,
+            Format::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+        }
+    }
+
+    /// Pick the exposition format from an HTTP `Accept` header. OpenMetrics is only used when the
+    /// client explicitly asks for it with a higher preference than the classic text format.
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Format::Prometheus,
+        };
+
+        let mut openmetrics_q: f32 = 0.0;
+        let mut text_q: f32 = 0.0;
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            match media_type.as_str() {
+                "application/openmetrics-text" => openmetrics_q = openmetrics_q.max(q),
+                "text/plain" | "text/*" | "*/*" => text_q = text_q.max(q),
+                _ => {}
+            }
+        }
+
+        if openmetrics_q > 0.0 && openmetrics_q >= text_q {
+            Format::OpenMetrics
+        } else {
+            Format::Prometheus
+        }
+    }
+}
+
+
+pub fn encode(families: &[MetricFamily], format: Format) -> String {
+    let mut output = String::new();
+
+    for family in families {
+        if family.samples.is_empty() {
+            continue;
+        }
+
+        // Classic text format keeps counter names as-is. OpenMetrics requires the `_total` suffix on
+        // counter samples, but not on the family name itself.
+        let (family_name, sample_name) = match (format, family.kind) {
+            (Format::OpenMetrics, MetricType::Counter) => match family.name.strip_suffix("_total") {
+                Some(base) => (base.to_string(), family.name.clone()),
+                None => (family.name.clone(), format!("{}_total", family.name)),
+            },
+            _ => (family.name.clone(), family.name.clone()),
+        };
+
+        let _ = writeln!(output, "# HELP {} {}", family_name, escape_help(&family.help));
+        let _ = writeln!(output, "# TYPE {} {}", family_name, family.kind.as_str());
+
+        for sample in &family.samples {
+            output += &sample_name;
+            output += sample.suffix;
+            output += &encode_labels(&sample.labels);
+            output += " ";
+            output += &format_value(sample.value);
+            if let Some(timestamp_ms) = sample.timestamp_ms {
+                output += " ";
+                output += &format_timestamp(timestamp_ms, format);
+            }
+
+            if let (Format::OpenMetrics, MetricType::Counter, Some(exemplar)) =
+                (format, family.kind, &sample.exemplar) {
+                output += " # ";
+                output += &encode_labels(&exemplar.labels);
+                output += " ";
+                output += &format_value(exemplar.value);
+                if let Some(ts) = exemplar.timestamp {
+                    output += " ";
+                    output += &format_value(ts);
+                }
+            }
+            output += "\n";
+        }
+    }
+
+    if format == Format::OpenMetrics {
+        output += "# EOF\n";
+    }
+
+    output
+}
+
+/// Label names must match `[a-zA-Z_][a-zA-Z0-9_]*`, names starting with `__` are reserved
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_start = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_');
+
+    valid_start && !name.starts_with("__") && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Metric names (and prefixes, which may be empty) must match `[a-zA-Z_:][a-zA-Z0-9_:]*`
+pub fn is_valid_metric_prefix(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+    let valid_start = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_' || c == ':',
+        None => true,
+    };
+
+    valid_start && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn encode_labels(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return "".to_string();
+    }
+
+    let pairs = labels.iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape_label_value(value)))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!("{{{pairs}}}")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', r"\\").replace('\n', r"\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    } else {
+        // Debug formatting keeps the trailing `.0` on whole numbers so the output matches the raw API
+        format!("{value:?}")
+    }
+}
+
+/// Milliseconds in the Prometheus text format, seconds in OpenMetrics
+fn format_timestamp(timestamp_ms: i64, format: Format) -> String {
+    match format {
+        Format::Prometheus => timestamp_ms.to_string(),
+        Format::OpenMetrics => format!("{}.{:03}", timestamp_ms.div_euclid(1000), timestamp_ms.rem_euclid(1000)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_families() -> Vec<MetricFamily> {
+        let mut gauge = MetricFamily::gauge("power_watts", "Current power draw");
+        gauge.push(vec![("hostname".to_string(), "a\"b".to_string())], 1.0);
+
+        let mut counter = MetricFamily::counter("energy_wh", "Energy consumed");
+        counter.samples.push(Sample {
+            labels: vec![("hostname".to_string(), "plug".to_string())],
+            value: 12.5,
+            exemplar: Some(Exemplar {
+                labels: vec![("poll_id".to_string(), "1".to_string())],
+                value: 0.5,
+                timestamp: Some(1735620900.0)
+            }),
+            suffix: "",
+            timestamp_ms: None,
+        });
+
+        vec![gauge, counter, MetricFamily::gauge("empty", "Nothing here")]
+    }
+
+    #[test]
+    fn test_encode_prometheus() {
+        let actual = encode(&sample_families(), Format::Prometheus);
+
+        assert_eq!(actual,
+r#"# HELP power_watts Current power draw
+# TYPE power_watts gauge
+power_watts{hostname="a\"b"} 1.0
+# HELP energy_wh Energy consumed
+# TYPE energy_wh counter
+energy_wh{hostname="plug"} 12.5
+"#
+        );
+    }
+
+    #[test]
+    fn test_encode_openmetrics() {
+        let actual = encode(&sample_families(), Format::OpenMetrics);
+
+        assert_eq!(actual,
+r#"# HELP power_watts Current power draw
+# TYPE power_watts gauge
+power_watts{hostname="a\"b"} 1.0
+# HELP energy_wh Energy consumed
+# TYPE energy_wh counter
+energy_wh_total{hostname="plug"} 12.5 # {poll_id="1"} 0.5 1735620900.0
+# EOF
+"#
+        );
+    }
+
+    #[test]
+    fn test_encode_timestamps() {
+        let mut gauge = MetricFamily::gauge("power_watts", "Current power draw");
+        gauge.samples.push(Sample { timestamp_ms: Some(1735620900042), ..Sample::new(vec![], 1.0) });
+
+        assert!(encode(&[gauge.clone()], Format::Prometheus).contains("\npower_watts 1.0 1735620900042\n"));
+        assert!(encode(&[gauge], Format::OpenMetrics).contains("\npower_watts 1.0 1735620900.042\n"));
+    }
+
+    #[test]
+    fn test_encode_counter_with_total_suffix() {
+        let mut counter = MetricFamily::counter("cost_total", "Money spent");
+        counter.push(vec![], 2.5);
+
+        assert_eq!(encode(&[counter.clone()], Format::Prometheus),
+            "# HELP cost_total Money spent\n# TYPE cost_total counter\ncost_total 2.5\n");
+        assert_eq!(encode(&[counter], Format::OpenMetrics),
+            "# HELP cost Money spent\n# TYPE cost counter\ncost_total 2.5\n# EOF\n");
+    }
+
+    #[test]
+    fn test_encode_histogram() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        for value in [0.05, 0.5, 0.5, 3.0] {
+            histogram.observe(value);
+        }
+        let mut family = MetricFamily::histogram("duration_seconds", "Time taken");
+        family.push_histogram(vec![("hostname".to_string(), "plug".to_string())], &histogram);
+
+        assert_eq!(encode(&[family], Format::Prometheus),
+r#"# HELP duration_seconds Time taken
+# TYPE duration_seconds histogram
+duration_seconds_bucket{hostname="plug",le="0.1"} 1.0
+duration_seconds_bucket{hostname="plug",le="1.0"} 3.0
+duration_seconds_bucket{hostname="plug",le="+Inf"} 4.0
+duration_seconds_sum{hostname="plug"} 4.05
+duration_seconds_count{hostname="plug"} 4.0
+"#
+        );
+    }
+
+    #[test]
+    fn test_is_valid_buckets() {
+        assert!(is_valid_buckets(&[0.1, 0.5, 1.0]));
+        assert!(is_valid_buckets(&[]));
+        assert!(!is_valid_buckets(&[0.5, 0.1]));
+        assert!(!is_valid_buckets(&[0.5, 0.5]));
+        assert!(!is_valid_buckets(&[f64::INFINITY]));
+    }
+
+    #[test]
+    fn test_is_valid_label_name() {
+        assert!(is_valid_label_name("room"));
+        assert!(is_valid_label_name("_circuit_2"));
+        assert!(!is_valid_label_name(""));
+        assert!(!is_valid_label_name("2nd_floor"));
+        assert!(!is_valid_label_name("floor-2"));
+        assert!(!is_valid_label_name("__name__"));
+    }
+
+    #[test]
+    fn test_metric_naming() {
+        let mut plug_up = MetricFamily::gauge("shelly_plug_up", "Up");
+        plug_up.push(vec![("hostname".to_string(), "kettle".to_string())], 1.0);
+        let families = vec![MetricFamily::gauge("power_watts", "Power"), plug_up];
+
+        let mut default = families.clone();
+        MetricNaming::default().apply(&mut default);
+        assert_eq!(default.iter().map(|family| family.name.as_str()).collect::<Vec<_>>(), vec!["shelly_power_watts", "shelly_plug_up"]);
+        assert_eq!(default[1].samples[0].labels[0].0, "hostname");
+
+        let mut custom = families.clone();
+        MetricNaming { prefix: Some("home_".to_string()), instance_label: "plug".to_string() }.apply(&mut custom);
+        assert_eq!(custom.iter().map(|family| family.name.as_str()).collect::<Vec<_>>(), vec!["home_power_watts", "home_plug_up"]);
+        assert_eq!(custom[1].samples[0].labels[0], ("plug".to_string(), "kettle".to_string()));
+
+        let mut legacy = families.clone();
+        MetricNaming::legacy().apply(&mut legacy);
+        assert_eq!(legacy, families);
+    }
+
+    #[test]
+    fn test_is_valid_metric_prefix() {
+        assert!(is_valid_metric_prefix(""));
+        assert!(is_valid_metric_prefix("home:shelly_"));
+        assert!(!is_valid_metric_prefix("9lives_"));
+        assert!(!is_valid_metric_prefix("shelly-"));
+    }
+
+    #[test]
+    fn test_format_special_values() {
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_value(3.0), "3.0");
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let prometheus_scraper = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;\
+            version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+        assert_eq!(Format::negotiate(None), Format::Prometheus);
+        assert_eq!(Format::negotiate(Some("*/*")), Format::Prometheus);
+        assert_eq!(Format::negotiate(Some("text/plain")), Format::Prometheus);
+        assert_eq!(Format::negotiate(Some(prometheus_scraper)), Format::OpenMetrics);
+        assert_eq!(Format::negotiate(Some("application/openmetrics-text;q=0.2, text/plain")), Format::Prometheus);
+        assert_eq!(Format::negotiate(Some("application/openmetrics-text;q=0")), Format::Prometheus);
+    }
+}
@@ -0,0 +1,255 @@
+//! MQTT publisher. Every background poll's readings are published to `<prefix>/<alias>/<metric>`
+//! topics, alongside Home Assistant discovery payloads so the plugs show up as sensors.
+//!
+//! Ref: https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{error, info};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use tokio::sync::broadcast;
+
+use crate::client::ShellySmartPlug;
+use crate::poller::{self, Readings};
+use crate::status::SwitchStatus;
+
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_TOPIC_PREFIX: &str = "shelly";
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    pub topic_prefix: String,
+    /// Home Assistant discovery prefix, `None` disables the discovery payloads
+    pub discovery_prefix: Option<String>,
+}
+
+impl MqttConfig {
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+}
+
+
+struct Sensor {
+    name: &'static str,
+    device_class: &'static str,
+    unit: &'static str,
+    state_class: &'static str,
+}
+
+const SENSORS: [Sensor; 5] = [
+    Sensor { name: "power", device_class: "power", unit: "W", state_class: "measurement" },
+    Sensor { name: "voltage", device_class: "voltage", unit: "V", state_class: "measurement" },
+    Sensor { name: "current", device_class: "current", unit: "A", state_class: "measurement" },
+    Sensor { name: "temperature", device_class: "temperature", unit: "°C", state_class: "measurement" },
+    Sensor { name: "energy", device_class: "energy", unit: "Wh", state_class: "total_increasing" },
+];
+
+
+/// Publish every poll's readings until the poller goes away
+pub async fn run(config: MqttConfig, mut readings: broadcast::Receiver<Readings>) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(config.availability_topic(), "offline", QoS::AtLeastOnce, true));
+    if let Some((username, password)) = &config.credentials {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+    // Discovery payloads are re-sent after every (re)connect in case the broker lost them
+    let announced: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+
+    let connection_announced = announced.clone();
+    let connection_client = client.clone();
+    let availability_topic = config.availability_topic();
+    info!("Publishing readings to MQTT broker {}:{}", config.host, config.port);
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    connection_announced.lock().unwrap().clear();
+                    let _ = connection_client
+                        .publish(&availability_topic, QoS::AtLeastOnce, true, "online")
+                        .await;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    error!("MQTT connection error, reconnecting - {err}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+
+    while let Some(readings) = poller::next_readings(&mut readings).await {
+        for (plug, status) in readings.iter() {
+            let needs_discovery = config.discovery_prefix.is_some()
+                && announced.lock().unwrap().insert(plug.alias.clone());
+            if needs_discovery {
+                for (topic, payload) in discovery_messages(&config, plug) {
+                    publish(&client, topic, payload, true).await;
+                }
+            }
+
+            for (topic, payload) in state_messages(&config, plug, status) {
+                publish(&client, topic, payload, false).await;
+            }
+        }
+    }
+}
+
+async fn publish(client: &AsyncClient, topic: String, payload: String, retain: bool) {
+    if let Err(err) = client.publish(&topic, QoS::AtLeastOnce, retain, payload).await {
+        error!("Failed to publish to `{topic}` - {err}");
+    }
+}
+
+/// MQTT topic levels can't contain wildcards or separators
+pub fn topic_safe(alias: &str) -> String {
+    alias.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+pub fn state_messages(config: &MqttConfig, plug: &ShellySmartPlug, status: &SwitchStatus) -> Vec<(String, String)> {
+    let base = format!("{}/{}", config.topic_prefix, topic_safe(&plug.alias));
+    let mut messages = vec![
+        (format!("{base}/power"), status.apower.to_string()),
+        (format!("{base}/voltage"), status.voltage.to_string()),
+        (format!("{base}/current"), status.current.to_string()),
+        (format!("{base}/temperature"), status.temperature.celsius.to_string()),
+        (format!("{base}/energy"), status.aenergy.total.to_string()),
+    ];
+
+    if let Some(output) = status.output {
+        messages.push((format!("{base}/output"), if output { "ON" } else { "OFF" }.to_string()));
+    }
+
+    messages
+}
+
+pub fn discovery_messages(config: &MqttConfig, plug: &ShellySmartPlug) -> Vec<(String, String)> {
+    let discovery_prefix = match &config.discovery_prefix {
+        Some(prefix) => prefix,
+        None => return vec![],
+    };
+
+    let node_id = topic_safe(&plug.alias);
+    let device = json!({
+        "identifiers": [format!("shelly_exporter_{node_id}")],
+        "name": plug.alias,
+        "manufacturer": "Shelly",
+    });
+
+    let mut messages: Vec<(String, String)> = SENSORS.iter()
+        .map(|sensor| {
+            let payload = json!({
+                "name": sensor.name,
+                "unique_id": format!("shelly_exporter_{node_id}_{}", sensor.name),
+                "state_topic": format!("{}/{node_id}/{}", config.topic_prefix, sensor.name),
+                "availability_topic": config.availability_topic(),
+                "device_class": sensor.device_class,
+                "unit_of_measurement": sensor.unit,
+                "state_class": sensor.state_class,
+                "device": device,
+            });
+
+            (format!("{discovery_prefix}/sensor/{node_id}/{}/config", sensor.name), payload.to_string())
+        })
+        .collect();
+
+    let relay = json!({
+        "name": "output",
+        "unique_id": format!("shelly_exporter_{node_id}_output"),
+        "state_topic": format!("{}/{node_id}/output", config.topic_prefix),
+        "availability_topic": config.availability_topic(),
+        "device_class": "power",
+        "device": device,
+    });
+    messages.push((format!("{discovery_prefix}/binary_sensor/{node_id}/output/config"), relay.to_string()));
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use crate::status::{EnergyCounter, Temperature};
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: DEFAULT_PORT,
+            client_id: "test".to_string(),
+            credentials: None,
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            discovery_prefix: Some(DEFAULT_DISCOVERY_PREFIX.to_string()),
+        }
+    }
+
+    fn plug() -> ShellySmartPlug {
+        ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: "living room/tv".to_string(), labels: vec![] }
+    }
+
+    #[test]
+    fn test_topic_safe() {
+        assert_eq!(topic_safe("kitchen"), "kitchen");
+        assert_eq!(topic_safe("living room/tv+#"), "living_room_tv__");
+        assert_eq!(topic_safe("10.0.0.2"), "10_0_0_2");
+    }
+
+    #[test]
+    fn test_state_messages() {
+        let status = SwitchStatus {
+            id: 0,
+            output: Some(false),
+            apower: 1.5,
+            voltage: 2.0,
+            current: 3.0,
+            temperature: Temperature { celsius: 20.1, fahrenheit: 68.2 },
+            aenergy: EnergyCounter { total: 45.5, by_minute: vec![], minute_ts: None },
+            sys: None,
+            wifi: None,
+            freq: None,
+            pf: None,
+        };
+
+        let actual = state_messages(&config(), &plug(), &status);
+
+        assert_eq!(actual, vec![
+            ("shelly/living_room_tv/power".to_string(), "1.5".to_string()),
+            ("shelly/living_room_tv/voltage".to_string(), "2".to_string()),
+            ("shelly/living_room_tv/current".to_string(), "3".to_string()),
+            ("shelly/living_room_tv/temperature".to_string(), "20.1".to_string()),
+            ("shelly/living_room_tv/energy".to_string(), "45.5".to_string()),
+            ("shelly/living_room_tv/output".to_string(), "OFF".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let actual = discovery_messages(&config(), &plug());
+
+        assert_eq!(actual.len(), 6);
+        assert_eq!(actual[0].0, "homeassistant/sensor/living_room_tv/power/config");
+        let payload: Value = serde_json::from_str(&actual[0].1).unwrap();
+        assert_eq!(payload["state_topic"], "shelly/living_room_tv/power");
+        assert_eq!(payload["unit_of_measurement"], "W");
+        assert_eq!(payload["device"]["name"], "living room/tv");
+        assert_eq!(actual[5].0, "homeassistant/binary_sensor/living_room_tv/output/config");
+
+        let disabled = MqttConfig { discovery_prefix: None, ..config() };
+        assert!(discovery_messages(&disabled, &plug()).is_empty());
+    }
+}
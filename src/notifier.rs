@@ -0,0 +1,267 @@
+//! Notifications on state changes of the plugs, for setups without Alertmanager: a plug going down
+//! or coming back up, its power crossing the `max_power_watts` threshold of the config file, and
+//! its relay switching. Changes are detected on the background polls and posted to a webhook as
+//! JSON, or published to an ntfy topic.
+//!
+//! Ref: https://docs.ntfy.sh/publish/
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use reqwest::Client;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::client::ShellySmartPlug;
+use crate::poller::{self, Readings};
+use crate::registry::PlugRegistry;
+use crate::status::SwitchStatus;
+use crate::thresholds::ThresholdTracker;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NotifyTarget {
+    /// Receives every notification as a JSON `POST`
+    Webhook(String),
+    /// Topic URL, e.g. `https://ntfy.sh/my-plugs`
+    Ntfy(String),
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    /// `down`, `up`, `power_above_threshold`, `power_within_threshold`, `relay_on` or `relay_off`
+    pub event: &'static str,
+    pub alias: String,
+    pub address: String,
+    pub labels: BTreeMap<String, String>,
+    /// RFC 3339
+    pub at: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_watts: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_watts: Option<f64>,
+}
+
+impl Notification {
+    fn new(event: &'static str, plug: &ShellySmartPlug, at: DateTime<Utc>, message: String) -> Notification {
+        Notification {
+            event,
+            alias: plug.alias.clone(),
+            address: plug.address(),
+            labels: plug.labels.iter().cloned().collect(),
+            at: at.to_rfc3339(),
+            message,
+            power_watts: None,
+            limit_watts: None,
+        }
+    }
+}
+
+
+/// What the previous poll saw of a plug
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct PlugState {
+    up: bool,
+    output: Option<bool>,
+    above_threshold: bool,
+}
+
+
+pub struct Notifier {
+    target: NotifyTarget,
+    thresholds: Option<Arc<ThresholdTracker>>,
+    states: HashMap<String, PlugState>,
+}
+
+impl Notifier {
+    pub fn new(target: NotifyTarget, thresholds: Option<Arc<ThresholdTracker>>) -> Notifier {
+        Notifier { target, thresholds, states: HashMap::new() }
+    }
+
+    /// Compare the readings of a poll to the previous one, `plugs` without a reading are down. The
+    /// first poll of a plug only sets the baseline, nothing is notified for it.
+    pub fn changes(
+        &mut self,
+        plugs: &[ShellySmartPlug],
+        readings: &[(ShellySmartPlug, SwitchStatus)],
+        at: DateTime<Utc>,
+    ) -> Vec<Notification> {
+        let mut notifications = vec![];
+        for plug in plugs {
+            let status = readings.iter().find(|(read, _)| read.alias == plug.alias).map(|(_, status)| status);
+            let limit = self.thresholds.as_ref().and_then(|thresholds| thresholds.thresholds_of(plug).max_power_watts);
+            let current = match status {
+                Some(status) => {
+                    PlugState { up: true, output: status.output, above_threshold: limit.is_some_and(|limit| status.apower > limit) }
+                }
+                // Nothing is known about a plug which is down, keep what it was last seen at
+                None => PlugState { up: false, ..self.states.get(&plug.alias).copied().unwrap_or_default() },
+            };
+            let Some(previous) = self.states.insert(plug.alias.clone(), current) else { continue };
+
+            match (previous.up, current.up) {
+                (true, false) => notifications.push(Notification::new("down", plug, at, format!("`{}` is down", plug.alias))),
+                (false, true) => notifications.push(Notification::new("up", plug, at, format!("`{}` is back up", plug.alias))),
+                _ => {}
+            }
+            let Some(status) = status else { continue };
+
+            if let (Some(before), Some(now)) = (previous.output, current.output) {
+                if before != now {
+                    let (event, state) = if now { ("relay_on", "on") } else { ("relay_off", "off") };
+                    notifications.push(Notification::new(event, plug, at, format!("`{}` switched {state}", plug.alias)));
+                }
+            }
+            if previous.above_threshold != current.above_threshold {
+                let (event, message) = match current.above_threshold {
+                    true => ("power_above_threshold", format!("`{}` draws {} W", plug.alias, status.apower)),
+                    false => ("power_within_threshold", format!("`{}` is back to {} W", plug.alias, status.apower)),
+                };
+                notifications.push(Notification {
+                    power_watts: Some(status.apower),
+                    limit_watts: limit,
+                    ..Notification::new(event, plug, at, message)
+                });
+            }
+        }
+        self.states.retain(|alias, _| plugs.iter().any(|plug| plug.alias == *alias));
+
+        notifications
+    }
+}
+
+
+/// Notify the changes of every poll until the poller goes away
+pub async fn run(mut notifier: Notifier, plugs: Arc<PlugRegistry>, mut readings: broadcast::Receiver<Readings>) {
+    let http = Client::new();
+    info!("Sending notifications on state changes of the plugs");
+
+    while let Some(readings) = poller::next_readings(&mut readings).await {
+        for notification in notifier.changes(&plugs.snapshot(), &readings, Utc::now()) {
+            let _ = send(&http, &notifier.target, &notification).await;
+        }
+    }
+}
+
+/// Not retried, the next change is notified anyway
+pub async fn send(http: &Client, target: &NotifyTarget, notification: &Notification) -> Result<(), &'static str> {
+    let (url, request) = match target {
+        NotifyTarget::Webhook(url) => (url, http.post(url).json(notification)),
+        NotifyTarget::Ntfy(url) => {
+            let tags = match notification.event {
+                "down" | "power_above_threshold" => "warning",
+                _ => "electric_plug",
+            };
+            let request = http.post(url)
+                .header("Title", format!("Shelly {}", notification.alias))
+                .header("Tags", tags)
+                .body(notification.message.clone());
+            (url, request)
+        }
+    };
+
+    match request.send().await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => {
+            warn!("Notifying {url} of `{}` failed with status {}", notification.alias, response.status());
+            Err("Failed to send notification!")
+        }
+        Err(err) => {
+            warn!("Notifying {url} of `{}` failed - {err}", notification.alias);
+            Err("Failed to send notification!")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use mockito::{Matcher, Server};
+    use serde_json::json;
+
+    use crate::config::ThresholdConfig;
+
+    fn reading(apower: f64, output: bool) -> SwitchStatus {
+        serde_json::from_value(json!({
+            "output": output,
+            "apower": apower,
+            "voltage": 230.0,
+            "current": 1.0,
+            "temperature": { "tC": 40.0, "tF": 104.0 },
+            "aenergy": { "total": 10.0 }
+        })).unwrap()
+    }
+
+    fn events(notifications: &[Notification]) -> Vec<(&str, &str)> {
+        notifications.iter().map(|notification| (notification.alias.as_str(), notification.event)).collect()
+    }
+
+    #[test]
+    fn test_changes() {
+        let thresholds = ThresholdTracker::new(HashMap::from([
+            ("kettle".to_string(), ThresholdConfig { max_power_watts: Some(2000.0), max_temperature_c: None }),
+        ])).map(Arc::new);
+        let mut notifier = Notifier::new(NotifyTarget::Webhook("http://localhost".to_string()), thresholds);
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let lamp = ShellySmartPlug { alias: "lamp".to_string(), ..ShellySmartPlug::from_address("10.0.0.3").unwrap() };
+        let plugs = [kettle.clone(), lamp.clone()];
+        let at = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+
+        // The first poll is the baseline, even for plugs which are down
+        assert!(notifier.changes(&plugs, &[(kettle.clone(), reading(0.0, false))], at).is_empty());
+
+        let actual = notifier.changes(&plugs, &[(kettle.clone(), reading(2200.0, true)), (lamp.clone(), reading(5.0, true))], at);
+        assert_eq!(events(&actual), vec![("kettle", "relay_on"), ("kettle", "power_above_threshold"), ("lamp", "up")]);
+        assert_eq!(actual[1].power_watts, Some(2200.0));
+        assert_eq!(actual[1].limit_watts, Some(2000.0));
+
+        let actual = notifier.changes(&plugs, &[(lamp.clone(), reading(5.0, true))], at);
+        assert_eq!(events(&actual), vec![("kettle", "down")]);
+        assert_eq!(actual[0].message, "`kettle` is down");
+
+        let actual = notifier.changes(&plugs, &[(kettle, reading(1500.0, true)), (lamp, reading(5.0, true))], at);
+        assert_eq!(events(&actual), vec![("kettle", "up"), ("kettle", "power_within_threshold")]);
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let mut server = Server::new_async().await;
+        let plug = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let at = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+        let notification = Notification::new("down", &plug, at, "`kettle` is down".to_string());
+        let webhook = server.mock("POST", "/hook")
+            .match_body(Matcher::Json(json!({
+                "event": "down",
+                "alias": "kettle",
+                "address": "10.0.0.2",
+                "labels": {},
+                "at": "2025-01-01T12:00:00+00:00",
+                "message": "`kettle` is down",
+            })))
+            .with_status(200)
+            .create_async()
+            .await;
+        let ntfy = server.mock("POST", "/my-plugs")
+            .match_header("Title", "Shelly kettle")
+            .match_header("Tags", "warning")
+            .match_body("`kettle` is down")
+            .with_status(200)
+            .create_async()
+            .await;
+        let http = Client::new();
+
+        let target = NotifyTarget::Webhook(format!("{}/hook", server.url()));
+        assert_eq!(send(&http, &target, &notification).await, Ok(()));
+        let target = NotifyTarget::Ntfy(format!("{}/my-plugs", server.url()));
+        assert_eq!(send(&http, &target, &notification).await, Ok(()));
+        let target = NotifyTarget::Ntfy(format!("{}/unknown", server.url()));
+        assert_eq!(send(&http, &target, &notification).await, Err("Failed to send notification!"));
+
+        webhook.assert_async().await;
+        ntfy.assert_async().await;
+    }
+}
@@ -0,0 +1,195 @@
+//! OpenTelemetry export, every background poll's samples are sent to an OTLP/HTTP receiver such as
+//! the OpenTelemetry Collector. Gauges become OTLP gauges and counters cumulative monotonic sums.
+//!
+//! Ref: https://opentelemetry.io/docs/specs/otlp/#otlphttp
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+use crate::metrics::{MetricFamily, MetricType};
+use crate::poller::{self, Readings};
+use crate::server::AppState;
+
+
+pub const DEFAULT_SERVICE_NAME: &str = "shelly_smartplug_exporter";
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE`
+const CUMULATIVE: u8 = 2;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtlpConfig {
+    /// Base URL of the receiver, e.g. `http://otel-collector:4318`
+    pub endpoint: String,
+    /// `service.name` resource attribute
+    pub service_name: String,
+}
+
+impl OtlpConfig {
+    pub fn new(endpoint: &str, service_name: &str) -> OtlpConfig {
+        OtlpConfig { endpoint: endpoint.trim_end_matches('/').to_string(), service_name: service_name.to_string() }
+    }
+
+    fn metrics_url(&self) -> String {
+        format!("{}/v1/metrics", self.endpoint)
+    }
+}
+
+
+/// Build an `ExportMetricsServiceRequest` in the JSON encoding. Counters count from `start`, the
+/// time the exporter started.
+pub fn encode(
+    families: &[MetricFamily],
+    service_name: &str,
+    start: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Value {
+    let start_nanos = start.timestamp_nanos_opt().unwrap_or_default().to_string();
+    let now_nanos = now.timestamp_nanos_opt().unwrap_or_default().to_string();
+
+    let metrics: Vec<Value> = families.iter()
+        .filter(|family| !family.samples.is_empty())
+        // The flat `_bucket` samples of histograms don't map onto OTLP data points, they're left out
+        .filter(|family| family.kind != MetricType::Histogram)
+        .map(|family| {
+            let data_points: Vec<Value> = family.samples.iter()
+                .map(|sample| {
+                    let attributes: Vec<Value> = sample.labels.iter()
+                        .map(|(name, value)| json!({ "key": name, "value": { "stringValue": value } }))
+                        .collect();
+                    match family.kind {
+                        MetricType::Gauge | MetricType::Histogram => json!({
+                            "attributes": attributes,
+                            "timeUnixNano": now_nanos,
+                            "asDouble": sample.value,
+                        }),
+                        MetricType::Counter => json!({
+                            "attributes": attributes,
+                            "startTimeUnixNano": start_nanos,
+                            "timeUnixNano": now_nanos,
+                            "asDouble": sample.value,
+                        }),
+                    }
+                })
+                .collect();
+
+            match family.kind {
+                MetricType::Gauge | MetricType::Histogram => json!({
+                    "name": family.name,
+                    "description": family.help,
+                    "gauge": { "dataPoints": data_points },
+                }),
+                MetricType::Counter => json!({
+                    "name": family.name,
+                    "description": family.help,
+                    "sum": { "aggregationTemporality": CUMULATIVE, "isMonotonic": true, "dataPoints": data_points },
+                }),
+            }
+        })
+        .collect();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+
+/// Export every poll's readings until the poller goes away. Failed exports aren't retried, the
+/// next poll has fresher data anyway.
+pub async fn run(state: AppState, config: OtlpConfig, mut readings: broadcast::Receiver<Readings>) {
+    let http = Client::new();
+    let start = Utc::now();
+    info!("Exporting metrics over OTLP to {} after every poll", config.metrics_url());
+
+    while let Some(readings) = poller::next_readings(&mut readings).await {
+        let body = encode(&state.collect_polled(&readings), &config.service_name, start, Utc::now());
+        let _ = export(&http, &config, &body).await;
+    }
+}
+
+pub async fn export(http: &Client, config: &OtlpConfig, body: &Value) -> Result<(), &'static str> {
+    let url = config.metrics_url();
+    match http.post(&url).json(body).send().await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => {
+            warn!("OTLP export to {url} failed with status {}", response.status());
+            Err("Failed to export metrics!")
+        }
+        Err(err) => {
+            warn!("OTLP export to {url} failed - {err}");
+            Err("Failed to export metrics!")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use mockito::{Matcher, Server};
+
+    fn families() -> Vec<MetricFamily> {
+        let mut power = MetricFamily::gauge("shelly_power_watts", "Instantaneous active power in watts");
+        power.push(vec![("hostname".to_string(), "kettle".to_string())], 114.2);
+        let mut energy = MetricFamily::counter("shelly_energy_wh", "Energy");
+        energy.push(vec![("hostname".to_string(), "kettle".to_string())], 12.5);
+        let empty = MetricFamily::gauge("shelly_unused", "Nothing");
+
+        vec![power, energy, empty]
+    }
+
+    #[test]
+    fn test_encode() {
+        let start = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+        let now = Utc.with_ymd_and_hms(2025, 1, 1, 12, 1, 0).unwrap();
+
+        let actual = encode(&families(), "shelly", start, now);
+
+        let scope = &actual["resourceMetrics"][0]["scopeMetrics"][0];
+        assert_eq!(actual["resourceMetrics"][0]["resource"]["attributes"][0]["value"]["stringValue"], "shelly");
+        assert_eq!(scope["metrics"].as_array().unwrap().len(), 2);
+        assert_eq!(scope["metrics"][0], json!({
+            "name": "shelly_power_watts",
+            "description": "Instantaneous active power in watts",
+            "gauge": { "dataPoints": [{
+                "attributes": [{ "key": "hostname", "value": { "stringValue": "kettle" } }],
+                "timeUnixNano": "1735732860000000000",
+                "asDouble": 114.2,
+            }]},
+        }));
+        assert_eq!(scope["metrics"][1]["sum"]["aggregationTemporality"], 2);
+        assert_eq!(scope["metrics"][1]["sum"]["isMonotonic"], true);
+        assert_eq!(scope["metrics"][1]["sum"]["dataPoints"][0]["startTimeUnixNano"], "1735732800000000000");
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let mut server = Server::new_async().await;
+        let mock = server.mock("POST", "/v1/metrics")
+            .match_header("content-type", "application/json")
+            .match_body(Matcher::Regex(r#""name":"shelly_power_watts""#.to_string()))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let config = OtlpConfig::new(&format!("{}/", server.url()), DEFAULT_SERVICE_NAME);
+        let body = encode(&families(), DEFAULT_SERVICE_NAME, Utc::now(), Utc::now());
+
+        assert_eq!(export(&Client::new(), &config, &body).await, Ok(()));
+        assert_eq!(
+            export(&Client::new(), &OtlpConfig::new("http://127.0.0.1:1", "shelly"), &body).await,
+            Err("Failed to export metrics!")
+        );
+        mock.assert_async().await;
+    }
+}
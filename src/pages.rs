@@ -0,0 +1,251 @@
+//! Pages for humans: a landing page at `/` and the state of every device on `/plugs`, to see at a
+//! glance why the metrics of a plug disappeared.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::cache::CachedStatus;
+use crate::capabilities::Capabilities;
+use crate::client::ShellySmartPlug;
+use crate::health::PlugHealth;
+
+
+/// Values of the latest reading of a plug
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LastReading {
+    /// RFC 3339
+    pub at: String,
+    pub output: Option<bool>,
+    pub power_watts: f64,
+    pub voltage: f64,
+    pub current_amps: f64,
+    pub temperature_celsius: f64,
+    pub energy_total_wh: f64,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceStatus {
+    /// `[https://]host[:port][/base/path]`, like `--ip-addr`
+    pub address: String,
+    pub alias: String,
+    pub labels: BTreeMap<String, String>,
+    /// `plug` or `meter`
+    pub kind: &'static str,
+    /// `None` until the device was first contacted
+    pub up: Option<bool>,
+    /// RFC 3339
+    pub last_success: Option<String>,
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    /// Only kept for plugs
+    pub last_reading: Option<LastReading>,
+    /// `None` unless detection is enabled and the device answered
+    pub capabilities: Option<Capabilities>,
+}
+
+impl DeviceStatus {
+    pub fn new(
+        device: &ShellySmartPlug,
+        kind: &'static str,
+        health: Option<PlugHealth>,
+        cached: Option<CachedStatus>,
+        capabilities: Option<Capabilities>,
+    ) -> DeviceStatus {
+        let health = health.as_ref();
+        let last_reading = cached.and_then(|cached| {
+            let status = cached.status?;
+            Some(LastReading {
+                at: cached.updated_at.to_rfc3339(),
+                output: status.output,
+                power_watts: status.apower,
+                voltage: status.voltage,
+                current_amps: status.current,
+                temperature_celsius: status.temperature.celsius,
+                energy_total_wh: status.aenergy.total,
+            })
+        });
+
+        DeviceStatus {
+            address: device.address(),
+            alias: device.alias.clone(),
+            labels: device.labels.iter().cloned().collect(),
+            kind,
+            up: health.map(|health| health.up),
+            last_success: health.and_then(|health| health.last_success).as_ref().map(DateTime::<Utc>::to_rfc3339),
+            consecutive_failures: health.map(|health| health.consecutive_failures).unwrap_or_default(),
+            last_error: health.and_then(|health| health.last_error.clone()),
+            last_reading,
+            capabilities,
+        }
+    }
+}
+
+
+pub fn landing_page() -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <head><title>Shelly Smart Plug Exporter</title></head>\n\
+        <body>\n\
+        <h1>Shelly Smart Plug Exporter</h1>\n\
+        <p>Version {}</p>\n\
+        <ul>\n\
+        <li><a href=\"/metrics\">Metrics</a></li>\n\
+        <li><a href=\"/plugs\">Plugs</a></li>\n\
+        <li><a href=\"/sd\">Service discovery</a></li>\n\
+        </ul>\n\
+        </body>\n\
+        </html>\n",
+        env!("CARGO_PKG_VERSION"),
+    )
+}
+
+
+/// `/plugs` as an HTML table
+pub fn devices_page(devices: &[DeviceStatus]) -> String {
+    let mut rows = String::new();
+    for device in devices {
+        let state = match device.up {
+            Some(true) => "up",
+            Some(false) => "down",
+            None => "not polled yet",
+        };
+        let reading = match &device.last_reading {
+            Some(reading) => format!(
+                "{} W, {} V, {} A, {} °C, {} Wh at {}",
+                reading.power_watts,
+                reading.voltage,
+                reading.current_amps,
+                reading.temperature_celsius,
+                reading.energy_total_wh,
+                reading.at,
+            ),
+            None => String::new(),
+        };
+        let capabilities = match &device.capabilities {
+            Some(capabilities) => {
+                let measured: Vec<&str> = [
+                    (true, "power"),
+                    (capabilities.voltage, "voltage"),
+                    (capabilities.current, "current"),
+                    (capabilities.frequency, "frequency"),
+                    (capabilities.power_factor, "power factor"),
+                    (true, "energy"),
+                ].into_iter().filter(|(supported, _)| *supported).map(|(_, name)| name).collect();
+                let firmware = capabilities.firmware.as_deref().map(|firmware| format!(" {firmware}")).unwrap_or_default();
+                format!("Gen{}{firmware}: {}", capabilities.generation, measured.join(", "))
+            }
+            None => String::new(),
+        };
+        let labels: Vec<String> = device.labels.iter().map(|(name, value)| format!("{name}={value}")).collect();
+
+        let _ = writeln!(
+            rows,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{state}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&device.alias),
+            escape(&device.address),
+            device.kind,
+            escape(&labels.join(", ")),
+            device.last_success.as_deref().unwrap_or(""),
+            device.consecutive_failures,
+            escape(device.last_error.as_deref().unwrap_or("")),
+            escape(&reading),
+            escape(&capabilities),
+        );
+    }
+
+    format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <head><title>Plugs - Shelly Smart Plug Exporter</title></head>\n\
+        <body>\n\
+        <h1>Plugs</h1>\n\
+        <table border=\"1\">\n\
+        <tr><th>Alias</th><th>Address</th><th>Kind</th><th>Labels</th><th>State</th><th>Last success</th>\
+        <th>Consecutive failures</th><th>Last error</th><th>Last reading</th><th>Capabilities</th></tr>\n\
+        {rows}\
+        </table>\n\
+        </body>\n\
+        </html>\n",
+    )
+}
+
+
+fn escape(raw: &str) -> String {
+    raw.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    #[test]
+    fn test_device_status() {
+        let plug = ShellySmartPlug {
+            labels: vec![("room".to_string(), "den".to_string())],
+            ..ShellySmartPlug::from_address("10.0.0.2").unwrap()
+        };
+        let at = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+        let health = PlugHealth {
+            up: false,
+            last_success: Some(at),
+            consecutive_failures: 3,
+            last_error: Some("Failed to connect to API!".to_string()),
+            errors: Default::default(),
+        };
+
+        let capabilities = Capabilities {
+            generation: 2,
+            model: Some("SNPL-00112EU".to_string()),
+            firmware: Some("0.14.1".to_string()),
+            voltage: true,
+            current: true,
+            frequency: false,
+            power_factor: false,
+        };
+
+        let actual = DeviceStatus::new(&plug, "plug", Some(health), None, Some(capabilities));
+
+        assert_eq!(serde_json::to_value(&actual).unwrap(), json!({
+            "address": "10.0.0.2",
+            "alias": "10.0.0.2",
+            "labels": { "room": "den" },
+            "kind": "plug",
+            "up": false,
+            "last_success": "2025-01-01T12:00:00+00:00",
+            "consecutive_failures": 3,
+            "last_error": "Failed to connect to API!",
+            "last_reading": null,
+            "capabilities": {
+                "generation": 2,
+                "model": "SNPL-00112EU",
+                "firmware": "0.14.1",
+                "voltage": true,
+                "current": true,
+                "frequency": false,
+                "power_factor": false,
+            },
+        }));
+        assert_eq!(DeviceStatus::new(&plug, "plug", None, None, None).up, None);
+        assert!(devices_page(&[actual]).contains("<td>Gen2 0.14.1: power, voltage, current, energy</td>"));
+    }
+
+    #[test]
+    fn test_devices_page_escapes() {
+        let plug = ShellySmartPlug { alias: "<script>".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+
+        let actual = devices_page(&[DeviceStatus::new(&plug, "plug", None, None, None)]);
+
+        assert!(actual.contains("<td>&lt;script&gt;</td><td>10.0.0.2</td><td>plug</td>"));
+        assert!(actual.contains("<td>not polled yet</td>"));
+    }
+}
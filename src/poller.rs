@@ -0,0 +1,228 @@
+//! Background polling shared by the push style outputs (Pushgateway, MQTT) and the cache. Every
+//! round's readings are broadcast to all subscribers so the devices are only queried once per
+//! interval.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use futures_util::future::join_all;
+use log::error;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use crate::cache::ReadingCache;
+use crate::client::{ShellyClient, ShellySmartPlug};
+use crate::registry::PlugRegistry;
+use crate::scheduler::{Schedule, Scheduler};
+use crate::status::SwitchStatus;
+
+
+pub type Readings = Arc<Vec<(ShellySmartPlug, SwitchStatus)>>;
+
+
+#[derive(Clone)]
+pub struct Poller {
+    sender: broadcast::Sender<Readings>,
+}
+
+impl Poller {
+    pub fn new() -> Poller {
+        // Subscribers only care about the latest readings, a lagging one just skips ahead
+        let (sender, _) = broadcast::channel(4);
+        Poller { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Readings> {
+        self.sender.subscribe()
+    }
+
+    /// Poll every plug on its own interval from the schedule until `shutdown` resolves. Plugs are
+    /// polled individually so one unreachable plug doesn't hold back the readings of the others,
+    /// and looked up again on every round so plugs registered at runtime are picked up. Fresh
+    /// readings go into the cache, and every round broadcasts the latest reading of each plug which
+    /// answered its last poll. A poll in progress is always finished, dropping the poller
+    /// afterwards closes the subscribers.
+    pub async fn run(
+        self,
+        client: ShellyClient,
+        plugs: Arc<PlugRegistry>,
+        cache: Arc<ReadingCache>,
+        schedule: Schedule,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut scheduler = Scheduler::new(schedule);
+        let mut latest: HashMap<String, SwitchStatus> = HashMap::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(scheduler.next_due(&plugs.snapshot())) => {}
+                _ = &mut shutdown => break,
+            }
+            let current = plugs.snapshot();
+            let due = scheduler.take_due(&current, Instant::now());
+            let results = join_all(due.iter().map(|plug| client.get_status(plug))).await;
+
+            for (plug, result) in due.iter().zip(results) {
+                match result {
+                    Ok(status) => {
+                        cache.update(&plug.alias, &status);
+                        latest.insert(plug.alias.clone(), status);
+                    }
+                    Err(e) => {
+                        error!("Failed to poll `{}` - {e}", plug.alias);
+                        latest.remove(&plug.alias);
+                    }
+                }
+            }
+            latest.retain(|alias, _| current.iter().any(|plug| plug.alias == *alias));
+
+            let readings = current.into_iter()
+                .filter_map(|plug| latest.get(&plug.alias).cloned().map(|status| (plug, status)))
+                .collect();
+            // Only fails when nobody is subscribed, nothing to do about that
+            let _ = self.sender.send(Arc::new(readings));
+        }
+    }
+}
+
+impl Default for Poller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Poll every plug concurrently, failed plugs are logged and left out
+pub async fn poll_once(client: &ShellyClient, plugs: &[ShellySmartPlug]) -> Vec<(ShellySmartPlug, SwitchStatus)> {
+    let results = join_all(plugs.iter().map(|plug| client.get_status(plug))).await;
+
+    plugs.iter()
+        .zip(results)
+        .filter_map(|(plug, result)| match result {
+            Ok(status) => Some((plug.clone(), status)),
+            Err(e) => {
+                error!("Failed to poll `{}` - {e}", plug.alias);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Wait for the next readings, skipping over any that were missed while busy.
+/// Returns `None` once the poller is gone.
+pub async fn next_readings(receiver: &mut broadcast::Receiver<Readings>) -> Option<Readings> {
+    loop {
+        match receiver.recv().await {
+            Ok(readings) => return Some(readings),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use mockito::Server;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_poll_skips_failed_plugs() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/good")
+            .with_status(200)
+            .with_body(json!({
+                "apower": 1.0,
+                "voltage": 2.0,
+                "current": 3.0,
+                "temperature": { "tC": 20.1, "tF": 68.2 },
+                "aenergy": { "total": 10.0 }
+            }).to_string())
+            .create_async()
+            .await;
+        server.mock("GET", "/bad")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let plugs = vec![
+            ShellySmartPlug { url: format!("{}/bad", server.url()), alias: "bad".to_string(), labels: vec![] },
+            ShellySmartPlug { url: format!("{}/good", server.url()), alias: "good".to_string(), labels: vec![] },
+        ];
+
+        let poller = Poller::new();
+        let mut receiver = poller.subscribe();
+        tokio::spawn(poller.run(
+            ShellyClient::new(),
+            Arc::new(PlugRegistry::new(plugs)),
+            Arc::default(),
+            Schedule::new(Duration::from_secs(60)),
+            std::future::pending(),
+        ));
+
+        let readings = next_readings(&mut receiver).await.unwrap();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].0.alias, "good");
+    }
+
+    #[tokio::test]
+    async fn test_per_plug_intervals() {
+        let mut server = Server::new_async().await;
+        let body = json!({
+            "apower": 1.0,
+            "voltage": 2.0,
+            "current": 3.0,
+            "temperature": { "tC": 20.1, "tF": 68.2 },
+            "aenergy": { "total": 10.0 }
+        }).to_string();
+        let heater_mock = server.mock("GET", "/heater").with_status(200).with_body(&body).expect_at_least(3).create_async().await;
+        let lamp_mock = server.mock("GET", "/lamp").with_status(200).with_body(&body).expect(1).create_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: format!("{}/heater", server.url()), alias: "heater".to_string(), labels: vec![] },
+            ShellySmartPlug { url: format!("{}/lamp", server.url()), alias: "lamp".to_string(), labels: vec![] },
+        ];
+        let schedule = Schedule::new(Duration::from_secs(60))
+            .with_intervals(HashMap::from([("heater".to_string(), Duration::from_millis(50))]));
+        let cache = Arc::new(ReadingCache::new());
+
+        let poller = Poller::new();
+        let mut receiver = poller.subscribe();
+        tokio::spawn(poller.run(
+            ShellyClient::new(),
+            Arc::new(PlugRegistry::new(plugs)),
+            cache.clone(),
+            schedule,
+            std::future::pending(),
+        ));
+
+        for _ in 0..3 {
+            // The lamp's reading from the first round is kept until its next poll
+            assert_eq!(next_readings(&mut receiver).await.unwrap().len(), 2);
+        }
+        assert!(cache.get("heater").is_some());
+        heater_mock.assert_async().await;
+        lamp_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_subscribers() {
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let poller = Poller::new();
+        let mut receiver = poller.subscribe();
+        let task = tokio::spawn(poller.run(
+            ShellyClient::new(),
+            Arc::default(),
+            Arc::default(),
+            Schedule::new(Duration::from_secs(60)),
+            async { let _ = stopped.await; },
+        ));
+
+        assert!(next_readings(&mut receiver).await.unwrap().is_empty());
+        stop.send(()).unwrap();
+
+        assert!(next_readings(&mut receiver).await.is_none());
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+//! Push mode, for setups where Prometheus can't reach the exporter. Every background poll's
+//! samples are pushed to a Prometheus Pushgateway.
+//!
+//! Ref: https://github.com/prometheus/pushgateway#api
+
+use std::time::Duration;
+use log::{error, info, warn};
+use reqwest::Client;
+use tokio::sync::broadcast;
+
+use crate::metrics::{self, Format};
+use crate::poller::{self, Readings};
+use crate::server::AppState;
+
+
+pub const DEFAULT_JOB: &str = "shelly_smartplug_exporter";
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PushConfig {
+    /// Base URL of the Pushgateway, e.g. `http://pushgateway:9091`
+    pub gateway_url: String,
+    pub job: String,
+    /// Poll interval, retries never back off for longer than this
+    pub interval: Duration,
+    /// Attempts per push, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub initial_backoff: Duration,
+}
+
+impl PushConfig {
+    pub fn new(gateway_url: &str, job: &str, interval: Duration) -> PushConfig {
+        PushConfig {
+            gateway_url: gateway_url.trim_end_matches('/').to_string(),
+            job: job.to_string(),
+            interval,
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+        }
+    }
+
+    fn push_url(&self) -> String {
+        format!("{}/metrics/job/{}", self.gateway_url, self.job)
+    }
+}
+
+
+/// Push every poll's readings until the poller goes away
+pub async fn run(state: AppState, config: PushConfig, mut readings: broadcast::Receiver<Readings>) {
+    let http = Client::new();
+    info!("Pushing metrics to {} after every poll", config.push_url());
+
+    while let Some(readings) = poller::next_readings(&mut readings).await {
+        let body = metrics::encode(&state.collect_polled(&readings), Format::Prometheus);
+        let _ = push_with_retry(&http, &config, body).await;
+    }
+}
+
+/// PUT replaces every metric of the job group, so plugs which disappeared don't linger
+pub async fn push_with_retry(http: &Client, config: &PushConfig, body: String) -> Result<(), &'static str> {
+    let url = config.push_url();
+    let mut backoff = config.initial_backoff;
+
+    for attempt in 1..=config.max_attempts {
+        let result = http.put(&url)
+            .header(reqwest::header::CONTENT_TYPE, Format::Prometheus.content_type())
+            .body(body.clone())
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => warn!("Push attempt {attempt}/{} to {url} failed with status {}",
+                config.max_attempts, response.status()),
+            Err(err) => warn!("Push attempt {attempt}/{} to {url} failed - {err}", config.max_attempts),
+        }
+
+        if attempt < config.max_attempts {
+            tokio::time::sleep(backoff).await;
+            // Never back off longer than the poll interval, the next poll has fresher data anyway
+            backoff = (backoff * 2).min(config.interval);
+        }
+    }
+
+    error!("Giving up on pushing metrics to {url} after {} attempts", config.max_attempts);
+    Err("Failed to push metrics!")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+
+    fn config(url: &str) -> PushConfig {
+        PushConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_attempts: 3,
+            ..PushConfig::new(url, "shelly", Duration::from_secs(60))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let mut server = Server::new_async().await;
+        let mock = server.mock("PUT", "/metrics/job/shelly")
+            .match_body("power_watts 1.0\n")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let actual = push_with_retry(&Client::new(), &config(&format!("{}/", server.url())), "power_watts 1.0\n".to_string()).await;
+
+        assert_eq!(actual, Ok(()));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_push_retries_then_gives_up() {
+        let mut server = Server::new_async().await;
+        let mock = server.mock("PUT", "/metrics/job/shelly")
+            .with_status(503)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let actual = push_with_retry(&Client::new(), &config(&server.url()), "".to_string()).await;
+
+        assert_eq!(actual, Err("Failed to push metrics!"));
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,157 @@
+//! The plugs the exporter polls, shared between the endpoints and the background poller so plugs
+//! can be added and removed at runtime through the admin API.
+
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use log::info;
+
+use crate::client::ShellySmartPlug;
+use crate::config::{self, PlugConfig};
+use crate::metrics::is_valid_label_name;
+
+
+/// Labels every plug metric already carries
+pub const RESERVED_LABELS: [&str; 12] = [
+    "hostname", "channel", "currency", "phase", "input", "threshold", "reason", "script", "script_name", "schedule",
+    "sensor", "window",
+];
+
+
+#[derive(Debug, Default)]
+pub struct PlugRegistry {
+    plugs: RwLock<Vec<ShellySmartPlug>>,
+    /// Config file the plugs are written back to after every change, if any
+    config_file: Option<PathBuf>,
+}
+
+impl PlugRegistry {
+    pub fn new(plugs: Vec<ShellySmartPlug>) -> PlugRegistry {
+        PlugRegistry { plugs: RwLock::new(plugs), config_file: None }
+    }
+
+    /// Persist every change to the `[[plugs]]` of the config file at `path`
+    pub fn with_config_file(self, path: &Path) -> PlugRegistry {
+        PlugRegistry { config_file: Some(path.to_path_buf()), ..self }
+    }
+
+    /// Current plugs, in registration order
+    pub fn snapshot(&self) -> Vec<ShellySmartPlug> {
+        self.plugs.read().unwrap().clone()
+    }
+
+    pub fn get(&self, alias: &str) -> Option<ShellySmartPlug> {
+        self.plugs.read().unwrap().iter().find(|plug| plug.alias == alias).cloned()
+    }
+
+    pub fn contains(&self, alias: &str) -> bool {
+        self.plugs.read().unwrap().iter().any(|plug| plug.alias == alias)
+    }
+
+    /// Register a new plug, the config file is written before the plug gets polled
+    pub fn add(&self, plug: ShellySmartPlug) -> Result<(), &'static str> {
+        for (name, _) in &plug.labels {
+            if !is_valid_label_name(name) || RESERVED_LABELS.contains(&name.as_str()) {
+                return Err("Invalid plug label!");
+            }
+        }
+
+        let mut plugs = self.plugs.write().unwrap();
+        if plugs.iter().any(|existing| existing.alias == plug.alias || existing.url == plug.url) {
+            return Err("Plug is already registered!");
+        }
+
+        let mut updated = plugs.clone();
+        updated.push(plug.clone());
+        self.persist(&updated)?;
+
+        info!("Registered plug `{}` at {}", plug.alias, plug.address());
+        *plugs = updated;
+        Ok(())
+    }
+
+    /// Remove the plug with the given alias, `None` if there is none
+    pub fn remove(&self, alias: &str) -> Result<Option<ShellySmartPlug>, &'static str> {
+        let mut plugs = self.plugs.write().unwrap();
+        let position = match plugs.iter().position(|plug| plug.alias == alias) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+
+        let mut updated = plugs.clone();
+        let removed = updated.remove(position);
+        self.persist(&updated)?;
+
+        info!("Removed plug `{}` at {}", removed.alias, removed.address());
+        *plugs = updated;
+        Ok(Some(removed))
+    }
+
+    fn persist(&self, plugs: &[ShellySmartPlug]) -> Result<(), &'static str> {
+        let path = match &self.config_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let entries: Vec<PlugConfig> = plugs.iter()
+            .map(|plug| PlugConfig {
+                address: plug.address(),
+                alias: Some(plug.alias.clone()).filter(|alias| *alias != plug.default_alias()),
+                labels: plug.labels.iter().cloned().collect(),
+            })
+            .collect();
+        config::save_plugs(path, &entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plug(address: &str, alias: &str) -> ShellySmartPlug {
+        ShellySmartPlug { alias: alias.to_string(), ..ShellySmartPlug::from_address(address).unwrap() }
+    }
+
+    #[test]
+    fn test_add_and_remove() {
+        let registry = PlugRegistry::new(vec![plug("10.0.0.2", "kettle")]);
+
+        registry.add(plug("10.0.0.3", "tv")).unwrap();
+        assert_eq!(registry.add(plug("10.0.0.4", "tv")), Err("Plug is already registered!"));
+        assert_eq!(registry.add(plug("10.0.0.2", "other")), Err("Plug is already registered!"));
+        assert_eq!(
+            registry.add(ShellySmartPlug { labels: vec![("hostname".to_string(), "x".to_string())], ..plug("10.0.0.5", "x") }),
+            Err("Invalid plug label!")
+        );
+        assert_eq!(registry.snapshot().iter().map(|plug| plug.alias.as_str()).collect::<Vec<_>>(), vec!["kettle", "tv"]);
+
+        assert_eq!(registry.remove("kettle").unwrap().map(|plug| plug.alias), Some("kettle".to_string()));
+        assert_eq!(registry.remove("kettle").unwrap(), None);
+        assert!(!registry.contains("kettle"));
+        assert!(registry.get("tv").is_some());
+    }
+
+    #[test]
+    fn test_persist_to_config_file() {
+        let path = std::env::temp_dir().join(format!("shelly_registry_{}.toml", std::process::id()));
+        std::fs::write(&path, "[tariff]\nprice_per_kwh = 0.25\n").unwrap();
+        let registry = PlugRegistry::new(vec![]).with_config_file(&path);
+
+        registry.add(ShellySmartPlug { labels: vec![("room".to_string(), "den".to_string())], ..plug("10.0.0.2", "kettle") }).unwrap();
+        registry.add(ShellySmartPlug::from_address("https://plug.lan").unwrap()).unwrap();
+
+        let actual = config::load(&path).unwrap();
+        assert_eq!(actual.tariff.unwrap().price_per_kwh, Some(0.25));
+        assert_eq!(actual.plugs, vec![
+            PlugConfig {
+                address: "10.0.0.2".to_string(),
+                alias: Some("kettle".to_string()),
+                labels: [("room".to_string(), "den".to_string())].into(),
+            },
+            PlugConfig { address: "https://plug.lan".to_string(), alias: None, labels: Default::default() },
+        ]);
+
+        registry.remove("kettle").unwrap();
+        assert_eq!(config::load(&path).unwrap().plugs.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
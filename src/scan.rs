@@ -0,0 +1,221 @@
+//! Discovery of Shelly devices by probing every address of IPv4 ranges with `Shelly.GetDeviceInfo`,
+//! for networks where mDNS doesn't reach the exporter, e.g. across VLANs.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use futures_util::{stream, StreamExt};
+use log::{debug, error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::energy::write_atomic;
+use crate::status::DeviceInfo;
+
+
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_CONCURRENT_PROBES: usize = 64;
+/// Anything wider than a /16 is most likely a typo
+const MIN_PREFIX_LEN: u8 = 16;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    /// Addresses to probe, without the network and broadcast addresses of ranges which have them
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let first = u32::from(self.network);
+        let last = first | u32::MAX.checked_shr(self.prefix_len.into()).unwrap_or(0);
+        let (first, last) = match self.prefix_len {
+            31 | 32 => (first, last),
+            _ => (first + 1, last - 1),
+        };
+
+        (first..=last).map(Ipv4Addr::from)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = &'static str;
+
+    fn from_str(raw: &str) -> Result<Ipv4Cidr, &'static str> {
+        let (address, prefix_len) = raw.split_once('/').ok_or("Invalid CIDR range!")?;
+        let address: Ipv4Addr = address.parse().map_err(|_| "Invalid CIDR range!")?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| "Invalid CIDR range!")?;
+        if prefix_len > 32 {
+            return Err("Invalid CIDR range!");
+        }
+        if prefix_len < MIN_PREFIX_LEN {
+            return Err("CIDR range too large, use a /16 or smaller!");
+        }
+
+        let mask = u32::MAX << (32 - prefix_len as u32);
+        Ok(Ipv4Cidr { network: Ipv4Addr::from(u32::from(address) & mask), prefix_len })
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+
+/// Result of an earlier scan, reused as long as the scanned ranges didn't change
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScanCache {
+    pub ranges: Vec<String>,
+    pub addresses: Vec<String>,
+}
+
+
+/// Ask `address` (`host[:port]`) for its device info, `None` if nothing Shelly-like answers
+pub async fn probe(http: &Client, address: &str) -> Option<DeviceInfo> {
+    let response = http.get(format!("http://{address}/rpc/Shelly.GetDeviceInfo")).send().await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+
+    match response.json::<DeviceInfo>().await {
+        Ok(info) => Some(info),
+        Err(err) => {
+            debug!("{address} answered, but isn't a Shelly device - {err}");
+            None
+        }
+    }
+}
+
+
+/// Probe every host of the ranges and return the addresses of the Shelly devices, sorted
+pub async fn scan(ranges: &[Ipv4Cidr], timeout: Duration) -> Result<Vec<Ipv4Addr>, &'static str> {
+    let http = Client::builder().timeout(timeout).build().map_err(|err| {
+        error!("Failed to build the scan HTTP client - {err}");
+        "Unable to scan for devices!"
+    })?;
+
+    let mut found: Vec<Ipv4Addr> = stream::iter(ranges.iter().flat_map(Ipv4Cidr::hosts))
+        .map(|address| {
+            let http = &http;
+            async move {
+                let info = probe(http, &address.to_string()).await?;
+                info!("Found `{}` at {address}", info.name.as_deref().unwrap_or(&info.id));
+                Some(address)
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_PROBES)
+        .filter_map(|address| async move { address })
+        .collect()
+        .await;
+
+    found.sort();
+    found.dedup();
+    Ok(found)
+}
+
+
+/// Like `scan`, but reuses the addresses stored in `cache_path` when it was written for the same
+/// ranges, and stores the result there otherwise
+pub async fn scan_cached(
+    ranges: &[Ipv4Cidr],
+    timeout: Duration,
+    cache_path: Option<&Path>,
+) -> Result<Vec<String>, &'static str> {
+    let range_names: Vec<String> = ranges.iter().map(Ipv4Cidr::to_string).collect();
+
+    if let Some(path) = cache_path {
+        match std::fs::read_to_string(path).map(|raw| serde_json::from_str::<ScanCache>(&raw)) {
+            Ok(Ok(cache)) if cache.ranges == range_names => {
+                info!("Using the {} devices found by the last scan in `{}`", cache.addresses.len(), path.display());
+                return Ok(cache.addresses);
+            }
+            Ok(Ok(_)) => info!("Scan ranges changed since `{}` was written, rescanning", path.display()),
+            Ok(Err(err)) => warn!("Ignoring invalid scan cache `{}` - {err}", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to read scan cache `{}` - {err}", path.display()),
+        }
+    }
+
+    info!("Scanning {} for Shelly devices", range_names.join(", "));
+    let addresses: Vec<String> = scan(ranges, timeout).await?.iter().map(Ipv4Addr::to_string).collect();
+    if addresses.is_empty() {
+        warn!("No Shelly devices answered in {}", range_names.join(", "));
+    }
+
+    if let Some(path) = cache_path {
+        let cache = ScanCache { ranges: range_names, addresses: addresses.clone() };
+        let raw = serde_json::to_vec_pretty(&cache).expect("The scan cache is always serializable");
+        if let Err(err) = write_atomic(path, &raw) {
+            // Not fatal, the next start just scans again
+            warn!("Failed to write scan cache `{}` - {err}", path.display());
+        }
+    }
+
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+
+    #[test]
+    fn test_parse_cidr() {
+        let cidr: Ipv4Cidr = "192.168.1.77/24".parse().unwrap();
+
+        assert_eq!(cidr.to_string(), "192.168.1.0/24");
+        assert_eq!("10.0.0.0/8".parse::<Ipv4Cidr>(), Err("CIDR range too large, use a /16 or smaller!"));
+        for invalid in ["192.168.1.0", "192.168.1.0/33", "192.168.1/24", "fe80::/64"] {
+            assert_eq!(invalid.parse::<Ipv4Cidr>(), Err("Invalid CIDR range!"), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_hosts() {
+        let hosts: Vec<Ipv4Addr> = "10.0.0.0/30".parse::<Ipv4Cidr>().unwrap().hosts().collect();
+        assert_eq!(hosts, vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]);
+
+        let single: Vec<Ipv4Addr> = "10.0.0.7/32".parse::<Ipv4Cidr>().unwrap().hosts().collect();
+        assert_eq!(single, vec![Ipv4Addr::new(10, 0, 0, 7)]);
+
+        assert_eq!("192.168.0.0/16".parse::<Ipv4Cidr>().unwrap().hosts().count(), 65534);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/rpc/Shelly.GetDeviceInfo")
+            .with_status(200)
+            .with_body(r#"{"id": "shellyplusplugs-80646fd17d72", "name": "Kettle", "gen": 2}"#)
+            .create_async()
+            .await;
+        let mut other = Server::new_async().await;
+        other.mock("GET", "/rpc/Shelly.GetDeviceInfo")
+            .with_status(200)
+            .with_body("<html>router login</html>")
+            .create_async()
+            .await;
+        let http = Client::new();
+
+        assert_eq!(probe(&http, &server.host_with_port()).await.unwrap().name, Some("Kettle".to_string()));
+        assert_eq!(probe(&http, &other.host_with_port()).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_scan_cached_reuses_cache() {
+        let path = std::env::temp_dir().join(format!("shelly_scan_{}.json", std::process::id()));
+        let ranges = vec!["10.255.255.0/30".parse::<Ipv4Cidr>().unwrap()];
+        let cache = ScanCache { ranges: vec!["10.255.255.0/30".to_string()], addresses: vec!["10.255.255.1".to_string()] };
+        std::fs::write(&path, serde_json::to_vec(&cache).unwrap()).unwrap();
+
+        let actual = scan_cached(&ranges, Duration::from_millis(100), Some(&path)).await.unwrap();
+
+        assert_eq!(actual, vec!["10.255.255.1"]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! When each plug is due for its next background poll. Plugs are polled on the default interval
+//! unless the config file gives them their own, so a heater can be polled every few seconds while
+//! a lamp is only polled every minute.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::client::ShellySmartPlug;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    default: Duration,
+    /// Keyed by IP or alias, like the config file
+    intervals: HashMap<String, Duration>,
+}
+
+impl Schedule {
+    pub fn new(default: Duration) -> Schedule {
+        Schedule { default, intervals: HashMap::new() }
+    }
+
+    pub fn with_intervals(self, intervals: HashMap<String, Duration>) -> Schedule {
+        Schedule { intervals, ..self }
+    }
+
+    /// Poll interval of the plug, an alias entry wins over the IP one
+    pub fn interval_of(&self, plug: &ShellySmartPlug) -> Duration {
+        self.intervals.get(&plug.alias)
+            .or_else(|| self.intervals.get(&plug.target()))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+
+/// Next poll of every plug. Plugs it hasn't seen yet, e.g. just registered through the admin API,
+/// are due right away.
+#[derive(Debug)]
+pub struct Scheduler {
+    schedule: Schedule,
+    due: HashMap<String, Instant>,
+    /// Next round when there are no plugs at all, so subscribers still hear from the poller
+    idle_due: Instant,
+}
+
+impl Scheduler {
+    pub fn new(schedule: Schedule) -> Scheduler {
+        Scheduler { schedule, due: HashMap::new(), idle_due: Instant::now() }
+    }
+
+    /// When the next of the plugs is due
+    pub fn next_due(&self, plugs: &[ShellySmartPlug]) -> Instant {
+        plugs.iter()
+            .map(|plug| self.due.get(&plug.alias).copied().unwrap_or_else(Instant::now))
+            .min()
+            .unwrap_or(self.idle_due)
+    }
+
+    /// The plugs due at `now`, each is scheduled for its next poll. Plugs no longer in `plugs` are
+    /// forgotten.
+    pub fn take_due(&mut self, plugs: &[ShellySmartPlug], now: Instant) -> Vec<ShellySmartPlug> {
+        self.due.retain(|alias, _| plugs.iter().any(|plug| plug.alias == *alias));
+        self.idle_due = now + self.schedule.default;
+
+        let due: Vec<ShellySmartPlug> = plugs.iter()
+            .filter(|plug| self.due.get(&plug.alias).is_none_or(|due| *due <= now))
+            .cloned()
+            .collect();
+        for plug in &due {
+            self.due.insert(plug.alias.clone(), now + self.schedule.interval_of(plug));
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_of() {
+        let schedule = Schedule::new(Duration::from_secs(60)).with_intervals(HashMap::from([
+            ("10.0.0.2".to_string(), Duration::from_secs(30)),
+            ("heater".to_string(), Duration::from_secs(5)),
+        ]));
+
+        let heater = ShellySmartPlug { alias: "heater".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        assert_eq!(schedule.interval_of(&heater), Duration::from_secs(5));
+        assert_eq!(schedule.interval_of(&ShellySmartPlug::from_address("10.0.0.2").unwrap()), Duration::from_secs(30));
+        assert_eq!(schedule.interval_of(&ShellySmartPlug::from_address("10.0.0.3").unwrap()), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_take_due() {
+        let schedule = Schedule::new(Duration::from_secs(60))
+            .with_intervals(HashMap::from([("heater".to_string(), Duration::from_secs(5))]));
+        let mut scheduler = Scheduler::new(schedule);
+        let heater = ShellySmartPlug { alias: "heater".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let lamp = ShellySmartPlug { alias: "lamp".to_string(), ..ShellySmartPlug::from_address("10.0.0.3").unwrap() };
+        let plugs = vec![heater, lamp];
+        let start = Instant::now();
+
+        assert_eq!(scheduler.take_due(&plugs, start).len(), 2);
+        assert_eq!(scheduler.next_due(&plugs), start + Duration::from_secs(5));
+
+        let due = scheduler.take_due(&plugs, start + Duration::from_secs(5));
+        assert_eq!(due.iter().map(|plug| plug.alias.as_str()).collect::<Vec<_>>(), vec!["heater"]);
+
+        let due = scheduler.take_due(&plugs, start + Duration::from_secs(60));
+        assert_eq!(due.len(), 2);
+        // Removed plugs are forgotten
+        scheduler.take_due(&plugs[..1], start + Duration::from_secs(65));
+        assert!(!scheduler.due.contains_key("lamp"));
+    }
+
+    #[test]
+    fn test_next_due_without_plugs() {
+        let mut scheduler = Scheduler::new(Schedule::new(Duration::from_secs(60)));
+        let start = Instant::now();
+
+        scheduler.take_due(&[], start);
+
+        assert_eq!(scheduler.next_due(&[]), start + Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,119 @@
+//! Short lived cache of scrape results, so scrapers asking within the TTL of each other (e.g. two
+//! Prometheus replicas) are served the same result instead of polling every device twice.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::Instant;
+
+use crate::error::ShellyError;
+use crate::metrics::{self, Format, MetricFamily};
+
+
+type Entry = Arc<AsyncMutex<Option<(Instant, CachedScrape)>>>;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedScrape {
+    pub families: Vec<MetricFamily>,
+    /// Hash of the families, taken once per scrape rather than on every request served from it
+    pub tag: u64,
+}
+
+impl CachedScrape {
+    fn new(families: Vec<MetricFamily>) -> CachedScrape {
+        let mut hasher = DefaultHasher::new();
+        metrics::encode(&families, Format::Prometheus).hash(&mut hasher);
+        CachedScrape { families, tag: hasher.finish() }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct ScrapeCache {
+    ttl: Duration,
+    /// Keyed by what was scraped, e.g. every device or a single `/probe` target
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicU64,
+}
+
+impl ScrapeCache {
+    pub fn new(ttl: Duration) -> ScrapeCache {
+        ScrapeCache { ttl, entries: Mutex::new(HashMap::new()), hits: AtomicU64::new(0) }
+    }
+
+    /// The result of the last scrape of `key` if it's younger than the TTL, otherwise the result of
+    /// `scrape`. Scrapes of the same key wait on the one in progress rather than polling again.
+    /// Failed scrapes aren't cached.
+    pub async fn get_or_scrape<F, Fut>(&self, key: &str, scrape: F) -> Result<CachedScrape, ShellyError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<MetricFamily>, ShellyError>>,
+    {
+        let entry = self.entries.lock().unwrap().entry(key.to_string()).or_default().clone();
+        let mut entry = entry.lock().await;
+
+        if let Some((scraped_at, scraped)) = entry.as_ref() {
+            if scraped_at.elapsed() < self.ttl {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(scraped.clone());
+            }
+        }
+
+        let scraped = CachedScrape::new(scrape().await?);
+        *entry = Some((Instant::now(), scraped.clone()));
+        Ok(scraped)
+    }
+
+    pub fn collect(&self) -> MetricFamily {
+        let mut family = MetricFamily::counter(
+            "shelly_exporter_cache_hits_total",
+            "Scrapes served from the scrape cache instead of polling the devices"
+        );
+        family.push(vec![], self.hits.load(Ordering::Relaxed) as f64);
+        family
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn test_get_or_scrape() {
+        let cache = ScrapeCache::new(Duration::from_secs(60));
+        let scrapes = AtomicUsize::new(0);
+        let scrape = || async {
+            scrapes.fetch_add(1, Ordering::Relaxed);
+            let mut family = MetricFamily::gauge("shelly_power_watts", "Power");
+            family.push(vec![], 5.0);
+            Ok(vec![family])
+        };
+
+        let (first, second) = tokio::join!(cache.get_or_scrape("all", scrape), cache.get_or_scrape("all", scrape));
+        assert_eq!(first.unwrap(), second.unwrap());
+        let kettle = cache.get_or_scrape("kettle", scrape).await.unwrap();
+        assert_eq!(kettle.tag, CachedScrape::new(kettle.families.clone()).tag);
+        assert_ne!(kettle.tag, CachedScrape::new(vec![]).tag);
+
+        assert_eq!(scrapes.load(Ordering::Relaxed), 2);
+        assert_eq!(cache.collect().samples[0].value, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_expired_and_failed_scrapes() {
+        let cache = ScrapeCache::new(Duration::ZERO);
+
+        let failed = cache.get_or_scrape("all", || async { Err(ShellyError::Timeout { plug: "kettle".to_string() }) }).await;
+        assert!(matches!(failed, Err(ShellyError::Timeout { .. })));
+        assert_eq!(cache.get_or_scrape("all", || async { Ok(vec![]) }).await.unwrap().families, vec![]);
+        cache.get_or_scrape("all", || async { Ok(vec![]) }).await.unwrap();
+
+        assert_eq!(cache.collect().samples[0].value, 0.0);
+    }
+}
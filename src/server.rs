@@ -0,0 +1,1887 @@
+//! HTTP endpoints of the exporter.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::http::header;
+use actix_web::{delete, get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Local, Utc};
+use futures_util::future::join_all;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::addons::{self, AddonSensors};
+use crate::cache::ReadingCache;
+use crate::capabilities::DeviceCapabilities;
+use crate::client::{ShellyClient, ShellySmartPlug};
+use crate::cost::CostTracker;
+use crate::derived::PowerStats;
+use crate::groups::Groups;
+use crate::scrape_cache::ScrapeCache;
+use crate::tenants::Tenants;
+use crate::thresholds::ThresholdTracker;
+use crate::energy::EnergyLedger;
+use crate::error::ShellyError;
+use crate::firmware::{self, FirmwareChecks};
+use crate::history::{History, HistoryPoint};
+use crate::registry::PlugRegistry;
+use crate::pages::{self, DeviceStatus};
+use crate::{exporter, formatter, grafana, influx, webhook};
+use crate::metrics::{self, Format, MetricFamily, MetricNaming};
+use crate::status::{AddonReading, Automations, AvailableUpdates, EmReading, InputStatus, SwitchStatus};
+
+
+/// Header Prometheus sends with the scrape timeout of the job
+pub const SCRAPE_TIMEOUT_HEADER: &str = "X-Prometheus-Scrape-Timeout-Seconds";
+/// Part of the scrape timeout kept for building and sending the response
+const SCRAPE_TIMEOUT_MARGIN: Duration = Duration::from_millis(500);
+
+
+#[derive(Clone)]
+pub struct AppState {
+    pub client: ShellyClient,
+    pub plugs: Arc<PlugRegistry>,
+    /// 3-phase energy meters, always polled live on `/metrics` and `/probe`
+    pub meters: Vec<ShellySmartPlug>,
+    pub cost: Option<Arc<CostTracker>>,
+    pub thresholds: Option<Arc<ThresholdTracker>>,
+    /// Averages, peak and last hour energy derived from the readings, if enabled
+    pub power_stats: Option<Arc<PowerStats>>,
+    /// Power and energy summed per group of plugs, if any are configured
+    pub groups: Option<Arc<Groups>>,
+    pub energy: Arc<EnergyLedger>,
+    pub cache: Arc<ReadingCache>,
+    /// Local history served on `/history`, if enabled
+    pub history: Option<Arc<History>>,
+    /// Serve the cached readings instead of polling the plugs on every scrape
+    pub serve_cached: bool,
+    /// Whether plugs can be added and removed through `/plugs`
+    pub admin_api: bool,
+    pub naming: MetricNaming,
+    /// Also read the physical input of every plug on `/metrics` and `/probe`
+    pub inputs: bool,
+    /// Also read the scripts and schedules of every plug on `/metrics` and `/probe`
+    pub automations: bool,
+    /// Plus Add-On sensors read on `/metrics` and `/probe`, if any are configured
+    pub addons: Option<Arc<AddonSensors>>,
+    /// Firmware updates checked on `/metrics` and `/probe`, if enabled
+    pub firmware: Option<Arc<FirmwareChecks>>,
+    /// Serve scrapes within its TTL of each other from the same result
+    pub scrape_cache: Option<Arc<ScrapeCache>>,
+    /// Leave out the metrics each plug can't measure, if detection is enabled
+    pub capabilities: Option<Arc<DeviceCapabilities>>,
+    /// Devices served on `/metrics/<tenant>`, if any tenants are configured
+    pub tenants: Option<Arc<Tenants>>,
+    /// Render the readings of the plugs with the time they were polled
+    pub sample_timestamps: bool,
+}
+
+impl AppState {
+    /// Poll (or read from the cache) the given plugs, poll the given meters and build every metric
+    /// family the exporter serves
+    pub async fn scrape(
+        &self,
+        plugs: &[ShellySmartPlug],
+        meters: &[ShellySmartPlug],
+    ) -> Result<Vec<MetricFamily>, ShellyError> {
+        let readings = self.readings(plugs).await?;
+        let mut families = self.collect(&readings);
+        if self.inputs {
+            families.push(exporter::collect_inputs(&self.input_readings(plugs, None).await));
+        }
+        if self.automations {
+            families.extend(exporter::collect_automations(&self.automation_readings(plugs, None).await));
+        }
+        if self.addons.is_some() {
+            families.extend(addons::collect(&self.addon_readings(plugs, None).await));
+        }
+        if self.firmware.is_some() {
+            families.push(firmware::collect(&self.firmware_readings(plugs, None).await));
+        }
+        families.extend(exporter::collect_meters(&self.meter_readings(meters, None).await));
+        families.extend(self.client.health().collect(&[plugs, meters].concat()));
+        self.naming.apply(&mut families);
+        Ok(families)
+    }
+
+    pub async fn readings(&self, plugs: &[ShellySmartPlug]) -> Result<Vec<(ShellySmartPlug, SwitchStatus)>, ShellyError> {
+        if self.serve_cached {
+            return Ok(self.cache.readings(plugs));
+        }
+
+        let readings = self.client.get_all_statuses(plugs).await?;
+        self.cache.update_all(&readings);
+        Ok(readings)
+    }
+
+    /// Like `scrape`, but plugs which don't answer within `budget` are left out and reported down
+    /// instead of failing the whole scrape
+    pub async fn scrape_within(
+        &self,
+        plugs: &[ShellySmartPlug],
+        meters: &[ShellySmartPlug],
+        budget: Duration,
+    ) -> Vec<MetricFamily> {
+        let (
+            (readings, _),
+            meter_readings,
+            input_readings,
+            automation_readings,
+            addon_readings,
+            firmware_readings,
+        ) = tokio::join!(
+            self.readings_within(plugs, budget),
+            self.meter_readings(meters, Some(budget)),
+            async {
+                match self.inputs {
+                    true => Some(self.input_readings(plugs, Some(budget)).await),
+                    false => None,
+                }
+            },
+            async {
+                match self.automations {
+                    true => Some(self.automation_readings(plugs, Some(budget)).await),
+                    false => None,
+                }
+            },
+            async {
+                match self.addons {
+                    Some(_) => Some(self.addon_readings(plugs, Some(budget)).await),
+                    None => None,
+                }
+            },
+            async {
+                match self.firmware {
+                    Some(_) => Some(self.firmware_readings(plugs, Some(budget)).await),
+                    None => None,
+                }
+            },
+        );
+
+        let mut families = self.collect(&readings);
+        if let Some(input_readings) = input_readings {
+            families.push(exporter::collect_inputs(&input_readings));
+        }
+        if let Some(automation_readings) = automation_readings {
+            families.extend(exporter::collect_automations(&automation_readings));
+        }
+        if let Some(addon_readings) = addon_readings {
+            families.extend(addons::collect(&addon_readings));
+        }
+        if let Some(firmware_readings) = firmware_readings {
+            families.push(firmware::collect(&firmware_readings));
+        }
+        families.extend(exporter::collect_meters(&meter_readings));
+        families.extend(self.client.health().collect(&[plugs, meters].concat()));
+        self.naming.apply(&mut families);
+        families
+    }
+
+    /// State of the first input of every plug which has one and answered (within `budget`, if any)
+    pub async fn input_readings(
+        &self,
+        plugs: &[ShellySmartPlug],
+        budget: Option<Duration>,
+    ) -> Vec<(ShellySmartPlug, InputStatus)> {
+        optional_readings(plugs, budget, "input state", |plug| self.client.get_input_status(plug, 0)).await
+    }
+
+    /// Scripts and schedules of every plug which answered (within `budget`, if any)
+    pub async fn automation_readings(
+        &self,
+        plugs: &[ShellySmartPlug],
+        budget: Option<Duration>,
+    ) -> Vec<(ShellySmartPlug, Automations)> {
+        optional_readings(plugs, budget, "scripts and schedules", |plug| self.client.get_automations(plug)).await
+    }
+
+    /// Add-on sensors of every plug which has any configured and answered (within `budget`, if any)
+    pub async fn addon_readings(
+        &self,
+        plugs: &[ShellySmartPlug],
+        budget: Option<Duration>,
+    ) -> Vec<(ShellySmartPlug, AddonReading)> {
+        let Some(addons) = &self.addons else { return vec![] };
+        let plugs: Vec<ShellySmartPlug> = plugs.iter().filter(|plug| addons.sensors_of(plug).is_some()).cloned().collect();
+
+        optional_readings(&plugs, budget, "add-on sensor readings", |plug| async move {
+            match addons.sensors_of(plug) {
+                Some(sensors) => self.client.get_addon_sensors(plug, sensors).await,
+                None => Ok(AddonReading::default()),
+            }
+        }).await
+    }
+
+    /// Firmware updates available to every plug which answered (within `budget`, if any), checked
+    /// once per interval
+    pub async fn firmware_readings(
+        &self,
+        plugs: &[ShellySmartPlug],
+        budget: Option<Duration>,
+    ) -> Vec<(ShellySmartPlug, AvailableUpdates)> {
+        let Some(firmware) = &self.firmware else { return vec![] };
+        optional_readings(plugs, budget, "firmware updates", |plug| firmware.updates_of(&self.client, plug)).await
+    }
+
+    /// Readings of the meters which answered (within `budget`, if any). Meters which don't are
+    /// reported down rather than failing the scrape, so one meter can't hide every plug.
+    pub async fn meter_readings(
+        &self,
+        meters: &[ShellySmartPlug],
+        budget: Option<Duration>,
+    ) -> Vec<(ShellySmartPlug, EmReading)> {
+        let results = join_all(meters.iter().map(|meter| async move {
+            let result = match budget {
+                Some(budget) => tokio::time::timeout(budget, self.client.get_em_status(meter)).await,
+                None => Ok(self.client.get_em_status(meter).await),
+            };
+
+            match result {
+                Ok(Ok(reading)) => Some((meter.clone(), reading)),
+                Ok(Err(e)) => {
+                    warn!("Marking `{}` as down - {e}", meter.alias);
+                    None
+                }
+                Err(_) => {
+                    warn!("Marking `{}` as down, it didn't answer within the scrape timeout", meter.alias);
+                    self.client.health().record_failure(&meter.alias, &ShellyError::Timeout { plug: meter.alias.clone() });
+                    None
+                }
+            }
+        })).await;
+
+        results.into_iter().flatten().collect()
+    }
+
+    /// Readings of the plugs which answered within `budget`, and the plugs which didn't
+    pub async fn readings_within(
+        &self,
+        plugs: &[ShellySmartPlug],
+        budget: Duration,
+    ) -> (Vec<(ShellySmartPlug, SwitchStatus)>, Vec<ShellySmartPlug>) {
+        let results = match self.serve_cached {
+            true => plugs.iter().map(|plug| self.cache.get(&plug.alias).and_then(|cached| cached.status)).collect(),
+            false => join_all(plugs.iter().map(|plug| async move {
+                match tokio::time::timeout(budget, self.client.get_status(plug)).await {
+                    Ok(Ok(status)) => Some(status),
+                    Ok(Err(e)) => {
+                        warn!("Marking `{}` as down - {e}", plug.alias);
+                        None
+                    }
+                    Err(_) => {
+                        warn!("Marking `{}` as down, it didn't answer within {budget:?}", plug.alias);
+                        self.client.health().record_failure(&plug.alias, &ShellyError::Timeout { plug: plug.alias.clone() });
+                        None
+                    }
+                }
+            })).await,
+        };
+
+        let mut readings = vec![];
+        let mut down = vec![];
+        for (plug, status) in plugs.iter().zip(results) {
+            match status {
+                Some(status) => readings.push((plug.clone(), status)),
+                None => down.push(plug.clone()),
+            }
+        }
+
+        if !self.serve_cached {
+            self.cache.update_all(&readings);
+        }
+        (readings, down)
+    }
+
+    /// Build every metric family the exporter serves from already polled readings, with the
+    /// original names
+    pub fn collect(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) -> Vec<MetricFamily> {
+        // Live readings went into the cache before they got here, so it knows when they were polled
+        let polls: Vec<(ShellySmartPlug, DateTime<Utc>)> = readings.iter()
+            .map(|(plug, _)| (plug.clone(), self.cache.updated_at(&plug.alias).unwrap_or_else(Utc::now)))
+            .collect();
+        let mut families = exporter::collect(readings);
+        if self.sample_timestamps {
+            exporter::stamp(&mut families, &polls);
+        }
+        families.push(exporter::collect_poll_timestamps(&polls));
+        families.extend(exporter::collect_system(readings));
+        // Cached readings are too old to tell the device clock apart from the age of the reading
+        if !self.serve_cached {
+            families.push(exporter::collect_time_drift(readings, Utc::now()));
+        }
+        families.push(self.energy.collect(readings));
+        if let Some(groups) = &self.groups {
+            families.extend(groups.collect(readings, &self.energy));
+        }
+        if let Some(cost) = &self.cost {
+            families.push(cost.collect(readings, Local::now().time()));
+        }
+        if let Some(thresholds) = &self.thresholds {
+            families.push(thresholds.collect(readings));
+        }
+        if let Some(power_stats) = &self.power_stats {
+            families.extend(power_stats.collect(readings, &polls, Utc::now()));
+        }
+        if let Some(capabilities) = &self.capabilities {
+            capabilities.strip(readings, &mut families);
+        }
+
+        families
+    }
+
+    /// `collect` plus the health of every configured plug with the configured names, for the outputs
+    /// fed by the poller
+    pub fn collect_polled(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) -> Vec<MetricFamily> {
+        let mut families = self.collect(readings);
+        families.extend(self.client.health().collect(&self.plugs.snapshot()));
+        self.naming.apply(&mut families);
+        families
+    }
+}
+
+
+/// Register all endpoints, `AppState` must be provided as app data
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(landing_page)
+        .service(metrics_endpoint)
+        .service(tenant_metrics)
+        .service(probe)
+        .service(service_discovery)
+        .service(influx_endpoint)
+        .service(export_endpoint)
+        .service(history_endpoint)
+        .service(web::scope("/grafana")
+            .service(grafana_health)
+            .service(grafana_search)
+            .service(grafana_query)
+            .service(grafana_annotations))
+        .service(webhook_endpoint)
+        .service(list_plugs)
+        .service(add_plug)
+        .service(remove_plug)
+        .service(plug_state)
+        .service(reboot_plug)
+        .service(update_plug);
+}
+
+
+#[get("/")]
+async fn landing_page() -> impl Responder {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(pages::landing_page())
+}
+
+
+/// Every device, or only the ones selected by (repeated) `target` parameters
+#[get("/metrics")]
+async fn metrics_endpoint(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let params = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    let targets: Vec<&str> = params.iter()
+        .filter(|(name, _)| name == "target")
+        .map(|(_, target)| target.as_str())
+        .collect();
+    if targets.is_empty() {
+        return render_metrics(&req, &state, &state.plugs.snapshot(), &state.meters).await;
+    }
+
+    let matches = |device: &ShellySmartPlug| {
+        targets.iter().any(|target| device.alias == *target || device.target() == *target)
+    };
+    let plugs: Vec<ShellySmartPlug> = state.plugs.snapshot().into_iter().filter(|plug| matches(plug)).collect();
+    let meters: Vec<ShellySmartPlug> = state.meters.iter().filter(|meter| matches(meter)).cloned().collect();
+    let unknown = targets.iter().find(|target| {
+        !plugs.iter().chain(&meters).any(|device| device.alias == **target || device.target() == **target)
+    });
+    if let Some(target) = unknown {
+        return HttpResponse::NotFound().body(format!("Unknown target `{target}`"));
+    }
+
+    render_metrics(&req, &state, &plugs, &meters).await
+}
+
+
+/// The plugs and meters of a tenant from the config file
+#[get("/metrics/{tenant}")]
+async fn tenant_metrics(req: HttpRequest, state: web::Data<AppState>, tenant: web::Path<String>) -> impl Responder {
+    let devices = state.tenants.as_ref().and_then(|tenants| {
+        Some((tenants.devices_of(&tenant, &state.plugs.snapshot())?, tenants.devices_of(&tenant, &state.meters)?))
+    });
+    let Some((plugs, meters)) = devices else {
+        return HttpResponse::NotFound().body(format!("Unknown tenant `{tenant}`"));
+    };
+
+    render_metrics(&req, &state, &plugs, &meters).await
+}
+
+
+#[derive(Deserialize)]
+struct ProbeParams {
+    target: String,
+}
+
+/// Multi-target exporter pattern, scrape a single configured plug by alias or address
+#[get("/probe")]
+async fn probe(req: HttpRequest, state: web::Data<AppState>, params: web::Query<ProbeParams>) -> impl Responder {
+    let matches = |plug: &ShellySmartPlug| plug.alias == params.target || plug.target() == params.target;
+    let plugs: Vec<ShellySmartPlug> = state.plugs.snapshot().into_iter().filter(|plug| matches(plug)).collect();
+    let meters: Vec<ShellySmartPlug> = state.meters.iter().filter(|meter| matches(meter)).cloned().collect();
+
+    if plugs.is_empty() && meters.is_empty() {
+        return HttpResponse::NotFound().body(format!("Unknown target `{}`", params.target));
+    }
+
+    render_metrics(&req, &state, &plugs, &meters).await
+}
+
+
+/// The same readings as `/metrics`, in InfluxDB line protocol
+#[get("/influx")]
+async fn influx_endpoint(state: web::Data<AppState>) -> impl Responder {
+    match state.readings(&state.plugs.snapshot()).await {
+        Ok(readings) => {
+            let timestamp_ns = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+            HttpResponse::Ok()
+                .content_type(influx::CONTENT_TYPE)
+                .body(influx::format_line_protocol(&readings, timestamp_ns))
+        }
+        Err(e) => device_failed(e, "scrape"),
+    }
+}
+
+
+#[derive(Deserialize)]
+struct ExportParams {
+    /// `json` or `csv`, defaults to `json`
+    format: Option<String>,
+}
+
+/// The latest readings of every plug as JSON or CSV, for scripts and spreadsheets
+#[get("/export")]
+async fn export_endpoint(state: web::Data<AppState>, params: web::Query<ExportParams>) -> impl Responder {
+    let name = params.format.as_deref().unwrap_or("json");
+    let Some(formatter) = formatter::by_name(name) else {
+        return HttpResponse::BadRequest().body(format!("Unknown format `{name}`, expected json or csv"));
+    };
+
+    match state.readings(&state.plugs.snapshot()).await {
+        Ok(readings) => HttpResponse::Ok().content_type(formatter.content_type()).body(formatter.format(&readings)),
+        Err(e) => device_failed(e, "scrape"),
+    }
+}
+
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    alias: String,
+    /// RFC 3339 timestamp or unix seconds, defaults to a day before `to`
+    from: Option<String>,
+    /// RFC 3339 timestamp or unix seconds, defaults to now
+    to: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    alias: String,
+    points: Vec<HistoryPoint>,
+}
+
+/// Time series of a plug's power and energy readings from the local history
+#[get("/history")]
+async fn history_endpoint(state: web::Data<AppState>, params: web::Query<HistoryParams>) -> impl Responder {
+    let history = match &state.history {
+        Some(history) => history.clone(),
+        None => return HttpResponse::NotFound().body("History is not enabled"),
+    };
+    if !state.plugs.contains(&params.alias) {
+        return HttpResponse::NotFound().body(format!("Unknown plug `{}`", params.alias));
+    }
+
+    let to = match params.to.as_deref().map(parse_time) {
+        Some(Some(to)) => to,
+        Some(None) => return HttpResponse::BadRequest().body("Invalid `to` time"),
+        None => Utc::now(),
+    };
+    let from = match params.from.as_deref().map(parse_time) {
+        Some(Some(from)) => from,
+        Some(None) => return HttpResponse::BadRequest().body("Invalid `from` time"),
+        None => to - chrono::Duration::days(1),
+    };
+
+    let alias = params.alias.clone();
+    let query_alias = alias.clone();
+    match web::block(move || history.query(&query_alias, from, to)).await {
+        Ok(Ok(points)) => HttpResponse::Ok().json(HistoryResponse { alias, points }),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e),
+        Err(e) => {
+            error!("History query failed - {e}");
+            HttpResponse::InternalServerError().body("Failed to process, please check application logs")
+        }
+    }
+}
+
+fn parse_time(raw: &str) -> Option<DateTime<Utc>> {
+    match raw.parse::<i64>() {
+        Ok(seconds) => DateTime::from_timestamp(seconds, 0),
+        Err(_) => DateTime::parse_from_rfc3339(raw).ok().map(|time| time.with_timezone(&Utc)),
+    }
+}
+
+
+/// Grafana's "Save & test" of a simple JSON datasource pointed at `/grafana`
+#[get("/")]
+async fn grafana_health(state: web::Data<AppState>) -> impl Responder {
+    match state.history {
+        Some(_) => HttpResponse::Ok().body("OK"),
+        None => HttpResponse::NotFound().body("History is not enabled"),
+    }
+}
+
+#[post("/search")]
+async fn grafana_search(
+    state: web::Data<AppState>,
+    body: Option<web::Json<grafana::SearchRequest>>,
+) -> impl Responder {
+    if state.history.is_none() {
+        return HttpResponse::NotFound().body("History is not enabled");
+    }
+
+    let filter = body.map(|body| body.into_inner()).unwrap_or_default().target;
+    HttpResponse::Ok().json(grafana::search(&state.plugs.snapshot(), &filter))
+}
+
+/// Grafana time series built from the local history, unknown targets are left out
+#[post("/query")]
+async fn grafana_query(state: web::Data<AppState>, body: web::Json<grafana::QueryRequest>) -> impl Responder {
+    let history = match &state.history {
+        Some(history) => history.clone(),
+        None => return HttpResponse::NotFound().body("History is not enabled"),
+    };
+    let (from, to) = match body.range.parse() {
+        Some(range) => range,
+        None => return HttpResponse::BadRequest().body("Invalid query range"),
+    };
+
+    let request = body.into_inner();
+    let targets: Vec<(String, String, &'static str)> = request.targets.into_iter()
+        .filter_map(|target| {
+            let (alias, series) = grafana::parse_target(&target.target)?;
+            let alias = alias.to_string();
+            state.plugs.contains(&alias).then_some((target.target, alias, series))
+        })
+        .collect();
+
+    let max_data_points = request.max_data_points;
+    let result = web::block(move || {
+        targets.iter()
+            .map(|(target, alias, series)| {
+                let points = history.query(alias, from, to)?;
+                Ok(grafana::time_series(target, series, &points, max_data_points))
+            })
+            .collect::<Result<Vec<_>, &'static str>>()
+    }).await;
+
+    match result {
+        Ok(Ok(series)) => HttpResponse::Ok().json(series),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e),
+        Err(e) => {
+            error!("History query failed - {e}");
+            HttpResponse::InternalServerError().body("Failed to process, please check application logs")
+        }
+    }
+}
+
+/// No events are recorded, but Grafana requires the endpoint to exist
+#[post("/annotations")]
+async fn grafana_annotations(state: web::Data<AppState>) -> impl Responder {
+    match state.history {
+        Some(_) => HttpResponse::Ok().json(Vec::<Value>::new()),
+        None => HttpResponse::NotFound().body("History is not enabled"),
+    }
+}
+
+
+#[derive(Deserialize)]
+struct WebhookParams {
+    target: Option<String>,
+}
+
+/// Ingest `NotifyStatus` frames pushed by the devices into the cache. The plug is identified by
+/// the `target` query parameter (alias or address), or else by the address of the sender.
+#[post("/webhook")]
+async fn webhook_endpoint(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Query<WebhookParams>,
+    body: web::Json<Value>
+) -> impl Responder {
+    let plugs = state.plugs.snapshot();
+    let plug = match &params.target {
+        Some(target) => plugs.iter().find(|plug| &plug.alias == target || &plug.target() == target),
+        None => req.peer_addr().and_then(|peer| {
+            plugs.iter().find(|plug| plug.device_address().ip() == Some(peer.ip().to_canonical()))
+        }),
+    };
+    let plug = match plug {
+        Some(plug) => plug,
+        None => {
+            warn!("Received a notification for an unknown plug from {:?}", req.peer_addr());
+            return HttpResponse::NotFound().body("Unknown plug");
+        }
+    };
+
+    match webhook::switch_patch(&body) {
+        Ok(Some(patch)) => {
+            state.cache.apply_patch(&plug.alias, patch);
+            HttpResponse::Accepted().finish()
+        }
+        Ok(None) => HttpResponse::Accepted().finish(),
+        Err(e) => {
+            warn!("Invalid notification for `{}` - {e}", plug.alias);
+            HttpResponse::BadRequest().body(e)
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Serialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+    labels: Map<String, Value>,
+}
+
+/// Prometheus HTTP service discovery, one target group per plug
+///
+/// Ref: https://prometheus.io/docs/prometheus/latest/http_sd/
+#[get("/sd")]
+async fn service_discovery(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(target_groups(&state.plugs.snapshot()))
+}
+
+fn target_groups(plugs: &[ShellySmartPlug]) -> Vec<TargetGroup> {
+    plugs.iter()
+        .map(|plug| {
+            let mut labels = Map::new();
+            labels.insert("__meta_shelly_alias".to_string(), Value::String(plug.alias.clone()));
+            labels.insert("__meta_shelly_url".to_string(), Value::String(plug.url.clone()));
+
+            TargetGroup { targets: vec![plug.target()], labels }
+        })
+        .collect()
+}
+
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct PlugEntry {
+    /// `[https://]host[:port]`, like `--ip-addr`
+    address: String,
+    /// Defaults to the address
+    alias: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+impl From<&ShellySmartPlug> for PlugEntry {
+    fn from(plug: &ShellySmartPlug) -> PlugEntry {
+        PlugEntry {
+            address: plug.address(),
+            alias: Some(plug.alias.clone()),
+            labels: plug.labels.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Every configured plug and meter with its latest state, as HTML for browsers and JSON otherwise.
+/// Available without the admin API, which only adds changing the plugs.
+#[get("/plugs")]
+async fn list_plugs(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let health = state.client.health();
+    let mut devices: Vec<DeviceStatus> = state.plugs.snapshot().iter()
+        .map(|plug| {
+            let capabilities = state.capabilities.as_ref().and_then(|capabilities| capabilities.get(&plug.alias));
+            DeviceStatus::new(plug, "plug", health.get(&plug.alias), state.cache.get(&plug.alias), capabilities)
+        })
+        .collect();
+    devices.extend(state.meters.iter().map(|meter| DeviceStatus::new(meter, "meter", health.get(&meter.alias), None, None)));
+
+    match accepts_html(&req) {
+        true => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(pages::devices_page(&devices)),
+        false => HttpResponse::Ok().json(devices),
+    }
+}
+
+/// Admin API, start polling another plug
+#[post("/plugs")]
+async fn add_plug(state: web::Data<AppState>, body: web::Json<PlugEntry>) -> impl Responder {
+    if !state.admin_api {
+        return HttpResponse::NotFound().body("Admin API is not enabled");
+    }
+
+    let entry = body.into_inner();
+    let mut plug = match ShellySmartPlug::from_address(&entry.address) {
+        Ok(plug) => plug,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    if let Some(alias) = entry.alias {
+        plug.alias = alias;
+    }
+    plug.labels = entry.labels.into_iter().collect();
+
+    let registry = state.plugs.clone();
+    let added = plug.clone();
+    match web::block(move || registry.add(added)).await {
+        Ok(Ok(())) => {
+            if let Some(capabilities) = state.capabilities.clone() {
+                let (client, plug) = (state.client.clone(), plug.clone());
+                tokio::spawn(async move { capabilities.detect(&client, &plug).await });
+            }
+            HttpResponse::Created().json(PlugEntry::from(&plug))
+        }
+        Ok(Err(e @ "Plug is already registered!")) => HttpResponse::Conflict().body(e),
+        Ok(Err(e @ "Invalid plug label!")) => HttpResponse::BadRequest().body(e),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e),
+        Err(e) => {
+            error!("Registering a plug failed - {e}");
+            HttpResponse::InternalServerError().body("Failed to process, please check application logs")
+        }
+    }
+}
+
+/// Admin API, stop polling a plug
+#[delete("/plugs/{alias}")]
+async fn remove_plug(state: web::Data<AppState>, alias: web::Path<String>) -> impl Responder {
+    if !state.admin_api {
+        return HttpResponse::NotFound().body("Admin API is not enabled");
+    }
+
+    let registry = state.plugs.clone();
+    let alias = alias.into_inner();
+    let removed_alias = alias.clone();
+    match web::block(move || registry.remove(&removed_alias)).await {
+        Ok(Ok(Some(_))) => {
+            if let Some(capabilities) = &state.capabilities {
+                capabilities.forget(&alias);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Ok(Ok(None)) => HttpResponse::NotFound().body(format!("Unknown plug `{alias}`")),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e),
+        Err(e) => {
+            error!("Removing a plug failed - {e}");
+            HttpResponse::InternalServerError().body("Failed to process, please check application logs")
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PlugState {
+    alias: String,
+    /// `on`, `off`, or `unknown` for plugs which don't report their relay
+    state: &'static str,
+    attributes: PlugStateAttributes,
+    /// RFC 3339, when the reading was polled
+    last_updated: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PlugStateAttributes {
+    power_watts: f64,
+    voltage: f64,
+    current_amps: f64,
+    energy_total_wh: f64,
+    temperature_celsius: f64,
+}
+
+/// Latest reading of a plug shaped like the states of the Home Assistant REST API, for its RESTful
+/// sensor. Served from the cache with `--serve-from-cache`, polled otherwise.
+#[get("/plugs/{alias}/state")]
+async fn plug_state(state: web::Data<AppState>, alias: web::Path<String>) -> impl Responder {
+    let Some(plug) = state.plugs.get(&alias) else {
+        return HttpResponse::NotFound().body(format!("Unknown plug `{alias}`"));
+    };
+    let status = match state.readings(std::slice::from_ref(&plug)).await {
+        Ok(readings) => readings.into_iter().next().map(|(_, status)| status),
+        Err(e) => return device_failed(e, "read"),
+    };
+    let Some(status) = status else {
+        return HttpResponse::ServiceUnavailable().body(format!("No reading of `{alias}` yet"));
+    };
+
+    let updated_at = state.cache.updated_at(&plug.alias).unwrap_or_else(Utc::now);
+    HttpResponse::Ok().json(PlugState {
+        alias: plug.alias,
+        state: match status.output {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "unknown",
+        },
+        attributes: PlugStateAttributes {
+            power_watts: status.apower,
+            voltage: status.voltage,
+            current_amps: status.current,
+            energy_total_wh: status.aenergy.total,
+            temperature_celsius: status.temperature.celsius,
+        },
+        last_updated: updated_at.to_rfc3339(),
+    })
+}
+
+/// Reboot a plug whose RPC interface hangs, counted in `shelly_device_reboots_triggered_total`
+#[post("/plugs/{alias}/reboot")]
+async fn reboot_plug(state: web::Data<AppState>, alias: web::Path<String>) -> impl Responder {
+    if !state.admin_api {
+        return HttpResponse::NotFound().body("Admin API is not enabled");
+    }
+
+    let Some(plug) = state.plugs.get(&alias) else {
+        return HttpResponse::NotFound().body(format!("Unknown plug `{alias}`"));
+    };
+    match state.client.reboot(&plug).await {
+        Ok(()) => {
+            info!("Rebooting `{}` on request", plug.alias);
+            HttpResponse::Accepted().finish()
+        }
+        Err(e) => device_failed(e, "reboot"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateParams {
+    /// `stable` or `beta`
+    stage: Option<String>,
+}
+
+/// Have a plug install the latest firmware of the stage, `stable` unless asked otherwise
+#[post("/plugs/{alias}/update")]
+async fn update_plug(
+    state: web::Data<AppState>,
+    alias: web::Path<String>,
+    params: web::Query<UpdateParams>,
+) -> impl Responder {
+    if !state.admin_api {
+        return HttpResponse::NotFound().body("Admin API is not enabled");
+    }
+
+    let stage = params.stage.as_deref().unwrap_or("stable");
+    if !firmware::CHANNELS.contains(&stage) {
+        return HttpResponse::BadRequest().body(format!("Unknown stage `{stage}`, expected stable or beta"));
+    }
+    let Some(plug) = state.plugs.get(&alias) else {
+        return HttpResponse::NotFound().body(format!("Unknown plug `{alias}`"));
+    };
+    match state.client.update_firmware(&plug, stage).await {
+        Ok(()) => {
+            info!("Updating `{}` to the latest {stage} firmware on request", plug.alias);
+            if let Some(firmware) = &state.firmware {
+                firmware.forget(&plug.alias);
+            }
+            HttpResponse::Accepted().finish()
+        }
+        Err(e) => device_failed(e, "update"),
+    }
+}
+
+/// Readings which not every plug has, plugs which fail to answer are left out without marking them
+/// down
+async fn optional_readings<'a, T, F, Fut>(
+    plugs: &'a [ShellySmartPlug],
+    budget: Option<Duration>,
+    what: &str,
+    fetch: F,
+) -> Vec<(ShellySmartPlug, T)>
+where
+    F: Fn(&'a ShellySmartPlug) -> Fut,
+    Fut: Future<Output = Result<T, ShellyError>>,
+{
+    let results = join_all(plugs.iter().map(|plug| {
+        let request = fetch(plug);
+        async move {
+            let result = match budget {
+                Some(budget) => tokio::time::timeout(budget, request)
+                    .await
+                    .unwrap_or_else(|_| Err(ShellyError::Timeout { plug: plug.alias.clone() })),
+                None => request.await,
+            };
+
+            match result {
+                Ok(reading) => Some((plug.clone(), reading)),
+                Err(e) => {
+                    debug!("No {what} for `{}` - {e}", plug.alias);
+                    None
+                }
+            }
+        }
+    })).await;
+
+    results.into_iter().flatten().collect()
+}
+
+fn accepts_html(req: &HttpRequest) -> bool {
+    req.headers().get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn negotiate_format(req: &HttpRequest) -> Format {
+    let accept = req.headers().get(header::ACCEPT).and_then(|value| value.to_str().ok());
+    Format::negotiate(accept)
+}
+
+/// Time left for polling the plugs, from the scrape timeout Prometheus sends along
+fn scrape_budget(req: &HttpRequest) -> Option<Duration> {
+    let timeout = req.headers().get(SCRAPE_TIMEOUT_HEADER)?.to_str().ok()?.trim().parse::<f64>().ok()?;
+    let timeout = Duration::try_from_secs_f64(timeout).ok().filter(|timeout| !timeout.is_zero())?;
+
+    match timeout.checked_sub(SCRAPE_TIMEOUT_MARGIN) {
+        Some(budget) if !budget.is_zero() => Some(budget),
+        // Very short timeouts, leave half of it for the response
+        _ => Some(timeout / 2),
+    }
+}
+
+async fn render_metrics(
+    req: &HttpRequest,
+    state: &AppState,
+    plugs: &[ShellySmartPlug],
+    meters: &[ShellySmartPlug],
+) -> HttpResponse {
+    let format = negotiate_format(req);
+    let scrape = || async {
+        match scrape_budget(req) {
+            Some(budget) => Ok(state.scrape_within(plugs, meters, budget).await),
+            None => state.scrape(plugs, meters).await,
+        }
+    };
+    let cache = match &state.scrape_cache {
+        Some(cache) => cache,
+        None => {
+            return match scrape().await {
+                Ok(families) => HttpResponse::Ok()
+                    .content_type(format.content_type())
+                    .body(metrics::encode(&families, format)),
+                Err(e) => device_failed(e, "scrape"),
+            };
+        }
+    };
+
+    let key: Vec<&str> = plugs.iter().chain(meters).map(|device| device.alias.as_str()).collect();
+    match cache.get_or_scrape(&key.join(","), scrape).await {
+        Ok(scraped) => {
+            // Tagged without the hit counter, so scrapers asking again within the TTL get a 304. The
+            // format is part of the tag, both are served from the same scrape. Weak, because the
+            // compression middleware may encode the same body differently per request.
+            let variant = match format {
+                Format::Prometheus => "text",
+                Format::OpenMetrics => "openmetrics",
+            };
+            let etag = header::EntityTag::new_weak(format!("{:016x}-{variant}", scraped.tag));
+            if let Some(header::IfNoneMatch::Items(tags)) = req.get_header::<header::IfNoneMatch>() {
+                if tags.iter().any(|tag| tag.weak_eq(&etag)) {
+                    return HttpResponse::NotModified().insert_header(header::ETag(etag)).finish();
+                }
+            }
+
+            let mut hits = [cache.collect()];
+            state.naming.apply(&mut hits);
+            let mut families = scraped.families;
+            families.extend(hits);
+            HttpResponse::Ok()
+                .content_type(format.content_type())
+                .insert_header(header::ETag(etag))
+                .body(metrics::encode(&families, format))
+        }
+        Err(e) => device_failed(e, "scrape"),
+    }
+}
+
+/// A device which didn't answer in time is a `504`, any other failure of a device a `502`
+fn device_failed(e: ShellyError, action: &str) -> HttpResponse {
+    error!("An error occurred during processing - {e}");
+    let mut response = match e {
+        ShellyError::Timeout { .. } => HttpResponse::GatewayTimeout(),
+        _ => HttpResponse::BadGateway(),
+    };
+    response.body(format!("Failed to {action} `{}` ({}), please check application logs", e.plug(), e.reason()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use crate::config::AddonSensorConfig;
+    use mockito::{Server, ServerGuard};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    async fn fake_plug(server: &mut ServerGuard, path: &str) -> String {
+        server.mock("GET", path)
+            .with_status(200)
+            .with_body(json!({
+                "apower": 1.0,
+                "voltage": 2.0,
+                "current": 3.0,
+                "temperature": { "tC": 20.1, "tF": 68.2 },
+                "aenergy": { "total": 10.0 }
+            }).to_string())
+            .create_async()
+            .await;
+
+        format!("{}{path}", server.url())
+    }
+
+    fn state(plugs: Vec<ShellySmartPlug>) -> AppState {
+        AppState {
+            client: ShellyClient::new(),
+            plugs: Arc::new(PlugRegistry::new(plugs)),
+            meters: vec![],
+            cost: None,
+            thresholds: None,
+            power_stats: None,
+            groups: None,
+            energy: Arc::new(EnergyLedger::new()),
+            cache: Arc::new(ReadingCache::new()),
+            history: None,
+            serve_cached: false,
+            admin_api: false,
+            naming: MetricNaming::legacy(),
+            inputs: false,
+            automations: false,
+            addons: None,
+            firmware: None,
+            scrape_cache: None,
+            capabilities: None,
+            tenants: None,
+            sample_timestamps: false,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_probe() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+            ShellySmartPlug { url: fake_plug(&mut server, "/b").await, alias: "office".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/probe?target=office").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="office"} 1.0"#));
+        assert!(!body.contains("kitchen"));
+
+        let missing = call_service(&app, TestRequest::get().uri("/probe?target=garage").to_request()).await;
+        assert_eq!(missing.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_scrape() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/denied").with_status(401).create_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: format!("{}/denied", server.url()), alias: "kitchen".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/probe?target=kitchen").to_request()).await;
+        assert_eq!(response.status(), 502);
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Failed to scrape `kitchen` (unauthorized), please check application logs");
+    }
+
+    #[actix_web::test]
+    async fn test_scrape_timeout_marks_slow_plugs_down() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/slow")
+            .with_status(200)
+            .with_chunked_body(|writer| {
+                std::thread::sleep(Duration::from_secs(1));
+                writer.write_all(b"{}")
+            })
+            .create_async()
+            .await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+            ShellySmartPlug { url: format!("{}/slow", server.url()), alias: "garage".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+
+        let response = call_service(&app, TestRequest::get()
+            .uri("/metrics")
+            .insert_header((SCRAPE_TIMEOUT_HEADER, "1"))
+            .to_request()
+        ).await;
+        assert_eq!(response.status(), 200);
+
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="kitchen"} 1.0"#));
+        assert!(!body.contains(r#"power_watts{hostname="garage"}"#));
+        assert!(body.contains(r#"shelly_plug_up{hostname="kitchen"} 1.0"#));
+        assert!(body.contains(r#"shelly_plug_up{hostname="garage"} 0.0"#));
+        assert!(body.contains(r#"shelly_plug_consecutive_failures{hostname="garage"} 1.0"#));
+        assert!(body.contains(r#"shelly_plug_last_successful_scrape_timestamp_seconds{hostname="kitchen"}"#));
+        assert!(!body.contains(r#"shelly_plug_last_successful_scrape_timestamp_seconds{hostname="garage"}"#));
+    }
+
+    #[actix_web::test]
+    async fn test_compressed_metrics() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new()
+            .wrap(actix_web::middleware::Compress::default())
+            .app_data(web::Data::new(state(plugs)))
+            .configure(configure)
+        ).await;
+
+        let response = call_service(&app, TestRequest::get()
+            .uri("/metrics")
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request()
+        ).await;
+        assert_eq!(response.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+
+        let plain = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn test_scrape_budget() {
+        let budget = |value: &str| scrape_budget(&TestRequest::get()
+            .insert_header((SCRAPE_TIMEOUT_HEADER, value))
+            .to_http_request());
+
+        assert_eq!(budget("10"), Some(Duration::from_millis(9500)));
+        assert_eq!(budget("0.5"), Some(Duration::from_millis(250)));
+        assert_eq!(budget("0"), None);
+        assert_eq!(budget("soon"), None);
+        assert_eq!(scrape_budget(&TestRequest::get().to_http_request()), None);
+    }
+
+    #[actix_web::test]
+    async fn test_history() {
+        let plugs = vec![
+            ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: "kettle".to_string(), labels: vec![] },
+        ];
+        let history = History::open(std::path::Path::new(":memory:"), None).unwrap();
+        let status: SwitchStatus = serde_json::from_value(json!({
+            "apower": 1.0,
+            "voltage": 2.0,
+            "current": 3.0,
+            "temperature": { "tC": 20.1, "tF": 68.2 },
+            "aenergy": { "total": 10.0 }
+        })).unwrap();
+        history.record(&[(plugs[0].clone(), status)], DateTime::from_timestamp(1735732800, 0).unwrap()).unwrap();
+        let enabled = AppState { history: Some(Arc::new(history)), ..state(plugs.clone()) };
+        let app = init_service(App::new().app_data(web::Data::new(enabled)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get()
+            .uri("/history?alias=kettle&from=2025-01-01T00:00:00Z&to=1735776000")
+            .to_request()
+        ).await;
+        let actual: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(actual, json!({
+            "alias": "kettle",
+            "points": [{ "timestamp": "2025-01-01T12:00:00+00:00", "power_watts": 1.0, "energy_total_wh": 10.0 }]
+        }));
+
+        let unknown = call_service(&app, TestRequest::get().uri("/history?alias=garage").to_request()).await;
+        assert_eq!(unknown.status(), 404);
+        let invalid = call_service(&app, TestRequest::get().uri("/history?alias=kettle&from=yesterday").to_request()).await;
+        assert_eq!(invalid.status(), 400);
+
+        let disabled = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+        let response = call_service(&disabled, TestRequest::get().uri("/history?alias=kettle").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_grafana() {
+        let plugs = vec![
+            ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: "kettle".to_string(), labels: vec![] },
+        ];
+        let history = History::open(std::path::Path::new(":memory:"), None).unwrap();
+        let status: SwitchStatus = serde_json::from_value(json!({
+            "apower": 1.0,
+            "voltage": 2.0,
+            "current": 3.0,
+            "temperature": { "tC": 20.1, "tF": 68.2 },
+            "aenergy": { "total": 10.0 }
+        })).unwrap();
+        history.record(&[(plugs[0].clone(), status)], DateTime::from_timestamp(1735732800, 0).unwrap()).unwrap();
+        let enabled = AppState { history: Some(Arc::new(history)), ..state(plugs.clone()) };
+        let app = init_service(App::new().app_data(web::Data::new(enabled)).configure(configure)).await;
+
+        let health = call_service(&app, TestRequest::get().uri("/grafana/").to_request()).await;
+        assert_eq!(health.status(), 200);
+
+        let body = call_and_read_body(&app, TestRequest::post()
+            .uri("/grafana/search")
+            .set_json(json!({ "target": "power" }))
+            .to_request()
+        ).await;
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!(["kettle.power_watts"]));
+
+        let body = call_and_read_body(&app, TestRequest::post()
+            .uri("/grafana/query")
+            .set_json(json!({
+                "range": { "from": "2025-01-01T00:00:00.000Z", "to": "2025-01-02T00:00:00.000Z" },
+                "targets": [
+                    { "target": "kettle.energy_total_wh", "refId": "A", "type": "timeserie" },
+                    { "target": "garage.power_watts", "refId": "B", "type": "timeserie" }
+                ],
+                "maxDataPoints": 500
+            }))
+            .to_request()
+        ).await;
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!([
+            { "target": "kettle.energy_total_wh", "datapoints": [[10.0, 1735732800000i64]] }
+        ]));
+
+        let annotations = call_and_read_body(&app, TestRequest::post()
+            .uri("/grafana/annotations")
+            .set_json(json!({}))
+            .to_request()
+        ).await;
+        assert_eq!(serde_json::from_slice::<Value>(&annotations).unwrap(), json!([]));
+
+        let disabled = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+        let response = call_service(&disabled, TestRequest::get().uri("/grafana/").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_admin_api() {
+        let plugs = vec![
+            ShellySmartPlug { url: "http://10.0.0.2".to_string(), alias: "kettle".to_string(), labels: vec![] },
+        ];
+        let enabled = AppState { admin_api: true, ..state(plugs.clone()) };
+        let registry = enabled.plugs.clone();
+        let app = init_service(App::new().app_data(web::Data::new(enabled)).configure(configure)).await;
+
+        let added = call_service(&app, TestRequest::post()
+            .uri("/plugs")
+            .set_json(json!({ "address": "10.0.0.3", "alias": "tv", "labels": { "room": "den" } }))
+            .to_request()
+        ).await;
+        assert_eq!(added.status(), 201);
+        assert_eq!(registry.get("tv").unwrap().labels, vec![("room".to_string(), "den".to_string())]);
+
+        let duplicate = call_service(&app, TestRequest::post()
+            .uri("/plugs")
+            .set_json(json!({ "address": "10.0.0.4", "alias": "tv" }))
+            .to_request()
+        ).await;
+        assert_eq!(duplicate.status(), 409);
+        let reserved = call_service(&app, TestRequest::post()
+            .uri("/plugs")
+            .set_json(json!({ "address": "10.0.0.4", "labels": { "hostname": "other" } }))
+            .to_request()
+        ).await;
+        assert_eq!(reserved.status(), 400);
+        let unknown_scheme = call_service(&app, TestRequest::post()
+            .uri("/plugs")
+            .set_json(json!({ "address": "ftp://10.0.0.4" }))
+            .to_request()
+        ).await;
+        assert_eq!(unknown_scheme.status(), 400);
+
+        let removed = call_service(&app, TestRequest::delete().uri("/plugs/kettle").to_request()).await;
+        assert_eq!(removed.status(), 204);
+        let missing = call_service(&app, TestRequest::delete().uri("/plugs/kettle").to_request()).await;
+        assert_eq!(missing.status(), 404);
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/plugs").to_request()).await;
+        let listed: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["address"], "10.0.0.3");
+        assert_eq!(listed[0]["alias"], "tv");
+        assert_eq!(listed[0]["labels"], json!({ "room": "den" }));
+
+        let disabled = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+        let response = call_service(&disabled, TestRequest::post()
+            .uri("/plugs")
+            .set_json(json!({ "address": "10.0.0.3" }))
+            .to_request()
+        ).await;
+        assert_eq!(response.status(), 404);
+        let response = call_service(&disabled, TestRequest::delete().uri("/plugs/kettle").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_reboot_plug() {
+        let mut server = Server::new_async().await;
+        let reboot = server.mock("GET", "/rpc/Shelly.Reboot").with_status(200).with_body("null").create_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: server.url(), alias: "kettle".to_string(), labels: vec![] },
+        ];
+        let enabled = AppState { admin_api: true, ..state(plugs.clone()) };
+        let client = enabled.client.clone();
+        let app = init_service(App::new().app_data(web::Data::new(enabled)).configure(configure)).await;
+
+        let response = call_service(&app, TestRequest::post().uri("/plugs/kettle/reboot").to_request()).await;
+        assert_eq!(response.status(), 202);
+        reboot.assert_async().await;
+        let unknown = call_service(&app, TestRequest::post().uri("/plugs/tv/reboot").to_request()).await;
+        assert_eq!(unknown.status(), 404);
+
+        let reboots = &client.health().collect(&plugs)[5];
+        assert_eq!(reboots.name, "shelly_device_reboots_triggered_total");
+        assert_eq!(reboots.samples[0].value, 1.0);
+
+        let disabled = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+        let response = call_service(&disabled, TestRequest::post().uri("/plugs/kettle/reboot").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_plug_state() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+            ShellySmartPlug { url: "http://127.0.0.1:1".to_string(), alias: "garage".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs.clone()))).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/plugs/kitchen/state").to_request()).await;
+        let mut actual: Value = serde_json::from_slice(&body).unwrap();
+        assert!(actual["last_updated"].as_str().and_then(|at| DateTime::parse_from_rfc3339(at).ok()).is_some());
+        actual.as_object_mut().unwrap().remove("last_updated");
+        assert_eq!(actual, serde_json::json!({
+            "alias": "kitchen",
+            "state": "unknown",
+            "attributes": {
+                "power_watts": 1.0,
+                "voltage": 2.0,
+                "current_amps": 3.0,
+                "energy_total_wh": 10.0,
+                "temperature_celsius": 20.1,
+            },
+        }));
+
+        let response = call_service(&app, TestRequest::get().uri("/plugs/garage/state").to_request()).await;
+        assert_eq!(response.status(), 502);
+        let response = call_service(&app, TestRequest::get().uri("/plugs/tv/state").to_request()).await;
+        assert_eq!(response.status(), 404);
+
+        let cached = AppState { serve_cached: true, ..state(plugs) };
+        let app = init_service(App::new().app_data(web::Data::new(cached)).configure(configure)).await;
+        let response = call_service(&app, TestRequest::get().uri("/plugs/kitchen/state").to_request()).await;
+        assert_eq!(response.status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn test_status_pages() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+            ShellySmartPlug { url: "http://127.0.0.1:1".to_string(), alias: "garage".to_string(), labels: vec![] },
+            ShellySmartPlug { url: "http://127.0.0.1:1".to_string(), alias: "attic".to_string(), labels: vec![] },
+        ];
+        let state = state(plugs.clone());
+        state.scrape_within(&plugs[..2], &[], Duration::from_secs(5)).await;
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let landing = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(String::from_utf8(landing.to_vec()).unwrap().contains(r#"<a href="/metrics">"#));
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/plugs").to_request()).await;
+        let devices: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(devices[0]["up"], true);
+        assert_eq!(devices[0]["last_reading"]["power_watts"], 1.0);
+        assert_eq!(devices[1]["up"], false);
+        assert_eq!(devices[1]["consecutive_failures"], 1);
+        assert!(devices[1]["last_error"].as_str().unwrap().starts_with("Failed to connect to `garage`"));
+        assert_eq!(devices[1]["last_reading"], Value::Null);
+        assert_eq!(devices[2]["up"], Value::Null);
+
+        let html = call_and_read_body(&app, TestRequest::get()
+            .uri("/plugs")
+            .insert_header((header::ACCEPT, "text/html,application/xhtml+xml"))
+            .to_request()
+        ).await;
+        assert!(String::from_utf8(html.to_vec()).unwrap().contains("<td>garage</td>"));
+    }
+
+    #[actix_web::test]
+    async fn test_energy_meters() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/rpc/EM.GetStatus?id=0")
+            .with_status(200)
+            .with_body(json!({
+                "id": 0,
+                "a_current": 4.0, "a_voltage": 230.0, "a_act_power": 900.0, "a_aprt_power": 920.0, "a_pf": 0.98,
+                "b_current": 1.0, "b_voltage": 231.0, "b_act_power": 200.0, "b_aprt_power": 230.0, "b_pf": 0.87,
+                "c_current": 0.0, "c_voltage": 229.0, "c_act_power": 0.0, "c_aprt_power": 0.0, "c_pf": 0.0,
+                "total_act_power": 1100.0
+            }).to_string())
+            .create_async()
+            .await;
+        server.mock("GET", "/rpc/EMData.GetStatus?id=0")
+            .with_status(200)
+            .with_body(r#"{"id": 0, "a_total_act_energy": 1500.5, "b_total_act_energy": 20.0, "c_total_act_energy": 0.0}"#)
+            .create_async()
+            .await;
+        let plug = ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] };
+        let meter = ShellySmartPlug { alias: "mains".to_string(), ..ShellySmartPlug::from_address(&server.host_with_port()).unwrap() };
+        let broken = ShellySmartPlug { alias: "garage".to_string(), ..ShellySmartPlug::from_address("127.0.0.1:1").unwrap() };
+        let state = AppState { meters: vec![meter, broken], ..state(vec![plug]) };
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="kitchen"} 1.0"#));
+        assert!(body.contains(r#"shelly_em_active_power_watts{hostname="mains",phase="b"} 200.0"#));
+        assert!(body.contains(r#"shelly_em_active_energy_wh{hostname="mains",phase="a"} 1500.5"#));
+        assert!(body.contains(r#"shelly_plug_up{hostname="mains"} 1.0"#));
+        assert!(body.contains(r#"shelly_plug_up{hostname="garage"} 0.0"#));
+
+        let probed = call_and_read_body(&app, TestRequest::get().uri("/probe?target=mains").to_request()).await;
+        let probed = String::from_utf8(probed.to_vec()).unwrap();
+        assert!(probed.contains(r#"shelly_em_voltage{hostname="mains",phase="c"} 229.0"#));
+        assert!(!probed.contains("kitchen"));
+    }
+
+    #[actix_web::test]
+    async fn test_input_state() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/k/rpc/Input.GetStatus?id=0")
+            .with_status(200)
+            .with_body(r#"{"id": 0, "state": true}"#)
+            .create_async()
+            .await;
+        let plugs = vec![
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/k/rpc/Switch.GetStatus?id=0").await,
+                alias: "kitchen".to_string(),
+                labels: vec![],
+            },
+            ShellySmartPlug { url: fake_plug(&mut server, "/b").await, alias: "tv".to_string(), labels: vec![] },
+        ];
+        let state = AppState { inputs: true, ..state(plugs) };
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"shelly_input_state{hostname="kitchen",input="0"} 1.0"#));
+        // Plugs without an input don't fail the scrape
+        assert!(body.contains(r#"power_watts{hostname="tv"} 1.0"#));
+        assert!(!body.contains(r#"shelly_input_state{hostname="tv""#));
+    }
+
+    #[actix_web::test]
+    async fn test_automation_state() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/k/rpc/Script.List")
+            .with_status(200)
+            .with_body(r#"{"scripts": [
+                {"id": 1, "name": "night_light", "enable": true, "running": true},
+                {"id": 2, "name": "backup", "enable": false, "running": false}
+            ]}"#)
+            .create_async()
+            .await;
+        server.mock("GET", "/k/rpc/Script.GetStatus?id=1")
+            .with_status(200)
+            .with_body(r#"{"id": 1, "running": true}"#)
+            .create_async()
+            .await;
+        server.mock("GET", "/k/rpc/Script.GetStatus?id=2")
+            .with_status(200)
+            .with_body(r#"{"id": 2, "running": false, "errors": ["crashed"]}"#)
+            .create_async()
+            .await;
+        server.mock("GET", "/k/rpc/Schedule.List")
+            .with_status(200)
+            .with_body(r#"{"jobs": [{"id": 3, "enable": false, "timespec": "0 0 22 * * FRI", "calls": []}], "rev": 1}"#)
+            .create_async()
+            .await;
+        let plugs = vec![
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/k/rpc/Switch.GetStatus?id=0").await,
+                alias: "kitchen".to_string(),
+                labels: vec![],
+            },
+            ShellySmartPlug { url: fake_plug(&mut server, "/b").await, alias: "tv".to_string(), labels: vec![] },
+        ];
+        let state = AppState { automations: true, ..state(plugs) };
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"shelly_script_running{hostname="kitchen",script="1",script_name="night_light"} 1.0"#));
+        assert!(body.contains(r#"shelly_script_error{hostname="kitchen",script="1",script_name="night_light"} 0.0"#));
+        assert!(body.contains(r#"shelly_script_error{hostname="kitchen",script="2",script_name="backup"} 1.0"#));
+        assert!(body.contains(r#"shelly_schedule_enabled{hostname="kitchen",schedule="3"} 0.0"#));
+        assert!(body.contains(r#"power_watts{hostname="tv"} 1.0"#));
+        assert!(!body.contains(r#"shelly_script_running{hostname="tv""#));
+    }
+
+    #[actix_web::test]
+    async fn test_firmware_updates() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/k/rpc/Shelly.CheckForUpdate")
+            .with_status(200)
+            .with_body(r#"{"stable": {"version": "1.4.4", "build_id": "20241011-114455/1.4.4-g6d2a586"}}"#)
+            .create_async()
+            .await;
+        let update = server.mock("GET", "/k/rpc/Shelly.Update?stage=beta")
+            .with_status(200)
+            .with_body("null")
+            .create_async()
+            .await;
+        let plugs = vec![
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/k/rpc/Switch.GetStatus?id=0").await,
+                alias: "kitchen".to_string(),
+                labels: vec![],
+            },
+        ];
+        let state = AppState {
+            firmware: Some(Arc::new(FirmwareChecks::new(Duration::from_secs(3600)))),
+            admin_api: true,
+            ..state(plugs)
+        };
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"shelly_firmware_update_available{hostname="kitchen",channel="stable"} 1.0"#), "{body}");
+        assert!(body.contains(r#"shelly_firmware_update_available{hostname="kitchen",channel="beta"} 0.0"#));
+
+        let response = call_service(&app, TestRequest::post().uri("/plugs/kitchen/update?stage=beta").to_request()).await;
+        assert_eq!(response.status(), 202);
+        update.assert_async().await;
+        let response = call_service(&app, TestRequest::post().uri("/plugs/kitchen/update?stage=nightly").to_request()).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_capabilities() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/gen1/rpc/Shelly.GetDeviceInfo")
+            .with_status(404)
+            .create_async()
+            .await;
+        let plugs = vec![
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/gen1/rpc/Switch.GetStatus?id=0").await,
+                alias: "lamp".to_string(),
+                labels: vec![],
+            },
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/unknown/rpc/Switch.GetStatus?id=0").await,
+                alias: "tv".to_string(),
+                labels: vec![],
+            },
+        ];
+        let capabilities = Arc::new(DeviceCapabilities::new());
+        let state = AppState { capabilities: Some(capabilities.clone()), ..state(plugs.clone()) };
+        capabilities.detect_all(&state.client, &state.plugs.snapshot()).await;
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="lamp"} 1.0"#), "{body}");
+        assert!(!body.contains(r#"voltage{hostname="lamp"}"#));
+        assert!(body.contains(r#"voltage{hostname="tv"} 2.0"#));
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/plugs").to_request()).await;
+        let devices: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(devices[0]["capabilities"]["generation"], 1);
+        assert_eq!(devices[1]["capabilities"], Value::Null);
+
+        // The plug which couldn't be asked is asked again once it answers
+        server.mock("GET", "/unknown/rpc/Shelly.GetDeviceInfo")
+            .with_status(404)
+            .create_async()
+            .await;
+        capabilities.detect_missing(&ShellyClient::new(), &plugs).await;
+        let body = call_and_read_body(&app, TestRequest::get().uri("/plugs").to_request()).await;
+        let devices: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(devices[1]["capabilities"]["generation"], 1);
+    }
+
+    #[actix_web::test]
+    async fn test_tenant_metrics() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/a/rpc/Switch.GetStatus?id=0").await,
+                alias: "kettle".to_string(),
+                labels: vec![],
+            },
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/b/rpc/Switch.GetStatus?id=0").await,
+                alias: "tv".to_string(),
+                labels: vec![],
+            },
+        ];
+        let tenants = Tenants::new(&HashMap::from([
+            ("home-a".to_string(), crate::config::TenantConfig { plugs: vec!["kettle".to_string()], token_file: None }),
+        ])).map(Arc::new);
+        let state = AppState { tenants, ..state(plugs) };
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics/home-a").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="kettle"} 1.0"#), "{body}");
+        assert!(!body.contains(r#"hostname="tv""#));
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains(r#"power_watts{hostname="tv"} 1.0"#));
+
+        let response = call_service(&app, TestRequest::get().uri("/metrics/home-b").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_tenant_token_with_encoded_path() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![ShellySmartPlug {
+            url: fake_plug(&mut server, "/a/rpc/Switch.GetStatus?id=0").await,
+            alias: "kettle".to_string(),
+            labels: vec![],
+        }];
+        let tenants = Tenants::new(&HashMap::from([
+            ("home-a".to_string(), crate::config::TenantConfig { plugs: vec!["kettle".to_string()], token_file: None }),
+        ])).map(Arc::new);
+        let state = AppState { tenants, ..state(plugs) };
+        let authenticator = crate::auth::Authenticator::new(None, Some("s3cret".to_string()))
+            .with_tenant_tokens(HashMap::from([("home-a".to_string(), "a-token".to_string())]));
+        let app = init_service(App::new()
+            .app_data(web::Data::new(state))
+            .app_data(web::Data::new(authenticator))
+            .configure(configure)
+            .wrap(actix_web::middleware::from_fn(crate::auth::require_auth))
+        ).await;
+        let call = |uri: &str, token: Option<&str>| {
+            let mut request = TestRequest::get().uri(uri);
+            if let Some(token) = token {
+                request = request.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+            }
+            call_service(&app, request.to_request())
+        };
+
+        for uri in ["/metrics/home-a", "/metrics/home%2Da", "/metrics/%68ome-a"] {
+            assert_eq!(call(uri, None).await.status(), 401, "{uri}");
+            assert_eq!(call(uri, Some("a-token")).await.status(), 200, "{uri}");
+            assert_eq!(call(uri, Some("s3cret")).await.status(), 200, "{uri}");
+        }
+        // The devices of the tenant can't be read around its path
+        for uri in ["/probe?target=kettle", "/metrics?target=kettle", "/export"] {
+            assert_eq!(call(uri, None).await.status(), 401, "{uri}");
+            assert_eq!(call(uri, Some("a-token")).await.status(), 401, "{uri}");
+        }
+        assert_eq!(call("/probe?target=kettle", Some("s3cret")).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_addon_sensors() {
+        let mut server = Server::new_async().await;
+        server.mock("GET", "/k/rpc/Temperature.GetStatus?id=100")
+            .with_status(200)
+            .with_body(r#"{"id": 100, "tC": 21.5, "tF": 70.7}"#)
+            .create_async()
+            .await;
+        server.mock("GET", "/k/rpc/Temperature.GetStatus?id=101")
+            .with_status(200)
+            .with_body(r#"{"id": 101, "tC": null, "tF": null, "errors": ["disconnected"]}"#)
+            .create_async()
+            .await;
+        server.mock("GET", "/k/rpc/Humidity.GetStatus?id=100")
+            .with_status(200)
+            .with_body(r#"{"id": 100, "rh": 48.2}"#)
+            .create_async()
+            .await;
+        let plugs = vec![
+            ShellySmartPlug {
+                url: fake_plug(&mut server, "/k/rpc/Switch.GetStatus?id=0").await,
+                alias: "kitchen".to_string(),
+                labels: vec![],
+            },
+            ShellySmartPlug { url: fake_plug(&mut server, "/b").await, alias: "tv".to_string(), labels: vec![] },
+        ];
+        let sensors = AddonSensorConfig { temperature: vec![100, 101], humidity: vec![100] };
+        let addons = AddonSensors::new(HashMap::from([("kitchen".to_string(), sensors)])).map(Arc::new);
+        let state = AppState { addons, ..state(plugs) };
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"shelly_addon_temperature_celsius{hostname="kitchen",sensor="100"} 21.5"#));
+        assert!(!body.contains(r#"sensor="101""#));
+        assert!(body.contains(r#"shelly_addon_humidity_percent{hostname="kitchen",sensor="100"} 48.2"#));
+        assert!(body.contains(r#"power_watts{hostname="tv"} 1.0"#));
+    }
+
+    #[actix_web::test]
+    async fn test_scrape_cache() {
+        let mut server = Server::new_async().await;
+        let mock = server.mock("GET", "/a")
+            .with_status(200)
+            .with_body(json!({
+                "apower": 1.0,
+                "voltage": 2.0,
+                "current": 3.0,
+                "temperature": { "tC": 20.1, "tF": 68.2 },
+                "aenergy": { "total": 10.0 }
+            }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let plugs = vec![ShellySmartPlug { url: format!("{}/a", server.url()), alias: "kitchen".to_string(), labels: vec![] }];
+        let state = AppState { scrape_cache: Some(Arc::new(ScrapeCache::new(Duration::from_secs(60)))), ..state(plugs) };
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="kitchen"} 1.0"#));
+        assert!(body.contains("\nshelly_exporter_cache_hits_total 1.0"), "{body}");
+        mock.assert_async().await;
+
+        let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""), "{etag:?}");
+        let request = TestRequest::get().uri("/metrics").insert_header((header::IF_NONE_MATCH, etag.clone())).to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers().get(header::ETAG), Some(&etag));
+        let request = TestRequest::get().uri("/metrics").insert_header((header::IF_NONE_MATCH, "\"stale\"")).to_request();
+        assert_eq!(call_service(&app, request).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_targets() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+            ShellySmartPlug { url: fake_plug(&mut server, "/b").await, alias: "office".to_string(), labels: vec![] },
+            ShellySmartPlug { url: fake_plug(&mut server, "/c").await, alias: "garage".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+
+        let request = TestRequest::get().uri("/metrics?target=kitchen&target=office").to_request();
+        let body = String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="kitchen"} 1.0"#));
+        assert!(body.contains(r#"power_watts{hostname="office"} 1.0"#));
+        assert!(!body.contains("garage"));
+
+        let response = call_service(&app, TestRequest::get().uri("/metrics?target=kitchen&target=attic").to_request()).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_influx() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/influx").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.starts_with("shelly,hostname=kitchen,channel=0 power_watts=1.0,"));
+    }
+
+    #[actix_web::test]
+    async fn test_export() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/export").to_request()).await;
+        let actual: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(actual[0]["alias"], "kitchen");
+        assert_eq!(actual[0]["power_watts"], 1.0);
+
+        let response = call_service(&app, TestRequest::get().uri("/export?format=csv").to_request()).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "text/csv; charset=utf-8");
+        let body = String::from_utf8(actix_web::test::read_body(response).await.to_vec()).unwrap();
+        assert!(body.lines().nth(1).unwrap().starts_with("kitchen,"), "{body}");
+
+        for format in ["xml", "prometheus"] {
+            let response = call_service(&app, TestRequest::get().uri(&format!("/export?format={format}")).to_request()).await;
+            assert_eq!(response.status(), 400, "{format}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_updates_cached_metrics() {
+        let mut server = Server::new_async().await;
+        let plugs = vec![
+            ShellySmartPlug { url: fake_plug(&mut server, "/a").await, alias: "kitchen".to_string(), labels: vec![] },
+        ];
+        let state = AppState { serve_cached: true, ..state(plugs.clone()) };
+        state.cache.update_all(&state.client.get_all_statuses(&plugs).await.unwrap());
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        let notification = json!({
+            "src": "shellyplusplugs-80646fd17d72",
+            "method": "NotifyStatus",
+            "params": { "ts": 1735620900.43, "switch:0": { "id": 0, "apower": 42.5 } }
+        });
+        let accepted = call_service(&app, TestRequest::post()
+            .uri("/webhook?target=kitchen")
+            .set_json(&notification)
+            .to_request()
+        ).await;
+        assert_eq!(accepted.status(), 202);
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"power_watts{hostname="kitchen"} 42.5"#));
+        assert!(body.contains(r#"voltage{hostname="kitchen"} 2.0"#));
+
+        let unknown = call_service(&app, TestRequest::post()
+            .uri("/webhook?target=garage")
+            .set_json(&notification)
+            .to_request()
+        ).await;
+        assert_eq!(unknown.status(), 404);
+
+        let invalid = call_service(&app, TestRequest::post()
+            .uri("/webhook?target=kitchen")
+            .set_json(json!({ "params": {} }))
+            .to_request()
+        ).await;
+        assert_eq!(invalid.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_service_discovery() {
+        let plugs = vec![
+            ShellySmartPlug { url: "http://10.0.0.2/rpc/Switch.GetStatus?id=0".to_string(), alias: "kitchen".to_string(), labels: vec![] },
+            ShellySmartPlug { url: "http://10.0.0.3:8080/rpc/Switch.GetStatus?id=0".to_string(), alias: "10.0.0.3".to_string(), labels: vec![] },
+        ];
+        let app = init_service(App::new().app_data(web::Data::new(state(plugs))).configure(configure)).await;
+
+        let body = call_and_read_body(&app, TestRequest::get().uri("/sd").to_request()).await;
+        let actual: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(actual, json!([
+            {
+                "targets": ["10.0.0.2"],
+                "labels": {
+                    "__meta_shelly_alias": "kitchen",
+                    "__meta_shelly_url": "http://10.0.0.2/rpc/Switch.GetStatus?id=0"
+                }
+            },
+            {
+                "targets": ["10.0.0.3:8080"],
+                "labels": {
+                    "__meta_shelly_alias": "10.0.0.3",
+                    "__meta_shelly_url": "http://10.0.0.3:8080/rpc/Switch.GetStatus?id=0"
+                }
+            }
+        ]));
+    }
+}
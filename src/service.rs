@@ -0,0 +1,145 @@
+//! Running outside of containers and service managers with `Type=notify`: detaching from the
+//! terminal with a pid file on Unix, and as a native service on Windows. Both have to be set up
+//! before the async runtime starts.
+//!
+//! Ref: https://learn.microsoft.com/en-us/windows/win32/services/service-programs
+
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use log::{error, warn};
+
+
+/// Fork into the background, detached from the terminal, keeping the working directory so relative
+/// paths of the flags still resolve. Only the daemon returns, logs go to `log_file` or are dropped.
+/// The pid file is locked while the daemon runs, so a second daemon with the same file won't start.
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&Path>, log_file: Option<&Path>) -> Result<(), &'static str> {
+    let working_directory = std::env::current_dir().map_err(|err| {
+        error!("Failed to read the working directory - {err}");
+        "Unable to daemonize!"
+    })?;
+    let mut daemon = daemonize::Daemonize::new().working_directory(working_directory).umask(0o022);
+    if let Some(path) = pid_file {
+        daemon = daemon.pid_file(path);
+    }
+    if let Some(path) = log_file {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path).map_err(|err| {
+            error!("Failed to open log file `{}` - {err}", path.display());
+            "Unable to open log file!"
+        })?;
+        daemon = daemon.stderr(file);
+    }
+
+    daemon.start().map_err(|err| {
+        error!("Failed to daemonize - {err}");
+        "Unable to daemonize!"
+    })
+}
+
+/// Write the pid of the exporter, for supervisors which don't start it as a daemon
+#[cfg(unix)]
+pub fn write_pid_file(path: &Path) -> Result<(), &'static str> {
+    crate::energy::write_atomic(path, format!("{}\n", std::process::id()).as_bytes()).map_err(|err| {
+        error!("Failed to write pid file `{}` - {err}", path.display());
+        "Unable to write pid file!"
+    })
+}
+
+#[cfg(unix)]
+pub fn remove_pid_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        warn!("Failed to remove pid file `{}` - {err}", path.display());
+    }
+}
+
+
+#[cfg(windows)]
+pub mod windows {
+    //! The service control manager starts the exporter with `serve --windows-service`, which hands
+    //! the main thread to the service dispatcher. Stop and shutdown requests trigger the same
+    //! graceful shutdown as Ctrl-C.
+
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use log::{error, info};
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use crate::shutdown;
+
+
+    /// Name of the service and of its event log source
+    pub const SERVICE_NAME: &str = "ShellyExporter";
+
+
+    type Run = Box<dyn FnOnce() -> std::io::Result<()> + Send>;
+
+    /// What the service runs, the dispatcher only takes a plain function
+    static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+
+    /// Send the logs to the Application event log, the service has no console. The source is
+    /// registered with `New-EventLog -LogName Application -Source ShellyExporter`.
+    pub fn init_event_log(level: log::Level) -> Result<(), &'static str> {
+        eventlog::init(SERVICE_NAME, level).map_err(|_| "Unable to log to the event log!")
+    }
+
+    /// Run `run` as the service, until the service control manager stops it
+    pub fn run(run: impl FnOnce() -> std::io::Result<()> + Send + 'static) -> std::io::Result<()> {
+        *RUN.lock().unwrap() = Some(Box::new(run));
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(std::io::Error::other)
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(err) = run_service() {
+            error!("Windows service failed - {err}");
+        }
+    }
+
+    fn run_service() -> windows_service::Result<()> {
+        let status_handle = service_control_handler::register(SERVICE_NAME, |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                shutdown::request();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+        status_handle.set_service_status(status(ServiceState::Running, 0))?;
+        info!("Windows service started");
+        let run = RUN.lock().unwrap().take();
+        let exit_code = match run.map(|run| run()) {
+            Some(Ok(())) => 0,
+            Some(Err(err)) => {
+                error!("Exporter failed - {err}");
+                1
+            }
+            None => 1,
+        };
+        info!("Windows service stopped");
+        status_handle.set_service_status(status(ServiceState::Stopped, exit_code))
+    }
+
+    fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: match state {
+                ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+                _ => ServiceControlAccept::empty(),
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Process signal handling. SIGINT and SIGTERM both trigger a graceful shutdown: new scrapes are
+//! refused, in-flight device calls get a grace period to finish and state is flushed to disk.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::info;
+use tokio::sync::Notify;
+
+
+pub const DEFAULT_GRACE_PERIOD_SECS: u64 = 30;
+
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static STOP: Notify = Notify::const_new();
+
+
+/// Shut down like on SIGTERM, for stop requests which don't come as a signal, e.g. from the Windows
+/// service control manager
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+    STOP.notify_waiters();
+}
+
+/// Resolves once the process was asked to stop, with SIGINT (Ctrl-C), SIGTERM or `request`
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+            _ = requested() => info!("Received a stop request, shutting down"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C, shutting down"),
+        _ = requested() => info!("Received a stop request, shutting down"),
+    }
+}
+
+async fn requested() {
+    loop {
+        // Registered before checking the flag, so a request in between still wakes it
+        let notified = STOP.notified();
+        if REQUESTED.load(Ordering::SeqCst) {
+            return;
+        }
+        notified.await;
+    }
+}
@@ -0,0 +1,431 @@
+use serde::{Deserialize, Serialize};
+
+
+/// Response of the `Switch.GetStatus` RPC method.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Switch/#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SwitchStatus {
+    #[serde(default)]
+    pub id: u8,
+    pub output: Option<bool>,
+    pub apower: f64,
+    pub voltage: f64,
+    pub current: f64,
+    pub temperature: Temperature,
+    pub aenergy: EnergyCounter,
+    /// Only filled in when the status comes from `Shelly.GetStatus`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sys: Option<SysStatus>,
+    /// Only filled in when the status comes from `Shelly.GetStatus`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wifi: Option<WifiStatus>,
+    /// Grid frequency in hertz, not reported by every model and firmware
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freq: Option<f64>,
+    /// Power factor, not reported by every model and firmware
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pf: Option<f64>,
+}
+
+
+/// Response of the `Shelly.GetStatus` RPC method, the status of every component of the device in
+/// a single round trip. Only the components the exporter reads are kept.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Shelly#shellygetstatus
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ShellyStatus {
+    #[serde(rename = "switch:0")]
+    pub switch: SwitchStatus,
+    pub sys: Option<SysStatus>,
+    pub wifi: Option<WifiStatus>,
+}
+
+
+/// Status of the `Sys` component.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Sys#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SysStatus {
+    /// Seconds since the device booted
+    pub uptime: u64,
+    /// Free RAM in bytes
+    pub ram_free: u64,
+    /// Whether a changed setting only applies after a reboot
+    #[serde(default)]
+    pub restart_required: bool,
+}
+
+
+/// Status of the `WiFi` component.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/WiFi#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WifiStatus {
+    pub ssid: Option<String>,
+    /// Signal strength in dBm, `None` while disconnected
+    pub rssi: Option<f64>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Temperature {
+    #[serde(rename = "tC")]
+    pub celsius: f64,
+    #[serde(rename = "tF")]
+    pub fahrenheit: f64,
+}
+
+
+/// Active energy counter, `total` is in watt-hours
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EnergyCounter {
+    pub total: f64,
+    #[serde(default)]
+    pub by_minute: Vec<f64>,
+    pub minute_ts: Option<i64>,
+}
+
+
+/// Response of the `Shelly.GetDeviceInfo` RPC method.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Shelly#shellygetdeviceinfo
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    /// Device id, e.g. `shellyplusplugs-80646fd17d72`
+    pub id: String,
+    /// Name set in the Shelly app, `None` until one is set
+    pub name: Option<String>,
+    pub model: Option<String>,
+    #[serde(rename = "gen")]
+    pub generation: Option<u8>,
+    #[serde(rename = "ver")]
+    pub firmware: Option<String>,
+    pub app: Option<String>,
+}
+
+
+/// Response of the `Input.GetStatus` RPC method.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Input#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct InputStatus {
+    pub id: u8,
+    /// Whether the input is on, `None` for inputs of the `button` type, which have no state
+    pub state: Option<bool>,
+}
+
+
+/// Response of the `Temperature.GetStatus` RPC method, for sensors of the Plus Add-On.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Temperature#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AddonTemperature {
+    pub id: u16,
+    /// `None` while the sensor is disconnected
+    #[serde(rename = "tC")]
+    pub celsius: Option<f64>,
+}
+
+
+/// Response of the `Humidity.GetStatus` RPC method, for sensors of the Plus Add-On.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Humidity#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AddonHumidity {
+    pub id: u16,
+    /// Relative humidity in percent, `None` while the sensor is disconnected
+    pub rh: Option<f64>,
+}
+
+
+/// Readings of the configured Plus Add-On sensors of a device
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AddonReading {
+    pub temperatures: Vec<AddonTemperature>,
+    pub humidities: Vec<AddonHumidity>,
+}
+
+
+/// Entry of the response of the `Script.List` RPC method.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Script#scriptlist
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScriptInfo {
+    pub id: u16,
+    #[serde(default)]
+    pub name: String,
+    /// Whether the script is started when the device boots
+    pub enable: bool,
+    pub running: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScriptList {
+    pub scripts: Vec<ScriptInfo>,
+}
+
+
+/// Response of the `Script.GetStatus` RPC method.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Script#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScriptStatus {
+    pub id: u16,
+    pub running: bool,
+    /// Why the script stopped, e.g. `crashed` or `out_of_memory`, only sent when it did
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+
+/// Entry of the response of the `Schedule.List` RPC method.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Schedule#schedulelist
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScheduleJob {
+    pub id: u16,
+    pub enable: bool,
+    /// Cron like expression of when the job runs, e.g. `0 0 22 * * FRI`
+    pub timespec: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScheduleList {
+    pub jobs: Vec<ScheduleJob>,
+}
+
+
+/// The scripts and schedules configured on a device
+#[derive(Clone, Debug, PartialEq)]
+pub struct Automations {
+    pub scripts: Vec<(ScriptInfo, ScriptStatus)>,
+    pub schedules: Vec<ScheduleJob>,
+}
+
+
+/// Response of `Shelly.CheckForUpdate`, a channel is only there when it has a newer version
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/Shelly#shellycheckforupdate
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct AvailableUpdates {
+    pub stable: Option<FirmwareVersion>,
+    pub beta: Option<FirmwareVersion>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FirmwareVersion {
+    pub version: String,
+}
+
+
+/// Response of the `EM.GetStatus` RPC method of the 3-phase energy meters (Pro 3EM, 3EM-63).
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/EM#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EmStatus {
+    #[serde(default)]
+    pub id: u8,
+    pub a_current: f64,
+    pub a_voltage: f64,
+    pub a_act_power: f64,
+    pub a_aprt_power: f64,
+    pub a_pf: f64,
+    pub b_current: f64,
+    pub b_voltage: f64,
+    pub b_act_power: f64,
+    pub b_aprt_power: f64,
+    pub b_pf: f64,
+    pub c_current: f64,
+    pub c_voltage: f64,
+    pub c_act_power: f64,
+    pub c_aprt_power: f64,
+    pub c_pf: f64,
+    /// Only measured on devices with a neutral current sensor
+    pub n_current: Option<f64>,
+}
+
+
+/// Response of the `EMData.GetStatus` RPC method, energy counters are in watt-hours.
+///
+/// Ref: https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/EMData#status
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EmDataStatus {
+    #[serde(default)]
+    pub id: u8,
+    pub a_total_act_energy: f64,
+    pub b_total_act_energy: f64,
+    pub c_total_act_energy: f64,
+}
+
+
+/// Readings of a single phase of an energy meter
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhaseReading {
+    pub voltage: f64,
+    pub current: f64,
+    pub active_power: f64,
+    pub apparent_power: f64,
+    pub power_factor: f64,
+    pub total_active_energy: f64,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmReading {
+    pub status: EmStatus,
+    pub data: EmDataStatus,
+}
+
+impl EmReading {
+    /// Readings of phases `a`, `b` and `c`
+    pub fn phases(&self) -> [(&'static str, PhaseReading); 3] {
+        let (status, data) = (&self.status, &self.data);
+        [
+            ("a", PhaseReading {
+                voltage: status.a_voltage,
+                current: status.a_current,
+                active_power: status.a_act_power,
+                apparent_power: status.a_aprt_power,
+                power_factor: status.a_pf,
+                total_active_energy: data.a_total_act_energy,
+            }),
+            ("b", PhaseReading {
+                voltage: status.b_voltage,
+                current: status.b_current,
+                active_power: status.b_act_power,
+                apparent_power: status.b_aprt_power,
+                power_factor: status.b_pf,
+                total_active_energy: data.b_total_act_energy,
+            }),
+            ("c", PhaseReading {
+                voltage: status.c_voltage,
+                current: status.c_current,
+                active_power: status.c_act_power,
+                apparent_power: status.c_aprt_power,
+                power_factor: status.c_pf,
+                total_active_energy: data.c_total_act_energy,
+            }),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_deserialize_switch_status() {
+        let raw = json!({
+            "id": 0,
+            "source": "HTTP",
+            "output": true,
+            "apower": 114.2,
+            "voltage": 121.5,
+            "current": 1.018,
+            "aenergy": {
+                "total": 65115.638,
+                "by_minute": [1858.011, 1907.853, 1903.627],
+                "minute_ts": 1735620900
+            },
+            "temperature": {
+                "tC": 46.4,
+                "tF": 115.5
+            }
+        });
+
+        let actual: SwitchStatus = serde_json::from_value(raw).unwrap();
+
+        assert_eq!(actual.output, Some(true));
+        assert_eq!(actual.apower, 114.2);
+        assert_eq!(actual.temperature.celsius, 46.4);
+        assert_eq!(actual.temperature.fahrenheit, 115.5);
+        assert_eq!(actual.aenergy.total, 65115.638);
+        assert_eq!(actual.aenergy.by_minute.len(), 3);
+        assert_eq!(actual.aenergy.minute_ts, Some(1735620900));
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let status = SwitchStatus {
+            id: 0,
+            output: None,
+            apower: 1.0,
+            voltage: 2.0,
+            current: 3.0,
+            temperature: Temperature { celsius: 20.1, fahrenheit: 68.2 },
+            aenergy: EnergyCounter { total: 10.0, by_minute: vec![], minute_ts: None },
+            sys: None,
+            wifi: None,
+            freq: None,
+            pf: None,
+        };
+
+        let raw = serde_json::to_value(&status).unwrap();
+
+        assert_eq!(raw["temperature"]["tC"], 20.1);
+        assert_eq!(serde_json::from_value::<SwitchStatus>(raw).unwrap(), status);
+    }
+
+    #[test]
+    fn test_deserialize_missing_fields() {
+        let raw = json!({ "apower": 1.0 });
+
+        assert!(serde_json::from_value::<SwitchStatus>(raw).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_device_info() {
+        let raw = json!({
+            "name": null,
+            "id": "shellyplusplugs-80646fd17d72",
+            "mac": "80646FD17D72",
+            "slot": 1,
+            "model": "SNPL-00116US",
+            "gen": 2,
+            "fw_id": "20241011-114455/1.4.4-g6d2a586",
+            "ver": "1.4.4",
+            "app": "PlusPlugUS",
+            "auth_en": false,
+            "auth_domain": null
+        });
+
+        let actual: DeviceInfo = serde_json::from_value(raw).unwrap();
+
+        assert_eq!(actual.id, "shellyplusplugs-80646fd17d72");
+        assert_eq!(actual.name, None);
+        assert_eq!(actual.generation, Some(2));
+        assert_eq!(actual.firmware, Some("1.4.4".to_string()));
+    }
+
+    #[test]
+    fn test_em_phases() {
+        let status: EmStatus = serde_json::from_value(json!({
+            "id": 0,
+            "a_current": 4.029, "a_voltage": 236.1, "a_act_power": 951.2, "a_aprt_power": 951.9, "a_pf": 1, "a_freq": 50,
+            "b_current": 4.027, "b_voltage": 236.201, "b_act_power": -951.1, "b_aprt_power": 951.8, "b_pf": 1,
+            "c_current": 3.03, "c_voltage": 236.402, "c_act_power": 715.4, "c_aprt_power": 716.2, "c_pf": 1,
+            "n_current": null,
+            "total_current": 11.029,
+            "total_act_power": 2484.078,
+            "total_aprt_power": 2486.66,
+            "user_calibrated_phase": []
+        })).unwrap();
+        let data: EmDataStatus = serde_json::from_value(json!({
+            "id": 0,
+            "a_total_act_energy": 2.03, "a_total_act_ret_energy": 0,
+            "b_total_act_energy": 0, "b_total_act_ret_energy": 2.03,
+            "c_total_act_energy": 1.53, "c_total_act_ret_energy": 0,
+            "total_act": 3.56, "total_act_ret": 2.03
+        })).unwrap();
+
+        let phases = EmReading { status, data }.phases();
+
+        assert_eq!(phases[1].0, "b");
+        assert_eq!(phases[1].1.active_power, -951.1);
+        assert_eq!(phases[2].1.total_active_energy, 1.53);
+        assert_eq!(phases[0].1.power_factor, 1.0);
+    }
+}
@@ -0,0 +1,188 @@
+//! systemd integration: readiness and watchdog notifications for `Type=notify` services, and
+//! listening sockets passed by socket activation. Everything is a no-op outside of systemd.
+//!
+//! Ref: https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+//! Ref: https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+
+use std::net::TcpListener;
+use std::time::Duration;
+use log::{debug, info, warn};
+use tokio::sync::broadcast;
+
+use crate::poller::{self, Readings};
+
+
+/// First file descriptor passed by socket activation
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+
+/// Tell systemd the exporter is up, `Type=notify` units stay in `activating` until then
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+pub fn notify_watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// Send a state change to the socket in `NOTIFY_SOCKET`, if any. Failures are logged, the exporter
+/// runs fine without systemd.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Some(path) = std::env::var_os("NOTIFY_SOCKET") {
+        match notify_socket(&path, state) {
+            Ok(()) => debug!("Notified systemd of {state}"),
+            Err(err) => warn!("Failed to notify systemd of {state} - {err}"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn notify_socket(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(std::io::Error::other("Abstract sockets are only supported on Linux")),
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
+
+
+/// How often systemd expects a `WATCHDOG=1`, from `WATCHDOG_USEC` when it's meant for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok();
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    parse_watchdog_interval(usec.as_deref(), pid.as_deref(), std::process::id())
+}
+
+fn parse_watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec))
+}
+
+
+/// Ping the watchdog after every background poll which returned readings. A poll where no plug
+/// answered sends nothing, so systemd restarts the exporter if polling stays broken.
+pub async fn run_watchdog(interval: Duration, poll_interval: Duration, mut readings: broadcast::Receiver<Readings>) {
+    if poll_interval >= interval {
+        warn!("The poll interval of {poll_interval:?} exceeds the systemd watchdog of {interval:?}, expect restarts");
+    }
+    info!("Pinging the systemd watchdog after every successful poll");
+
+    while let Some(readings) = poller::next_readings(&mut readings).await {
+        if !readings.is_empty() {
+            notify_watchdog();
+        }
+    }
+}
+
+/// Ping the watchdog at half its interval, for when there is no background poll to tie it to
+pub async fn run_watchdog_timer(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval / 2);
+    loop {
+        ticker.tick().await;
+        notify_watchdog();
+    }
+}
+
+
+/// The TCP sockets passed by systemd socket activation, empty when not socket activated
+pub fn listeners() -> std::io::Result<Vec<TcpListener>> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::FromRawFd;
+
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        let count = parse_listen_fds(pid.as_deref(), count.as_deref(), std::process::id());
+
+        let listeners = (LISTEN_FDS_START..LISTEN_FDS_START + count as i32)
+            .map(|fd| {
+                // Safety: systemd hands these descriptors over to this process, and nothing else
+                // in it takes ownership of them
+                let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                // Fails for anything but a TCP socket, e.g. a `ListenDatagram=` or a unix socket
+                listener.local_addr()?;
+                listener.set_nonblocking(true)?;
+                Ok(listener)
+            })
+            .collect::<std::io::Result<Vec<TcpListener>>>()?;
+        if !listeners.is_empty() {
+            info!("Listening on {} socket(s) passed by systemd", listeners.len());
+        }
+        Ok(listeners)
+    }
+
+    #[cfg(not(unix))]
+    Ok(vec![])
+}
+
+#[cfg(unix)]
+fn parse_listen_fds(pid: Option<&str>, count: Option<&str>, own_pid: u32) -> usize {
+    match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) if pid == own_pid => count.and_then(|count| count.parse().ok()).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_notify_socket() {
+        let path = std::env::temp_dir().join(format!("shelly_notify_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buffer = [0; 16];
+        let received = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..received], b"READY=1");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_watchdog_interval() {
+        assert_eq!(parse_watchdog_interval(Some("30000000"), None, 42), Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog_interval(Some("30000000"), Some("42"), 42), Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog_interval(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(parse_watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(parse_listen_fds(Some("42"), Some("2"), 42), 2);
+        // Inherited from a parent which was socket activated
+        assert_eq!(parse_listen_fds(Some("7"), Some("2"), 42), 0);
+        assert_eq!(parse_listen_fds(None, Some("2"), 42), 0);
+    }
+}
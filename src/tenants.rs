@@ -0,0 +1,62 @@
+//! Devices grouped per tenant in the config file, e.g. one household of several sharing the
+//! exporter. Each tenant's devices are served on `/metrics/<tenant>`, `/metrics` keeps serving
+//! every device.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::client::ShellySmartPlug;
+use crate::config::TenantConfig;
+
+
+#[derive(Debug)]
+pub struct Tenants {
+    /// IPs and aliases of the devices keyed by tenant
+    tenants: HashMap<String, HashSet<String>>,
+}
+
+impl Tenants {
+    /// `None` when no tenants are configured
+    pub fn new(tenants: &HashMap<String, TenantConfig>) -> Option<Tenants> {
+        if tenants.is_empty() {
+            return None;
+        }
+
+        let tenants = tenants.iter()
+            .map(|(name, tenant)| (name.clone(), tenant.plugs.iter().cloned().collect()))
+            .collect();
+        Some(Tenants { tenants })
+    }
+
+    /// The devices of the tenant, `None` for unknown tenants
+    pub fn devices_of(&self, tenant: &str, devices: &[ShellySmartPlug]) -> Option<Vec<ShellySmartPlug>> {
+        let members = self.tenants.get(tenant)?;
+        let devices = devices.iter()
+            .filter(|device| members.contains(&device.alias) || members.contains(&device.target()))
+            .cloned()
+            .collect();
+        Some(devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devices_of() {
+        let tenants = Tenants::new(&HashMap::from([
+            ("home-a".to_string(), TenantConfig { plugs: vec!["kettle".to_string(), "10.0.0.3".to_string()], token_file: None }),
+            ("home-b".to_string(), TenantConfig { plugs: vec!["10.0.0.4".to_string()], token_file: None }),
+        ])).unwrap();
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let devices = vec![kettle, ShellySmartPlug::from_address("10.0.0.3").unwrap(), ShellySmartPlug::from_address("10.0.0.4").unwrap()];
+
+        let aliases = |tenant| tenants.devices_of(tenant, &devices)
+            .map(|devices| devices.into_iter().map(|device| device.alias).collect::<Vec<_>>());
+
+        assert_eq!(aliases("home-a"), Some(vec!["kettle".to_string(), "10.0.0.3".to_string()]));
+        assert_eq!(aliases("home-b"), Some(vec!["10.0.0.4".to_string()]));
+        assert_eq!(aliases("home-c"), None);
+        assert!(Tenants::new(&HashMap::new()).is_none());
+    }
+}
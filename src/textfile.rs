@@ -0,0 +1,61 @@
+//! Textfile collector output, for hosts already running node_exporter. Every background poll's
+//! metrics are written to a `.prom` file which node_exporter picks up on its next scrape.
+//!
+//! Ref: https://github.com/prometheus/node_exporter#textfile-collector
+
+use std::path::{Path, PathBuf};
+use log::{error, info};
+use tokio::sync::broadcast;
+
+use crate::energy::write_atomic;
+use crate::metrics::{self, Format, MetricFamily};
+use crate::poller::{self, Readings};
+use crate::server::AppState;
+
+
+/// Write every poll's metrics to `path` until the poller goes away
+pub async fn run(state: AppState, path: PathBuf, mut readings: broadcast::Receiver<Readings>) {
+    info!("Writing metrics to `{}` after every poll", path.display());
+
+    while let Some(readings) = poller::next_readings(&mut readings).await {
+        // A failed write is logged and retried after the next poll
+        let _ = write_metrics(&path, &state.collect_polled(&readings));
+    }
+}
+
+/// Replace the file atomically, so node_exporter never reads a half written one
+pub fn write_metrics(path: &Path, families: &[MetricFamily]) -> Result<(), &'static str> {
+    let body = metrics::encode(families, Format::Prometheus);
+    write_atomic(path, body.as_bytes()).map_err(|err| {
+        error!("Failed to write textfile `{}` - {err}", path.display());
+        "Unable to write textfile!"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_metrics() {
+        let path = std::env::temp_dir().join(format!("shelly_textfile_{}.prom", std::process::id()));
+        let mut family = MetricFamily::gauge("power_watts", "Instantaneous active power in watts");
+        family.push(vec![("hostname".to_string(), "kettle".to_string())], 1.5);
+
+        write_metrics(&path, &[family]).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(),
+            "# HELP power_watts Instantaneous active power in watts\n\
+            # TYPE power_watts gauge\n\
+            power_watts{hostname=\"kettle\"} 1.5\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_metrics_to_missing_dir() {
+        let path = Path::new("/i/do/not/exist/shelly.prom");
+
+        assert_eq!(write_metrics(path, &[]), Err("Unable to write textfile!"));
+    }
+}
@@ -0,0 +1,123 @@
+//! Per plug limits from the config file, flagged as `shelly_threshold_exceeded` and logged when a
+//! reading crosses them, for setups without an alerting rule per device.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::client::ShellySmartPlug;
+use crate::config::ThresholdConfig;
+use crate::metrics::MetricFamily;
+use crate::status::SwitchStatus;
+
+
+#[derive(Debug)]
+pub struct ThresholdTracker {
+    /// Keyed by IP or alias, like the config file
+    thresholds: HashMap<String, ThresholdConfig>,
+    /// `(alias, threshold)` currently exceeded, to only log when that changes
+    exceeded: Mutex<HashSet<(String, &'static str)>>,
+}
+
+impl ThresholdTracker {
+    /// `None` when no thresholds are configured
+    pub fn new(thresholds: HashMap<String, ThresholdConfig>) -> Option<ThresholdTracker> {
+        match thresholds.is_empty() {
+            true => None,
+            false => Some(ThresholdTracker { thresholds, exceeded: Mutex::new(HashSet::new()) }),
+        }
+    }
+
+    /// Thresholds of the plug, the alias entry overrides the IP one per field
+    pub fn thresholds_of(&self, plug: &ShellySmartPlug) -> ThresholdConfig {
+        let by_ip = self.thresholds.get(&plug.target()).copied().unwrap_or_default();
+        let by_alias = self.thresholds.get(&plug.alias).copied().unwrap_or_default();
+        ThresholdConfig {
+            max_power_watts: by_alias.max_power_watts.or(by_ip.max_power_watts),
+            max_temperature_c: by_alias.max_temperature_c.or(by_ip.max_temperature_c),
+        }
+    }
+
+    /// Check every reading against the thresholds of its plug, plugs without any are left out
+    pub fn collect(&self, readings: &[(ShellySmartPlug, SwitchStatus)]) -> MetricFamily {
+        let mut family = MetricFamily::gauge(
+            "shelly_threshold_exceeded",
+            "Whether the latest reading crossed the threshold configured for the plug"
+        );
+
+        for (plug, status) in readings {
+            let thresholds = self.thresholds_of(plug);
+            let checks = [
+                ("max_power_watts", thresholds.max_power_watts, status.apower),
+                ("max_temperature_c", thresholds.max_temperature_c, status.temperature.celsius),
+            ];
+
+            for (threshold, limit, value) in checks {
+                let Some(limit) = limit else { continue };
+                let exceeded = value > limit;
+                self.record(plug, threshold, exceeded, value, limit);
+
+                let mut labels = plug.metric_labels();
+                labels.push(("threshold".to_string(), threshold.to_string()));
+                family.push(labels, if exceeded { 1.0 } else { 0.0 });
+            }
+        }
+
+        family
+    }
+
+    fn record(&self, plug: &ShellySmartPlug, threshold: &'static str, exceeded: bool, value: f64, limit: f64) {
+        let key = (plug.alias.clone(), threshold);
+        let mut current = self.exceeded.lock().unwrap();
+
+        if !exceeded {
+            if current.remove(&key) {
+                info!(alias = %plug.alias, threshold, value, limit, "Reading is back within the threshold");
+            }
+        } else if current.insert(key) {
+            warn!(alias = %plug.alias, threshold, value, limit, "Reading crossed the threshold");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reading(apower: f64, celsius: f64) -> SwitchStatus {
+        serde_json::from_value(json!({
+            "apower": apower,
+            "voltage": 230.0,
+            "current": 1.0,
+            "temperature": { "tC": celsius, "tF": 0.0 },
+            "aenergy": { "total": 10.0 }
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_collect() {
+        let tracker = ThresholdTracker::new(HashMap::from([
+            ("10.0.0.2".to_string(), ThresholdConfig { max_power_watts: Some(1000.0), max_temperature_c: Some(60.0) }),
+            ("kettle".to_string(), ThresholdConfig { max_power_watts: Some(2200.0), max_temperature_c: None }),
+        ])).unwrap();
+        let kettle = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address("10.0.0.2").unwrap() };
+        let lamp = ShellySmartPlug::from_address("10.0.0.3").unwrap();
+
+        let actual = tracker.collect(&[(kettle.clone(), reading(1500.0, 65.0)), (lamp, reading(5000.0, 90.0))]);
+
+        let samples: Vec<(String, f64)> = actual.samples.iter()
+            .map(|sample| (sample.labels[1].1.clone(), sample.value))
+            .collect();
+        assert_eq!(samples, vec![("max_power_watts".to_string(), 0.0), ("max_temperature_c".to_string(), 1.0)]);
+        assert!(tracker.exceeded.lock().unwrap().contains(&("kettle".to_string(), "max_temperature_c")));
+
+        tracker.collect(&[(kettle, reading(1500.0, 40.0))]);
+        assert!(tracker.exceeded.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_new_without_thresholds() {
+        assert!(ThresholdTracker::new(HashMap::new()).is_none());
+    }
+}
@@ -0,0 +1,192 @@
+//! TLS termination for the exporter's web server, with hot reloading of rotated certificates.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use log::{error, info};
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+
+
+/// Serves the most recently loaded certificate, swapped out in place when the files on disk change
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    loaded_mtimes: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<ReloadingCertResolver, &'static str> {
+        let certified_key = load_certified_key(cert_path, key_path)?;
+
+        Ok(ReloadingCertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(certified_key)),
+            loaded_mtimes: RwLock::new(modified_times(cert_path, key_path)),
+        })
+    }
+
+    /// Reload the certificate if either file was modified since the last load. Returns `true` on
+    /// a successful reload. A broken certificate keeps the previous one in service.
+    pub fn reload_if_changed(&self) -> bool {
+        let mtimes = modified_times(&self.cert_path, &self.key_path);
+        if *self.loaded_mtimes.read().unwrap() == mtimes {
+            return false;
+        }
+
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(certified_key) => {
+                *self.current.write().unwrap() = Arc::new(certified_key);
+                *self.loaded_mtimes.write().unwrap() = mtimes;
+                info!("Reloaded TLS certificate from `{}`", self.cert_path.display());
+                true
+            }
+            Err(err) => {
+                error!("Failed to reload TLS certificate, continuing with the previous one - {err}");
+                false
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+
+pub fn server_config(resolver: Arc<ReloadingCertResolver>) -> ServerConfig {
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("The ring provider supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(resolver)
+}
+
+/// Periodically check the certificate files for rotation in the background
+pub fn spawn_reload_task(resolver: Arc<ReloadingCertResolver>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately, the certificate was just loaded
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            resolver.reload_if_changed();
+        }
+    });
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, &'static str> {
+    let certs = match File::open(cert_path) {
+        Ok(file) => rustls_pemfile::certs(&mut BufReader::new(file))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| {
+                error!("Failed to parse certificate `{}` - {err}", cert_path.display());
+                "Invalid TLS certificate!"
+            })?,
+        Err(err) => {
+            error!("Failed to open certificate `{}` - {err}", cert_path.display());
+            return Err("Unable to read TLS certificate!");
+        }
+    };
+    if certs.is_empty() {
+        error!("No certificates found in `{}`", cert_path.display());
+        return Err("Invalid TLS certificate!");
+    }
+
+    let key = match File::open(key_path) {
+        Ok(file) => match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                error!("No private key found in `{}`", key_path.display());
+                return Err("Invalid TLS private key!");
+            }
+            Err(err) => {
+                error!("Failed to parse private key `{}` - {err}", key_path.display());
+                return Err("Invalid TLS private key!");
+            }
+        },
+        Err(err) => {
+            error!("Failed to open private key `{}` - {err}", key_path.display());
+            return Err("Unable to read TLS private key!");
+        }
+    };
+
+    let signing_key = ring::sign::any_supported_type(&key).map_err(|err| {
+        error!("Unsupported private key type in `{}` - {err}", key_path.display());
+        "Invalid TLS private key!"
+    })?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified_times(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    (mtime(cert_path), mtime(key_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &Path, hostname: &str) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shelly_tls_{name}_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_and_reload() {
+        let dir = temp_dir("reload");
+        let (cert_path, key_path) = write_cert(&dir, "first.local");
+        let resolver = ReloadingCertResolver::new(&cert_path, &key_path).unwrap();
+        let first = resolver.current.read().unwrap().cert[0].clone();
+
+        // Nothing changed on disk yet
+        assert!(!resolver.reload_if_changed());
+
+        // Rotate the cert, forcing a different mtime in case the filesystem has coarse timestamps
+        write_cert(&dir, "second.local");
+        *resolver.loaded_mtimes.write().unwrap() = (None, None);
+        assert!(resolver.reload_if_changed());
+        assert_ne!(resolver.current.read().unwrap().cert[0], first);
+
+        // A broken rotation keeps the last good certificate
+        std::fs::write(&cert_path, "garbage").unwrap();
+        *resolver.loaded_mtimes.write().unwrap() = (None, None);
+        assert!(!resolver.reload_if_changed());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid_files() {
+        let dir = temp_dir("invalid");
+        let (cert_path, key_path) = write_cert(&dir, "test.local");
+        let missing = dir.join("missing.pem");
+
+        assert_eq!(load_certified_key(&missing, &key_path).unwrap_err(), "Unable to read TLS certificate!");
+        assert_eq!(load_certified_key(&cert_path, &missing).unwrap_err(), "Unable to read TLS private key!");
+        assert_eq!(load_certified_key(&key_path, &key_path).unwrap_err(), "Invalid TLS certificate!");
+        assert_eq!(load_certified_key(&cert_path, &cert_path).unwrap_err(), "Invalid TLS private key!");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
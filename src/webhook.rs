@@ -0,0 +1,60 @@
+//! Parsing of the status notifications Gen2 devices push to the exporter.
+//!
+//! Ref: https://shelly-api-docs.shelly.cloud/gen2/General/Notifications
+
+use serde_json::Value;
+
+
+const SWITCH_COMPONENT: &str = "switch:0";
+
+
+/// Extract the (partial) switch status from a `NotifyStatus` / `NotifyFullStatus` frame
+pub fn switch_patch(notification: &Value) -> Result<Option<&Value>, &'static str> {
+    let method = notification["method"].as_str().ok_or("Notification is missing the method!")?;
+    if method != "NotifyStatus" && method != "NotifyFullStatus" {
+        // Events like button presses don't carry any status
+        return Ok(None);
+    }
+
+    let params = notification.get("params")
+        .filter(|params| params.is_object())
+        .ok_or("Notification is missing the params!")?;
+
+    Ok(params.get(SWITCH_COMPONENT).filter(|patch| patch.is_object()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_switch_patch() {
+        let notification = json!({
+            "src": "shellyplusplugs-80646fd17d72",
+            "dst": "exporter",
+            "method": "NotifyStatus",
+            "params": {
+                "ts": 1735620900.43,
+                "switch:0": { "id": 0, "apower": 12.3 }
+            }
+        });
+
+        assert_eq!(switch_patch(&notification), Ok(Some(&json!({ "id": 0, "apower": 12.3 }))));
+    }
+
+    #[test]
+    fn test_switch_patch_without_switch() {
+        let wifi_only = json!({ "method": "NotifyStatus", "params": { "wifi": { "rssi": -60 } } });
+        let event = json!({ "method": "NotifyEvent", "params": { "events": [] } });
+
+        assert_eq!(switch_patch(&wifi_only), Ok(None));
+        assert_eq!(switch_patch(&event), Ok(None));
+    }
+
+    #[test]
+    fn test_switch_patch_invalid() {
+        assert_eq!(switch_patch(&json!({ "params": {} })), Err("Notification is missing the method!"));
+        assert_eq!(switch_patch(&json!({ "method": "NotifyStatus" })), Err("Notification is missing the params!"));
+    }
+}
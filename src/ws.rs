@@ -0,0 +1,456 @@
+//! Persistent WebSocket RPC transport. One connection is kept open per device and RPC frames
+//! are multiplexed over it, avoiding a TCP handshake per scrape and noticing immediately when a
+//! device drops off the network. Devices reached over HTTPS are connected with `wss://`, verified
+//! like their HTTP requests would be.
+//!
+//! Ref: https://shelly-api-docs.shelly.cloud/gen2/General/RPCChannels#websocket
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::Connector;
+use tracing::{error, info, warn};
+
+use crate::client::{DeviceTls, ShellySmartPlug};
+use crate::error::ShellyError;
+use crate::health::HealthTracker;
+
+
+const SOURCE: &str = "shelly_smartplug_exporter";
+
+
+/// Alias of the device and where its reply goes
+type Reply = (String, oneshot::Sender<Result<Value, ShellyError>>);
+
+
+struct Request {
+    method: String,
+    params: Value,
+    reply: Reply,
+}
+
+
+#[derive(Clone)]
+struct Connection {
+    requests: mpsc::Sender<Request>,
+    connected: Arc<AtomicBool>,
+}
+
+
+/// WebSocket connections keyed by their `ws://host/rpc` URL, (re)connected on demand
+pub struct WsPool {
+    connections: Mutex<HashMap<String, Connection>>,
+    timeout: Duration,
+    next_id: Arc<AtomicU64>,
+    /// Plugs whose connection drops are recorded as down right away
+    health: Arc<HealthTracker>,
+    tls: Arc<ClientConfig>,
+    /// TLS settings per device keyed by IP or alias, instead of `tls`
+    device_tls: HashMap<String, Arc<ClientConfig>>,
+}
+
+impl WsPool {
+    pub fn new(timeout: Duration, health: Arc<HealthTracker>) -> WsPool {
+        WsPool {
+            connections: Mutex::new(HashMap::new()),
+            timeout,
+            next_id: Arc::new(AtomicU64::new(1)),
+            health,
+            tls: tls_config(&DeviceTls::default()).expect("The built-in roots are valid"),
+            device_tls: HashMap::new(),
+        }
+    }
+
+    /// Use custom TLS settings for `wss://` connections, and other ones for some devices keyed by
+    /// IP or alias
+    pub fn with_tls(self, tls: &DeviceTls, overrides: &HashMap<String, DeviceTls>) -> Result<WsPool, &'static str> {
+        let device_tls = overrides.iter()
+            .map(|(device, tls)| Ok((device.clone(), tls_config(tls)?)))
+            .collect::<Result<_, &'static str>>()?;
+        Ok(WsPool { tls: tls_config(tls)?, device_tls, ..self })
+    }
+
+    /// Whether the connection to the device is currently open, `None` if it was never opened
+    pub fn is_connected(&self, url: &str) -> Option<bool> {
+        self.connections.lock().unwrap()
+            .get(url)
+            .map(|connection| connection.connected.load(Ordering::Relaxed))
+    }
+
+    /// Call `method` on the device over its `ws_url`
+    pub async fn call(&self, device: &ShellySmartPlug, method: &str, params: Value) -> Result<Value, ShellyError> {
+        let (plug, url) = (device.alias.as_str(), device.ws_url());
+        let connection = self.connection(device, &url).await?;
+        let (reply, response) = oneshot::channel();
+        let request = Request { method: method.to_string(), params, reply: (plug.to_string(), reply) };
+
+        if connection.requests.send(request).await.is_err() {
+            return Err(ShellyError::ConnectionLost { plug: plug.to_string() });
+        }
+
+        match tokio::time::timeout(self.timeout, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(ShellyError::ConnectionLost { plug: plug.to_string() }),
+            Err(_) => {
+                error!(alias = plug, %url, method, "Timed out waiting for the RPC response");
+                Err(ShellyError::Timeout { plug: plug.to_string() })
+            }
+        }
+    }
+
+    async fn connection(&self, device: &ShellySmartPlug, url: &str) -> Result<Connection, ShellyError> {
+        let existing = self.connections.lock().unwrap().get(url).cloned();
+        if let Some(connection) = existing {
+            if connection.connected.load(Ordering::Relaxed) {
+                return Ok(connection);
+            }
+        }
+
+        let connection = self.connect(device, url).await?;
+        self.connections.lock().unwrap().insert(url.to_string(), connection.clone());
+        Ok(connection)
+    }
+
+    async fn connect(&self, device: &ShellySmartPlug, url: &str) -> Result<Connection, ShellyError> {
+        let plug = device.alias.clone();
+        // Only used for `wss://` URLs
+        let tls = self.device_tls.get(&device.alias)
+            .or_else(|| self.device_tls.get(&device.target()))
+            .unwrap_or(&self.tls);
+        let connect = tokio_tungstenite::connect_async_tls_with_config(url, None, false, Some(Connector::Rustls(tls.clone())));
+        let stream = match tokio::time::timeout(self.timeout, connect).await {
+            Ok(Ok((stream, _))) => stream,
+            Ok(Err(err)) => {
+                error!(alias = %plug, %url, "Failed to open WebSocket - {err}");
+                return Err(ShellyError::Connect { plug, source: err.into() });
+            }
+            Err(_) => {
+                error!(alias = %plug, %url, "Timed out opening WebSocket");
+                return Err(ShellyError::Timeout { plug });
+            }
+        };
+        info!(alias = %plug, %url, "Opened WebSocket RPC channel");
+
+        let (requests, mut incoming_requests) = mpsc::channel::<Request>(16);
+        let connected = Arc::new(AtomicBool::new(true));
+        let task_connected = connected.clone();
+        let next_id = self.next_id.clone();
+        let health = self.health.clone();
+        let url = url.to_string();
+
+        tokio::spawn(async move {
+            let (mut sink, mut stream) = stream.split();
+            let mut pending: HashMap<u64, Reply> = HashMap::new();
+            let mut lost = true;
+
+            loop {
+                tokio::select! {
+                    request = incoming_requests.recv() => {
+                        let request = match request {
+                            Some(request) => request,
+                            // Pool dropped, nobody left to serve
+                            None => {
+                                lost = false;
+                                break;
+                            }
+                        };
+
+                        let id = next_id.fetch_add(1, Ordering::Relaxed);
+                        let frame = json!({ "id": id, "src": SOURCE, "method": request.method, "params": request.params });
+                        if let Err(err) = sink.send(Message::text(frame.to_string())).await {
+                            warn!(alias = %plug, %url, "Failed to send RPC frame - {err}");
+                            let (plug, reply) = request.reply;
+                            let _ = reply.send(Err(ShellyError::ConnectionLost { plug }));
+                            break;
+                        }
+                        pending.insert(id, request.reply);
+                    }
+                    message = stream.next() => {
+                        match message {
+                            Some(Ok(Message::Text(text))) => handle_frame(&plug, &url, &text, &mut pending),
+                            Some(Ok(Message::Close(_))) | None => break,
+                            // Pings are answered by tungstenite itself
+                            Some(Ok(_)) => {}
+                            Some(Err(err)) => {
+                                warn!(alias = %plug, %url, "WebSocket failed - {err}");
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+
+            task_connected.store(false, Ordering::Relaxed);
+            if !lost {
+                return;
+            }
+            warn!(alias = %plug, %url, "WebSocket RPC channel closed");
+            // Requests waiting on the socket fail and are recorded by their callers, an idle
+            // socket would only be noticed on the next poll
+            if pending.is_empty() {
+                health.record_failure(&plug, &ShellyError::ConnectionLost { plug: plug.clone() });
+            }
+            for (_, (plug, reply)) in pending.drain() {
+                let _ = reply.send(Err(ShellyError::ConnectionLost { plug }));
+            }
+        });
+
+        Ok(Connection { requests, connected })
+    }
+}
+
+fn handle_frame(alias: &str, url: &str, text: &str, pending: &mut HashMap<u64, Reply>) {
+    let frame: Value = match serde_json::from_str(text) {
+        Ok(frame) => frame,
+        Err(err) => {
+            warn!(alias, %url, "Ignoring non-JSON frame - {err}");
+            return;
+        }
+    };
+
+    // Frames without a known id are notifications, which we don't need here
+    let (plug, reply) = match frame["id"].as_u64().and_then(|id| pending.remove(&id)) {
+        Some(reply) => reply,
+        None => return,
+    };
+
+    let result = match (frame.get("result"), frame.get("error")) {
+        (Some(result), _) => Ok(result.clone()),
+        (None, Some(rpc_error)) => {
+            error!(alias, %url, "RPC request failed - {rpc_error}");
+            Err(ShellyError::Rpc {
+                plug,
+                code: rpc_error["code"].as_i64().unwrap_or_default(),
+                message: rpc_error["message"].as_str().unwrap_or_default().to_string(),
+            })
+        }
+        (None, None) => Err(ShellyError::InvalidResponse { plug, source: "Frame without result or error".into() }),
+    };
+    let _ = reply.send(result);
+}
+
+
+/// rustls settings matching those of the HTTP client: the built-in roots plus the extra CAs, or
+/// no verification at all
+pub fn tls_config(tls: &DeviceTls) -> Result<Arc<ClientConfig>, &'static str> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("The ring provider supports the default protocol versions");
+
+    if tls.insecure_skip_verify {
+        let config = builder.dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+            .with_no_client_auth();
+        return Ok(Arc::new(config));
+    }
+
+    let mut roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    if let Some(pem) = &tls.ca_certs {
+        let certs: Vec<CertificateDer> = rustls_pemfile::certs(&mut pem.as_slice())
+            .collect::<Result<_, _>>()
+            .ok()
+            .filter(|certs: &Vec<CertificateDer>| !certs.is_empty())
+            .ok_or_else(|| {
+                error!("No valid PEM certificates found in the device CA bundle");
+                "Invalid CA certificate!"
+            })?;
+        let (_, rejected) = roots.add_parsable_certificates(certs);
+        if rejected > 0 {
+            error!("Rejected {rejected} certificates of the device CA bundle");
+            return Err("Invalid CA certificate!");
+        }
+    }
+    Ok(Arc::new(builder.with_root_certificates(roots).with_no_client_auth()))
+}
+
+
+/// Verifier for `insecure_skip_verify`, signatures are still checked so the handshake completes
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::ServerConfig;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Answer every `Switch.GetStatus` frame, hang up on `Shelly.Hangup` and close the socket after
+    /// answering `Shelly.Bye`
+    async fn serve_device<S: AsyncRead + AsyncWrite + Unpin>(stream: S) {
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+        while let Some(Ok(Message::Text(text))) = ws.next().await {
+            let frame: Value = serde_json::from_str(&text).unwrap();
+            let reply = match frame["method"].as_str().unwrap() {
+                "Switch.GetStatus" | "Shelly.Bye" => json!({ "id": frame["id"], "result": { "apower": 5.0 } }),
+                "Shelly.Hangup" => break,
+                _ => json!({ "id": frame["id"], "error": { "code": 404, "message": "No handler" } }),
+            };
+            // Notifications are interleaved with the responses on a real device
+            ws.send(Message::text(json!({ "method": "NotifyStatus", "params": {} }).to_string())).await.unwrap();
+            ws.send(Message::text(reply.to_string())).await.unwrap();
+            if frame["method"] == "Shelly.Bye" {
+                break;
+            }
+        }
+    }
+
+    /// Fake device accepting a single connection, the plug `kettle` pointing at it
+    async fn fake_device() -> ShellySmartPlug {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            serve_device(tcp).await;
+        });
+
+        ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address(&addr.to_string()).unwrap() }
+    }
+
+    /// Fake device behind HTTPS with a self-signed certificate for `localhost`, and its PEM
+    async fn fake_tls_device() -> (ShellySmartPlug, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(tls) = acceptor.accept(tcp).await {
+                        serve_device(tls).await;
+                    }
+                });
+            }
+        });
+
+        let plug = ShellySmartPlug { alias: "kettle".to_string(), ..ShellySmartPlug::from_address(&format!("https://localhost:{port}")).unwrap() };
+        (plug, cert.cert.pem())
+    }
+
+    #[tokio::test]
+    async fn test_call_and_disconnect() {
+        let plug = fake_device().await;
+        let url = plug.ws_url();
+        let pool = WsPool::new(Duration::from_secs(5), Arc::new(HealthTracker::new()));
+
+        assert_eq!(pool.is_connected(&url), None);
+
+        let actual = pool.call(&plug, "Switch.GetStatus", json!({ "id": 0 })).await.unwrap();
+        assert_eq!(actual, json!({ "apower": 5.0 }));
+        assert_eq!(pool.is_connected(&url), Some(true));
+
+        let rpc_error = pool.call(&plug, "Nope.Nope", json!({})).await;
+        assert!(matches!(rpc_error, Err(ShellyError::Rpc { code: 404, .. })), "{rpc_error:?}");
+
+        let hangup = pool.call(&plug, "Shelly.Hangup", json!({})).await;
+        assert!(matches!(hangup, Err(ShellyError::ConnectionLost { plug }) if plug == "kettle"));
+        assert_eq!(pool.is_connected(&url), Some(false));
+        // The failed call is recorded by its caller
+        assert!(pool.health.get("kettle").is_none());
+
+        // Nothing is listening anymore, reconnecting fails
+        let reconnect = pool.call(&plug, "Switch.GetStatus", json!({ "id": 0 })).await;
+        assert!(matches!(reconnect, Err(ShellyError::Connect { .. })), "{reconnect:?}");
+    }
+
+    #[tokio::test]
+    async fn test_idle_disconnect_marks_plug_down() {
+        let plug = fake_device().await;
+        let health = Arc::new(HealthTracker::new());
+        let pool = WsPool::new(Duration::from_secs(5), health.clone());
+
+        health.record_success("kettle", chrono::Utc::now());
+        pool.call(&plug, "Shelly.Bye", json!({})).await.unwrap();
+
+        let down = async {
+            while health.get("kettle").unwrap().up {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), down).await.unwrap();
+        assert_eq!(health.get("kettle").unwrap().errors.get("connect"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_wss() {
+        let (plug, ca) = fake_tls_device().await;
+        assert!(plug.ws_url().starts_with("wss://localhost:"));
+        let health = Arc::new(HealthTracker::new());
+
+        let untrusted = WsPool::new(Duration::from_secs(5), health.clone());
+        let actual = untrusted.call(&plug, "Switch.GetStatus", json!({ "id": 0 })).await;
+        assert!(matches!(actual, Err(ShellyError::Connect { .. })), "{actual:?}");
+
+        let trusted = DeviceTls { ca_certs: Some(ca.into_bytes()), insecure_skip_verify: false };
+        let pool = WsPool::new(Duration::from_secs(5), health.clone()).with_tls(&trusted, &HashMap::new()).unwrap();
+        assert_eq!(pool.call(&plug, "Switch.GetStatus", json!({ "id": 0 })).await.unwrap(), json!({ "apower": 5.0 }));
+
+        let insecure = DeviceTls { ca_certs: None, insecure_skip_verify: true };
+        let pool = WsPool::new(Duration::from_secs(5), health.clone())
+            .with_tls(&DeviceTls::default(), &HashMap::from([("kettle".to_string(), insecure)]))
+            .unwrap();
+        assert_eq!(pool.call(&plug, "Switch.GetStatus", json!({ "id": 0 })).await.unwrap(), json!({ "apower": 5.0 }));
+
+        let garbage = DeviceTls { ca_certs: Some(b"not a certificate".to_vec()), insecure_skip_verify: false };
+        let actual = WsPool::new(Duration::from_secs(5), health).with_tls(&garbage, &HashMap::new());
+        assert_eq!(actual.err(), Some("Invalid CA certificate!"));
+    }
+}